@@ -0,0 +1,643 @@
+//! Reading and writing exported directories in the file formats supported by `snas admin export`
+//! and `snas admin import`
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use snas_lib::admin::{ConflictPolicy, Directory, GroupRecord, UserRecord};
+use snas_lib::handlers::DEFAULT_RESET_EXPIRY;
+use snas_lib::{PasswordResetPhase, PosixAttributes, SecureString};
+
+/// The file formats a directory can be exported to and imported from
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum DirectoryFormat {
+    /// One JSON object per line, each a user or a group
+    Jsonl,
+    /// A CSV file with a row per user or group
+    Csv,
+    /// Classic passwd, shadow and group files, all in one directory
+    Passwd,
+}
+
+/// What to do with imported users and groups that already exist
+#[derive(ValueEnum, Debug, Clone, Copy, Default)]
+pub enum OnConflict {
+    /// Leave the existing entry as it is
+    Skip,
+    /// Replace the existing entry
+    Overwrite,
+    /// Don't import anything
+    #[default]
+    Fail,
+}
+
+impl From<OnConflict> for ConflictPolicy {
+    fn from(value: OnConflict) -> Self {
+        match value {
+            OnConflict::Skip => ConflictPolicy::Skip,
+            OnConflict::Overwrite => ConflictPolicy::Overwrite,
+            OnConflict::Fail => ConflictPolicy::Fail,
+        }
+    }
+}
+
+/// Separates group names in a single CSV field
+const CSV_GROUP_SEPARATOR: char = ';';
+
+/// A single line of a JSON lines export
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum JsonRecord {
+    User(UserRecord),
+    Group(GroupRecord),
+}
+
+/// A single row of a CSV export. Users and groups share the same columns, and any column that
+/// doesn't apply is left empty
+#[derive(Serialize, Deserialize, Default)]
+struct CsvRecord {
+    kind: String,
+    name: String,
+    #[serde(default)]
+    password_hash: String,
+    #[serde(default)]
+    password_change_phase: String,
+    #[serde(default)]
+    groups: String,
+    #[serde(default)]
+    uid: Option<u32>,
+    #[serde(default)]
+    gid: Option<u32>,
+    #[serde(default)]
+    home: String,
+    #[serde(default)]
+    shell: String,
+    #[serde(default)]
+    gecos: String,
+    #[serde(default)]
+    description: String,
+}
+
+/// Writes the directory in the given format. JSON lines and CSV are written to stdout if no path
+/// is given. The passwd format needs a directory to write its three files to. Existing files are
+/// never overwritten, as exports contain password hashes
+pub fn write(
+    format: DirectoryFormat,
+    directory: &Directory,
+    path: Option<&Path>,
+) -> anyhow::Result<()> {
+    match format {
+        DirectoryFormat::Jsonl => with_output(path, |out| write_jsonl(directory, out)),
+        DirectoryFormat::Csv => with_output(path, |out| write_csv(directory, out)),
+        DirectoryFormat::Passwd => {
+            let dir = path.context("a directory is required for the passwd format")?;
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("unable to create directory {}", dir.display()))?;
+            let files = passwd_files(directory);
+            for (name, contents, mode) in [
+                ("passwd", files.passwd, 0o644),
+                ("group", files.group, 0o644),
+                ("shadow", files.shadow, 0o600),
+            ] {
+                let mut file = create_file(&dir.join(name), mode)?;
+                file.write_all(contents.as_bytes())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Reads a directory in the given format. JSON lines and CSV are read from stdin if no path is
+/// given. The passwd format reads the passwd, shadow and group files from the given directory
+pub fn read(format: DirectoryFormat, path: Option<&Path>) -> anyhow::Result<Directory> {
+    match format {
+        DirectoryFormat::Jsonl => with_input(path, read_jsonl),
+        DirectoryFormat::Csv => with_input(path, read_csv),
+        DirectoryFormat::Passwd => {
+            let dir = path.context("a directory is required for the passwd format")?;
+            let read_file = |name: &str| {
+                let path = dir.join(name);
+                std::fs::read_to_string(&path)
+                    .with_context(|| format!("unable to read {}", path.display()))
+            };
+            let shadow = match read_file("shadow") {
+                Ok(shadow) => shadow,
+                Err(err) => {
+                    eprintln!("warning: {err:#}, imported users will have no password");
+                    String::new()
+                }
+            };
+            parse_passwd_files(&read_file("passwd")?, &shadow, &read_file("group")?)
+        }
+    }
+}
+
+fn with_output(
+    path: Option<&Path>,
+    f: impl FnOnce(&mut dyn Write) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    match path {
+        Some(path) => {
+            let mut out = BufWriter::new(create_file(path, 0o600)?);
+            f(&mut out)?;
+            out.flush().map_err(Into::into)
+        }
+        None => {
+            let mut out = std::io::stdout().lock();
+            f(&mut out)?;
+            out.flush().map_err(Into::into)
+        }
+    }
+}
+
+fn with_input(
+    path: Option<&Path>,
+    f: impl FnOnce(&mut dyn BufRead) -> anyhow::Result<Directory>,
+) -> anyhow::Result<Directory> {
+    match path {
+        Some(path) => {
+            let file =
+                File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+            f(&mut BufReader::new(file))
+        }
+        None => f(&mut std::io::stdin().lock()),
+    }
+}
+
+fn create_file(path: &Path, mode: u32) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)
+        .with_context(|| format!("unable to create {}", path.display()))
+}
+
+fn write_jsonl(directory: &Directory, out: &mut dyn Write) -> anyhow::Result<()> {
+    let records = directory
+        .groups
+        .iter()
+        .cloned()
+        .map(JsonRecord::Group)
+        .chain(directory.users.iter().cloned().map(JsonRecord::User));
+    for record in records {
+        serde_json::to_writer(&mut *out, &record)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn read_jsonl(input: &mut dyn BufRead) -> anyhow::Result<Directory> {
+    let mut directory = Directory::default();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line)
+            .with_context(|| format!("invalid record on line {}", i + 1))?
+        {
+            JsonRecord::User(user) => directory.users.push(user),
+            JsonRecord::Group(group) => directory.groups.push(group),
+        }
+    }
+    Ok(directory)
+}
+
+fn write_csv(directory: &Directory, out: &mut dyn Write) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    for group in directory.groups.iter() {
+        writer.serialize(CsvRecord {
+            kind: "group".to_string(),
+            name: group.name.clone(),
+            gid: Some(group.gid),
+            description: group.description.clone(),
+            ..Default::default()
+        })?;
+    }
+    for user in directory.users.iter() {
+        let posix = user.posix.as_ref();
+        writer.serialize(CsvRecord {
+            kind: "user".to_string(),
+            name: user.username.clone(),
+            password_hash: user
+                .password_hash
+                .as_ref()
+                .map(|hash| AsRef::<str>::as_ref(hash).to_owned())
+                .unwrap_or_default(),
+            password_change_phase: format_phase(user.password_change_phase.as_ref()),
+            groups: user
+                .groups
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(&CSV_GROUP_SEPARATOR.to_string()),
+            uid: posix.map(|p| p.uid),
+            gid: posix.map(|p| p.gid),
+            home: posix.map(|p| p.home.clone()).unwrap_or_default(),
+            shell: posix.map(|p| p.shell.clone()).unwrap_or_default(),
+            gecos: posix.map(|p| p.gecos.clone()).unwrap_or_default(),
+            description: String::new(),
+        })?;
+    }
+    writer.flush().map_err(Into::into)
+}
+
+fn read_csv(input: &mut dyn BufRead) -> anyhow::Result<Directory> {
+    let mut directory = Directory::default();
+    let mut reader = csv::Reader::from_reader(input);
+    for (i, record) in reader.deserialize::<CsvRecord>().enumerate() {
+        // The header is the first line, so records start on line 2
+        let line = i + 2;
+        let record = record.with_context(|| format!("invalid record on line {line}"))?;
+        match record.kind.as_str() {
+            "group" => directory.groups.push(GroupRecord {
+                name: record.name,
+                gid: record
+                    .gid
+                    .with_context(|| format!("group on line {line} has no gid"))?,
+                description: record.description,
+            }),
+            "user" => {
+                let posix = match (record.uid, record.gid) {
+                    (Some(uid), gid) => Some(PosixAttributes {
+                        uid,
+                        gid: gid.unwrap_or(uid),
+                        home: record.home,
+                        shell: record.shell,
+                        gecos: record.gecos,
+                    }),
+                    (None, None) => None,
+                    (None, Some(_)) => anyhow::bail!("user on line {line} has a gid but no uid"),
+                };
+                directory.users.push(UserRecord {
+                    username: record.name,
+                    password_hash: (!record.password_hash.is_empty())
+                        .then(|| SecureString::from(record.password_hash)),
+                    password_change_phase: parse_phase(&record.password_change_phase)
+                        .with_context(|| format!("invalid password change phase on line {line}"))?,
+                    groups: record
+                        .groups
+                        .split(CSV_GROUP_SEPARATOR)
+                        .filter(|group| !group.is_empty())
+                        .map(ToOwned::to_owned)
+                        .collect(),
+                    posix,
+                });
+            }
+            other => anyhow::bail!("unknown kind {other} on line {line}"),
+        }
+    }
+    Ok(directory)
+}
+
+/// Formats a password change phase for a CSV field as `reset:<expiry>`, `initial_login:<expiry>` or
+/// `locked`, with expiries in seconds since the Unix epoch
+fn format_phase(phase: Option<&PasswordResetPhase>) -> String {
+    match phase {
+        None => String::new(),
+        Some(PasswordResetPhase::Reset(expiry)) => format!("reset:{}", expiry.as_secs()),
+        Some(PasswordResetPhase::InitialLogin(expiry)) => {
+            format!("initial_login:{}", expiry.as_secs())
+        }
+        Some(PasswordResetPhase::Locked) => "locked".to_string(),
+    }
+}
+
+fn parse_phase(phase: &str) -> anyhow::Result<Option<PasswordResetPhase>> {
+    let expiry = |secs: &str| -> anyhow::Result<Duration> {
+        Ok(Duration::from_secs(secs.parse().context("invalid expiry")?))
+    };
+    Ok(match phase.split_once(':') {
+        None if phase.is_empty() => None,
+        None if phase == "locked" => Some(PasswordResetPhase::Locked),
+        Some(("reset", secs)) => Some(PasswordResetPhase::Reset(expiry(secs)?)),
+        Some(("initial_login", secs)) => Some(PasswordResetPhase::InitialLogin(expiry(secs)?)),
+        _ => anyhow::bail!("unknown phase {phase}"),
+    })
+}
+
+struct PasswdFiles {
+    passwd: String,
+    shadow: String,
+    group: String,
+}
+
+/// Renders the directory as passwd, shadow and group files. These can't represent everything, so
+/// anything that is left out is warned about
+fn passwd_files(directory: &Directory) -> PasswdFiles {
+    let mut files = PasswdFiles {
+        passwd: String::new(),
+        shadow: String::new(),
+        group: String::new(),
+    };
+    let mut members: BTreeMap<&str, Vec<&str>> = directory
+        .groups
+        .iter()
+        .map(|group| (group.name.as_str(), Vec::new()))
+        .collect();
+    let mut private_groups = Vec::new();
+    for user in directory.users.iter() {
+        let Some(posix) = user.posix.as_ref() else {
+            eprintln!(
+                "warning: user {} has no POSIX attributes and was left out",
+                user.username
+            );
+            continue;
+        };
+        files.passwd.push_str(&format!(
+            "{}:x:{}:{}:{}:{}:{}\n",
+            user.username, posix.uid, posix.gid, posix.gecos, posix.home, posix.shell
+        ));
+        let hash = user
+            .password_hash
+            .as_ref()
+            .map(|hash| hash.as_ref())
+            .unwrap_or("*");
+        // A `!` prefix locks the account, and a last change of 0 forces a password change
+        let (hash_prefix, last_change) = match user.password_change_phase {
+            None => ("", ""),
+            Some(PasswordResetPhase::Reset(_) | PasswordResetPhase::InitialLogin(_)) => ("", "0"),
+            Some(PasswordResetPhase::Locked) => ("!", ""),
+        };
+        files.shadow.push_str(&format!(
+            "{}:{hash_prefix}{hash}:{last_change}::::::\n",
+            user.username
+        ));
+        for group in user.groups.iter() {
+            match members.get_mut(group.as_str()) {
+                Some(members) => members.push(&user.username),
+                None => eprintln!(
+                    "warning: group {group} has no GID, so it was left out of the groups for user {}",
+                    user.username
+                ),
+            }
+        }
+        if posix.gid == posix.uid && !members.contains_key(user.username.as_str()) {
+            private_groups.push((user.username.as_str(), posix.gid));
+        }
+    }
+    for group in directory.groups.iter() {
+        files.group.push_str(&format!(
+            "{}:x:{}:{}\n",
+            group.name,
+            group.gid,
+            members[group.name.as_str()].join(",")
+        ));
+    }
+    for (name, gid) in private_groups {
+        files.group.push_str(&format!("{name}:x:{gid}:\n"));
+    }
+    files
+}
+
+/// Parses passwd, shadow and group files into a directory. User private groups (ones named after
+/// a user with that user's primary GID) are left out, as SNAS creates those for every user
+fn parse_passwd_files(passwd: &str, shadow: &str, group: &str) -> anyhow::Result<Directory> {
+    let mut users: BTreeMap<String, UserRecord> = BTreeMap::new();
+    for (i, line) in entries(passwd) {
+        let fields: Vec<&str> = line.split(':').collect();
+        let [name, _, uid, gid, gecos, home, shell] = fields[..] else {
+            anyhow::bail!("passwd line {i} should have 7 fields");
+        };
+        let posix = PosixAttributes {
+            uid: uid
+                .parse()
+                .with_context(|| format!("invalid UID on passwd line {i}"))?,
+            gid: gid
+                .parse()
+                .with_context(|| format!("invalid GID on passwd line {i}"))?,
+            home: home.to_string(),
+            shell: shell.to_string(),
+            gecos: gecos.to_string(),
+        };
+        users.insert(
+            name.to_string(),
+            UserRecord {
+                username: name.to_string(),
+                password_hash: None,
+                password_change_phase: None,
+                groups: BTreeSet::new(),
+                posix: Some(posix),
+            },
+        );
+    }
+
+    for (i, line) in entries(shadow) {
+        let mut fields = line.split(':');
+        let (Some(name), Some(hash)) = (fields.next(), fields.next()) else {
+            anyhow::bail!("shadow line {i} should have at least 2 fields");
+        };
+        let Some(user) = users.get_mut(name) else {
+            eprintln!("warning: {name} is in the shadow file but not the passwd file");
+            continue;
+        };
+        // A `!` prefix locks an account without losing its hash
+        let unlocked = hash.trim_start_matches('!');
+        let locked = unlocked.len() != hash.len();
+        // Anything else that isn't a hash (such as `*`) means the account has no password
+        if unlocked.starts_with('$') {
+            user.password_hash = Some(SecureString::from(unlocked));
+        }
+        user.password_change_phase = if locked {
+            Some(PasswordResetPhase::Locked)
+        } else if fields.next() == Some("0") {
+            Some(PasswordResetPhase::Reset(reset_expiry()?))
+        } else {
+            None
+        };
+    }
+
+    let mut groups = Vec::new();
+    for (i, line) in entries(group) {
+        let fields: Vec<&str> = line.split(':').collect();
+        let [name, _, gid, members] = fields[..] else {
+            anyhow::bail!("group line {i} should have 4 fields");
+        };
+        let gid: u32 = gid
+            .parse()
+            .with_context(|| format!("invalid GID on group line {i}"))?;
+        for member in members.split(',').filter(|member| !member.is_empty()) {
+            match users.get_mut(member) {
+                Some(user) => {
+                    user.groups.insert(name.to_string());
+                }
+                None => eprintln!("warning: member {member} of group {name} is not a user"),
+            }
+        }
+        let is_private_group = users
+            .get(name)
+            .and_then(|user| user.posix.as_ref())
+            .is_some_and(|posix| posix.gid == gid);
+        if !is_private_group {
+            groups.push(GroupRecord {
+                name: name.to_string(),
+                gid,
+                description: String::new(),
+            });
+        }
+    }
+
+    Ok(Directory {
+        users: users.into_values().collect(),
+        groups,
+    })
+}
+
+/// Returns the non empty, non comment lines of a file along with their line numbers
+fn entries(contents: &str) -> impl Iterator<Item = (usize, &str)> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim_end_matches('\r')))
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+}
+
+/// The expiry a forced password change gets, which matches what the server gives new users
+fn reset_expiry() -> anyhow::Result<Duration> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("system time is before the Unix epoch")?;
+    Ok(now + DEFAULT_RESET_EXPIRY)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn directory() -> Directory {
+        Directory {
+            users: vec![
+                UserRecord {
+                    username: "alice".to_string(),
+                    password_hash: Some("$6$salt$hash".into()),
+                    password_change_phase: None,
+                    groups: ["admins".to_string()].into(),
+                    posix: Some(PosixAttributes {
+                        uid: 10000,
+                        gid: 10000,
+                        home: "/home/alice".to_string(),
+                        shell: "/bin/bash".to_string(),
+                        gecos: "Alice, Room 1".to_string(),
+                    }),
+                },
+                UserRecord {
+                    username: "bob".to_string(),
+                    password_hash: Some("$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA".into()),
+                    password_change_phase: Some(PasswordResetPhase::Locked),
+                    groups: ["admins".to_string(), "users".to_string()].into(),
+                    posix: Some(PosixAttributes {
+                        uid: 10001,
+                        gid: 20000,
+                        home: "/home/bob".to_string(),
+                        shell: "/bin/zsh".to_string(),
+                        gecos: String::new(),
+                    }),
+                },
+            ],
+            groups: vec![
+                GroupRecord {
+                    name: "admins".to_string(),
+                    gid: 20000,
+                    description: "Admins, of course".to_string(),
+                },
+                GroupRecord {
+                    name: "users".to_string(),
+                    gid: 20001,
+                    description: String::new(),
+                },
+            ],
+        }
+    }
+
+    /// Compares directories through JSON, as the records don't implement `PartialEq`
+    fn assert_same(actual: &Directory, expected: &Directory) {
+        assert_eq!(
+            serde_json::to_value(actual).unwrap(),
+            serde_json::to_value(expected).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_jsonl_round_trip() {
+        let mut out = Vec::new();
+        write_jsonl(&directory(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(r#"{"kind":"group","name":"admins","gid":20000"#));
+        assert_eq!(out.lines().count(), 4);
+
+        let parsed = read_jsonl(&mut out.as_bytes()).expect("Should parse the export");
+        assert_same(&parsed, &directory());
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let mut out = Vec::new();
+        write_csv(&directory(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\"Admins, of course\""));
+        assert!(out.contains("user,bob,"));
+        assert!(out.contains(",locked,admins;users,10001,20000,"));
+
+        let parsed = read_csv(&mut out.as_bytes()).expect("Should parse the export");
+        assert_same(&parsed, &directory());
+    }
+
+    #[test]
+    fn test_passwd_round_trip() {
+        let files = passwd_files(&directory());
+        assert_eq!(
+            files.passwd,
+            "alice:x:10000:10000:Alice, Room 1:/home/alice:/bin/bash\nbob:x:10001:20000::/home/bob:/bin/zsh\n"
+        );
+        assert!(files
+            .shadow
+            .contains("bob:!$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA:::::::\n"));
+        assert_eq!(
+            files.group,
+            "admins:x:20000:alice,bob\nusers:x:20001:bob\nalice:x:10000:\n"
+        );
+
+        let parsed = parse_passwd_files(&files.passwd, &files.shadow, &files.group)
+            .expect("Should parse the export");
+        // Descriptions aren't part of the group file
+        let mut expected = directory();
+        expected.groups[0].description = String::new();
+        assert_same(&parsed, &expected);
+    }
+
+    #[test]
+    fn test_parse_system_files() {
+        let passwd = "# Some comment\nalice:x:1000:1000:Alice:/home/alice:/bin/bash\nbob:x:1001:100::/home/bob:/bin/sh\ncarol:x:1002:1002::/home/carol:/bin/sh\n";
+        let shadow = "alice:$y$j9T$salt$hash:19000:0:99999:7:::\nbob:!!:0::::::\ncarol:$6$salt$hash:0:0:99999:7:::\n";
+        let group = "users:x:100:alice\nalice:x:1000:\n";
+        let parsed = parse_passwd_files(passwd, shadow, group).expect("Should parse the files");
+
+        assert_eq!(parsed.groups.len(), 1, "Private groups should be skipped");
+        assert_eq!(parsed.groups[0].name, "users");
+        let alice = &parsed.users[0];
+        assert_eq!(alice.groups, ["users".to_string()].into());
+        assert!(alice.password_change_phase.is_none());
+        let bob = &parsed.users[1];
+        assert!(bob.password_hash.is_none(), "Bob has no usable password");
+        assert!(matches!(
+            bob.password_change_phase,
+            Some(PasswordResetPhase::Locked)
+        ));
+        let carol = &parsed.users[2];
+        assert!(matches!(
+            carol.password_change_phase,
+            Some(PasswordResetPhase::Reset(_))
+        ));
+
+        parse_passwd_files("alice:x:nope:1000::/:/bin/sh\n", "", "")
+            .expect_err("Invalid UIDs should be rejected");
+    }
+}
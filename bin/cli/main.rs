@@ -1,15 +1,34 @@
 use std::collections::BTreeSet;
 use std::path::PathBuf;
+use std::process::ExitCode;
 
 use anyhow::Context;
 use async_nats::ConnectOptions;
-use clap::{Parser, Subcommand};
-use snas_lib::clients::NatsClient;
-use snas_lib::SecureString;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use snas_lib::admin::PosixAttributesRequest;
+use snas_lib::api::{ErrorKind, ResponseError};
+use snas_lib::clients::{AdminClient, NatsClient, SocketClient, UserClient};
+use snas_lib::{SecureString, DEFAULT_SOCKET_PATH};
+
+use directory::{DirectoryFormat, OnConflict};
+use output::OutputFormat;
+
+mod directory;
+mod output;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about = "SNAS admin CLI", long_about = None)]
+#[command(author, version, about = "SNAS CLI", long_about = None)]
 struct Cli {
+    #[command(flatten)]
+    nats: NatsArgs,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+/// Options for connecting to NATS. The connection is only made for commands that need it
+#[derive(Args, Debug)]
+struct NatsArgs {
     /// NATS server host
     #[arg(
         long = "nats-server",
@@ -51,105 +70,588 @@ struct Cli {
     /// Optional JetStream domain
     #[arg(long = "js-domain", env = "SNAS_JS_DOMAIN")]
     _js_domain: Option<String>,
-
-    #[command(subcommand)]
-    command: Commands,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Administrative actions
     Admin {
+        /// Optional topic prefix for the admin NATS API
+        #[arg(long = "admin-topic-prefix", global = true)]
+        admin_topic_prefix: Option<String>,
+        /// The format to print results in
+        #[arg(long, short = 'o', value_enum, default_value_t, global = true)]
+        output: OutputFormat,
         #[command(subcommand)]
         command: AdminCmd,
     },
+    /// Self-service actions for your own account
+    User {
+        /// How to connect to SNAS
+        #[arg(
+            long,
+            value_enum,
+            default_value_t,
+            global = true,
+            env = "SNAS_USER_TRANSPORT"
+        )]
+        transport: Transport,
+        /// Path to the SNAS user socket when using the socket transport
+        #[arg(
+            long = "socket-path",
+            default_value = DEFAULT_SOCKET_PATH,
+            global = true,
+            env = "SNAS_SOCKET_PATH"
+        )]
+        socket_path: PathBuf,
+        /// Optional topic prefix for the user NATS API when using the NATS transport
+        #[arg(long = "user-topic-prefix", global = true)]
+        user_topic_prefix: Option<String>,
+        #[command(subcommand)]
+        command: UserCmd,
+    },
+}
+
+/// The transport used to reach the user API
+#[derive(ValueEnum, Debug, Clone, Copy, Default)]
+enum Transport {
+    /// The local unix socket
+    #[default]
+    Socket,
+    /// The NATS user API
+    Nats,
+}
+
+/// Exit codes returned by the user commands so that scripts can tell failures apart. Any other
+/// error (such as being unable to reach the server) exits with 1
+mod exit_code {
+    /// The current password was rejected, either because it is wrong or the account is locked
+    pub const INVALID_CREDENTIALS: u8 = 2;
+    /// The password is valid but has to be changed before it can be used to log in. When changing
+    /// a password, this means the temporary password has expired and an admin has to reset it again
+    pub const NEEDS_PASSWORD_RESET: u8 = 3;
+    /// The new password doesn't satisfy the password policy
+    pub const PASSWORD_REJECTED: u8 = 4;
+}
+
+#[derive(Subcommand, Debug)]
+enum UserCmd {
+    /// Change your password. The current and new passwords are prompted for. Exits with 2 if the
+    /// current password is wrong, 3 if it was a temporary password that has expired, and 4 if the
+    /// new password is rejected
+    Passwd {
+        /// Username. Defaults to the current user
+        #[arg(long, env = "USER")]
+        username: String,
+    },
+    /// Check that a password is valid for a user. Exits with 0 if it is valid, 2 if it is not, and
+    /// 3 if it is valid but has to be changed
+    Verify {
+        /// Username. Defaults to the current user
+        #[arg(long, env = "USER")]
+        username: String,
+    },
 }
 
 #[derive(Subcommand, Debug)]
 enum AdminCmd {
-    /// Add a user
+    /// Add a user. The password is prompted for unless --password-stdin is given
     AddUser {
         /// Username
         #[arg(long)]
         username: String,
-        /// Password
-        #[arg(long)]
-        password: String,
+        /// Read the password from the first line of stdin instead of prompting for it
+        #[arg(long = "password-stdin", default_value_t = false)]
+        password_stdin: bool,
+        /// The password is an existing sha512-crypt, sha256-crypt, bcrypt or Argon2 hash to import,
+        /// such as one from /etc/shadow. It is converted to Argon2 when the user first logs in
+        #[arg(long = "prehashed", default_value_t = false)]
+        prehashed: bool,
         /// Group memberships (can repeat)
         #[arg(long = "group")]
         groups: Vec<String>,
         /// Force password change on first login
         #[arg(long = "force-reset", default_value_t = false)]
         force_reset: bool,
-        /// Optional user topic prefix for user/admin APIs
-        #[arg(long = "user-topic-prefix")]
-        user_topic_prefix: Option<String>,
-        /// Optional admin topic prefix for admin APIs
-        #[arg(long = "admin-topic-prefix")]
-        admin_topic_prefix: Option<String>,
+        #[command(flatten)]
+        posix: PosixArgs,
+    },
+    /// Get a user
+    GetUser {
+        /// Username
+        #[arg(long)]
+        username: String,
+    },
+    /// List all usernames
+    ListUsers,
+    /// Remove a user
+    RemoveUser {
+        /// Username
+        #[arg(long)]
+        username: String,
+    },
+    /// Reset a user's password, returning a temporary password they must change on login
+    ResetPassword {
+        /// Username
+        #[arg(long)]
+        username: String,
+    },
+    /// Set a user's password without making them change it, such as for a service account. The
+    /// password is prompted for unless --password-stdin is given
+    SetPassword {
+        /// Username
+        #[arg(long)]
+        username: String,
+        /// Read the password from the first line of stdin instead of prompting for it
+        #[arg(long = "password-stdin", default_value_t = false)]
+        password_stdin: bool,
+        /// Force password change on next login
+        #[arg(long = "force-reset", default_value_t = false)]
+        force_reset: bool,
+    },
+    /// Unlock a user that was locked out after too many failed login attempts
+    UnlockUser {
+        /// Username
+        #[arg(long)]
+        username: String,
+    },
+    /// Set the POSIX attributes of a user. Attributes that aren't given are left unchanged
+    SetAttributes {
+        /// Username
+        #[arg(long)]
+        username: String,
+        #[command(flatten)]
+        posix: PosixArgs,
+    },
+    /// Add groups to a user
+    AddGroups {
+        /// Username
+        #[arg(long)]
+        username: String,
+        /// Groups to add (can repeat)
+        #[arg(long = "group", required = true)]
+        groups: Vec<String>,
+    },
+    /// Remove groups from a user
+    RemoveGroups {
+        /// Username
+        #[arg(long)]
+        username: String,
+        /// Groups to remove (can repeat)
+        #[arg(long = "group", required = true)]
+        groups: Vec<String>,
+    },
+    /// Create a group
+    AddGroup {
+        /// Group name
+        #[arg(long)]
+        name: String,
+        /// GID for the group. If not set, the server allocates one
+        #[arg(long)]
+        gid: Option<u32>,
+        /// Description of the group
+        #[arg(long, default_value = "")]
+        description: String,
+    },
+    /// Get a group
+    GetGroup {
+        /// Group name
+        #[arg(long)]
+        name: String,
+    },
+    /// List all group names
+    ListGroups,
+    /// Remove a group, removing it from all of its members
+    RemoveGroup {
+        /// Group name
+        #[arg(long)]
+        name: String,
+    },
+    /// List the members of a group
+    GroupMembers {
+        /// Group name
+        #[arg(long)]
+        name: String,
+    },
+    /// Export every user (including their password hash) and group
+    Export {
+        /// The format to export in
+        #[arg(long, value_enum)]
+        format: DirectoryFormat,
+        /// Where to write the export. For the passwd format, this is a directory that the passwd,
+        /// shadow and group files are written to. Otherwise it is a file, and the export is written
+        /// to stdout if it isn't given. Existing files are never overwritten
+        #[arg(long)]
+        path: Option<PathBuf>,
+    },
+    /// Import users and groups from an export or from passwd, shadow and group files
+    Import {
+        /// The format to import from
+        #[arg(long, value_enum)]
+        format: DirectoryFormat,
+        /// Where to read the import from. For the passwd format, this is a directory containing the
+        /// passwd, shadow and group files. Otherwise it is a file, and the import is read from
+        /// stdin if it isn't given
+        #[arg(long)]
+        path: Option<PathBuf>,
+        /// What to do with users and groups that already exist
+        #[arg(long = "on-conflict", value_enum, default_value_t)]
+        on_conflict: OnConflict,
+        /// Check the import and show what would happen without changing anything
+        #[arg(long = "dry-run", default_value_t = false)]
+        dry_run: bool,
     },
 }
 
+#[derive(Args, Debug)]
+struct PosixArgs {
+    /// UID for the user. If not set, the server allocates one
+    #[arg(long)]
+    uid: Option<u32>,
+    /// Primary GID for the user. Defaults to the UID
+    #[arg(long)]
+    gid: Option<u32>,
+    /// Home directory for the user. Defaults to a directory named after the user in the server's
+    /// home base
+    #[arg(long)]
+    home: Option<String>,
+    /// Login shell for the user. Defaults to the server's default shell
+    #[arg(long)]
+    shell: Option<String>,
+    /// Full name or other descriptive information about the user
+    #[arg(long)]
+    gecos: Option<String>,
+}
+
+impl From<PosixArgs> for PosixAttributesRequest {
+    fn from(args: PosixArgs) -> Self {
+        PosixAttributesRequest {
+            uid: args.uid,
+            gid: args.gid,
+            home: args.home,
+            shell: args.shell,
+            gecos: args.gecos,
+        }
+    }
+}
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> anyhow::Result<ExitCode> {
     let cli = Cli::parse();
-    let nc = get_nats_client(
-        format!("{}:{}", cli.nats_server, cli.nats_port),
-        cli.creds,
-        cli.nats_username,
-        cli.nats_password,
-        cli.nats_ca_cert,
-    )
-    .await?;
 
     match cli.command {
-        Commands::Admin { command: admin } => match admin {
-            AdminCmd::AddUser {
-                username,
-                password,
-                groups,
-                force_reset,
-                user_topic_prefix,
-                admin_topic_prefix,
-            } => {
-                use snas_lib::clients::AdminClient;
-                let client =
-                    NatsClient::new_with_prefix(nc, user_topic_prefix, admin_topic_prefix)?;
-                let groups: BTreeSet<String> = groups.into_iter().collect();
+        Commands::Admin {
+            admin_topic_prefix,
+            output,
+            command,
+        } => {
+            let nc = cli.nats.connect().await?;
+            let client = NatsClient::new_with_prefix(nc, None, admin_topic_prefix)?;
+            run_admin(client, output, command).await?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Commands::User {
+            transport,
+            socket_path,
+            user_topic_prefix,
+            command,
+        } => match transport {
+            Transport::Socket => {
+                let client = SocketClient::new(&socket_path).await.with_context(|| {
+                    format!("Unable to connect to socket {}", socket_path.display())
+                })?;
+                run_user(client, command).await
+            }
+            Transport::Nats => {
+                let nc = cli.nats.connect().await?;
+                let client = NatsClient::new_with_prefix(nc, user_topic_prefix, None)?;
+                run_user(client, command).await
+            }
+        },
+    }
+}
+
+async fn run_admin(
+    client: impl AdminClient,
+    output: OutputFormat,
+    command: AdminCmd,
+) -> anyhow::Result<()> {
+    match command {
+        AdminCmd::AddUser {
+            username,
+            password_stdin,
+            prehashed,
+            groups,
+            force_reset,
+            posix,
+        } => {
+            let password = if password_stdin {
+                read_password_stdin()?
+            } else if prehashed {
+                // There's no point confirming a hash, it was copied from somewhere
+                prompt_password("Password hash: ")?
+            } else {
+                prompt_new_password("Password")?
+            };
+            let groups: BTreeSet<String> = groups.into_iter().collect();
+            if prehashed {
+                client
+                    .add_user_with_hash(&username, password, groups, force_reset, posix.into())
+                    .await
+            } else {
                 client
-                    .add_user(&username, SecureString::from(password), groups, force_reset)
+                    .add_user(&username, password, groups, force_reset, posix.into())
                     .await
-                    .context("failed to add user")?;
-                println!("User {} added", username);
             }
-        },
+            .context("failed to add user")?;
+            output.message(format!("User {username} added"))
+        }
+        AdminCmd::GetUser { username } => {
+            let user = client
+                .get_user(&username)
+                .await
+                .context("failed to get user")?;
+            output.print(&user)
+        }
+        AdminCmd::ListUsers => {
+            let mut users = client.list_users().await.context("failed to list users")?;
+            users.sort();
+            output.print(&users)
+        }
+        AdminCmd::RemoveUser { username } => {
+            client
+                .remove_user(&username)
+                .await
+                .context("failed to remove user")?;
+            output.message(format!("User {username} removed"))
+        }
+        AdminCmd::ResetPassword { username } => {
+            let resp = client
+                .reset_password(&username)
+                .await
+                .context("failed to reset password")?;
+            output.print(&resp)
+        }
+        AdminCmd::SetPassword {
+            username,
+            password_stdin,
+            force_reset,
+        } => {
+            let password = if password_stdin {
+                read_password_stdin()?
+            } else {
+                prompt_new_password("Password")?
+            };
+            client
+                .set_password(&username, password, force_reset)
+                .await
+                .context("failed to set password")?;
+            output.message(format!("Password set for user {username}"))
+        }
+        AdminCmd::UnlockUser { username } => {
+            client
+                .unlock_user(&username)
+                .await
+                .context("failed to unlock user")?;
+            output.message(format!("User {username} unlocked"))
+        }
+        AdminCmd::SetAttributes { username, posix } => {
+            let attributes = client
+                .set_attributes(&username, posix.into())
+                .await
+                .context("failed to set attributes")?;
+            output.print(&attributes)
+        }
+        AdminCmd::AddGroups { username, groups } => {
+            let groups = client
+                .add_groups(&username, groups.into_iter().collect())
+                .await
+                .context("failed to add groups")?;
+            output.print(&groups)
+        }
+        AdminCmd::RemoveGroups { username, groups } => {
+            let groups = client
+                .remove_groups(&username, groups.into_iter().collect())
+                .await
+                .context("failed to remove groups")?;
+            output.print(&groups)
+        }
+        AdminCmd::AddGroup {
+            name,
+            gid,
+            description,
+        } => {
+            let group = client
+                .add_group(&name, gid, description)
+                .await
+                .context("failed to add group")?;
+            output.print(&group)
+        }
+        AdminCmd::GetGroup { name } => {
+            let group = client
+                .get_group(&name)
+                .await
+                .context("failed to get group")?;
+            output.print(&group)
+        }
+        AdminCmd::ListGroups => {
+            let mut groups = client
+                .list_groups()
+                .await
+                .context("failed to list groups")?;
+            groups.sort();
+            output.print(&groups)
+        }
+        AdminCmd::RemoveGroup { name } => {
+            client
+                .remove_group(&name)
+                .await
+                .context("failed to remove group")?;
+            output.message(format!("Group {name} removed"))
+        }
+        AdminCmd::GroupMembers { name } => {
+            let members = client
+                .group_members(&name)
+                .await
+                .context("failed to get group members")?;
+            output.print(&members)
+        }
+        AdminCmd::Export { format, path } => {
+            let exported = client
+                .export_directory()
+                .await
+                .context("failed to export directory")?;
+            directory::write(format, &exported, path.as_deref())?;
+            // Don't mix a message in with an export written to stdout
+            match path {
+                Some(path) => output.message(format!(
+                    "Exported {} users and {} groups to {}",
+                    exported.users.len(),
+                    exported.groups.len(),
+                    path.display()
+                )),
+                None => Ok(()),
+            }
+        }
+        AdminCmd::Import {
+            format,
+            path,
+            on_conflict,
+            dry_run,
+        } => {
+            let imported = directory::read(format, path.as_deref())?;
+            let summary = client
+                .import_directory(imported, on_conflict.into(), dry_run)
+                .await
+                .context("failed to import directory")?;
+            output.print(&summary)?;
+            let failed = summary.users.failed + summary.groups.failed;
+            if failed > 0 {
+                anyhow::bail!("{failed} entries could not be imported");
+            }
+            Ok(())
+        }
     }
+}
 
-    Ok(())
+async fn run_user(client: impl UserClient, command: UserCmd) -> anyhow::Result<ExitCode> {
+    match command {
+        UserCmd::Passwd { username } => {
+            let current = prompt_password("Current password: ")?;
+            let new_password = prompt_new_password("New password")?;
+            // Don't verify the current password first. A plain verify counts as a login, which
+            // would move a user logging in with a temporary password out of the reset flow, and
+            // changing the password checks the current one anyway
+            let Err(err) = client
+                .change_password(&username, current, new_password)
+                .await
+            else {
+                println!("Password changed");
+                return Ok(ExitCode::SUCCESS);
+            };
+            let code = match err.downcast_ref::<ResponseError>().and_then(|e| e.kind) {
+                Some(ErrorKind::InvalidCredentials | ErrorKind::AccountLocked) => {
+                    exit_code::INVALID_CREDENTIALS
+                }
+                Some(ErrorKind::PasswordResetExpired) => exit_code::NEEDS_PASSWORD_RESET,
+                Some(ErrorKind::PasswordPolicy) => exit_code::PASSWORD_REJECTED,
+                _ => return Err(err.context("failed to change password")),
+            };
+            eprintln!("{err:#}");
+            Ok(ExitCode::from(code))
+        }
+        UserCmd::Verify { username } => {
+            let password = prompt_password("Password: ")?;
+            let resp = client
+                .verify(&username, password)
+                .await
+                .context("failed to verify password")?;
+            if !resp.valid {
+                eprintln!("{}", resp.message);
+                Ok(ExitCode::from(exit_code::INVALID_CREDENTIALS))
+            } else if resp.needs_password_reset {
+                eprintln!("Password is valid but must be changed");
+                Ok(ExitCode::from(exit_code::NEEDS_PASSWORD_RESET))
+            } else {
+                println!("Password is valid");
+                Ok(ExitCode::SUCCESS)
+            }
+        }
+    }
 }
 
-async fn get_nats_client(
-    nats_addr: String,
-    creds: Option<PathBuf>,
-    username: Option<String>,
-    password: Option<String>,
-    ca_cert: Option<PathBuf>,
-) -> anyhow::Result<async_nats::Client> {
-    let mut opts = ConnectOptions::new();
-    if let Some(cert) = ca_cert {
-        opts = opts.add_root_certificates(cert)
+/// Prompts for a password without echoing it
+fn prompt_password(prompt: &str) -> anyhow::Result<SecureString> {
+    rpassword::prompt_password(prompt)
+        .map(SecureString::from)
+        .context("failed to read password")
+}
+
+/// Prompts for a new password (without echoing it) and asks for it again to confirm. The label is
+/// used to build both prompts
+fn prompt_new_password(label: &str) -> anyhow::Result<SecureString> {
+    let password = prompt_password(&format!("{label}: "))?;
+    let confirm = prompt_password(&format!("Confirm {}: ", label.to_lowercase()))?;
+    if password != confirm {
+        anyhow::bail!("passwords do not match");
     }
+    Ok(password)
+}
 
-    if let Some(creds_file) = creds {
-        opts = opts
-            .credentials_file(creds_file)
-            .await
-            .context("Unable to open credentials file")?;
-    } else if let (Some(user), Some(pass)) = (username, password) {
-        opts = opts.user_and_password(user, pass);
+/// Reads a password from the first line of stdin, for use in scripts
+fn read_password_stdin() -> anyhow::Result<SecureString> {
+    let mut line = String::new();
+    std::io::stdin()
+        .read_line(&mut line)
+        .context("failed to read password from stdin")?;
+    // Truncate in place rather than copying so the only copy of the password ends up in the
+    // SecureString
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    if line.is_empty() {
+        anyhow::bail!("no password was given on stdin");
     }
+    Ok(SecureString::from(line))
+}
+
+impl NatsArgs {
+    async fn connect(self) -> anyhow::Result<async_nats::Client> {
+        let mut opts = ConnectOptions::new();
+        if let Some(cert) = self.nats_ca_cert {
+            opts = opts.add_root_certificates(cert)
+        }
 
-    opts.connect(nats_addr)
-        .await
-        .context("Unable to connect to NATS")
+        if let Some(creds_file) = self.creds {
+            opts = opts
+                .credentials_file(creds_file)
+                .await
+                .context("Unable to open credentials file")?;
+        } else if let (Some(user), Some(pass)) = (self.nats_username, self.nats_password) {
+            opts = opts.user_and_password(user, pass);
+        }
+
+        opts.connect(format!("{}:{}", self.nats_server, self.nats_port))
+            .await
+            .context("Unable to connect to NATS")
+    }
 }
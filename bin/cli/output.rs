@@ -0,0 +1,182 @@
+//! Rendering of command results in the different output formats
+
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+
+/// The format to print command results in
+#[derive(ValueEnum, Debug, Clone, Copy, Default)]
+pub enum OutputFormat {
+    /// Human readable tables
+    #[default]
+    Table,
+    /// Pretty printed JSON
+    Json,
+    /// YAML
+    Yaml,
+}
+
+impl OutputFormat {
+    /// Prints the given value in this format
+    pub fn print(&self, value: &impl Serialize) -> anyhow::Result<()> {
+        match self {
+            OutputFormat::Table => print!("{}", render_table(&serde_json::to_value(value)?)),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(value)?),
+            OutputFormat::Yaml => print!("{}", serde_yaml::to_string(value)?),
+        }
+        Ok(())
+    }
+
+    /// Prints a status message for commands that don't return any data
+    pub fn message(&self, message: impl Into<String>) -> anyhow::Result<()> {
+        let message = message.into();
+        match self {
+            OutputFormat::Table => {
+                println!("{message}");
+                Ok(())
+            }
+            _ => self.print(&serde_json::json!({ "message": message })),
+        }
+    }
+}
+
+/// Renders a value as a table. Objects become a table of fields and values, lists of objects become
+/// a table with a row per item, and anything else is printed one value per line
+fn render_table(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let mut fields = Vec::new();
+            flatten_fields(None, map, &mut fields);
+            let rows: Vec<Vec<String>> = fields
+                .into_iter()
+                .map(|(key, value)| vec![key, value])
+                .collect();
+            format_columns(&[], &rows)
+        }
+        Value::Array(items) if items.iter().all(Value::is_object) && !items.is_empty() => {
+            let mut headers: Vec<String> = Vec::new();
+            let rows: Vec<Vec<(String, String)>> = items
+                .iter()
+                .filter_map(Value::as_object)
+                .map(|map| {
+                    let mut fields = Vec::new();
+                    flatten_fields(None, map, &mut fields);
+                    for (key, _) in fields.iter() {
+                        if !headers.contains(key) {
+                            headers.push(key.clone());
+                        }
+                    }
+                    fields
+                })
+                .collect();
+            let rows: Vec<Vec<String>> = rows
+                .into_iter()
+                .map(|fields| {
+                    headers
+                        .iter()
+                        .map(|header| {
+                            fields
+                                .iter()
+                                .find(|(key, _)| key == header)
+                                .map(|(_, value)| value.clone())
+                                .unwrap_or_default()
+                        })
+                        .collect()
+                })
+                .collect();
+            let headers: Vec<String> = headers.iter().map(|h| h.to_uppercase()).collect();
+            format_columns(&headers, &rows)
+        }
+        Value::Array(items) => items
+            .iter()
+            .map(|item| format!("{}\n", scalar(item)))
+            .collect(),
+        other => format!("{}\n", scalar(other)),
+    }
+}
+
+/// Flattens nested objects into `parent.child` keys
+fn flatten_fields(
+    prefix: Option<&str>,
+    map: &serde_json::Map<String, Value>,
+    rows: &mut Vec<(String, String)>,
+) {
+    for (key, value) in map {
+        let key = match prefix {
+            Some(prefix) => format!("{prefix}.{key}"),
+            None => key.clone(),
+        };
+        match value {
+            Value::Object(inner) if !inner.is_empty() => flatten_fields(Some(&key), inner, rows),
+            other => rows.push((key, scalar(other))),
+        }
+    }
+}
+
+/// Formats a single value for display in a table cell
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items.iter().map(scalar).collect::<Vec<_>>().join(", "),
+        other => other.to_string(),
+    }
+}
+
+/// Pads each column to the width of its widest cell
+fn format_columns(headers: &[String], rows: &[Vec<String>]) -> String {
+    let all_rows = (!headers.is_empty())
+        .then_some(headers)
+        .into_iter()
+        .chain(rows.iter().map(Vec::as_slice));
+    let mut widths: Vec<usize> = Vec::new();
+    for row in all_rows.clone() {
+        for (i, cell) in row.iter().enumerate() {
+            let len = cell.chars().count();
+            match widths.get_mut(i) {
+                Some(width) => *width = (*width).max(len),
+                None => widths.push(len),
+            }
+        }
+    }
+    let mut out = String::new();
+    for row in all_rows {
+        let line = row
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| format!("{cell:<width$}", width = widths[i]))
+            .collect::<Vec<_>>()
+            .join("  ");
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_table() {
+        let value = serde_json::json!({
+            "username": "foo",
+            "groups": ["a", "b"],
+            "posix": { "uid": 10000, "home": "/home/foo" },
+            "locked_until": null,
+        });
+        assert_eq!(
+            render_table(&value),
+            "groups        a, b\nlocked_until  -\nposix.home    /home/foo\nposix.uid     10000\nusername      foo\n"
+        );
+
+        let value =
+            serde_json::json!([{ "name": "foo", "gid": 1 }, { "name": "barbaz", "gid": 20 }]);
+        assert_eq!(render_table(&value), "GID  NAME\n1    foo\n20   barbaz\n");
+
+        assert_eq!(
+            render_table(&serde_json::json!(["foo", "bar"])),
+            "foo\nbar\n"
+        );
+    }
+}
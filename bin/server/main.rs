@@ -1,4 +1,4 @@
-use std::{io::IsTerminal, path::PathBuf};
+use std::{io::IsTerminal, path::PathBuf, time::Duration};
 
 use anyhow::Context;
 use async_nats::{
@@ -6,19 +6,31 @@ use async_nats::{
     ConnectOptions,
 };
 use clap::Parser;
-use futures::future::{pending, Either};
-use tracing::error;
+use futures::future::{ready, Either};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
 use snas_lib::{
-    handlers::Handlers,
+    handlers::{Argon2Algorithm, Argon2Config, Handlers, LockoutPolicy, PosixConfig},
+    policy::PasswordPolicy,
     servers::{
-        nats::{admin::NatsAdminServer, user::NatsUserServer},
-        socket::SocketUserServer,
+        http::HttpServer,
+        ldap::{LdapServer, DEFAULT_MAX_CONNECTIONS},
+        nats::{
+            admin::NatsAdminServer,
+            callout::{GroupPermissionMap, NatsAuthCalloutServer, DEFAULT_USER_JWT_TTL},
+            user::NatsUserServer,
+            DEFAULT_MAX_CONCURRENT_REQUESTS,
+        },
+        socket::{SocketAccessRules, SocketOptions, SocketUserServer},
     },
     storage::CredStore,
-    DEFAULT_SOCKET_PATH,
+    SecureString, DEFAULT_SOCKET_PATH,
 };
 
+mod systemd;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -110,6 +122,14 @@ struct Args {
     )]
     admin_nats_topic_prefix: Option<String>,
 
+    /// The maximum number of admin NATS API requests to handle at once
+    #[arg(
+        long = "admin-nats-max-concurrent",
+        env = "SNAS_ADMIN_NATS_MAX_CONCURRENT",
+        default_value_t = DEFAULT_MAX_CONCURRENT_REQUESTS
+    )]
+    admin_nats_max_concurrent: usize,
+
     /// Listen on the user NATS API topics. By default this is off as listening to this on a host
     /// with a leaf node could allow anonymous access to the user API
     #[arg(
@@ -130,6 +150,262 @@ struct Args {
     )]
     user_nats_topic_prefix: Option<String>,
 
+    /// The maximum number of user NATS API requests to handle at once. Each verification hashes a
+    /// password, so this also bounds how much CPU logins can use
+    #[arg(
+        long = "user-nats-max-concurrent",
+        env = "SNAS_USER_NATS_MAX_CONCURRENT",
+        default_value_t = DEFAULT_MAX_CONCURRENT_REQUESTS
+    )]
+    user_nats_max_concurrent: usize,
+
+    /// Act as a NATS auth callout service, authenticating users connecting to NATS against SNAS.
+    /// The NATS user this server connects as must be listed in the `auth_users` of the auth
+    /// callout config. Requires the `--auth-callout-issuer-seed` flag
+    #[arg(
+        id = "auth_callout",
+        long = "auth-callout",
+        env = "SNAS_AUTH_CALLOUT",
+        default_value_t = false,
+        requires = "auth_callout_issuer_seed"
+    )]
+    auth_callout: bool,
+
+    /// A path to a file containing the seed of the account key configured as the auth callout
+    /// `issuer`. This is used to sign the responses and user JWTs
+    #[arg(
+        id = "auth_callout_issuer_seed",
+        long = "auth-callout-issuer-seed",
+        env = "SNAS_AUTH_CALLOUT_ISSUER_SEED",
+        requires = "auth_callout"
+    )]
+    auth_callout_issuer_seed: Option<PathBuf>,
+
+    /// The account that authenticated users are placed in. Defaults to the global account (`$G`)
+    #[arg(
+        long = "auth-callout-account",
+        env = "SNAS_AUTH_CALLOUT_ACCOUNT",
+        requires = "auth_callout"
+    )]
+    auth_callout_account: Option<String>,
+
+    /// A path to a JSON file mapping SNAS groups to NATS publish and subscribe permissions. If not
+    /// set, users will be issued JWTs with no explicit permissions
+    #[arg(
+        long = "auth-callout-permissions",
+        env = "SNAS_AUTH_CALLOUT_PERMISSIONS",
+        requires = "auth_callout"
+    )]
+    auth_callout_permissions: Option<PathBuf>,
+
+    /// The public keys (`N...`) of the NATS servers allowed to send auth callout requests, separated
+    /// by commas. Required unless `--auth-callout-insecure-any-server` is set
+    #[arg(
+        long = "auth-callout-server-keys",
+        env = "SNAS_AUTH_CALLOUT_SERVER_KEYS",
+        value_delimiter = ',',
+        requires = "auth_callout"
+    )]
+    auth_callout_server_keys: Vec<String>,
+
+    /// Handle auth callout requests signed by any NATS server key instead of requiring
+    /// `--auth-callout-server-keys`. This is insecure unless publishing on the auth callout subject
+    /// is restricted to the NATS servers, as anyone who can publish there can use it to guess
+    /// passwords
+    #[arg(
+        long = "auth-callout-insecure-any-server",
+        env = "SNAS_AUTH_CALLOUT_INSECURE_ANY_SERVER",
+        default_value_t = false,
+        requires = "auth_callout"
+    )]
+    auth_callout_insecure_any_server: bool,
+
+    /// The maximum number of auth callout requests to handle at once
+    #[arg(
+        long = "auth-callout-max-concurrent",
+        env = "SNAS_AUTH_CALLOUT_MAX_CONCURRENT",
+        default_value_t = DEFAULT_MAX_CONCURRENT_REQUESTS
+    )]
+    auth_callout_max_concurrent: usize,
+
+    /// How long (in seconds) the user JWTs issued by the auth callout are valid for. NATS
+    /// disconnects users once their JWT expires, so this is how long a removed or locked user can
+    /// stay connected
+    #[arg(
+        long = "auth-callout-jwt-ttl",
+        env = "SNAS_AUTH_CALLOUT_JWT_TTL",
+        default_value_t = DEFAULT_USER_JWT_TTL.as_secs(),
+        requires = "auth_callout"
+    )]
+    auth_callout_jwt_ttl: u64,
+
+    /// An address to serve a read-only LDAP directory of users and groups on (e.g.
+    /// `127.0.0.1:389`). The LDAP listener does not support TLS, so this should only be exposed on
+    /// trusted networks
+    #[arg(id = "ldap_listen", long = "ldap-listen", env = "SNAS_LDAP_LISTEN")]
+    ldap_listen: Option<String>,
+
+    /// The base DN to serve LDAP entries under
+    #[arg(
+        long = "ldap-base-dn",
+        env = "SNAS_LDAP_BASE_DN",
+        default_value = "dc=snas,dc=local",
+        requires = "ldap_listen"
+    )]
+    ldap_base_dn: String,
+
+    /// Allow LDAP clients to search the directory without binding as a user first
+    #[arg(
+        long = "ldap-allow-anonymous",
+        env = "SNAS_LDAP_ALLOW_ANONYMOUS",
+        default_value_t = false,
+        requires = "ldap_listen"
+    )]
+    ldap_allow_anonymous: bool,
+
+    /// The maximum number of LDAP connections to have open at once
+    #[arg(
+        long = "ldap-max-connections",
+        env = "SNAS_LDAP_MAX_CONNECTIONS",
+        default_value_t = DEFAULT_MAX_CONNECTIONS,
+        requires = "ldap_listen"
+    )]
+    ldap_max_connections: usize,
+
+    /// An address to serve HTTP operational endpoints on (e.g. `127.0.0.1:9090`). This serves
+    /// Prometheus metrics at `/metrics`, a liveness check at `/healthz` and a readiness check at
+    /// `/readyz`. Like the LDAP listener, this does not support TLS, so it should only be exposed
+    /// on trusted networks
+    #[arg(long = "http-listen", env = "SNAS_HTTP_LISTEN")]
+    http_listen: Option<String>,
+
+    /// The number of consecutive failed login attempts before a user is locked out. Set to 0 to
+    /// disable lockouts
+    #[arg(
+        long = "lockout-max-attempts",
+        env = "SNAS_LOCKOUT_MAX_ATTEMPTS",
+        default_value_t = 5
+    )]
+    lockout_max_attempts: u32,
+
+    /// How long (in seconds) a user stays locked out after too many failed login attempts
+    #[arg(
+        long = "lockout-duration",
+        env = "SNAS_LOCKOUT_DURATION",
+        default_value_t = 900
+    )]
+    lockout_duration: u64,
+
+    /// The Argon2 variant to hash passwords with (argon2id, argon2i or argon2d). Existing hashes
+    /// made with different settings are upgraded when the user next logs in
+    #[arg(
+        long = "argon2-algorithm",
+        env = "SNAS_ARGON2_ALGORITHM",
+        default_value = "argon2id",
+        value_parser = parse_argon2_algorithm
+    )]
+    argon2_algorithm: Argon2Algorithm,
+
+    /// The amount of memory (in KiB) Argon2 uses to hash a password
+    #[arg(
+        long = "argon2-memory-kib",
+        env = "SNAS_ARGON2_MEMORY_KIB",
+        default_value_t = Argon2Config::default().memory_cost
+    )]
+    argon2_memory_kib: u32,
+
+    /// The number of iterations Argon2 makes when hashing a password
+    #[arg(
+        long = "argon2-iterations",
+        env = "SNAS_ARGON2_ITERATIONS",
+        default_value_t = Argon2Config::default().iterations
+    )]
+    argon2_iterations: u32,
+
+    /// The degree of parallelism Argon2 uses when hashing a password
+    #[arg(
+        long = "argon2-parallelism",
+        env = "SNAS_ARGON2_PARALLELISM",
+        default_value_t = Argon2Config::default().parallelism
+    )]
+    argon2_parallelism: u32,
+
+    /// Refuse to verify credentials or change passwords while the local cache may be out of date
+    /// because the KV bucket watcher is recovering. By default the cached data is used
+    #[arg(
+        long = "reject-when-stale",
+        env = "SNAS_REJECT_WHEN_STALE",
+        default_value_t = false
+    )]
+    reject_when_stale: bool,
+
+    /// The minimum number of characters a password must have
+    #[arg(
+        long = "password-min-length",
+        env = "SNAS_PASSWORD_MIN_LENGTH",
+        default_value_t = 1
+    )]
+    password_min_length: usize,
+
+    /// The maximum number of characters a password can have
+    #[arg(
+        long = "password-max-length",
+        env = "SNAS_PASSWORD_MAX_LENGTH",
+        default_value_t = 1024
+    )]
+    password_max_length: usize,
+
+    /// Require passwords to contain at least one lowercase letter
+    #[arg(
+        long = "password-require-lowercase",
+        env = "SNAS_PASSWORD_REQUIRE_LOWERCASE"
+    )]
+    password_require_lowercase: bool,
+
+    /// Require passwords to contain at least one uppercase letter
+    #[arg(
+        long = "password-require-uppercase",
+        env = "SNAS_PASSWORD_REQUIRE_UPPERCASE"
+    )]
+    password_require_uppercase: bool,
+
+    /// Require passwords to contain at least one digit
+    #[arg(long = "password-require-digit", env = "SNAS_PASSWORD_REQUIRE_DIGIT")]
+    password_require_digit: bool,
+
+    /// Require passwords to contain at least one character that is not a letter or digit
+    #[arg(long = "password-require-symbol", env = "SNAS_PASSWORD_REQUIRE_SYMBOL")]
+    password_require_symbol: bool,
+
+    /// Allow passwords that are the same as the username
+    #[arg(long = "password-allow-username", env = "SNAS_PASSWORD_ALLOW_USERNAME")]
+    password_allow_username: bool,
+
+    /// A path to a file of passwords that are not allowed, one per line. Passwords are compared
+    /// case insensitively
+    #[arg(long = "password-banned-list", env = "SNAS_PASSWORD_BANNED_LIST")]
+    password_banned_list: Option<PathBuf>,
+
+    /// The first UID in the range that UIDs are automatically allocated from
+    #[arg(long = "uid-min", env = "SNAS_UID_MIN", default_value_t = 10000)]
+    uid_min: u32,
+
+    /// The last UID in the range that UIDs are automatically allocated from
+    #[arg(long = "uid-max", env = "SNAS_UID_MAX", default_value_t = 59999)]
+    uid_max: u32,
+
+    /// The directory user home directories are placed in when one isn't given
+    #[arg(long = "home-base", env = "SNAS_HOME_BASE", default_value = "/home")]
+    home_base: String,
+
+    /// The login shell for users when one isn't given
+    #[arg(
+        long = "default-shell",
+        env = "SNAS_DEFAULT_SHELL",
+        default_value = "/bin/bash"
+    )]
+    default_shell: String,
+
     /// Whether or not to enable the user socket. This is required if the admin and user NATS
     /// servers are not enabled
     #[cfg(unix)]
@@ -137,11 +413,13 @@ struct Args {
         long = "user-socket",
         env = "SNAS_USER_SOCKET",
         default_value_t = false,
-        required_unless_present_any = ["admin_nats", "user_nats"],
+        required_unless_present_any = ["admin_nats", "user_nats", "auth_callout", "ldap_listen"],
     )]
     user_socket: bool,
     /// The path to the socket file to use for the user API. This should exist in a directory that
-    /// is only accessible to root or other super admins so as to not be abused
+    /// is only accessible to root or other super admins so as to not be abused. If systemd passes
+    /// in a socket with socket activation, that socket is used instead and the socket file options
+    /// are ignored
     // TODO(thomastaylor312): Use named pipes on Windows instead as UDS support isn't in the
     // standard library or Tokio yet (and it might take a bit)
     #[cfg(unix)]
@@ -151,6 +429,39 @@ struct Args {
         default_value = DEFAULT_SOCKET_PATH,
     )]
     socket_file: PathBuf,
+    /// The UID to give ownership of the socket file to. Defaults to the user running the server
+    #[cfg(unix)]
+    #[arg(long = "socket-owner", env = "SNAS_SOCKET_OWNER")]
+    socket_owner: Option<u32>,
+    /// The GID to give ownership of the socket file to. Defaults to the primary group of the user
+    /// running the server
+    #[cfg(unix)]
+    #[arg(long = "socket-group", env = "SNAS_SOCKET_GROUP")]
+    socket_group: Option<u32>,
+    /// The permissions of the socket file in octal. Processes need write permission to connect, so
+    /// use something like `660` together with `--socket-group` to let another group connect
+    #[cfg(unix)]
+    #[arg(
+        long = "socket-mode",
+        env = "SNAS_SOCKET_MODE",
+        default_value = "700",
+        value_parser = parse_mode
+    )]
+    socket_mode: u32,
+    /// A path to a JSON file of rules deciding which socket methods each connecting UID or GID may
+    /// use. If not set, root may use every method and everyone else may only use the lookup methods
+    /// and verify or change the password of their own account
+    #[cfg(unix)]
+    #[arg(long = "socket-access-rules", env = "SNAS_SOCKET_ACCESS_RULES")]
+    socket_access_rules: Option<PathBuf>,
+
+    /// How long (in seconds) to wait for in flight requests to finish when shutting down
+    #[arg(
+        long = "shutdown-timeout",
+        env = "SNAS_SHUTDOWN_TIMEOUT",
+        default_value_t = 30
+    )]
+    shutdown_timeout: u64,
 }
 
 #[tokio::main]
@@ -166,6 +477,10 @@ async fn main() -> anyhow::Result<()> {
         builder.pretty().init();
     }
 
+    let shutdown = CancellationToken::new();
+    handle_signals(shutdown.clone())?;
+    let shutdown_timeout = Duration::from_secs(args.shutdown_timeout);
+
     let client = get_nats_client(
         format!("{}:{}", args.nats_server, args.nats_port),
         args.creds,
@@ -198,9 +513,45 @@ async fn main() -> anyhow::Result<()> {
         }
     };
     tracing::info!("Successfully connected to bucket");
-    let store = CredStore::new(bucket).await?;
+    systemd::notify_status("Loading users and groups");
+    let store = CredStore::new(bucket).await?.with_client(client.clone());
+
+    if args.uid_min > args.uid_max {
+        anyhow::bail!("--uid-min must not be greater than --uid-max");
+    }
+
+    let mut password_policy = PasswordPolicy {
+        min_length: args.password_min_length,
+        max_length: args.password_max_length,
+        require_lowercase: args.password_require_lowercase,
+        require_uppercase: args.password_require_uppercase,
+        require_digit: args.password_require_digit,
+        require_symbol: args.password_require_symbol,
+        differ_from_username: !args.password_allow_username,
+        ..Default::default()
+    };
+    if let Some(path) = args.password_banned_list {
+        password_policy = password_policy.load_banned_passwords(path)?;
+    }
 
-    let handlers = Handlers::new(store);
+    let handlers = Handlers::new(store)
+        .with_lockout_policy(LockoutPolicy {
+            max_failed_attempts: args.lockout_max_attempts,
+            lockout_duration: Duration::from_secs(args.lockout_duration),
+        })
+        .with_password_policy(password_policy)
+        .with_posix_config(PosixConfig {
+            uid_range: args.uid_min..=args.uid_max,
+            home_base: args.home_base,
+            shell: args.default_shell,
+        })
+        .with_reject_when_stale(args.reject_when_stale)
+        .with_argon2_config(Argon2Config {
+            algorithm: args.argon2_algorithm,
+            memory_cost: args.argon2_memory_kib,
+            iterations: args.argon2_iterations,
+            parallelism: args.argon2_parallelism,
+        })?;
 
     let nats_user_server = if args.user_nats {
         Either::Left(
@@ -210,10 +561,13 @@ async fn main() -> anyhow::Result<()> {
                 args.user_nats_topic_prefix,
             )
             .await?
+            .with_max_concurrent_requests(args.user_nats_max_concurrent)
+            .with_shutdown(shutdown.clone())
             .run(),
         )
     } else {
-        Either::Right(pending::<anyhow::Result<()>>())
+        // Disabled servers are done right away so they don't hold up shutdown
+        Either::Right(ready(anyhow::Ok(())))
     };
 
     let nats_admin_server = if args.admin_nats {
@@ -224,29 +578,192 @@ async fn main() -> anyhow::Result<()> {
                 args.admin_nats_topic_prefix,
             )
             .await?
+            .with_max_concurrent_requests(args.admin_nats_max_concurrent)
+            .with_shutdown(shutdown.clone())
             .run(),
         )
     } else {
-        Either::Right(pending::<anyhow::Result<()>>())
+        Either::Right(ready(anyhow::Ok(())))
     };
 
-    let socket_server = if args.user_socket {
+    let auth_callout_server = if args.auth_callout {
+        // The flag requirements are validated by clap, so this will always be set
+        let seed_file = args
+            .auth_callout_issuer_seed
+            .context("An issuer seed is required for auth callout")?;
+        if args.auth_callout_server_keys.is_empty() && !args.auth_callout_insecure_any_server {
+            anyhow::bail!("--auth-callout-server-keys is required unless --auth-callout-insecure-any-server is set");
+        }
+        let seed = SecureString::from(
+            tokio::fs::read_to_string(&seed_file)
+                .await
+                .context("Unable to read auth callout issuer seed")?,
+        );
+        let permissions = match args.auth_callout_permissions {
+            Some(path) => serde_json::from_slice::<GroupPermissionMap>(
+                &tokio::fs::read(&path)
+                    .await
+                    .context("Unable to read auth callout permissions file")?,
+            )
+            .context("Invalid auth callout permissions file")?,
+            None => GroupPermissionMap::default(),
+        };
         Either::Left(
-            SocketUserServer::new(handlers.clone(), args.socket_file)
+            NatsAuthCalloutServer::new(
+                handlers.clone(),
+                client.clone(),
+                seed.as_ref(),
+                args.auth_callout_account,
+                permissions,
+            )
+            .await?
+            .with_trusted_servers(args.auth_callout_server_keys)?
+            .allow_any_server(args.auth_callout_insecure_any_server)
+            .with_max_concurrent_requests(args.auth_callout_max_concurrent)
+            .with_user_jwt_ttl(Duration::from_secs(args.auth_callout_jwt_ttl))
+            .with_shutdown(shutdown.clone())
+            .run(),
+        )
+    } else {
+        Either::Right(ready(anyhow::Ok(())))
+    };
+
+    let ldap_server = if let Some(addr) = args.ldap_listen {
+        Either::Left(
+            LdapServer::new(
+                handlers.clone(),
+                addr,
+                args.ldap_base_dn,
+                args.ldap_allow_anonymous,
+            )
+            .await?
+            .with_max_connections(args.ldap_max_connections)
+            .with_shutdown(shutdown.clone())
+            .run(),
+        )
+    } else {
+        Either::Right(ready(anyhow::Ok(())))
+    };
+
+    let http_server = if let Some(addr) = args.http_listen {
+        Either::Left(
+            HttpServer::new(handlers.clone(), addr)
+                .await
+                .context("Unable to start HTTP listener")?
+                .with_shutdown(shutdown.clone())
+                .run(),
+        )
+    } else {
+        Either::Right(ready(anyhow::Ok(())))
+    };
+
+    let socket_server = if args.user_socket {
+        let rules = match args.socket_access_rules {
+            Some(path) => serde_json::from_slice::<SocketAccessRules>(
+                &tokio::fs::read(&path)
+                    .await
+                    .context("Unable to read socket access rules file")?,
+            )
+            .context("Invalid socket access rules file")?,
+            None => SocketAccessRules::default(),
+        };
+        let server = match systemd::inherited_listener()? {
+            Some(listener) => {
+                tracing::info!("Using user socket passed in by systemd");
+                SocketUserServer::from_listener(handlers.clone(), listener)?
+            }
+            None => {
+                SocketUserServer::new_with_options(
+                    handlers.clone(),
+                    args.socket_file,
+                    SocketOptions {
+                        owner: args.socket_owner,
+                        group: args.socket_group,
+                        mode: args.socket_mode,
+                    },
+                )
                 .await?
+            }
+        };
+        Either::Left(
+            server
+                .with_access_rules(rules)
+                .with_shutdown(shutdown.clone())
                 .run(),
         )
     } else {
-        Either::Right(pending::<anyhow::Result<()>>())
+        Either::Right(ready(anyhow::Ok(())))
     };
 
-    if let Err(err) = futures::try_join!(nats_user_server, nats_admin_server, socket_server) {
+    // Everything is listening and the cache was filled by CredStore::new, so we can take requests
+    systemd::notify_ready("Serving requests");
+
+    let servers = async {
+        futures::try_join!(
+            nats_user_server,
+            nats_admin_server,
+            auth_callout_server,
+            ldap_server,
+            http_server,
+            socket_server
+        )
+    };
+    tokio::pin!(servers);
+    let result = tokio::select! {
+        res = &mut servers => res.map(|_| ()),
+        _ = shutdown.cancelled() => {
+            systemd::notify_stopping("Finishing in flight requests");
+            match tokio::time::timeout(shutdown_timeout, &mut servers).await {
+                Ok(res) => res.map(|_| ()),
+                Err(_) => {
+                    warn!("Timed out waiting for in flight requests to finish");
+                    Ok(())
+                }
+            }
+        }
+    };
+    // Failed login attempts are recorded in the background, so make sure they are all written
+    if tokio::time::timeout(shutdown_timeout, handlers.wait_for_background_tasks())
+        .await
+        .is_err()
+    {
+        warn!("Timed out waiting for failed login attempts to be recorded");
+    }
+    if let Err(err) = result {
         error!(%err, "An error occurred, shutting down");
+        systemd::notify_stopping(&format!("Shutting down after error: {err}"));
         return Err(err);
     }
+    info!("Shutdown complete");
     Ok(())
 }
 
+/// Cancels the token on SIGTERM or SIGINT
+fn handle_signals(shutdown: CancellationToken) -> anyhow::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate()).context("Unable to listen for SIGTERM")?;
+    let mut sigint = signal(SignalKind::interrupt()).context("Unable to listen for SIGINT")?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+            _ = sigint.recv() => info!("Received SIGINT, shutting down"),
+        }
+        shutdown.cancel();
+    });
+    Ok(())
+}
+
+fn parse_mode(raw: &str) -> Result<u32, String> {
+    match u32::from_str_radix(raw, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("{raw} is not a valid octal file mode")),
+    }
+}
+
+fn parse_argon2_algorithm(raw: &str) -> Result<Argon2Algorithm, String> {
+    raw.parse()
+        .map_err(|_| format!("{raw} is not one of argon2id, argon2i or argon2d"))
+}
+
 async fn get_nats_client(
     nats_addr: String,
     creds: Option<PathBuf>,
@@ -0,0 +1,49 @@
+//! Integration with systemd for socket activation and service readiness notifications. All of
+//! these are no-ops when the server isn't started by systemd
+
+use std::os::fd::FromRawFd;
+use std::os::unix::net::UnixListener;
+
+use anyhow::Context;
+use sd_notify::NotifyState;
+use tracing::warn;
+
+/// Returns the listening socket passed in by systemd socket activation, if there is one
+pub fn inherited_listener() -> anyhow::Result<Option<UnixListener>> {
+    let mut fds = sd_notify::listen_fds().context("Invalid socket activation environment")?;
+    let Some(fd) = fds.next() else {
+        return Ok(None);
+    };
+    if fds.next().is_some() {
+        warn!("Multiple sockets were passed by systemd, only the first one will be used");
+    }
+    // SAFETY: systemd hands ownership of the passed file descriptors to us, and listen_fds makes
+    // sure they were meant for this process. Nothing else in the process uses them
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    // This fails if the descriptor isn't a unix socket, e.g. if the socket unit uses a TCP port
+    listener
+        .local_addr()
+        .context("Socket passed by systemd is not a unix socket")?;
+    Ok(Some(listener))
+}
+
+/// Tells systemd the server is ready to handle requests
+pub fn notify_ready(status: &str) {
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+/// Tells systemd the server is shutting down and why
+pub fn notify_stopping(status: &str) {
+    notify(&[NotifyState::Stopping, NotifyState::Status(status)]);
+}
+
+/// Updates the status line systemd shows for the service
+pub fn notify_status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(err) = sd_notify::notify(false, state) {
+        warn!(%err, "Unable to send notification to systemd");
+    }
+}
@@ -0,0 +1,102 @@
+//! Helpers for packing entries into the caller provided buffers that the `_r` NSS functions use
+//! for all string data
+
+use std::ffi::CString;
+
+use libc::c_char;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum BufferError {
+    /// The buffer is too small, so the caller should try again with a bigger one
+    TooSmall,
+    /// The data contains a NUL byte and can't be returned as a C string
+    InvalidString,
+}
+
+/// A caller provided buffer that data is appended to
+pub(crate) struct Buffer<'a> {
+    data: &'a mut [u8],
+    offset: usize,
+}
+
+impl<'a> Buffer<'a> {
+    /// # Safety
+    ///
+    /// `buf` must point to at least `len` bytes that are valid for writes for the lifetime of the
+    /// returned buffer
+    pub unsafe fn new(buf: *mut c_char, len: usize) -> Self {
+        Buffer {
+            data: std::slice::from_raw_parts_mut(buf.cast::<u8>(), len),
+            offset: 0,
+        }
+    }
+
+    /// Copies the string into the buffer as a C string, returning a pointer to it
+    pub fn add_str(&mut self, s: &str) -> Result<*mut c_char, BufferError> {
+        let s = CString::new(s).map_err(|_| BufferError::InvalidString)?;
+        let bytes = s.as_bytes_with_nul();
+        let start = self.reserve(bytes.len(), 1)?;
+        self.data[start..start + bytes.len()].copy_from_slice(bytes);
+        Ok(self.data[start..].as_mut_ptr().cast())
+    }
+
+    /// Copies the pointers into the buffer as a NULL terminated array, returning a pointer to it
+    pub fn add_ptr_array(&mut self, ptrs: &[*mut c_char]) -> Result<*mut *mut c_char, BufferError> {
+        let ptr_size = std::mem::size_of::<*mut c_char>();
+        let len = (ptrs.len() + 1) * ptr_size;
+        let start = self.reserve(len, std::mem::align_of::<*mut c_char>())?;
+        let array: *mut *mut c_char = self.data[start..].as_mut_ptr().cast();
+        for (i, ptr) in ptrs
+            .iter()
+            .chain(std::iter::once(&std::ptr::null_mut()))
+            .enumerate()
+        {
+            // SAFETY: We reserved enough aligned space for every pointer plus the terminator
+            unsafe { array.add(i).write(*ptr) };
+        }
+        Ok(array)
+    }
+
+    /// Reserves `len` bytes aligned to `align`, returning the offset they start at
+    fn reserve(&mut self, len: usize, align: usize) -> Result<usize, BufferError> {
+        let base = self.data.as_ptr() as usize;
+        let start = (base + self.offset).next_multiple_of(align) - base;
+        let end = start.checked_add(len).ok_or(BufferError::TooSmall)?;
+        if end > self.data.len() {
+            return Err(BufferError::TooSmall);
+        }
+        self.offset = end;
+        Ok(start)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CStr;
+
+    use super::*;
+
+    #[test]
+    fn test_buffer() {
+        let mut raw = vec![0 as c_char; 64];
+        let mut buffer = unsafe { Buffer::new(raw.as_mut_ptr(), raw.len()) };
+        let foo = buffer.add_str("foo").unwrap();
+        let bar = buffer.add_str("bar").unwrap();
+        let array = buffer.add_ptr_array(&[foo, bar]).unwrap();
+        assert_eq!(array as usize % std::mem::align_of::<*mut c_char>(), 0);
+        unsafe {
+            assert_eq!(CStr::from_ptr(*array).to_str().unwrap(), "foo");
+            assert_eq!(CStr::from_ptr(*array.add(1)).to_str().unwrap(), "bar");
+            assert!((*array.add(2)).is_null());
+        }
+
+        assert_eq!(
+            buffer.add_str(&"a".repeat(64)).unwrap_err(),
+            BufferError::TooSmall
+        );
+        assert_eq!(
+            buffer.add_str("nul\0byte").unwrap_err(),
+            BufferError::InvalidString
+        );
+    }
+}
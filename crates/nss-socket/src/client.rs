@@ -0,0 +1,191 @@
+//! A minimal blocking client for the SNAS user socket. NSS lookups happen inside of whatever process
+//! is resolving a user (e.g. `ls` or `sshd`), so we avoid spinning up an async runtime and just use
+//! a plain unix stream for each request.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use snas_lib::api::{ErrorKind, GenericResponse};
+use snas_lib::{DEFAULT_SOCKET_PATH, REQUEST_IDENTIFIER, RESPONSE_IDENTIFIER, TERMINATOR};
+
+/// The config file for the module. This is intentionally not configurable via environment
+/// variables as NSS modules are loaded into setuid binaries, which must not be able to be pointed
+/// at an untrusted socket
+const CONFIG_PATH: &str = "/etc/snas/nss.conf";
+/// How long to wait on the socket before giving up. Lookups block whatever process is resolving a
+/// user, so we don't want to hang forever if the server is wedged
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+static SOCKET_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// The ways a lookup can fail
+#[derive(Debug)]
+pub(crate) enum LookupError {
+    /// The server responded, but didn't have the requested entry
+    NotFound,
+    /// We couldn't talk to the server, it sent back something we didn't understand or it failed to
+    /// answer the lookup (e.g. the caller isn't allowed to look it up or its data is stale). There
+    /// is nowhere sensible to report the underlying error from inside of an NSS module, so it is
+    /// dropped
+    Unavailable,
+}
+
+impl From<anyhow::Error> for LookupError {
+    fn from(_: anyhow::Error) -> Self {
+        LookupError::Unavailable
+    }
+}
+
+/// Sends a request to the SNAS user socket using the given method and body, returning the
+/// response data
+pub(crate) fn request<Req: Serialize, Resp: DeserializeOwned + 'static>(
+    method: &str,
+    body: &Req,
+) -> Result<Resp, LookupError> {
+    let data = send_request(socket_path(), method, body)?;
+    parse_response(&data)
+}
+
+/// Parses a response from the server. Only failures the server marks as
+/// [`ErrorKind::NotFound`] are reported as [`LookupError::NotFound`], as glibc treats that as a
+/// definitive answer. Anything else means the server couldn't answer
+fn parse_response<Resp: DeserializeOwned + 'static>(data: &[u8]) -> Result<Resp, LookupError> {
+    let resp: GenericResponse<Resp> =
+        serde_json::from_slice(data).context("Unable to parse response")?;
+    match (resp.success, resp.response, resp.error) {
+        (true, Some(response), _) => Ok(response),
+        (false, _, Some(ErrorKind::NotFound)) => Err(LookupError::NotFound),
+        _ => Err(LookupError::Unavailable),
+    }
+}
+
+fn send_request<Req: Serialize>(
+    socket_path: &Path,
+    method: &str,
+    body: &Req,
+) -> anyhow::Result<Vec<u8>> {
+    let stream = UnixStream::connect(socket_path).context("Unable to connect to socket")?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut buf = Vec::new();
+    buf.extend_from_slice(REQUEST_IDENTIFIER);
+    buf.extend_from_slice(method.as_bytes());
+    buf.push(b'\n');
+    serde_json::to_writer(&mut buf, body)?;
+    buf.push(b'\r');
+    buf.extend_from_slice(TERMINATOR);
+    (&stream).write_all(&buf)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut identifier = [0u8; RESPONSE_IDENTIFIER.len()];
+    reader.read_exact(&mut identifier)?;
+    if identifier != RESPONSE_IDENTIFIER {
+        anyhow::bail!("Got malformed response");
+    }
+    let mut data = Vec::new();
+    reader.read_until(b'\r', &mut data)?;
+    if data.pop() != Some(b'\r') {
+        anyhow::bail!("Got malformed response");
+    }
+    let mut terminator = [0u8; TERMINATOR.len()];
+    reader.read_exact(&mut terminator)?;
+    if terminator != TERMINATOR {
+        anyhow::bail!("Got malformed response");
+    }
+    Ok(data)
+}
+
+fn socket_path() -> &'static Path {
+    SOCKET_PATH.get_or_init(|| {
+        std::fs::read_to_string(CONFIG_PATH)
+            .ok()
+            .and_then(|raw| parse_config(&raw))
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_SOCKET_PATH))
+    })
+}
+
+/// Parses the socket path out of a config file made up of `key=value` lines
+fn parse_config(raw: &str) -> Option<PathBuf> {
+    raw.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim() == "socket_path")
+        .map(|(_, value)| PathBuf::from(value.trim()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(
+            parse_config("# SNAS NSS config\n\nsocket_path = /run/snas/user.sock\n"),
+            Some(PathBuf::from("/run/snas/user.sock"))
+        );
+        assert_eq!(parse_config("other=value"), None);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(
+            parse_response::<Vec<u32>>(br#"{"success":true,"message":"","response":[1,2]}"#)
+                .unwrap(),
+            vec![1, 2]
+        );
+        assert!(matches!(
+            parse_response::<Vec<u32>>(
+                br#"{"success":false,"message":"User not found","error":"not_found"}"#
+            ),
+            Err(LookupError::NotFound)
+        ));
+        for unavailable in [
+            // Denied by an access rule or the server failed
+            r#"{"success":false,"message":"Permission denied"}"#,
+            r#"{"success":false,"message":"Stale","error":"some_new_kind"}"#,
+            r#"{"success":true,"message":""}"#,
+            "not json",
+        ] {
+            assert!(
+                matches!(
+                    parse_response::<Vec<u32>>(unavailable.as_bytes()),
+                    Err(LookupError::Unavailable)
+                ),
+                "{unavailable} should be reported as unavailable"
+            );
+        }
+    }
+
+    #[test]
+    fn test_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sock");
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut request = Vec::new();
+            while !request.ends_with(TERMINATOR) {
+                reader.read_until(b'\n', &mut request).unwrap();
+            }
+            (&stream)
+                .write_all(b"RES\n{\"success\":true,\"message\":\"\",\"response\":[1,2]}\r\nEND\n")
+                .unwrap();
+            request
+        });
+
+        let data = send_request(&path, "user_group_ids", &()).unwrap();
+        assert_eq!(data, br#"{"success":true,"message":"","response":[1,2]}"#);
+        assert_eq!(
+            handle.join().unwrap(),
+            b"REQ\nuser_group_ids\nnull\r\nEND\n".to_vec()
+        );
+    }
+}
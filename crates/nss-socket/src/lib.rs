@@ -0,0 +1,478 @@
+//! An NSS module that resolves users and groups from SNAS using the user socket API. This allows
+//! things like `getpwnam` and `getgrgid` to work for SNAS users without having to create matching
+//! local accounts.
+//!
+//! To use it, install `libnss_snas.so` as `libnss_snas.so.2` somewhere in the library path and add
+//! `snas` to the `passwd`, `group` and (optionally) `initgroups` lines of `/etc/nsswitch.conf`. The
+//! socket path can be changed by setting `socket_path=<path>` in `/etc/snas/nss.conf`.
+
+use std::ffi::CStr;
+use std::sync::Mutex;
+
+use libc::{c_char, c_int, c_long, gid_t, group, passwd, size_t, uid_t};
+use snas_lib::api::{
+    GroupEntry, GroupLookupRequest, PasswdEntry, UserGroupsRequest, UserLookupRequest,
+};
+
+use buffer::{Buffer, BufferError};
+use client::LookupError;
+
+mod buffer;
+mod client;
+
+/// The status codes returned by NSS module functions
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NssStatus {
+    TryAgain = -2,
+    Unavailable = -1,
+    NotFound = 0,
+    Success = 1,
+}
+
+/// The current position of a `getpwent`/`getgrent` enumeration
+struct Enumeration<T> {
+    entries: Vec<T>,
+    index: usize,
+}
+
+static PASSWD_ENUMERATION: Mutex<Option<Enumeration<PasswdEntry>>> = Mutex::new(None);
+static GROUP_ENUMERATION: Mutex<Option<Enumeration<GroupEntry>>> = Mutex::new(None);
+
+/// # Safety
+///
+/// `name` must be a valid C string, `pwd` must point to a valid `passwd`, `buf` must be valid for
+/// writes of `buflen` bytes and `errnop` must point to a valid int
+#[no_mangle]
+pub unsafe extern "C" fn _nss_snas_getpwnam_r(
+    name: *const c_char,
+    pwd: *mut passwd,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> NssStatus {
+    let Ok(name) = CStr::from_ptr(name).to_str() else {
+        return not_found(errnop);
+    };
+    match client::request("lookup_user", &UserLookupRequest::Name(name.to_owned())) {
+        Ok(entry) => fill_passwd(&entry, pwd, buf, buflen, errnop),
+        Err(e) => lookup_error(e, errnop),
+    }
+}
+
+/// # Safety
+///
+/// `pwd` must point to a valid `passwd`, `buf` must be valid for writes of `buflen` bytes and
+/// `errnop` must point to a valid int
+#[no_mangle]
+pub unsafe extern "C" fn _nss_snas_getpwuid_r(
+    uid: uid_t,
+    pwd: *mut passwd,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> NssStatus {
+    match client::request("lookup_user", &UserLookupRequest::Uid(uid)) {
+        Ok(entry) => fill_passwd(&entry, pwd, buf, buflen, errnop),
+        Err(e) => lookup_error(e, errnop),
+    }
+}
+
+/// # Safety
+///
+/// `name` must be a valid C string, `grp` must point to a valid `group`, `buf` must be valid for
+/// writes of `buflen` bytes and `errnop` must point to a valid int
+#[no_mangle]
+pub unsafe extern "C" fn _nss_snas_getgrnam_r(
+    name: *const c_char,
+    grp: *mut group,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> NssStatus {
+    let Ok(name) = CStr::from_ptr(name).to_str() else {
+        return not_found(errnop);
+    };
+    match client::request("lookup_group", &GroupLookupRequest::Name(name.to_owned())) {
+        Ok(entry) => fill_group(&entry, grp, buf, buflen, errnop),
+        Err(e) => lookup_error(e, errnop),
+    }
+}
+
+/// # Safety
+///
+/// `grp` must point to a valid `group`, `buf` must be valid for writes of `buflen` bytes and
+/// `errnop` must point to a valid int
+#[no_mangle]
+pub unsafe extern "C" fn _nss_snas_getgrgid_r(
+    gid: gid_t,
+    grp: *mut group,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> NssStatus {
+    match client::request("lookup_group", &GroupLookupRequest::Gid(gid)) {
+        Ok(entry) => fill_group(&entry, grp, buf, buflen, errnop),
+        Err(e) => lookup_error(e, errnop),
+    }
+}
+
+/// Adds the supplementary groups of the given user to the caller's group list, growing it as
+/// needed (up to `limit` if it is positive).
+///
+/// # Safety
+///
+/// `user` must be a valid C string, `start` and `size` must point to valid longs, `groupsp` must
+/// point to a `malloc`ed array of `size` gids and `errnop` must point to a valid int
+#[no_mangle]
+pub unsafe extern "C" fn _nss_snas_initgroups_dyn(
+    user: *const c_char,
+    group: gid_t,
+    start: *mut c_long,
+    size: *mut c_long,
+    groupsp: *mut *mut gid_t,
+    limit: c_long,
+    errnop: *mut c_int,
+) -> NssStatus {
+    let Ok(user) = CStr::from_ptr(user).to_str() else {
+        return not_found(errnop);
+    };
+    let gids: Vec<u32> = match client::request(
+        "user_group_ids",
+        &UserGroupsRequest {
+            username: user.to_owned(),
+        },
+    ) {
+        Ok(gids) => gids,
+        Err(e) => return lookup_error(e, errnop),
+    };
+    if append_groups(&gids, group, start, size, groupsp, limit) {
+        NssStatus::Success
+    } else {
+        *errnop = libc::ENOMEM;
+        NssStatus::TryAgain
+    }
+}
+
+/// Starts an enumeration of all users
+#[no_mangle]
+pub extern "C" fn _nss_snas_setpwent(_stayopen: c_int) -> NssStatus {
+    start_enumeration(&PASSWD_ENUMERATION, "enumerate_users")
+}
+
+/// Ends an enumeration of all users
+#[no_mangle]
+pub extern "C" fn _nss_snas_endpwent() -> NssStatus {
+    *lock(&PASSWD_ENUMERATION) = None;
+    NssStatus::Success
+}
+
+/// Returns the next user in the enumeration
+///
+/// # Safety
+///
+/// `pwd` must point to a valid `passwd`, `buf` must be valid for writes of `buflen` bytes and
+/// `errnop` must point to a valid int
+#[no_mangle]
+pub unsafe extern "C" fn _nss_snas_getpwent_r(
+    pwd: *mut passwd,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> NssStatus {
+    next_entry(
+        &PASSWD_ENUMERATION,
+        "enumerate_users",
+        errnop,
+        |entry, errnop| fill_passwd(entry, pwd, buf, buflen, errnop),
+    )
+}
+
+/// Starts an enumeration of all groups
+#[no_mangle]
+pub extern "C" fn _nss_snas_setgrent(_stayopen: c_int) -> NssStatus {
+    start_enumeration(&GROUP_ENUMERATION, "enumerate_groups")
+}
+
+/// Ends an enumeration of all groups
+#[no_mangle]
+pub extern "C" fn _nss_snas_endgrent() -> NssStatus {
+    *lock(&GROUP_ENUMERATION) = None;
+    NssStatus::Success
+}
+
+/// Returns the next group in the enumeration
+///
+/// # Safety
+///
+/// `grp` must point to a valid `group`, `buf` must be valid for writes of `buflen` bytes and
+/// `errnop` must point to a valid int
+#[no_mangle]
+pub unsafe extern "C" fn _nss_snas_getgrent_r(
+    grp: *mut group,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> NssStatus {
+    next_entry(
+        &GROUP_ENUMERATION,
+        "enumerate_groups",
+        errnop,
+        |entry, errnop| fill_group(entry, grp, buf, buflen, errnop),
+    )
+}
+
+fn lock<T>(
+    enumeration: &Mutex<Option<Enumeration<T>>>,
+) -> std::sync::MutexGuard<'_, Option<Enumeration<T>>> {
+    // A poisoned lock just means another thread panicked mid enumeration, the data is still fine
+    enumeration.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn start_enumeration<T: serde::de::DeserializeOwned + 'static>(
+    enumeration: &Mutex<Option<Enumeration<T>>>,
+    method: &str,
+) -> NssStatus {
+    match client::request::<_, Vec<T>>(method, &()) {
+        Ok(entries) => {
+            *lock(enumeration) = Some(Enumeration { entries, index: 0 });
+            NssStatus::Success
+        }
+        Err(_) => {
+            *lock(enumeration) = None;
+            NssStatus::Unavailable
+        }
+    }
+}
+
+/// Fills in the next entry of the enumeration, starting one if the caller didn't. The position is
+/// only advanced if the entry fit in the caller's buffer so it can be retried with a bigger one
+unsafe fn next_entry<T: serde::de::DeserializeOwned + 'static>(
+    enumeration: &Mutex<Option<Enumeration<T>>>,
+    method: &str,
+    errnop: *mut c_int,
+    fill: impl FnOnce(&T, *mut c_int) -> NssStatus,
+) -> NssStatus {
+    if lock(enumeration).is_none() {
+        let status = start_enumeration(enumeration, method);
+        if status != NssStatus::Success {
+            *errnop = libc::ENOENT;
+            return status;
+        }
+    }
+    let mut guard = lock(enumeration);
+    let Some(current) = guard.as_mut() else {
+        return not_found(errnop);
+    };
+    let Some(entry) = current.entries.get(current.index) else {
+        return not_found(errnop);
+    };
+    let status = fill(entry, errnop);
+    if status != NssStatus::TryAgain {
+        current.index += 1;
+    }
+    status
+}
+
+unsafe fn fill_passwd(
+    entry: &PasswdEntry,
+    pwd: *mut passwd,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> NssStatus {
+    let mut buffer = Buffer::new(buf, buflen);
+    let result = (|| {
+        Ok::<_, BufferError>((
+            buffer.add_str(&entry.name)?,
+            buffer.add_str("x")?,
+            buffer.add_str(&entry.gecos)?,
+            buffer.add_str(&entry.home)?,
+            buffer.add_str(&entry.shell)?,
+        ))
+    })();
+    match result {
+        Ok((name, password, gecos, home, shell)) => {
+            (*pwd).pw_name = name;
+            (*pwd).pw_passwd = password;
+            (*pwd).pw_uid = entry.uid;
+            (*pwd).pw_gid = entry.gid;
+            (*pwd).pw_gecos = gecos;
+            (*pwd).pw_dir = home;
+            (*pwd).pw_shell = shell;
+            NssStatus::Success
+        }
+        Err(e) => buffer_error(e, errnop),
+    }
+}
+
+unsafe fn fill_group(
+    entry: &GroupEntry,
+    grp: *mut group,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> NssStatus {
+    let mut buffer = Buffer::new(buf, buflen);
+    let result = (|| {
+        let name = buffer.add_str(&entry.name)?;
+        let password = buffer.add_str("x")?;
+        let members = entry
+            .members
+            .iter()
+            .map(|member| buffer.add_str(member))
+            .collect::<Result<Vec<_>, _>>()?;
+        let members = buffer.add_ptr_array(&members)?;
+        Ok::<_, BufferError>((name, password, members))
+    })();
+    match result {
+        Ok((name, password, members)) => {
+            (*grp).gr_name = name;
+            (*grp).gr_passwd = password;
+            (*grp).gr_gid = entry.gid;
+            (*grp).gr_mem = members;
+            NssStatus::Success
+        }
+        Err(e) => buffer_error(e, errnop),
+    }
+}
+
+/// Appends the gids to the group list, skipping the primary group and any duplicates. Returns false
+/// if the list could not be grown
+unsafe fn append_groups(
+    gids: &[gid_t],
+    primary: gid_t,
+    start: *mut c_long,
+    size: *mut c_long,
+    groupsp: *mut *mut gid_t,
+    limit: c_long,
+) -> bool {
+    for gid in gids.iter().copied().filter(|gid| *gid != primary) {
+        let existing = std::slice::from_raw_parts(*groupsp, *start as usize);
+        if existing.contains(&gid) {
+            continue;
+        }
+        if *start == *size {
+            if limit > 0 && *size >= limit {
+                // We've hit the caller's limit, so there is no more room for groups
+                break;
+            }
+            let mut new_size = (*size * 2).max(1);
+            if limit > 0 {
+                new_size = new_size.min(limit);
+            }
+            let new_groups = libc::realloc(
+                (*groupsp).cast(),
+                new_size as usize * std::mem::size_of::<gid_t>(),
+            )
+            .cast::<gid_t>();
+            if new_groups.is_null() {
+                return false;
+            }
+            *groupsp = new_groups;
+            *size = new_size;
+        }
+        (*groupsp).add(*start as usize).write(gid);
+        *start += 1;
+    }
+    true
+}
+
+unsafe fn lookup_error(err: LookupError, errnop: *mut c_int) -> NssStatus {
+    match err {
+        LookupError::NotFound => not_found(errnop),
+        LookupError::Unavailable => {
+            *errnop = libc::ENOENT;
+            NssStatus::Unavailable
+        }
+    }
+}
+
+unsafe fn buffer_error(err: BufferError, errnop: *mut c_int) -> NssStatus {
+    match err {
+        BufferError::TooSmall => {
+            *errnop = libc::ERANGE;
+            NssStatus::TryAgain
+        }
+        BufferError::InvalidString => not_found(errnop),
+    }
+}
+
+unsafe fn not_found(errnop: *mut c_int) -> NssStatus {
+    *errnop = libc::ENOENT;
+    NssStatus::NotFound
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fill_passwd() {
+        let entry = PasswdEntry {
+            name: "foo".to_string(),
+            uid: 10000,
+            gid: 10000,
+            gecos: "Foo Bar".to_string(),
+            home: "/home/foo".to_string(),
+            shell: "/bin/bash".to_string(),
+        };
+        let mut pwd: passwd = unsafe { std::mem::zeroed() };
+        let mut errno = 0;
+        let mut buf = vec![0 as c_char; 8];
+        let status =
+            unsafe { fill_passwd(&entry, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut errno) };
+        assert_eq!(status, NssStatus::TryAgain);
+        assert_eq!(errno, libc::ERANGE);
+
+        let mut buf = vec![0 as c_char; 128];
+        let status =
+            unsafe { fill_passwd(&entry, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut errno) };
+        assert_eq!(status, NssStatus::Success);
+        unsafe {
+            assert_eq!(CStr::from_ptr(pwd.pw_name).to_str().unwrap(), "foo");
+            assert_eq!(CStr::from_ptr(pwd.pw_dir).to_str().unwrap(), "/home/foo");
+            assert_eq!(CStr::from_ptr(pwd.pw_shell).to_str().unwrap(), "/bin/bash");
+        }
+        assert_eq!(pwd.pw_uid, 10000);
+    }
+
+    #[test]
+    fn test_append_groups() {
+        unsafe {
+            let groups = libc::malloc(std::mem::size_of::<gid_t>()).cast::<gid_t>();
+            groups.write(100);
+            let mut groupsp = groups;
+            let mut start: c_long = 1;
+            let mut size: c_long = 1;
+            assert!(append_groups(
+                &[100, 200, 300, 200],
+                100,
+                &mut start,
+                &mut size,
+                &mut groupsp,
+                0
+            ));
+            assert_eq!(
+                std::slice::from_raw_parts(groupsp, start as usize),
+                [100, 200, 300]
+            );
+            assert!(size >= start);
+
+            // Groups past the limit are dropped
+            assert!(append_groups(
+                &[400, 500],
+                100,
+                &mut start,
+                &mut size,
+                &mut groupsp,
+                4
+            ));
+            assert_eq!(
+                std::slice::from_raw_parts(groupsp, start as usize),
+                [100, 200, 300, 400]
+            );
+            libc::free(groupsp.cast());
+        }
+    }
+}
@@ -1,14 +1,201 @@
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::Context;
+use async_nats::ConnectOptions;
+use snas_lib::clients::NatsClient;
+use snas_pam_common::{initialize_runtime, ClientProvider, SnasPam};
+use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+/// Connected clients, keyed by the config they were created with. Long running PAM hosts (such as
+/// sshd) can load the module from several PAM service files with different arguments, so each
+/// distinct config gets its own connection rather than all of them sharing the first one
+static CLIENTS: Mutex<BTreeMap<ModuleConfig, &'static NatsClient>> =
+    Mutex::const_new(BTreeMap::new());
+const DEFAULT_CONFIG_PATH: &str = "/etc/snas/pam_nats.conf";
+const DEFAULT_NATS_SERVER: &str = "127.0.0.1:4222";
+
+/// Connection settings for the module. These can be set in a config file made up of `key=value`
+/// lines or passed directly as module arguments in the PAM config (e.g.
+/// `auth required pam_snas_nats.so server=nats://10.0.0.1:4222 creds=/etc/snas/user.creds`).
+/// Module arguments take precedence over values from the config file. A connection is made the
+/// first time a config is used and is reused by every later call with the same config.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct ModuleConfig {
+    /// The NATS server to connect to
+    server: Option<String>,
+    /// The creds file to use for authenticating to NATS
+    creds: Option<PathBuf>,
+    /// A CA certificate to use for TLS connections to NATS
+    ca_cert: Option<PathBuf>,
+    /// The topic prefix the SNAS user API is listening on
+    user_prefix: Option<String>,
+}
+
+impl ModuleConfig {
+    /// Builds the config from the given module arguments. If a `config=<path>` argument is given,
+    /// that file will be loaded first. Otherwise the default config file is loaded if it exists.
+    fn from_args(args: &[&CStr]) -> anyhow::Result<Self> {
+        let args = args
+            .iter()
+            .map(|arg| {
+                arg.to_str()
+                    .map(|s| s.to_owned())
+                    .context("Module argument is not valid UTF-8")
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let args = parse_pairs(args.iter().map(String::as_str))?;
+
+        let mut config = match args.get("config") {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(DEFAULT_CONFIG_PATH)?
+            }
+            None => Self::default(),
+        };
+        config.merge(&args)?;
+        Ok(config)
+    }
+
+    fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(&path).with_context(|| {
+            format!(
+                "Unable to read config file {}",
+                path.as_ref().to_string_lossy()
+            )
+        })?;
+        let pairs = parse_pairs(
+            raw.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#')),
+        )?;
+        let mut config = Self::default();
+        config.merge(&pairs)?;
+        Ok(config)
+    }
+
+    /// Overwrites any values in this config with the values in the given set of pairs
+    fn merge(&mut self, pairs: &HashMap<String, String>) -> anyhow::Result<()> {
+        for (key, value) in pairs {
+            match key.as_str() {
+                "server" => self.server = Some(value.to_owned()),
+                "creds" => self.creds = Some(PathBuf::from(value)),
+                "ca_cert" => self.ca_cert = Some(PathBuf::from(value)),
+                "user_prefix" => self.user_prefix = Some(value.to_owned()),
+                // This is handled when loading the config
+                "config" => (),
+                _ => anyhow::bail!("Unknown option {key}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_pairs<'a>(
+    items: impl Iterator<Item = &'a str>,
+) -> anyhow::Result<HashMap<String, String>> {
+    items
+        .map(|item| {
+            item.split_once('=')
+                .map(|(k, v)| (k.trim().to_owned(), v.trim().to_owned()))
+                .ok_or_else(|| anyhow::anyhow!("Option {item} is not of the form key=value"))
+        })
+        .collect()
+}
+
+struct NatsProvider;
+
+impl ClientProvider for NatsProvider {
+    type Client = NatsClient;
+
+    /// Connects to NATS using the given module arguments if there isn't already a client for the
+    /// resulting config. Unlike the runtime, a failed connection is not fatal and will be retried
+    /// on the next call.
+    fn client(args: &[&CStr]) -> anyhow::Result<(&'static Runtime, &'static NatsClient)> {
+        let runtime = RUNTIME.get_or_init(initialize_runtime);
+        let config = ModuleConfig::from_args(args)?;
+        let client = runtime.block_on(async {
+            // Holding the lock while connecting keeps concurrent calls from connecting twice
+            let mut clients = CLIENTS.lock().await;
+            if let Some(client) = clients.get(&config) {
+                return anyhow::Ok(*client);
+            }
+            // Clients live for as long as the module is loaded, like the runtime
+            let client: &'static NatsClient = Box::leak(Box::new(connect(config.clone()).await?));
+            clients.insert(config, client);
+            Ok(client)
+        })?;
+        Ok((runtime, client))
+    }
+}
+
+type PamNats = SnasPam<NatsProvider>;
+pam::pam_hooks!(PamNats);
+
+async fn connect(config: ModuleConfig) -> anyhow::Result<NatsClient> {
+    let mut opts = ConnectOptions::new();
+    if let Some(cert) = config.ca_cert {
+        opts = opts.add_root_certificates(cert)
+    }
+    if let Some(creds_file) = config.creds {
+        opts = opts
+            .credentials_file(creds_file)
+            .await
+            .context("Unable to open credentials file")?;
+    }
+    let client = opts
+        .connect(
+            config
+                .server
+                .unwrap_or_else(|| DEFAULT_NATS_SERVER.to_string()),
+        )
+        .await
+        .context("Unable to connect to NATS")?;
+    // This module only uses the user API, so the admin prefix is left as the default
+    NatsClient::new_with_prefix(client, config.user_prefix, None)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::ffi::CString;
+
     use super::*;
 
     #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+    fn test_config_parsing() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("pam_nats.conf");
+        std::fs::write(
+            &config_path,
+            "# A comment\nserver = nats://10.0.0.1:4222\n\ncreds=/etc/snas/user.creds\nuser_prefix=from.file\n",
+        )
+        .unwrap();
+
+        let config_arg = CString::new(format!("config={}", config_path.display())).unwrap();
+        let prefix_arg = CString::new("user_prefix=from.args").unwrap();
+        let config =
+            ModuleConfig::from_args(&[config_arg.as_c_str(), prefix_arg.as_c_str()]).unwrap();
+        assert_eq!(
+            config,
+            ModuleConfig {
+                server: Some("nats://10.0.0.1:4222".to_string()),
+                creds: Some(PathBuf::from("/etc/snas/user.creds")),
+                ca_cert: None,
+                user_prefix: Some("from.args".to_string()),
+            },
+            "Module arguments should override the config file"
+        );
+
+        let bad_arg = CString::new("nonsense").unwrap();
+        ModuleConfig::from_args(&[bad_arg.as_c_str()])
+            .expect_err("Arguments not in key=value form should error");
+
+        let unknown_arg = CString::new("foo=bar").unwrap();
+        ModuleConfig::from_args(&[unknown_arg.as_c_str()])
+            .expect_err("Unknown arguments should error");
     }
 }
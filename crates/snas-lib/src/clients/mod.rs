@@ -2,9 +2,12 @@ use std::collections::BTreeSet;
 use std::future::Future;
 
 use crate::{
-    admin::{PasswordResetResponse, UserResponse},
-    api::VerificationResponse,
-    SecureString,
+    admin::{
+        ConflictPolicy, Directory, GroupResponse, ImportSummary, PasswordResetResponse,
+        PosixAttributesRequest, UserResponse,
+    },
+    api::{GroupEntry, GroupLookupRequest, PasswdEntry, UserLookupRequest, VerificationResponse},
+    PosixAttributes, SecureString,
 };
 
 mod nats;
@@ -38,6 +41,20 @@ pub trait AdminClient: GetUserClient {
         password: SecureString,
         groups: BTreeSet<String>,
         force_password_change: bool,
+        posix: PosixAttributesRequest,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Create a new user with a password hash imported from another system, such as a sha512-crypt
+    /// hash from `/etc/shadow` or a bcrypt hash from an htpasswd file. The hash is converted to
+    /// Argon2 the next time the user logs in. Returns an error if the user already exists or the
+    /// hash isn't in a supported format.
+    fn add_user_with_hash(
+        &self,
+        username: &str,
+        password_hash: SecureString,
+        groups: BTreeSet<String>,
+        force_password_change: bool,
+        posix: PosixAttributesRequest,
     ) -> impl Future<Output = anyhow::Result<()>> + Send;
 
     /// List all usernames.
@@ -53,6 +70,30 @@ pub trait AdminClient: GetUserClient {
         username: &str,
     ) -> impl Future<Output = anyhow::Result<PasswordResetResponse>> + Send;
 
+    /// Set the password of the given user without going through the reset flow. If
+    /// `force_password_change` is set, the user has to change it the next time they log in.
+    /// Returns an error if the user does not exist or the password doesn't satisfy the password
+    /// policy.
+    fn set_password(
+        &self,
+        username: &str,
+        password: SecureString,
+        force_password_change: bool,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Unlock the given user after they were locked out due to failed login attempts. Returns an
+    /// error if the user does not exist.
+    fn unlock_user(&self, username: &str) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Set the POSIX attributes of the user with the given username. Any attributes that are not
+    /// set are left unchanged. Returns an error if the user does not exist. Returns the complete
+    /// set of attributes after the change.
+    fn set_attributes(
+        &self,
+        username: &str,
+        attributes: PosixAttributesRequest,
+    ) -> impl Future<Output = anyhow::Result<PosixAttributes>> + Send;
+
     /// Add the given groups to the user with the given username. Returns an error if the user does
     /// not exist. Returns the new list of groups.
     fn add_groups(
@@ -68,6 +109,46 @@ pub trait AdminClient: GetUserClient {
         username: &str,
         groups: BTreeSet<String>,
     ) -> impl Future<Output = anyhow::Result<BTreeSet<String>>> + Send;
+
+    /// Create a new group with the given name and description. If no GID is given, the server will
+    /// allocate one. Returns an error if the group already exists. Returns the created group.
+    fn add_group(
+        &self,
+        name: &str,
+        gid: Option<u32>,
+        description: String,
+    ) -> impl Future<Output = anyhow::Result<GroupResponse>> + Send;
+
+    /// Get the group with the given name. Returns an error if the group does not exist.
+    fn get_group(&self, name: &str) -> impl Future<Output = anyhow::Result<GroupResponse>> + Send;
+
+    /// List all group names.
+    fn list_groups(&self) -> impl Future<Output = anyhow::Result<Vec<String>>> + Send;
+
+    /// Delete the group with the given name, removing it from all of its members. Returns an error
+    /// if the group does not exist.
+    fn remove_group(&self, name: &str) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// List the usernames of all members of the given group. Returns an error if the group does
+    /// not exist.
+    fn group_members(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = anyhow::Result<BTreeSet<String>>> + Send;
+
+    /// Export every user (including their password hash) and group.
+    fn export_directory(&self) -> impl Future<Output = anyhow::Result<Directory>> + Send;
+
+    /// Import the given users and groups, handling any that already exist with the given conflict
+    /// policy. If `dry_run` is set, nothing is changed and the summary shows what would have
+    /// happened. Returns an error if the conflict policy is [`ConflictPolicy::Fail`] and any entry
+    /// already exists.
+    fn import_directory(
+        &self,
+        directory: Directory,
+        on_conflict: ConflictPolicy,
+        dry_run: bool,
+    ) -> impl Future<Output = anyhow::Result<ImportSummary>> + Send;
 }
 
 pub trait UserClient {
@@ -86,3 +167,31 @@ pub trait UserClient {
         new_password: SecureString,
     ) -> impl Future<Output = anyhow::Result<()>> + Send;
 }
+
+/// A trait for clients that can look up account information for users and groups. This is the
+/// read only API used by things like NSS modules
+pub trait LookupClient {
+    /// Look up a user by name or UID. Returns an error if the user does not exist.
+    fn lookup_user(
+        &self,
+        req: UserLookupRequest,
+    ) -> impl Future<Output = anyhow::Result<PasswdEntry>> + Send;
+
+    /// Look up a group by name or GID. Returns an error if the group does not exist.
+    fn lookup_group(
+        &self,
+        req: GroupLookupRequest,
+    ) -> impl Future<Output = anyhow::Result<GroupEntry>> + Send;
+
+    /// List the account information for all users.
+    fn enumerate_users(&self) -> impl Future<Output = anyhow::Result<Vec<PasswdEntry>>> + Send;
+
+    /// List all groups.
+    fn enumerate_groups(&self) -> impl Future<Output = anyhow::Result<Vec<GroupEntry>>> + Send;
+
+    /// Get the GIDs of the supplementary groups the given user is a member of.
+    fn user_group_ids(
+        &self,
+        username: &str,
+    ) -> impl Future<Output = anyhow::Result<Vec<u32>>> + Send;
+}
@@ -6,11 +6,18 @@ use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
     admin::{
-        GroupModifyRequest, PasswordResetRequest, PasswordResetResponse, UserAddRequest,
-        UserDeleteRequest, UserGetRequest, UserResponse,
+        ConflictPolicy, Directory, GroupAddRequest, GroupDeleteRequest, GroupGetRequest,
+        GroupMembersRequest, GroupModifyRequest, GroupResponse, ImportRequest, ImportSummary,
+        PasswordResetRequest, PasswordResetResponse, PosixAttributesRequest, SetAttributesRequest,
+        SetPasswordRequest, UserAddRequest, UserDeleteRequest, UserGetRequest, UserResponse,
+        UserUnlockRequest,
     },
-    api::{GenericResponse, PasswordChangeRequest, VerificationRequest, VerificationResponse},
-    SecureString, DEFAULT_ADMIN_NATS_SUBJECT_PREFIX, DEFAULT_USER_NATS_SUBJECT_PREFIX,
+    api::{
+        GenericResponse, PasswordChangeRequest, PolicyViolation, VerificationRequest,
+        VerificationResponse,
+    },
+    PosixAttributes, SecureString, DEFAULT_ADMIN_NATS_SUBJECT_PREFIX,
+    DEFAULT_USER_NATS_SUBJECT_PREFIX,
 };
 
 pub struct NatsClient {
@@ -60,6 +67,13 @@ impl NatsClient {
         let response = self.client.request(subject, serialized.into()).await?;
         serde_json::from_slice(&response.payload).context("unable to deserialize response")
     }
+
+    async fn send_add_user(&self, req: UserAddRequest) -> anyhow::Result<()> {
+        let subject = format!("{}.add_user", self.admin_topic_prefix);
+        // Failed responses contain any password policy violations
+        let resp: GenericResponse<Vec<PolicyViolation>> = self.do_request(subject, &req).await?;
+        resp.into_result_empty().context("Error while adding user")
+    }
 }
 
 impl super::UserClient for NatsClient {
@@ -91,7 +105,9 @@ impl super::UserClient for NatsClient {
             old_password,
             new_password,
         };
-        let resp: GenericResponse<()> = self.do_request(subject, &payload).await?;
+        // Failed responses contain any password policy violations
+        let resp: GenericResponse<Vec<PolicyViolation>> =
+            self.do_request(subject, &payload).await?;
         resp.into_result_empty()
             .context("Error while changing password")
     }
@@ -116,16 +132,36 @@ impl super::AdminClient for NatsClient {
         password: SecureString,
         groups: BTreeSet<String>,
         force_password_change: bool,
+        posix: PosixAttributesRequest,
     ) -> anyhow::Result<()> {
-        let subject = format!("{}.add_user", self.admin_topic_prefix);
-        let payload = UserAddRequest {
+        self.send_add_user(UserAddRequest {
             username: username.to_string(),
             password,
             groups,
             force_password_change,
-        };
-        let resp: GenericResponse<()> = self.do_request(subject, &payload).await?;
-        resp.into_result_empty().context("Error while adding user")
+            posix,
+            prehashed: false,
+        })
+        .await
+    }
+
+    async fn add_user_with_hash(
+        &self,
+        username: &str,
+        password_hash: SecureString,
+        groups: BTreeSet<String>,
+        force_password_change: bool,
+        posix: PosixAttributesRequest,
+    ) -> anyhow::Result<()> {
+        self.send_add_user(UserAddRequest {
+            username: username.to_string(),
+            password: password_hash,
+            groups,
+            force_password_change,
+            posix,
+            prehashed: true,
+        })
+        .await
     }
 
     async fn list_users(&self) -> anyhow::Result<Vec<String>> {
@@ -156,6 +192,50 @@ impl super::AdminClient for NatsClient {
             .context("Error while resetting password")
     }
 
+    async fn set_password(
+        &self,
+        username: &str,
+        password: SecureString,
+        force_password_change: bool,
+    ) -> anyhow::Result<()> {
+        let subject = format!("{}.set_password", self.admin_topic_prefix);
+        let payload = SetPasswordRequest {
+            username: username.to_string(),
+            password,
+            force_password_change,
+        };
+        // Failed responses contain any password policy violations
+        let resp: GenericResponse<Vec<PolicyViolation>> =
+            self.do_request(subject, &payload).await?;
+        resp.into_result_empty()
+            .context("Error while setting password")
+    }
+
+    async fn unlock_user(&self, username: &str) -> anyhow::Result<()> {
+        let subject = format!("{}.unlock_user", self.admin_topic_prefix);
+        let payload = UserUnlockRequest {
+            username: username.to_string(),
+        };
+        let resp: GenericResponse<()> = self.do_request(subject, &payload).await?;
+        resp.into_result_empty()
+            .context("Error while unlocking user")
+    }
+
+    async fn set_attributes(
+        &self,
+        username: &str,
+        attributes: PosixAttributesRequest,
+    ) -> anyhow::Result<PosixAttributes> {
+        let subject = format!("{}.set_attributes", self.admin_topic_prefix);
+        let payload = SetAttributesRequest {
+            username: username.to_string(),
+            attributes,
+        };
+        let resp: GenericResponse<PosixAttributes> = self.do_request(subject, &payload).await?;
+        resp.into_result_required()
+            .context("Error while setting attributes")
+    }
+
     async fn add_groups(
         &self,
         username: &str,
@@ -185,4 +265,82 @@ impl super::AdminClient for NatsClient {
         resp.into_result_required()
             .context("Error while removing groups")
     }
+
+    async fn add_group(
+        &self,
+        name: &str,
+        gid: Option<u32>,
+        description: String,
+    ) -> anyhow::Result<GroupResponse> {
+        let subject = format!("{}.add_group", self.admin_topic_prefix);
+        let payload = GroupAddRequest {
+            name: name.to_string(),
+            gid,
+            description,
+        };
+        let resp: GenericResponse<GroupResponse> = self.do_request(subject, &payload).await?;
+        resp.into_result_required()
+            .context("Error while adding group")
+    }
+
+    async fn get_group(&self, name: &str) -> anyhow::Result<GroupResponse> {
+        let subject = format!("{}.get_group", self.admin_topic_prefix);
+        let payload = GroupGetRequest {
+            name: name.to_string(),
+        };
+        let resp: GenericResponse<GroupResponse> = self.do_request(subject, &payload).await?;
+        resp.into_result_required()
+            .context("Error while getting group")
+    }
+
+    async fn list_groups(&self) -> anyhow::Result<Vec<String>> {
+        let subject = format!("{}.list_groups", self.admin_topic_prefix);
+        let resp: GenericResponse<Vec<String>> = self.do_request(subject, &()).await?;
+        resp.into_result_required()
+            .context("Error while listing groups")
+    }
+
+    async fn remove_group(&self, name: &str) -> anyhow::Result<()> {
+        let subject = format!("{}.remove_group", self.admin_topic_prefix);
+        let payload = GroupDeleteRequest {
+            name: name.to_string(),
+        };
+        let resp: GenericResponse<()> = self.do_request(subject, &payload).await?;
+        resp.into_result_empty()
+            .context("Error while removing group")
+    }
+
+    async fn group_members(&self, name: &str) -> anyhow::Result<BTreeSet<String>> {
+        let subject = format!("{}.group_members", self.admin_topic_prefix);
+        let payload = GroupMembersRequest {
+            name: name.to_string(),
+        };
+        let resp: GenericResponse<BTreeSet<String>> = self.do_request(subject, &payload).await?;
+        resp.into_result_required()
+            .context("Error while getting group members")
+    }
+
+    async fn export_directory(&self) -> anyhow::Result<Directory> {
+        let subject = format!("{}.export", self.admin_topic_prefix);
+        let resp: GenericResponse<Directory> = self.do_request(subject, &()).await?;
+        resp.into_result_required()
+            .context("Error while exporting directory")
+    }
+
+    async fn import_directory(
+        &self,
+        directory: Directory,
+        on_conflict: ConflictPolicy,
+        dry_run: bool,
+    ) -> anyhow::Result<ImportSummary> {
+        let subject = format!("{}.import", self.admin_topic_prefix);
+        let payload = ImportRequest {
+            directory,
+            on_conflict,
+            dry_run,
+        };
+        let resp: GenericResponse<ImportSummary> = self.do_request(subject, &payload).await?;
+        resp.into_result_required()
+            .context("Error while importing directory")
+    }
 }
@@ -9,9 +9,11 @@ use tokio::sync::Mutex;
 use tracing::{instrument, trace};
 
 use crate::api::{
-    GenericResponse, PasswordChangeRequest, VerificationRequest, VerificationResponse,
+    GenericResponse, GroupEntry, GroupLookupRequest, HealthResponse, PasswdEntry,
+    PasswordChangeRequest, PolicyViolation, UserGroupsRequest, UserLookupRequest,
+    VerificationRequest, VerificationResponse,
 };
-use crate::clients::UserClient;
+use crate::clients::{LookupClient, UserClient};
 use crate::{SecureString, REQUEST_IDENTIFIER, RESPONSE_IDENTIFIER, TERMINATOR};
 
 /// A client for communicating with the SNAS user API over a unix socket. It will automatically try
@@ -53,6 +55,15 @@ impl SocketClient {
         socket.shutdown().await.map_err(Into::into)
     }
 
+    /// Checks the health of the server. This succeeds even if the server isn't ready, so check
+    /// [`ready`](HealthResponse::ready) on the response
+    pub async fn health(&self) -> anyhow::Result<HealthResponse> {
+        self.reconnect().await?;
+        let resp = self.send_request("health", ()).await?;
+        resp.into_result_required()
+            .context("Error while checking server health")
+    }
+
     #[instrument(level = "debug", skip(self, data))]
     async fn send_request<Req: Serialize, Resp: DeserializeOwned + 'static>(
         &self,
@@ -149,7 +160,8 @@ impl UserClient for SocketClient {
         new_password: SecureString,
     ) -> anyhow::Result<()> {
         self.reconnect().await?;
-        let resp = self
+        // Failed responses contain any password policy violations
+        let resp: GenericResponse<Vec<PolicyViolation>> = self
             .send_request(
                 "change_password",
                 PasswordChangeRequest {
@@ -164,6 +176,50 @@ impl UserClient for SocketClient {
     }
 }
 
+impl LookupClient for SocketClient {
+    async fn lookup_user(&self, req: UserLookupRequest) -> anyhow::Result<PasswdEntry> {
+        self.reconnect().await?;
+        let resp = self.send_request("lookup_user", req).await?;
+        resp.into_result_required()
+            .context("Error while looking up user")
+    }
+
+    async fn lookup_group(&self, req: GroupLookupRequest) -> anyhow::Result<GroupEntry> {
+        self.reconnect().await?;
+        let resp = self.send_request("lookup_group", req).await?;
+        resp.into_result_required()
+            .context("Error while looking up group")
+    }
+
+    async fn enumerate_users(&self) -> anyhow::Result<Vec<PasswdEntry>> {
+        self.reconnect().await?;
+        let resp = self.send_request("enumerate_users", ()).await?;
+        resp.into_result_required()
+            .context("Error while listing users")
+    }
+
+    async fn enumerate_groups(&self) -> anyhow::Result<Vec<GroupEntry>> {
+        self.reconnect().await?;
+        let resp = self.send_request("enumerate_groups", ()).await?;
+        resp.into_result_required()
+            .context("Error while listing groups")
+    }
+
+    async fn user_group_ids(&self, username: &str) -> anyhow::Result<Vec<u32>> {
+        self.reconnect().await?;
+        let resp = self
+            .send_request(
+                "user_group_ids",
+                UserGroupsRequest {
+                    username: username.to_owned(),
+                },
+            )
+            .await?;
+        resp.into_result_required()
+            .context("Error while getting user groups")
+    }
+}
+
 async fn parse_response(stream: &mut UnixStream) -> anyhow::Result<Vec<u8>> {
     let mut reader = BufReader::new(stream);
     let mut buf = [0u8; RESPONSE_IDENTIFIER.len()];
@@ -1,3 +1,5 @@
+use crate::api::{ErrorKind, PolicyViolation};
+
 pub type Result<T> = std::result::Result<T, HandleError>;
 
 #[derive(Debug, thiserror::Error)]
@@ -11,10 +13,71 @@ pub enum HandleError {
     /// The password was reset and has expired
     #[error("Password reset has expired")]
     PasswordResetExpired,
+    /// The user has a pending password reset and has to change their password before logging in
+    /// somewhere that can't walk them through it
+    #[error("Password change required")]
+    PasswordChangeRequired,
+    /// The user has been locked out due to too many failed login attempts
+    #[error("Account is locked due to too many failed login attempts")]
+    AccountLocked,
+    /// The new password does not satisfy the password policy
+    #[error("Password does not meet the password policy: {}", join_violations(.0))]
+    PasswordPolicy(Vec<PolicyViolation>),
+    /// The username is not allowed (e.g. it uses a reserved prefix)
+    #[error("Invalid username: {0}")]
+    InvalidUsername(String),
+    /// The requested UID is already assigned to another user
+    #[error("UID {0} is already in use")]
+    UidTaken(u32),
+    /// The group requested for creation already exists
+    #[error("Group already exists")]
+    GroupTaken,
+    /// The group name is not allowed (e.g. it uses a reserved prefix)
+    #[error("Invalid group name: {0}")]
+    InvalidGroupName(String),
+    /// The requested GID is already assigned to another group or user
+    #[error("GID {0} is already in use")]
+    GidTaken(u32),
     /// The username sent for the requested operation does not exist
     #[error("Username does not exist")]
     UsernameDoesNotExist,
+    /// The group sent for the requested operation does not exist
+    #[error("Group does not exist")]
+    GroupDoesNotExist,
+    /// An imported password hash isn't in a supported format
+    #[error("Invalid password hash: {0}")]
+    InvalidPasswordHash(String),
+    /// An import was set to fail on conflicts and some of its entries already exist
+    #[error("Import conflicts with existing entries: {0}")]
+    ImportConflict(String),
+    /// The stored data may be out of date and the handlers are configured to fail closed
+    #[error("User data may be out of date, try again later")]
+    StaleData,
     /// Errors that occur when interacting with storage or other parts of the system
     #[error(transparent)]
     SystemError(#[from] anyhow::Error),
 }
+
+impl HandleError {
+    /// The kind of failure to report to clients, for errors they may want to handle differently
+    pub fn kind(&self) -> Option<ErrorKind> {
+        match self {
+            HandleError::InvalidCredentials => Some(ErrorKind::InvalidCredentials),
+            HandleError::AccountLocked => Some(ErrorKind::AccountLocked),
+            HandleError::PasswordResetExpired => Some(ErrorKind::PasswordResetExpired),
+            HandleError::PasswordPolicy(_) => Some(ErrorKind::PasswordPolicy),
+            HandleError::UsernameDoesNotExist | HandleError::GroupDoesNotExist => {
+                Some(ErrorKind::NotFound)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn join_violations(violations: &[PolicyViolation]) -> String {
+    violations
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
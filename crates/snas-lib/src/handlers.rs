@@ -1,42 +1,228 @@
 use std::{
-    collections::BTreeSet,
-    sync::Arc,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ops::RangeInclusive,
+    sync::{Arc, Weak},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Context;
 use argon2::{
     password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
+    Argon2, Params, Version,
 };
 use rand::rngs::OsRng;
 use rand::{distributions::Alphanumeric, Rng};
-use tracing::error;
+use tokio::sync::OnceCell;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, warn};
 
 use crate::{
-    admin::{PasswordResetResponse, UserAddRequest, UserResponse},
-    api::VerificationResponse,
+    admin::{
+        ConflictPolicy, Directory, GroupAddRequest, GroupRecord, GroupResponse, ImportRequest,
+        ImportSummary, PasswordResetResponse, PosixAttributesRequest, UserAddRequest, UserRecord,
+        UserResponse,
+    },
+    api::{
+        GroupEntry, GroupLookupRequest, HealthResponse, PasswdEntry, UserLookupRequest,
+        VerificationResponse,
+    },
     error::{HandleError, Result},
-    storage::CredStore,
-    PasswordResetPhase, SecureString, UserInfo,
+    metrics,
+    policy::PasswordPolicy,
+    storage::{CredentialBackend, RESERVED_KEY_PREFIX},
+    GroupInfo, PasswordResetPhase, PosixAttributes, SecureString, UserInfo,
 };
 
+/// How long a user has to log in and change their password after it is reset
 // TODO(thomastaylor312): We eventually should make this configurable
-const DEFAULT_RESET_EXPIRY: Duration = Duration::from_secs(60 * 60 * 24);
+pub const DEFAULT_RESET_EXPIRY: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Controls when users are locked out after repeated failed login attempts
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    /// The number of consecutive failed attempts before a user is locked out. A value of 0
+    /// disables lockouts entirely
+    pub max_failed_attempts: u32,
+    /// How long a user stays locked out once the threshold is reached
+    pub lockout_duration: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            max_failed_attempts: 5,
+            lockout_duration: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+pub use argon2::Algorithm as Argon2Algorithm;
+
+/// The Argon2 parameters new password hashes are made with. Hashes made with different parameters
+/// still verify, and are upgraded to these parameters the next time the user logs in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Config {
+    pub algorithm: Argon2Algorithm,
+    /// The amount of memory to use, in KiB
+    pub memory_cost: u32,
+    /// The number of passes over the memory
+    pub iterations: u32,
+    /// The number of lanes to hash in parallel
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Config {
+    /// The defaults recommended by the argon2 crate, which are the minimums OWASP recommends
+    fn default() -> Self {
+        Argon2Config {
+            algorithm: Argon2Algorithm::Argon2id,
+            memory_cost: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl Argon2Config {
+    /// Returns a hasher using these parameters, or an error if Argon2 doesn't accept them
+    pub fn hasher(&self) -> anyhow::Result<Argon2<'static>> {
+        let params = Params::new(self.memory_cost, self.iterations, self.parallelism, None)
+            .map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters: {e}"))?;
+        Ok(Argon2::new(self.algorithm, Version::V0x13, params))
+    }
+
+    /// Returns true if the hash was made with these parameters. Anything that can't be parsed as
+    /// an Argon2 hash is treated as outdated
+    fn is_current(&self, hashed_password: &SecureString) -> bool {
+        let Ok(hash) = PasswordHash::new(hashed_password.as_ref()) else {
+            return false;
+        };
+        let Ok(params) = Params::try_from(&hash) else {
+            return false;
+        };
+        hash.algorithm == self.algorithm.ident()
+            && hash.version == Some(Version::V0x13.into())
+            && params.m_cost() == self.memory_cost
+            && params.t_cost() == self.iterations
+            && params.p_cost() == self.parallelism
+    }
+}
+
+/// Defaults used when giving users POSIX attributes
+#[derive(Debug, Clone)]
+pub struct PosixConfig {
+    /// The range UIDs (and group GIDs) are allocated from when one isn't given
+    pub uid_range: RangeInclusive<u32>,
+    /// The directory user home directories are placed in
+    pub home_base: String,
+    /// The login shell for users
+    pub shell: String,
+}
+
+impl Default for PosixConfig {
+    fn default() -> Self {
+        PosixConfig {
+            uid_range: 10000..=59999,
+            home_base: "/home".to_string(),
+            shell: "/bin/bash".to_string(),
+        }
+    }
+}
+
+/// Per user locks that serialize operations on the same user within this process. Requests are
+/// handled concurrently, so without this two requests for the same user (e.g. a login racing a
+/// password reset) could each read the user and then overwrite the other's changes
+#[derive(Default)]
+struct UserLocks {
+    locks: std::sync::Mutex<HashMap<String, Weak<tokio::sync::Mutex<()>>>>,
+}
+
+impl UserLocks {
+    async fn lock(&self, username: &str) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+            match locks.get(username).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    // Clean up locks for users that no longer have any operations in flight
+                    locks.retain(|_, lock| lock.strong_count() > 0);
+                    let lock = Arc::new(tokio::sync::Mutex::new(()));
+                    locks.insert(username.to_owned(), Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+        lock.lock_owned().await
+    }
+}
 
 #[derive(Clone)]
 pub struct Handlers {
-    store: Arc<CredStore>,
+    store: Arc<dyn CredentialBackend>,
+    user_locks: Arc<UserLocks>,
+    lockout: LockoutPolicy,
+    password_policy: Arc<PasswordPolicy>,
+    posix: PosixConfig,
+    reject_when_stale: bool,
+    argon2: Argon2Config,
+    /// A hash of a random password that is checked against when a user doesn't exist
+    dummy_hash: Arc<OnceCell<SecureString>>,
+    /// Tracks failed login attempts that are still being recorded
+    background: TaskTracker,
 }
 
 impl Handlers {
     /// Configures the handlers with the given store and default groups.
-    pub fn new(store: CredStore) -> Handlers {
+    pub fn new(store: impl CredentialBackend) -> Handlers {
         Handlers {
             store: Arc::new(store),
+            user_locks: Arc::new(UserLocks::default()),
+            lockout: LockoutPolicy::default(),
+            password_policy: Arc::new(PasswordPolicy::default()),
+            posix: PosixConfig::default(),
+            reject_when_stale: false,
+            argon2: Argon2Config::default(),
+            dummy_hash: Arc::new(OnceCell::new()),
+            background: TaskTracker::new(),
         }
     }
 
+    /// Sets the lockout policy to use for failed login attempts
+    pub fn with_lockout_policy(mut self, policy: LockoutPolicy) -> Handlers {
+        self.lockout = policy;
+        self
+    }
+
+    /// Sets the policy that all new passwords must satisfy
+    pub fn with_password_policy(mut self, policy: PasswordPolicy) -> Handlers {
+        self.password_policy = Arc::new(policy);
+        self
+    }
+
+    /// Sets the defaults used when giving users POSIX attributes
+    pub fn with_posix_config(mut self, config: PosixConfig) -> Handlers {
+        self.posix = config;
+        self
+    }
+
+    /// Sets the Argon2 parameters used to hash passwords. Returns an error if Argon2 doesn't accept
+    /// them
+    pub fn with_argon2_config(mut self, config: Argon2Config) -> anyhow::Result<Handlers> {
+        config.hasher()?;
+        self.argon2 = config;
+        // The dummy hash has to use the same parameters as real hashes to take as long to verify
+        self.dummy_hash = Arc::new(OnceCell::new());
+        Ok(self)
+    }
+
+    /// Fail closed by refusing to verify credentials or change passwords while the store reports
+    /// that its data [may be out of date](CredentialBackend::is_stale). By default, requests are
+    /// answered from whatever data the store has
+    pub fn with_reject_when_stale(mut self, reject: bool) -> Handlers {
+        self.reject_when_stale = reject;
+        self
+    }
+
     /// Verify the given username and password. Returns the groups the user is a member of and
     /// whether or not the user was verified.
     pub async fn verify(
@@ -44,32 +230,86 @@ impl Handlers {
         username: &str,
         password: SecureString,
     ) -> Result<VerificationResponse> {
-        let current_user = self
-            .store
-            .get_user(username)
-            .await
-            .ok_or_else(|| HandleError::InvalidCredentials)?;
+        let result = self.verify_user(username, password, true).await;
+        metrics::record_verification(&result);
+        result
+    }
 
-        let current_user = self
-            .enforce_login_state(username, current_user, false)
-            .await?;
+    /// Verify the given username and password for a login that has no way to walk the user
+    /// through changing their password (such as NATS or LDAP). Users with a pending password reset
+    /// are refused with [`HandleError::PasswordChangeRequired`] (or
+    /// [`HandleError::PasswordResetExpired`] once it has expired) without moving their reset to
+    /// the next phase, so they can still log in somewhere that can change it
+    pub async fn verify_non_interactive(
+        &self,
+        username: &str,
+        password: SecureString,
+    ) -> Result<VerificationResponse> {
+        let result = self.verify_user(username, password, false).await;
+        metrics::record_verification(&result);
+        result
+    }
+
+    async fn verify_user(
+        &self,
+        username: &str,
+        password: SecureString,
+        interactive: bool,
+    ) -> Result<VerificationResponse> {
+        self.ensure_fresh()?;
+        let (_lock, current_user) = self.check_credentials(username, &password).await?;
+
+        let current_user = if interactive {
+            self.enforce_login_state(username, current_user, false)
+                .await?
+        } else {
+            current_user
+        };
 
-        verify_password(&current_user, &password)?;
+        let current_user = self.clear_failed_attempts(username, current_user).await?;
+        if !interactive {
+            ensure_no_pending_reset(&current_user)?;
+        }
+        let current_user = self.upgrade_hash(username, current_user, password).await;
         Ok(VerificationResponse {
             valid: true,
             message: "Successfully verified".to_string(),
             needs_password_reset: current_user.password_reset.is_some(),
             groups: current_user.groups,
+            posix: current_user.posix,
         })
     }
 
-    /// Add the given user to the system. This is meant to be used by admins only
+    /// Add the given user to the system. This is meant to be used by admins only. If the request
+    /// is [prehashed](UserAddRequest::prehashed), the password is an existing hash that is stored as
+    /// is (and upgraded to Argon2 when the user first logs in)
     pub async fn add(&self, req: UserAddRequest) -> Result<()> {
+        if req.username.starts_with(RESERVED_KEY_PREFIX) {
+            return Err(HandleError::InvalidUsername(format!(
+                "usernames cannot start with {RESERVED_KEY_PREFIX}"
+            )));
+        }
+        let _lock = self.user_locks.lock(&req.username).await;
         if self.store.exists(&req.username).await? {
             return Err(HandleError::UsernameTaken);
         }
-
-        let hashed_password = hash_password(&req.password)?;
+        // A created group would hide this user's private group, so don't allow the clash
+        if self.store.get_group(&req.username).await.is_some() {
+            return Err(HandleError::InvalidUsername(format!(
+                "a group named {} already exists",
+                req.username
+            )));
+        }
+        let hashed_password = if req.prehashed {
+            // We can't check the policy against a hash, so imported passwords are taken as is
+            HashFormat::validate(req.password.as_ref())?;
+            req.password.clone()
+        } else {
+            self.password_policy
+                .check(&req.username, &req.password)
+                .map_err(HandleError::PasswordPolicy)?;
+            hash_password(self.argon2, req.password.clone()).await?
+        };
         let password_reset = if req.force_password_change {
             Some(PasswordResetPhase::Reset(get_expiry_duration(
                 DEFAULT_RESET_EXPIRY,
@@ -77,16 +317,21 @@ impl Handlers {
         } else {
             None
         };
+        let posix = self.new_posix_attributes(&req.username, req.posix).await?;
+        let uid = posix.uid;
         let user_data = UserInfo {
             hashed_password,
             password_reset,
             groups: req.groups,
+            posix: Some(posix),
+            ..Default::default()
         };
 
-        self.store
-            .put_user(req.username, user_data)
-            .await
-            .map_err(HandleError::from)
+        if let Err(e) = self.store.put_user(req.username, user_data).await {
+            self.release_id(uid).await;
+            return Err(e.into());
+        }
+        Ok(())
     }
 
     /// Change the password for the given user. Requires the current password.
@@ -96,19 +341,32 @@ impl Handlers {
         current_password: SecureString,
         new_password: SecureString,
     ) -> Result<()> {
-        let current_user = self
-            .store
-            .get_user(username)
-            .await
-            .ok_or_else(|| HandleError::InvalidCredentials)?;
+        let result = self
+            .change_user_password(username, current_password, new_password)
+            .await;
+        metrics::record_password_change(&result);
+        result
+    }
 
-        let mut current_user = self
+    async fn change_user_password(
+        &self,
+        username: &str,
+        current_password: SecureString,
+        new_password: SecureString,
+    ) -> Result<()> {
+        self.ensure_fresh()?;
+        let (_lock, current_user) = self.check_credentials(username, &current_password).await?;
+
+        let current_user = self
             .enforce_login_state(username, current_user, true)
             .await?;
 
-        verify_password(&current_user, &current_password)?;
+        let mut current_user = self.clear_failed_attempts(username, current_user).await?;
+        self.password_policy
+            .check(username, &new_password)
+            .map_err(HandleError::PasswordPolicy)?;
 
-        let hashed_password = hash_password(&new_password)?;
+        let hashed_password = hash_password(self.argon2, new_password.clone()).await?;
         current_user.hashed_password = hashed_password;
         // State should now be reset to None if we got to this point
         current_user.password_reset = None;
@@ -121,19 +379,14 @@ impl Handlers {
 
     /// Reset the password for the given user. Returns temporary token for use as a password
     pub async fn reset_password(&self, username: &str) -> Result<PasswordResetResponse> {
+        let _lock = self.user_locks.lock(username).await;
         let mut current_user = self
             .store
             .get_user(username)
             .await
             .ok_or_else(|| HandleError::UsernameDoesNotExist)?;
-        // Generate a random string using OsRng to use as a password
-        let new_password: SecureString = std::iter::repeat(())
-            .map(|()| OsRng.sample(Alphanumeric))
-            .map(char::from)
-            .take(32)
-            .collect::<String>()
-            .into();
-        let hashed_password = hash_password(&new_password)?;
+        let new_password = random_password();
+        let hashed_password = hash_password(self.argon2, new_password.clone()).await?;
         let expiry = get_expiry_duration(DEFAULT_RESET_EXPIRY)?;
 
         // Store the new password and expiry in the store
@@ -149,12 +402,120 @@ impl Handlers {
         })
     }
 
+    /// Set the password for the given user to the given one. Unlike
+    /// [`reset_password`](Self::reset_password), the user can keep using the password unless
+    /// `force_password_change` is set, in which case they have to change it like a reset password.
+    /// The password still has to satisfy the password policy. This does not unlock a user that was
+    /// locked out
+    pub async fn set_password(
+        &self,
+        username: &str,
+        password: SecureString,
+        force_password_change: bool,
+    ) -> Result<()> {
+        let _lock = self.user_locks.lock(username).await;
+        let mut current_user = self
+            .store
+            .get_user(username)
+            .await
+            .ok_or_else(|| HandleError::UsernameDoesNotExist)?;
+        self.password_policy
+            .check(username, &password)
+            .map_err(HandleError::PasswordPolicy)?;
+
+        current_user.hashed_password = hash_password(self.argon2, password).await?;
+        current_user.password_reset = if force_password_change {
+            Some(PasswordResetPhase::Reset(get_expiry_duration(
+                DEFAULT_RESET_EXPIRY,
+            )?))
+        } else {
+            None
+        };
+        self.store
+            .put_user(username.to_owned(), current_user)
+            .await
+            .map_err(HandleError::from)
+    }
+
+    /// Sets the POSIX attributes for the given user. Returns the complete set of attributes after
+    /// the change. If the user doesn't have POSIX attributes yet, any attributes that aren't given
+    /// will use the defaults
+    pub async fn set_posix_attributes(
+        &self,
+        username: &str,
+        attributes: PosixAttributesRequest,
+    ) -> Result<PosixAttributes> {
+        let _lock = self.user_locks.lock(username).await;
+        let mut current_user = self
+            .store
+            .get_user(username)
+            .await
+            .ok_or_else(|| HandleError::UsernameDoesNotExist)?;
+
+        let (posix, old_uid) = match current_user.posix.take() {
+            Some(mut posix) => {
+                let old_uid = posix.uid;
+                if let Some(uid) = attributes.uid.filter(|uid| *uid != old_uid) {
+                    if !self.store.reserve_id(uid, username).await? {
+                        return Err(HandleError::UidTaken(uid));
+                    }
+                    posix.uid = uid;
+                }
+                if let Some(gid) = attributes.gid {
+                    posix.gid = gid;
+                }
+                if let Some(home) = attributes.home {
+                    posix.home = home;
+                }
+                if let Some(shell) = attributes.shell {
+                    posix.shell = shell;
+                }
+                if let Some(gecos) = attributes.gecos {
+                    posix.gecos = gecos;
+                }
+                (posix, Some(old_uid))
+            }
+            None => (self.new_posix_attributes(username, attributes).await?, None),
+        };
+        let new_uid = posix.uid;
+        current_user.posix = Some(posix.clone());
+
+        if let Err(e) = self.store.put_user(username.to_owned(), current_user).await {
+            if old_uid != Some(new_uid) {
+                self.release_id(new_uid).await;
+            }
+            return Err(e.into());
+        }
+        if let Some(old_uid) = old_uid.filter(|uid| *uid != new_uid) {
+            self.release_id(old_uid).await;
+        }
+        Ok(posix)
+    }
+
+    /// Clears any lockout and failed login attempts for the given user
+    pub async fn unlock(&self, username: &str) -> Result<()> {
+        let _lock = self.user_locks.lock(username).await;
+        let mut current_user = self
+            .store
+            .get_user(username)
+            .await
+            .ok_or_else(|| HandleError::UsernameDoesNotExist)?;
+        current_user.failed_attempts = 0;
+        current_user.locked_until = None;
+
+        self.store
+            .put_user(username.to_owned(), current_user)
+            .await
+            .map_err(HandleError::from)
+    }
+
     /// Add the given groups to the user. Returns the complete list of groups after the change.
     pub async fn add_groups(
         &self,
         username: &str,
         groups: BTreeSet<String>,
     ) -> Result<BTreeSet<String>> {
+        let _lock = self.user_locks.lock(username).await;
         let mut current_user = self
             .store
             .get_user(username)
@@ -176,6 +537,7 @@ impl Handlers {
         username: &str,
         groups: BTreeSet<String>,
     ) -> Result<BTreeSet<String>> {
+        let _lock = self.user_locks.lock(username).await;
         let mut current_user = self
             .store
             .get_user(username)
@@ -193,10 +555,13 @@ impl Handlers {
 
     /// Delete the given user
     pub async fn delete(&self, username: &str) -> Result<()> {
-        self.store
-            .delete_user(username)
-            .await
-            .map_err(HandleError::from)
+        let _lock = self.user_locks.lock(username).await;
+        let user = self.store.get_user(username).await;
+        self.store.delete_user(username).await?;
+        if let Some(posix) = user.and_then(|u| u.posix) {
+            self.release_id(posix.uid).await;
+        }
+        Ok(())
     }
 
     /// Get information for the given user. Returns None if the user doesn't exist.
@@ -206,6 +571,8 @@ impl Handlers {
                 username: username.to_owned(),
                 groups: user.groups,
                 password_change_phase: user.password_reset,
+                locked_until: user.locked_until,
+                posix: user.posix,
             }),
             None => Err(HandleError::UsernameDoesNotExist),
         }
@@ -216,97 +583,2053 @@ impl Handlers {
         self.store.list_users().await.map_err(HandleError::from)
     }
 
-    /// Checks if a password reset is needed for the given user and updates the current phase as
-    /// needed. Returns the updated user object if successful
-    async fn enforce_login_state(
-        &self,
-        username: &str,
-        mut user: UserInfo,
-        is_password_change: bool,
-    ) -> Result<UserInfo> {
-        let (user_data, allowed) = match user.password_reset {
-            Some(PasswordResetPhase::Reset(expiry)) => {
-                let now = current_time()?;
-                if now < expiry {
-                    user.password_reset = Some(PasswordResetPhase::InitialLogin(expiry));
-                    (user, true)
-                } else {
-                    user.password_reset = Some(PasswordResetPhase::Locked);
-                    (user, false)
-                }
-            }
-            Some(PasswordResetPhase::InitialLogin(expiry)) if is_password_change => {
-                let now = current_time()?;
-                if now >= expiry {
-                    user.password_reset = Some(PasswordResetPhase::Locked);
-                    (user, false)
-                } else {
-                    // If things haven't expired, then we can just return the user. If for some reason
-                    // they mistyped their temp password, then they should be able to try again anyway
-                    return Ok(user);
+    /// Create a new group. If no GID is given, one is allocated from the same range as UIDs
+    pub async fn add_group(&self, req: GroupAddRequest) -> Result<GroupResponse> {
+        if req.name.is_empty() || req.name.starts_with(RESERVED_KEY_PREFIX) {
+            return Err(HandleError::InvalidGroupName(format!(
+                "group names cannot be empty or start with {RESERVED_KEY_PREFIX}"
+            )));
+        }
+        // Every user has a private group with their name, so don't allow groups to shadow them
+        if self.store.exists(&req.name).await? {
+            return Err(HandleError::GroupTaken);
+        }
+        if self.store.get_group(&req.name).await.is_some() {
+            return Err(HandleError::GroupTaken);
+        }
+
+        let gid = match req.gid {
+            Some(gid) => {
+                if !self.store.reserve_id(gid, &req.name).await? {
+                    return Err(HandleError::GidTaken(gid));
                 }
+                gid
             }
-            Some(PasswordResetPhase::InitialLogin(_)) => {
-                // If this is not a password reset, then we should update to locked and deny, no
-                // matter what the expiry is
-                user.password_reset = Some(PasswordResetPhase::Locked);
-                (user, false)
+            None => self.allocate_id(&req.name).await?,
+        };
+        let info = GroupInfo {
+            gid,
+            description: req.description,
+        };
+        match self
+            .store
+            .create_group(req.name.clone(), info.clone())
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                self.release_id(gid).await;
+                return Err(HandleError::GroupTaken);
             }
-            Some(PasswordResetPhase::Locked) => {
-                // If we're locked, no update is needed, just deny
-                return Err(HandleError::PasswordResetExpired);
+            Err(e) => {
+                self.release_id(gid).await;
+                return Err(e.into());
             }
-            None => {
-                // In this case we don't need to modify anything and can just return the user
-                return Ok(user);
+        }
+        // Users can already be in a group with this name from before it was created
+        let members = self.group_members(&req.name).await?;
+        Ok(GroupResponse {
+            name: req.name,
+            gid: info.gid,
+            description: info.description,
+            members,
+        })
+    }
+
+    /// Delete the given group, removing it from all of its members
+    pub async fn delete_group(&self, name: &str) -> Result<()> {
+        let group = self
+            .store
+            .get_group(name)
+            .await
+            .ok_or(HandleError::GroupDoesNotExist)?;
+        // Remove the group from members first so that if any of them fail, the group still exists
+        // and the delete can be retried
+        for member in self.group_members(name).await? {
+            let _lock = self.user_locks.lock(&member).await;
+            let Some(mut user) = self.store.get_user(&member).await else {
+                continue;
+            };
+            user.groups.remove(name);
+            self.store.put_user(member, user).await?;
+        }
+        self.store.delete_group(name).await?;
+        self.release_id(group.gid).await;
+        Ok(())
+    }
+
+    /// Get information for the given group, including its members
+    pub async fn get_group(&self, name: &str) -> Result<GroupResponse> {
+        let group = self
+            .store
+            .get_group(name)
+            .await
+            .ok_or(HandleError::GroupDoesNotExist)?;
+        Ok(GroupResponse {
+            name: name.to_owned(),
+            gid: group.gid,
+            description: group.description,
+            members: self.group_members(name).await?,
+        })
+    }
+
+    /// Get all group names
+    pub async fn list_groups(&self) -> Result<Vec<String>> {
+        self.store.list_groups().await.map_err(HandleError::from)
+    }
+
+    /// Get the usernames of all members of the given group
+    pub async fn group_members(&self, name: &str) -> Result<BTreeSet<String>> {
+        if self.store.get_group(name).await.is_none() {
+            return Err(HandleError::GroupDoesNotExist);
+        }
+        Ok(self.store.group_members(name).await)
+    }
+
+    /// Returns the stored information (without membership) for all groups, keyed by name
+    pub async fn group_info(&self) -> Result<BTreeMap<String, GroupInfo>> {
+        let mut groups = BTreeMap::new();
+        for name in self.store.list_groups().await? {
+            // The group could have been deleted since we listed them
+            if let Some(info) = self.store.get_group(&name).await {
+                groups.insert(name, info);
             }
-        };
-        self.store
-            .put_user(username.to_owned(), user_data.clone())
-            .await?;
+        }
+        Ok(groups)
+    }
 
-        if !allowed {
-            return Err(HandleError::PasswordResetExpired);
+    /// Exports every user (including their password hash) and group, so the directory can be backed
+    /// up or copied to another server with [`import_directory`](Self::import_directory)
+    pub async fn export_directory(&self) -> Result<Directory> {
+        let mut usernames = self.store.list_users().await?;
+        usernames.sort();
+        let mut users = Vec::with_capacity(usernames.len());
+        for username in usernames {
+            // The user could have been deleted since we listed them
+            let Some(user) = self.store.get_user(&username).await else {
+                continue;
+            };
+            users.push(UserRecord {
+                username,
+                password_hash: Some(user.hashed_password),
+                password_change_phase: user.password_reset,
+                groups: user.groups,
+                posix: user.posix,
+            });
         }
-        Ok(user_data)
+        let groups = self
+            .group_info()
+            .await?
+            .into_iter()
+            .map(|(name, info)| GroupRecord {
+                name,
+                gid: info.gid,
+                description: info.description,
+            })
+            .collect();
+        Ok(Directory { users, groups })
     }
-}
 
-fn get_expiry_duration(time_to_expire: Duration) -> anyhow::Result<Duration> {
-    current_time().map(|t| t + time_to_expire)
-}
+    /// Imports users and groups, such as ones from [`export_directory`](Self::export_directory).
+    /// Entries that already exist are handled according to the conflict policy. Entries that are
+    /// invalid or fail to import are counted and reported in the summary without stopping the rest
+    /// of the import. A dry run does every check that doesn't need to write to the store, so it
+    /// can't catch things like an ID being reserved by another server at the same time
+    pub async fn import_directory(&self, req: ImportRequest) -> Result<ImportSummary> {
+        let existing_users: HashSet<String> = self.store.list_users().await?.into_iter().collect();
+        let existing_groups = self.group_info().await?;
 
-fn hash_password(password: &SecureString) -> Result<SecureString> {
-    let salt = SaltString::generate(&mut OsRng);
-    let argon = Argon2::default();
-    argon
-        .hash_password(password.as_ref(), &salt)
-        .map_err(|err| {
-            error!(%err, "Error occurred when hashing password");
-            HandleError::SystemError(anyhow::anyhow!("Error when hashing"))
-        })
-        .map(|hashed| hashed.to_string().into())
-}
+        if req.on_conflict == ConflictPolicy::Fail {
+            let conflicts: Vec<String> = req
+                .directory
+                .groups
+                .iter()
+                .filter(|group| existing_groups.contains_key(&group.name))
+                .map(|group| format!("group {}", group.name))
+                .chain(
+                    req.directory
+                        .users
+                        .iter()
+                        .filter(|user| existing_users.contains(&user.username))
+                        .map(|user| format!("user {}", user.username)),
+                )
+                .collect();
+            if !conflicts.is_empty() {
+                return Err(HandleError::ImportConflict(conflicts.join(", ")));
+            }
+        }
 
-// Verifies that the given password matches the stored password for the given user. Returns an error
-// if validation fails or another error occurs
-fn verify_password(user: &UserInfo, password: &SecureString) -> Result<()> {
-    let password_hash = match PasswordHash::new(user.hashed_password.as_ref()) {
-        Ok(hash) => hash,
-        Err(err) => {
-            error!(%err, "Error occurred when parsing password hash. This is likely a data corruption issue!");
-            return Err(HandleError::SystemError(anyhow::anyhow!(
-                "Error when reading user"
-            )));
+        // Track who owns every ID so that conflicts can be caught before anything is written
+        let mut ids: HashMap<u32, String> = existing_groups
+            .iter()
+            .map(|(name, info)| (info.gid, name.clone()))
+            .collect();
+        for username in existing_users.iter() {
+            if let Some(posix) = self.store.get_user(username).await.and_then(|u| u.posix) {
+                ids.insert(posix.uid, username.clone());
+            }
         }
-    };
-    Argon2::default()
-        .verify_password(password.as_ref(), &password_hash)
-        .map_err(|_| HandleError::InvalidCredentials)
-}
+        let imported_users: HashSet<&str> = req
+            .directory
+            .users
+            .iter()
+            .map(|user| user.username.as_str())
+            .collect();
 
-fn current_time() -> anyhow::Result<Duration> {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .context("Unable to calculate current system time")
+        let mut summary = ImportSummary {
+            dry_run: req.dry_run,
+            ..Default::default()
+        };
+        let skip_existing = req.on_conflict == ConflictPolicy::Skip;
+        // Groups go first so their GIDs are claimed before users are given default attributes
+        let mut seen = HashSet::new();
+        for group in req.directory.groups.iter() {
+            let current = existing_groups.get(&group.name);
+            if current.is_some() && skip_existing {
+                summary.groups.skipped += 1;
+                continue;
+            }
+            let mut result = check_group_record(group, &existing_users, &imported_users, &mut ids);
+            if result.is_ok() && !seen.insert(group.name.as_str()) {
+                result = Err(HandleError::GroupTaken);
+            }
+            if result.is_ok() && !req.dry_run {
+                result = self.import_group(group.clone(), current).await;
+            }
+            match result {
+                Ok(()) if current.is_some() => summary.groups.overwritten += 1,
+                Ok(()) => summary.groups.created += 1,
+                Err(err) => {
+                    summary.groups.failed += 1;
+                    summary.errors.push(format!("group {}: {err}", group.name));
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for user in req.directory.users {
+            let exists = existing_users.contains(&user.username);
+            if exists && skip_existing {
+                summary.users.skipped += 1;
+                continue;
+            }
+            let mut result = check_user_record(&user, &existing_groups, &mut ids);
+            if result.is_ok() && !seen.insert(user.username.clone()) {
+                result = Err(HandleError::UsernameTaken);
+            }
+            let username = user.username.clone();
+            if result.is_ok() && !req.dry_run {
+                result = self.import_user(user).await;
+            }
+            match result {
+                Ok(()) if exists => summary.users.overwritten += 1,
+                Ok(()) => summary.users.created += 1,
+                Err(err) => {
+                    summary.users.failed += 1;
+                    summary.errors.push(format!("user {username}: {err}"));
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Creates or replaces a group from an import, moving its ID reservation if the GID changed
+    async fn import_group(&self, group: GroupRecord, current: Option<&GroupInfo>) -> Result<()> {
+        let old_gid = current.map(|info| info.gid);
+        if old_gid != Some(group.gid) && !self.store.reserve_id(group.gid, &group.name).await? {
+            return Err(HandleError::GidTaken(group.gid));
+        }
+        let release_new = || async {
+            if old_gid != Some(group.gid) {
+                self.release_id(group.gid).await;
+            }
+        };
+        if current.is_some() {
+            // Membership is stored on users, so replacing the group leaves its members alone
+            if let Err(e) = self.store.delete_group(&group.name).await {
+                release_new().await;
+                return Err(e.into());
+            }
+        }
+        let info = GroupInfo {
+            gid: group.gid,
+            description: group.description,
+        };
+        match self.store.create_group(group.name.clone(), info).await {
+            Ok(true) => {}
+            Ok(false) => {
+                release_new().await;
+                return Err(HandleError::GroupTaken);
+            }
+            Err(e) => {
+                release_new().await;
+                return Err(e.into());
+            }
+        }
+        if let Some(old_gid) = old_gid.filter(|gid| *gid != group.gid) {
+            self.release_id(old_gid).await;
+        }
+        Ok(())
+    }
+
+    /// Creates or replaces a user from an import, moving their ID reservation if the UID changed
+    async fn import_user(&self, record: UserRecord) -> Result<()> {
+        let _lock = self.user_locks.lock(&record.username).await;
+        let current = self.store.get_user(&record.username).await;
+        let (hashed_password, password_reset) = match (record.password_hash, &current) {
+            (Some(hash), _) => (hash, record.password_change_phase),
+            (None, Some(current)) => (
+                current.hashed_password.clone(),
+                current.password_reset.clone(),
+            ),
+            // Nobody knows this password, so an admin has to reset it before the user can log in
+            (None, None) => (
+                hash_password(self.argon2, random_password()).await?,
+                Some(PasswordResetPhase::Locked),
+            ),
+        };
+        let old_uid = current
+            .as_ref()
+            .and_then(|user| user.posix.as_ref())
+            .map(|posix| posix.uid);
+        let posix = match (record.posix, current.and_then(|user| user.posix)) {
+            (Some(posix), _) => {
+                if old_uid != Some(posix.uid)
+                    && !self.store.reserve_id(posix.uid, &record.username).await?
+                {
+                    return Err(HandleError::UidTaken(posix.uid));
+                }
+                posix
+            }
+            (None, Some(current)) => current,
+            (None, None) => {
+                self.new_posix_attributes(&record.username, PosixAttributesRequest::default())
+                    .await?
+            }
+        };
+        let new_uid = posix.uid;
+        let user = UserInfo {
+            hashed_password,
+            password_reset,
+            groups: record.groups,
+            posix: Some(posix),
+            ..Default::default()
+        };
+        if let Err(e) = self.store.put_user(record.username, user).await {
+            if old_uid != Some(new_uid) {
+                self.release_id(new_uid).await;
+            }
+            return Err(e.into());
+        }
+        if let Some(old_uid) = old_uid.filter(|uid| *uid != new_uid) {
+            self.release_id(old_uid).await;
+        }
+        Ok(())
+    }
+
+    /// Creates POSIX attributes for a new user, filling in anything not given with the defaults. The
+    /// UID (whether given or allocated) is reserved in the store
+    async fn new_posix_attributes(
+        &self,
+        username: &str,
+        attributes: PosixAttributesRequest,
+    ) -> Result<PosixAttributes> {
+        let uid = match attributes.uid {
+            Some(uid) => {
+                if !self.store.reserve_id(uid, username).await? {
+                    return Err(HandleError::UidTaken(uid));
+                }
+                uid
+            }
+            None => self.allocate_id(username).await?,
+        };
+        Ok(PosixAttributes {
+            uid,
+            // Default to a user private group with the same ID as the user
+            gid: attributes.gid.unwrap_or(uid),
+            home: attributes.home.unwrap_or_else(|| {
+                format!("{}/{username}", self.posix.home_base.trim_end_matches('/'))
+            }),
+            shell: attributes.shell.unwrap_or_else(|| self.posix.shell.clone()),
+            gecos: attributes.gecos.unwrap_or_default(),
+        })
+    }
+
+    /// Reserves the lowest available ID in the configured range for the given user or group
+    async fn allocate_id(&self, owner: &str) -> Result<u32> {
+        // Skip IDs we already know are in use so we don't have to try to reserve each of them. The
+        // reservation is still what guarantees uniqueness in case another server allocated the same
+        // ID since we last saw it
+        for id in self.posix.uid_range.clone() {
+            if self.store.id_in_use(id).await {
+                continue;
+            }
+            if self.store.reserve_id(id, owner).await? {
+                return Ok(id);
+            }
+        }
+        Err(HandleError::SystemError(anyhow::anyhow!(
+            "No IDs are available in the range {:?}",
+            self.posix.uid_range
+        )))
+    }
+
+    /// Releases the given ID, logging instead of failing as a leaked reservation only means the ID
+    /// won't be reused
+    async fn release_id(&self, id: u32) {
+        if let Err(err) = self.store.release_id(id).await {
+            warn!(%err, id, "Unable to release ID reservation");
+        }
+    }
+
+    /// Checks the password for the given user. The check happens without holding the user lock,
+    /// and wrong passwords are [recorded in the background](Handlers::record_failed_attempt), so a
+    /// wrong password for a real user is answered as quickly as an unknown user. Once the password
+    /// is known to be correct, this takes the user lock and returns it along with the current user
+    async fn check_credentials(
+        &self,
+        username: &str,
+        password: &SecureString,
+    ) -> Result<(tokio::sync::OwnedMutexGuard<()>, UserInfo)> {
+        let Some(user) = self.store.get_user(username).await else {
+            return Err(self.reject_unknown_user(password.clone()).await);
+        };
+        // The password is always checked before the account state, so locked or expired accounts
+        // take as long to reject as a wrong password and can't be told apart by timing
+        let password_check = check_password(self.argon2, &user, password).await?;
+        ensure_unlocked(&user)?;
+        if let PasswordCheck::Invalid = password_check {
+            self.record_failed_attempt(username);
+            return Err(HandleError::InvalidCredentials);
+        }
+
+        let lock = self.user_locks.lock(username).await;
+        // The user may have changed while the password was being checked. Anything other than the
+        // password hash changing is fine, as the rest of the state is checked after this
+        match self.store.get_user(username).await {
+            Some(current) if current.hashed_password == user.hashed_password => {
+                ensure_unlocked(&current)?;
+                Ok((lock, current))
+            }
+            _ => Err(HandleError::InvalidCredentials),
+        }
+    }
+
+    /// Clears any failed attempts for a user that just logged in successfully, returning the
+    /// updated user object
+    async fn clear_failed_attempts(&self, username: &str, mut user: UserInfo) -> Result<UserInfo> {
+        // Only write back to the store if there was something to clear
+        if user.failed_attempts > 0 || user.locked_until.is_some() {
+            user.failed_attempts = 0;
+            user.locked_until = None;
+            self.store
+                .put_user(username.to_owned(), user.clone())
+                .await?;
+        }
+        Ok(user)
+    }
+
+    /// Records a failed login attempt for the user, locking them out if the lockout policy
+    /// threshold is reached. Writing to the store takes time that an unknown user wouldn't, so this
+    /// happens in the background instead of before the request is answered. Use
+    /// [`Handlers::wait_for_background_tasks`] to wait for it to finish
+    fn record_failed_attempt(&self, username: &str) {
+        let handlers = self.clone();
+        let username = username.to_owned();
+        self.background.spawn(async move {
+            let _lock = handlers.user_locks.lock(&username).await;
+            // Fetch the user again so concurrent failed attempts are all counted
+            let Some(mut user) = handlers.store.get_user(&username).await else {
+                return;
+            };
+            user.failed_attempts += 1;
+            if handlers.lockout.max_failed_attempts > 0
+                && user.failed_attempts >= handlers.lockout.max_failed_attempts
+            {
+                warn!(%username, "Too many failed login attempts, locking user");
+                user.failed_attempts = 0;
+                user.locked_until = match get_expiry_duration(handlers.lockout.lockout_duration) {
+                    Ok(until) => Some(until),
+                    Err(err) => {
+                        error!(%username, %err, "Unable to lock user");
+                        return;
+                    }
+                };
+            }
+            if let Err(err) = handlers.store.put_user(username.clone(), user).await {
+                error!(%username, %err, "Unable to record failed login attempt");
+            }
+        });
+    }
+
+    /// Waits for any failed login attempts that are still being recorded in the background. This
+    /// should be called before shutting down so no attempts are lost
+    pub async fn wait_for_background_tasks(&self) {
+        self.background.close();
+        self.background.wait().await;
+        self.background.reopen();
+    }
+
+    /// Looks up the account information for a user. Users without POSIX attributes are treated as
+    /// if they don't exist
+    pub async fn lookup_user(&self, req: UserLookupRequest) -> Result<PasswdEntry> {
+        let name = match req {
+            UserLookupRequest::Name(name) => name,
+            UserLookupRequest::Uid(uid) => self
+                .store
+                .username_for_uid(uid)
+                .await
+                .ok_or(HandleError::UsernameDoesNotExist)?,
+        };
+        self.store
+            .get_user(&name)
+            .await
+            .and_then(|user| user.posix)
+            .map(|posix| passwd_entry(name, posix))
+            .ok_or(HandleError::UsernameDoesNotExist)
+    }
+
+    /// Returns the account information for all users with POSIX attributes, sorted by name
+    pub async fn list_passwd(&self) -> Result<Vec<PasswdEntry>> {
+        let mut entries = Vec::new();
+        for username in self.store.list_users().await? {
+            if let Some(posix) = self.store.get_user(&username).await.and_then(|u| u.posix) {
+                entries.push(passwd_entry(username, posix));
+            }
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    /// Looks up a group by name or GID
+    pub async fn lookup_group(&self, req: GroupLookupRequest) -> Result<GroupEntry> {
+        match req {
+            GroupLookupRequest::Name(name) => {
+                if let Some(info) = self.store.get_group(&name).await {
+                    return Ok(self.group_entry(name, info.gid).await);
+                }
+                self.private_group(&name).await
+            }
+            GroupLookupRequest::Gid(gid) => {
+                // GIDs are reserved from the same pool as UIDs, so this can only be a private group
+                // if a user owns the matching UID
+                if let Some(username) = self.store.username_for_uid(gid).await {
+                    if let Ok(entry) = self.private_group(&username).await {
+                        return Ok(entry);
+                    }
+                }
+                for (name, info) in self.group_info().await? {
+                    if info.gid == gid {
+                        return Ok(self.group_entry(name, gid).await);
+                    }
+                }
+                Err(HandleError::GroupDoesNotExist)
+            }
+        }
+    }
+
+    /// Builds the entry for a created group, fetching its members
+    async fn group_entry(&self, name: String, gid: u32) -> GroupEntry {
+        let members = self.store.group_members(&name).await;
+        GroupEntry {
+            name,
+            gid,
+            members: members.into_iter().collect(),
+        }
+    }
+
+    /// Returns the private group of the given user if they have one. Created groups take
+    /// precedence over a user private group with the same name
+    async fn private_group(&self, username: &str) -> Result<GroupEntry> {
+        if self.store.get_group(username).await.is_some() {
+            return Err(HandleError::GroupDoesNotExist);
+        }
+        self.store
+            .get_user(username)
+            .await
+            .and_then(|user| user.posix)
+            .filter(|posix| posix.gid == posix.uid)
+            .map(|posix| GroupEntry {
+                name: username.to_string(),
+                gid: posix.gid,
+                members: Vec::new(),
+            })
+            .ok_or(HandleError::GroupDoesNotExist)
+    }
+
+    /// Returns all groups that have a GID, sorted by name. This includes both created groups and
+    /// user private groups, which exist for every user whose primary GID is the same as their UID
+    pub async fn list_group_entries(&self) -> Result<Vec<GroupEntry>> {
+        let mut entries = BTreeMap::new();
+        for (name, info) in self.group_info().await? {
+            entries.insert(name.clone(), self.group_entry(name, info.gid).await);
+        }
+        for (username, gid) in self.store.private_groups().await {
+            // Created groups take precedence over a user private group with the same name
+            entries.entry(username.clone()).or_insert(GroupEntry {
+                name: username,
+                gid,
+                members: Vec::new(),
+            });
+        }
+        Ok(entries.into_values().collect())
+    }
+
+    /// Returns the GIDs of the supplementary groups the given user is a member of. This does not
+    /// include the user's primary group or groups that were never created (and therefore have no
+    /// GID)
+    pub async fn user_group_ids(&self, username: &str) -> Result<Vec<u32>> {
+        let user = self
+            .store
+            .get_user(username)
+            .await
+            .ok_or(HandleError::UsernameDoesNotExist)?;
+        let primary = user.posix.map(|p| p.gid);
+        let mut gids = Vec::new();
+        for group in user.groups.iter() {
+            if let Some(info) = self.store.get_group(group).await {
+                if Some(info.gid) != primary {
+                    gids.push(info.gid);
+                }
+            }
+        }
+        Ok(gids)
+    }
+
+    /// Checks the health of the backing store. The server is only ready if every check passes
+    pub fn health(&self) -> HealthResponse {
+        let checks = self.store.health_checks();
+        HealthResponse {
+            ready: checks.iter().all(|check| check.healthy),
+            checks,
+        }
+    }
+
+    /// Rehashes the password if the stored hash was made with different parameters than the
+    /// configured ones, so the cost can be raised over time without forcing password resets. This
+    /// is only done after the password was verified. Failing to upgrade doesn't fail the request, as
+    /// the hash will just be upgraded on a later login
+    async fn upgrade_hash(
+        &self,
+        username: &str,
+        user: UserInfo,
+        password: SecureString,
+    ) -> UserInfo {
+        if self.argon2.is_current(&user.hashed_password) {
+            return user;
+        }
+        let hashed_password = match hash_password(self.argon2, password).await {
+            Ok(hashed) => hashed,
+            Err(err) => {
+                warn!(%username, %err, "Unable to rehash password with current parameters");
+                return user;
+            }
+        };
+        let upgraded = UserInfo {
+            hashed_password,
+            ..user.clone()
+        };
+        match self
+            .store
+            .put_user(username.to_owned(), upgraded.clone())
+            .await
+        {
+            Ok(()) => {
+                debug!(%username, "Upgraded password hash to current parameters");
+                upgraded
+            }
+            Err(err) => {
+                warn!(%username, %err, "Unable to store upgraded password hash");
+                user
+            }
+        }
+    }
+
+    /// Checks the password against a dummy hash before rejecting a user that doesn't exist. This
+    /// makes the request take as long as it would for a real user with the wrong password, so
+    /// timing responses can't be used to find out which usernames exist
+    async fn reject_unknown_user(&self, password: SecureString) -> HandleError {
+        let dummy_hash = match self
+            .dummy_hash
+            .get_or_try_init(|| hash_password(self.argon2, random_password()))
+            .await
+        {
+            Ok(hash) => hash.clone(),
+            Err(e) => return e,
+        };
+        match verify_password(self.argon2, dummy_hash, password).await {
+            // This is a random password nobody knows, but if it somehow matched, still reject it
+            Ok(()) | Err(HandleError::InvalidCredentials) => HandleError::InvalidCredentials,
+            Err(e) => e,
+        }
+    }
+
+    fn ensure_fresh(&self) -> Result<()> {
+        if self.reject_when_stale && self.store.is_stale() {
+            warn!("Rejecting request as the store may be out of date");
+            return Err(HandleError::StaleData);
+        }
+        Ok(())
+    }
+
+    /// Checks if a password reset is needed for the given user and updates the current phase as
+    /// needed. Returns the updated user object if successful
+    async fn enforce_login_state(
+        &self,
+        username: &str,
+        mut user: UserInfo,
+        is_password_change: bool,
+    ) -> Result<UserInfo> {
+        let (user_data, allowed) = match user.password_reset {
+            Some(PasswordResetPhase::Reset(expiry)) => {
+                let now = current_time()?;
+                if now < expiry {
+                    user.password_reset = Some(PasswordResetPhase::InitialLogin(expiry));
+                    (user, true)
+                } else {
+                    user.password_reset = Some(PasswordResetPhase::Locked);
+                    (user, false)
+                }
+            }
+            Some(PasswordResetPhase::InitialLogin(expiry)) if is_password_change => {
+                let now = current_time()?;
+                if now >= expiry {
+                    user.password_reset = Some(PasswordResetPhase::Locked);
+                    (user, false)
+                } else {
+                    // If things haven't expired, then we can just return the user. If for some reason
+                    // they mistyped their temp password, then they should be able to try again anyway
+                    return Ok(user);
+                }
+            }
+            Some(PasswordResetPhase::InitialLogin(_)) => {
+                // If this is not a password reset, then we should update to locked and deny, no
+                // matter what the expiry is
+                user.password_reset = Some(PasswordResetPhase::Locked);
+                (user, false)
+            }
+            Some(PasswordResetPhase::Locked) => {
+                // If we're locked, no update is needed, just deny
+                return Err(HandleError::PasswordResetExpired);
+            }
+            None => {
+                // In this case we don't need to modify anything and can just return the user
+                return Ok(user);
+            }
+        };
+        self.store
+            .put_user(username.to_owned(), user_data.clone())
+            .await?;
+
+        if !allowed {
+            return Err(HandleError::PasswordResetExpired);
+        }
+        Ok(user_data)
+    }
+}
+
+/// Whether a password matched the user's stored hash
+enum PasswordCheck {
+    Valid,
+    Invalid,
+}
+
+/// Checks the password against the user's stored hash without looking at or changing anything
+/// else about the user. Only returns an error if the hash couldn't be checked at all
+async fn check_password(
+    config: Argon2Config,
+    user: &UserInfo,
+    password: &SecureString,
+) -> Result<PasswordCheck> {
+    match verify_password(config, user.hashed_password.clone(), password.clone()).await {
+        Ok(()) => Ok(PasswordCheck::Valid),
+        Err(HandleError::InvalidCredentials) => Ok(PasswordCheck::Invalid),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns an error if the user is currently locked out
+fn ensure_unlocked(user: &UserInfo) -> Result<()> {
+    match user.locked_until {
+        Some(until) if current_time()? < until => Err(HandleError::AccountLocked),
+        _ => Ok(()),
+    }
+}
+
+/// Returns an error if the user has a pending password reset. Unlike
+/// [`Handlers::enforce_login_state`], this never changes the reset phase
+fn ensure_no_pending_reset(user: &UserInfo) -> Result<()> {
+    match user.password_reset {
+        None => Ok(()),
+        Some(PasswordResetPhase::Reset(expiry)) if current_time()? < expiry => {
+            Err(HandleError::PasswordChangeRequired)
+        }
+        Some(PasswordResetPhase::InitialLogin(_)) => Err(HandleError::PasswordChangeRequired),
+        Some(_) => Err(HandleError::PasswordResetExpired),
+    }
+}
+
+/// Checks that an imported group is valid and claims its GID
+fn check_group_record(
+    group: &GroupRecord,
+    existing_users: &HashSet<String>,
+    imported_users: &HashSet<&str>,
+    ids: &mut HashMap<u32, String>,
+) -> Result<()> {
+    if group.name.is_empty() || group.name.starts_with(RESERVED_KEY_PREFIX) {
+        return Err(HandleError::InvalidGroupName(format!(
+            "group names cannot be empty or start with {RESERVED_KEY_PREFIX}"
+        )));
+    }
+    // Every user has a private group with their name, so don't allow groups to shadow them
+    if existing_users.contains(&group.name) || imported_users.contains(group.name.as_str()) {
+        return Err(HandleError::GroupTaken);
+    }
+    claim_id(ids, group.gid, &group.name).map_err(HandleError::GidTaken)
+}
+
+/// Checks that an imported user is valid and claims their UID
+fn check_user_record(
+    user: &UserRecord,
+    existing_groups: &BTreeMap<String, GroupInfo>,
+    ids: &mut HashMap<u32, String>,
+) -> Result<()> {
+    if user.username.starts_with(RESERVED_KEY_PREFIX) {
+        return Err(HandleError::InvalidUsername(format!(
+            "usernames cannot start with {RESERVED_KEY_PREFIX}"
+        )));
+    }
+    if existing_groups.contains_key(&user.username) {
+        return Err(HandleError::InvalidUsername(format!(
+            "a group named {} already exists",
+            user.username
+        )));
+    }
+    if let Some(hash) = user.password_hash.as_ref() {
+        HashFormat::validate(hash.as_ref())?;
+    }
+    match user.posix.as_ref() {
+        Some(posix) => claim_id(ids, posix.uid, &user.username).map_err(HandleError::UidTaken),
+        None => Ok(()),
+    }
+}
+
+/// Records the owner of an ID, returning the ID as an error if something else already owns it
+fn claim_id(ids: &mut HashMap<u32, String>, id: u32, owner: &str) -> std::result::Result<(), u32> {
+    match ids.get(&id) {
+        Some(current) if current != owner => Err(id),
+        Some(_) => Ok(()),
+        None => {
+            ids.insert(id, owner.to_owned());
+            Ok(())
+        }
+    }
+}
+
+fn passwd_entry(name: String, posix: PosixAttributes) -> PasswdEntry {
+    PasswdEntry {
+        name,
+        uid: posix.uid,
+        gid: posix.gid,
+        gecos: posix.gecos,
+        home: posix.home,
+        shell: posix.shell,
+    }
+}
+
+/// Generates a random string using OsRng to use as a password
+fn random_password() -> SecureString {
+    std::iter::repeat(())
+        .map(|()| OsRng.sample(Alphanumeric))
+        .map(char::from)
+        .take(32)
+        .collect::<String>()
+        .into()
+}
+
+fn get_expiry_duration(time_to_expire: Duration) -> anyhow::Result<Duration> {
+    current_time().map(|t| t + time_to_expire)
+}
+
+/// Hashes the password on the blocking thread pool. Argon2 is intentionally slow, so running it on
+/// an async worker would stall every other request being handled by that worker
+async fn hash_password(config: Argon2Config, password: SecureString) -> Result<SecureString> {
+    run_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        let argon = config.hasher()?;
+        metrics::time_password_hash("hash", || argon.hash_password(password.as_ref(), &salt))
+            .map_err(|err| {
+                error!(%err, "Error occurred when hashing password");
+                HandleError::SystemError(anyhow::anyhow!("Error when hashing"))
+            })
+            .map(|hashed| hashed.to_string().into())
+    })
+    .await
+}
+
+/// The formats of password hashes that can be verified. New hashes are always Argon2. The others
+/// are only accepted so users can be imported from other systems (such as `/etc/shadow` or an
+/// htpasswd file) without resetting their passwords
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HashFormat {
+    /// An Argon2 PHC string, such as `$argon2id$v=19$...`
+    Argon2,
+    /// `$6$` SHA-512 crypt
+    Sha512Crypt,
+    /// `$5$` SHA-256 crypt
+    Sha256Crypt,
+    /// `$2b$` (or `$2a$` and `$2y$`) bcrypt
+    Bcrypt,
+}
+
+impl HashFormat {
+    fn detect(hash: &str) -> Option<HashFormat> {
+        if hash.starts_with("$argon2") {
+            Some(HashFormat::Argon2)
+        } else if hash.starts_with("$6$") {
+            Some(HashFormat::Sha512Crypt)
+        } else if hash.starts_with("$5$") {
+            Some(HashFormat::Sha256Crypt)
+        } else if ["$2a$", "$2b$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            Some(HashFormat::Bcrypt)
+        } else {
+            None
+        }
+    }
+
+    /// Checks that an imported hash is in a format we can verify. This catches things like the `!`
+    /// and `*` placeholders used in shadow files for accounts without a usable password
+    fn validate(hash: &str) -> Result<()> {
+        match HashFormat::detect(hash) {
+            Some(HashFormat::Argon2) => match PasswordHash::new(hash) {
+                Ok(parsed) if parsed.hash.is_some() => Ok(()),
+                Ok(_) => Err(HandleError::InvalidPasswordHash(
+                    "the Argon2 hash has no hash output".to_string(),
+                )),
+                Err(e) => Err(HandleError::InvalidPasswordHash(e.to_string())),
+            },
+            Some(_) => Ok(()),
+            None => Err(HandleError::InvalidPasswordHash(
+                "expected a sha512-crypt, sha256-crypt, bcrypt or Argon2 hash".to_string(),
+            )),
+        }
+    }
+}
+
+// Verifies that the given password matches the stored password hash. Returns an error if
+// validation fails or another error occurs. Like hashing, this runs on the blocking thread pool.
+// Argon2 hashes are checked with the configured hasher so anything it carries besides the
+// parameters (which always come from the hash itself) matches what hashing uses
+async fn verify_password(
+    config: Argon2Config,
+    hashed_password: SecureString,
+    password: SecureString,
+) -> Result<()> {
+    run_blocking(move || {
+        let hash: &str = hashed_password.as_ref();
+        let password: &[u8] = password.as_ref();
+        let valid = match HashFormat::detect(hash) {
+            Some(HashFormat::Argon2) => {
+                let password_hash = match PasswordHash::new(hash) {
+                    Ok(hash) => hash,
+                    Err(err) => {
+                        error!(%err, "Error occurred when parsing password hash. This is likely a data corruption issue!");
+                        return Err(HandleError::SystemError(anyhow::anyhow!(
+                            "Error when reading user"
+                        )));
+                    }
+                };
+                let argon = config.hasher()?;
+                metrics::time_password_hash("verify", || {
+                    argon.verify_password(password, &password_hash)
+                })
+                .is_ok()
+            }
+            Some(HashFormat::Sha512Crypt) => {
+                metrics::time_password_hash("verify", || pwhash::sha512_crypt::verify(password, hash))
+            }
+            Some(HashFormat::Sha256Crypt) => {
+                metrics::time_password_hash("verify", || pwhash::sha256_crypt::verify(password, hash))
+            }
+            Some(HashFormat::Bcrypt) => {
+                metrics::time_password_hash("verify", || pwhash::bcrypt::verify(password, hash))
+            }
+            None => {
+                error!("Stored password hash is in an unknown format. This is likely a data corruption issue!");
+                return Err(HandleError::SystemError(anyhow::anyhow!(
+                    "Error when reading user"
+                )));
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(HandleError::InvalidCredentials)
+        }
+    })
+    .await
+}
+
+async fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .context("Password hashing task failed")?
+}
+
+fn current_time() -> anyhow::Result<Duration> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("Unable to calculate current system time")
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future::BoxFuture;
+
+    use super::*;
+    use crate::{api::PolicyViolation, storage::MemoryStore};
+
+    fn add_request(username: &str, force_password_change: bool) -> UserAddRequest {
+        UserAddRequest {
+            username: username.to_string(),
+            password: "supersecure".into(),
+            groups: ["foo".to_string()].into(),
+            force_password_change,
+            prehashed: false,
+            posix: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let handlers = Handlers::new(MemoryStore::new());
+        handlers
+            .add(add_request("foo", false))
+            .await
+            .expect("Should be able to add a user");
+        assert!(
+            matches!(
+                handlers.add(add_request("foo", false)).await,
+                Err(HandleError::UsernameTaken)
+            ),
+            "Should not be able to add a user twice"
+        );
+
+        let resp = handlers
+            .verify("foo", "supersecure".into())
+            .await
+            .expect("Should verify with the correct password");
+        assert!(resp.valid, "Should be valid");
+        assert!(!resp.needs_password_reset, "Should not need a reset");
+        assert_eq!(resp.groups, ["foo".to_string()].into());
+
+        assert!(
+            matches!(
+                handlers.verify("foo", "wrong".into()).await,
+                Err(HandleError::InvalidCredentials)
+            ),
+            "Should not verify with an incorrect password"
+        );
+        assert!(
+            matches!(
+                handlers.verify("bar", "supersecure".into()).await,
+                Err(HandleError::InvalidCredentials)
+            ),
+            "Should not verify an unknown user"
+        );
+
+        handlers
+            .change_password("foo", "supersecure".into(), "newpassword".into())
+            .await
+            .expect("Should be able to change password");
+        handlers
+            .verify("foo", "newpassword".into())
+            .await
+            .expect("Should verify with the new password");
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_phases() {
+        let handlers = Handlers::new(MemoryStore::new());
+        handlers
+            .add(add_request("foo", false))
+            .await
+            .expect("Should be able to add a user");
+
+        let reset = handlers
+            .reset_password("foo")
+            .await
+            .expect("Should be able to reset password");
+        let resp = handlers
+            .verify("foo", reset.temp_password.clone())
+            .await
+            .expect("Should be able to log in with the temporary password");
+        assert!(resp.needs_password_reset, "Should need a password reset");
+        assert!(matches!(
+            handlers.get("foo").await.unwrap().password_change_phase,
+            Some(PasswordResetPhase::InitialLogin(_))
+        ));
+
+        // A second login without changing the password locks the user
+        assert!(
+            matches!(
+                handlers.verify("foo", reset.temp_password.clone()).await,
+                Err(HandleError::PasswordResetExpired)
+            ),
+            "Second login should be denied"
+        );
+        assert!(matches!(
+            handlers.get("foo").await.unwrap().password_change_phase,
+            Some(PasswordResetPhase::Locked)
+        ));
+
+        // Resetting again and changing the password clears the reset state
+        let reset = handlers
+            .reset_password("foo")
+            .await
+            .expect("Should be able to reset password");
+        handlers
+            .change_password("foo", reset.temp_password, "newpassword".into())
+            .await
+            .expect("Should be able to change password after reset");
+        assert!(handlers
+            .get("foo")
+            .await
+            .unwrap()
+            .password_change_phase
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_set_password() {
+        let handlers = Handlers::new(MemoryStore::new());
+        handlers
+            .add(add_request("foo", false))
+            .await
+            .expect("Should be able to add a user");
+        handlers.reset_password("foo").await.unwrap();
+
+        handlers
+            .set_password("foo", "servicepassword".into(), false)
+            .await
+            .expect("Should be able to set password");
+        assert!(
+            handlers
+                .get("foo")
+                .await
+                .unwrap()
+                .password_change_phase
+                .is_none(),
+            "Setting the password should clear the reset"
+        );
+        let resp = handlers
+            .verify("foo", "servicepassword".into())
+            .await
+            .expect("Should verify with the new password");
+        assert!(!resp.needs_password_reset);
+
+        handlers
+            .set_password("foo", "temporarypassword".into(), true)
+            .await
+            .expect("Should be able to set password");
+        let resp = handlers
+            .verify("foo", "temporarypassword".into())
+            .await
+            .expect("Should verify with the new password");
+        assert!(resp.needs_password_reset, "Should force a password change");
+
+        assert!(matches!(
+            handlers.set_password("foo", "foo".into(), false).await,
+            Err(HandleError::PasswordPolicy(_))
+        ));
+        assert!(matches!(
+            handlers
+                .set_password("nope", "servicepassword".into(), false)
+                .await,
+            Err(HandleError::UsernameDoesNotExist)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_lockout() {
+        let handlers = Handlers::new(MemoryStore::new()).with_lockout_policy(LockoutPolicy {
+            max_failed_attempts: 3,
+            lockout_duration: Duration::from_secs(60),
+        });
+        handlers
+            .add(add_request("foo", false))
+            .await
+            .expect("Should be able to add a user");
+
+        // A successful login resets the failure count
+        for _ in 0..2 {
+            assert!(matches!(
+                handlers.verify("foo", "wrong".into()).await,
+                Err(HandleError::InvalidCredentials)
+            ));
+        }
+        handlers.wait_for_background_tasks().await;
+        handlers
+            .verify("foo", "supersecure".into())
+            .await
+            .expect("Should verify before reaching the threshold");
+
+        for _ in 0..3 {
+            assert!(matches!(
+                handlers.verify("foo", "wrong".into()).await,
+                Err(HandleError::InvalidCredentials)
+            ));
+        }
+        handlers.wait_for_background_tasks().await;
+        assert!(
+            matches!(
+                handlers.verify("foo", "supersecure".into()).await,
+                Err(HandleError::AccountLocked)
+            ),
+            "Should be locked even with the correct password"
+        );
+        assert!(
+            matches!(
+                handlers
+                    .change_password("foo", "supersecure".into(), "newpassword".into())
+                    .await,
+                Err(HandleError::AccountLocked)
+            ),
+            "Should not be able to change password while locked"
+        );
+        assert!(handlers.get("foo").await.unwrap().locked_until.is_some());
+
+        handlers
+            .unlock("foo")
+            .await
+            .expect("Should be able to unlock user");
+        assert!(handlers.get("foo").await.unwrap().locked_until.is_none());
+        handlers
+            .verify("foo", "supersecure".into())
+            .await
+            .expect("Should verify after unlocking");
+    }
+
+    #[tokio::test]
+    async fn test_rehash_on_login() {
+        let old = Argon2Config {
+            algorithm: Argon2Algorithm::Argon2i,
+            memory_cost: 64,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let new = Argon2Config {
+            algorithm: Argon2Algorithm::Argon2id,
+            memory_cost: 128,
+            iterations: 2,
+            parallelism: 1,
+        };
+        let handlers = Handlers::new(MemoryStore::new())
+            .with_argon2_config(old)
+            .unwrap();
+        handlers
+            .add(add_request("foo", false))
+            .await
+            .expect("Should be able to add a user");
+        let hash = handlers
+            .store
+            .get_user("foo")
+            .await
+            .unwrap()
+            .hashed_password;
+        assert!(
+            old.is_current(&hash),
+            "Should hash with the configured parameters"
+        );
+
+        // The store is kept, so this is like restarting the server with new settings
+        let handlers = handlers.with_argon2_config(new).unwrap();
+        assert!(matches!(
+            handlers.verify("foo", "wrong".into()).await,
+            Err(HandleError::InvalidCredentials)
+        ));
+        let hash = handlers
+            .store
+            .get_user("foo")
+            .await
+            .unwrap()
+            .hashed_password;
+        assert!(
+            old.is_current(&hash),
+            "Should not rehash after a failed verification"
+        );
+
+        handlers
+            .verify("foo", "supersecure".into())
+            .await
+            .expect("Should verify with a hash made with old parameters");
+        let hash = handlers
+            .store
+            .get_user("foo")
+            .await
+            .unwrap()
+            .hashed_password;
+        assert!(new.is_current(&hash), "Should rehash after logging in");
+        handlers
+            .verify("foo", "supersecure".into())
+            .await
+            .expect("Should verify with the upgraded hash");
+
+        assert!(
+            Handlers::new(MemoryStore::new())
+                .with_argon2_config(Argon2Config {
+                    memory_cost: 1,
+                    ..Default::default()
+                })
+                .is_err(),
+            "Should reject parameters Argon2 doesn't accept"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let source = Handlers::new(MemoryStore::new());
+        source
+            .add_group(GroupAddRequest {
+                name: "devs".to_string(),
+                gid: Some(20000),
+                description: "Developers".to_string(),
+            })
+            .await
+            .unwrap();
+        source.add(add_request("foo", false)).await.unwrap();
+        source.add(add_request("bar", false)).await.unwrap();
+        source
+            .add_groups("foo", ["devs".to_string()].into())
+            .await
+            .unwrap();
+        source.reset_password("bar").await.unwrap();
+        let exported = source
+            .export_directory()
+            .await
+            .expect("Should export the directory");
+        assert_eq!(exported.users.len(), 2);
+        assert_eq!(exported.groups.len(), 1);
+
+        let import = |directory: Directory, on_conflict, dry_run| ImportRequest {
+            directory,
+            on_conflict,
+            dry_run,
+        };
+        let target = Handlers::new(MemoryStore::new());
+        let summary = target
+            .import_directory(import(exported.clone(), ConflictPolicy::Fail, true))
+            .await
+            .expect("Should check the import");
+        assert_eq!(summary.users.created, 2);
+        assert_eq!(summary.groups.created, 1);
+        assert!(
+            target.list().await.unwrap().is_empty(),
+            "A dry run shouldn't change anything"
+        );
+
+        let summary = target
+            .import_directory(import(exported.clone(), ConflictPolicy::Fail, false))
+            .await
+            .expect("Should import the directory");
+        assert_eq!(summary.users.created, 2);
+        assert!(summary.errors.is_empty(), "{:?}", summary.errors);
+        target
+            .verify("foo", "supersecure".into())
+            .await
+            .expect("Should keep the password hash");
+        let foo = target.get("foo").await.unwrap();
+        assert!(foo.groups.contains("devs"));
+        assert_eq!(
+            foo.posix,
+            source.get("foo").await.unwrap().posix,
+            "Should keep the POSIX attributes"
+        );
+        assert!(matches!(
+            target.get("bar").await.unwrap().password_change_phase,
+            Some(PasswordResetPhase::Reset(_))
+        ));
+        assert_eq!(target.get_group("devs").await.unwrap().gid, 20000);
+
+        assert!(matches!(
+            target
+                .import_directory(import(exported.clone(), ConflictPolicy::Fail, false))
+                .await,
+            Err(HandleError::ImportConflict(_))
+        ));
+        let summary = target
+            .import_directory(import(exported.clone(), ConflictPolicy::Skip, false))
+            .await
+            .unwrap();
+        assert_eq!(summary.users.skipped, 2);
+        assert_eq!(summary.groups.skipped, 1);
+
+        let mut changed = exported.clone();
+        changed.groups[0].gid = 20001;
+        let old_uid = changed.users[1].posix.as_ref().unwrap().uid;
+        changed.users[1].posix.as_mut().unwrap().uid = 30000;
+        changed.users[1].password_hash = None;
+        let summary = target
+            .import_directory(import(changed, ConflictPolicy::Overwrite, false))
+            .await
+            .unwrap();
+        assert_eq!(summary.users.overwritten, 2);
+        assert_eq!(summary.groups.overwritten, 1);
+        assert_eq!(target.get_group("devs").await.unwrap().gid, 20001);
+        let foo = target.get("foo").await.unwrap();
+        assert_eq!(foo.posix.unwrap().uid, 30000);
+        target
+            .verify("foo", "supersecure".into())
+            .await
+            .expect("Should keep the current password when none is imported");
+        assert!(
+            target.store.reserve_id(old_uid, "test").await.unwrap(),
+            "Should release the old UID"
+        );
+
+        let bad = Directory {
+            users: vec![
+                UserRecord {
+                    password_hash: Some("!".into()),
+                    ..exported.users[0].clone()
+                },
+                UserRecord {
+                    username: "baz".to_string(),
+                    password_hash: None,
+                    posix: None,
+                    ..exported.users[0].clone()
+                },
+                UserRecord {
+                    username: "devs".to_string(),
+                    posix: None,
+                    ..exported.users[0].clone()
+                },
+            ],
+            groups: vec![GroupRecord {
+                name: "baz".to_string(),
+                gid: 20002,
+                description: String::new(),
+            }],
+        };
+        let summary = target
+            .import_directory(import(bad, ConflictPolicy::Overwrite, false))
+            .await
+            .unwrap();
+        assert_eq!(
+            summary.users.failed, 2,
+            "Should reject the invalid hash and a user named after a group"
+        );
+        assert_eq!(
+            summary.groups.failed, 1,
+            "Should reject a group named after a user"
+        );
+        assert_eq!(summary.errors.len(), 3);
+        assert_eq!(
+            summary.users.created, 1,
+            "Valid users should still be imported"
+        );
+        assert!(matches!(
+            target.verify("baz", "supersecure".into()).await,
+            Err(HandleError::InvalidCredentials)
+        ));
+        assert!(
+            matches!(
+                target.store.get_user("baz").await.unwrap().password_reset,
+                Some(PasswordResetPhase::Locked)
+            ),
+            "Users imported without a password should need an admin to reset it"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_legacy_hashes() {
+        let handlers = Handlers::new(MemoryStore::new());
+        let hashes = [
+            ("sha512", pwhash::sha512_crypt::hash("supersecure").unwrap()),
+            // Generating sha256-crypt hashes is deprecated, so this one was made with `openssl passwd -5`
+            (
+                "sha256",
+                "$5$saltsalt$FRlNSaOISGKyx7INq5pp/cZ0NG5JpCku2TD7AH0Ej1C".to_string(),
+            ),
+            ("bcrypt", pwhash::bcrypt::hash("supersecure").unwrap()),
+        ];
+        for (username, hash) in hashes {
+            handlers
+                .add(UserAddRequest {
+                    password: hash.into(),
+                    prehashed: true,
+                    ..add_request(username, false)
+                })
+                .await
+                .unwrap_or_else(|e| panic!("Should be able to import a {username} hash: {e}"));
+            assert!(matches!(
+                handlers.verify(username, "wrong".into()).await,
+                Err(HandleError::InvalidCredentials)
+            ));
+            handlers
+                .verify(username, "supersecure".into())
+                .await
+                .unwrap_or_else(|e| panic!("Should verify against a {username} hash: {e}"));
+            let hash = handlers
+                .store
+                .get_user(username)
+                .await
+                .unwrap()
+                .hashed_password;
+            assert!(
+                Argon2Config::default().is_current(&hash),
+                "Should migrate a {username} hash to Argon2 after logging in"
+            );
+            handlers
+                .verify(username, "supersecure".into())
+                .await
+                .expect("Should verify with the migrated hash");
+        }
+
+        // Locked accounts in shadow files have no usable hash
+        for hash in ["!", "*", "$1$saltsalt$notsupported", "$argon2id$garbage"] {
+            assert!(
+                matches!(
+                    handlers
+                        .add(UserAddRequest {
+                            password: hash.into(),
+                            prehashed: true,
+                            ..add_request("bad", false)
+                        })
+                        .await,
+                    Err(HandleError::InvalidPasswordHash(_))
+                ),
+                "Should reject {hash} as a password hash"
+            );
+        }
+    }
+
+    /// A store that takes a while to write to, like a store backed by a remote server
+    struct SlowStore {
+        inner: MemoryStore,
+        write_delay: Duration,
+    }
+
+    impl CredentialBackend for SlowStore {
+        fn exists<'a>(&'a self, username: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+            CredentialBackend::exists(&self.inner, username)
+        }
+
+        fn get_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Option<UserInfo>> {
+            CredentialBackend::get_user(&self.inner, username)
+        }
+
+        fn put_user(&self, username: String, info: UserInfo) -> BoxFuture<'_, anyhow::Result<()>> {
+            Box::pin(async move {
+                tokio::time::sleep(self.write_delay).await;
+                self.inner.put_user(username, info).await
+            })
+        }
+
+        fn delete_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+            CredentialBackend::delete_user(&self.inner, username)
+        }
+
+        fn list_users(&self) -> BoxFuture<'_, anyhow::Result<Vec<String>>> {
+            CredentialBackend::list_users(&self.inner)
+        }
+
+        fn username_for_uid(&self, uid: u32) -> BoxFuture<'_, Option<String>> {
+            CredentialBackend::username_for_uid(&self.inner, uid)
+        }
+
+        fn group_members<'a>(&'a self, group: &'a str) -> BoxFuture<'a, BTreeSet<String>> {
+            CredentialBackend::group_members(&self.inner, group)
+        }
+
+        fn private_groups(&self) -> BoxFuture<'_, BTreeMap<String, u32>> {
+            CredentialBackend::private_groups(&self.inner)
+        }
+
+        fn id_in_use(&self, id: u32) -> BoxFuture<'_, bool> {
+            CredentialBackend::id_in_use(&self.inner, id)
+        }
+
+        fn reserve_id<'a>(
+            &'a self,
+            id: u32,
+            owner: &'a str,
+        ) -> BoxFuture<'a, anyhow::Result<bool>> {
+            CredentialBackend::reserve_id(&self.inner, id, owner)
+        }
+
+        fn release_id(&self, id: u32) -> BoxFuture<'_, anyhow::Result<()>> {
+            CredentialBackend::release_id(&self.inner, id)
+        }
+
+        fn get_group<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Option<GroupInfo>> {
+            CredentialBackend::get_group(&self.inner, name)
+        }
+
+        fn create_group(
+            &self,
+            name: String,
+            info: GroupInfo,
+        ) -> BoxFuture<'_, anyhow::Result<bool>> {
+            CredentialBackend::create_group(&self.inner, name, info)
+        }
+
+        fn delete_group<'a>(&'a self, name: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+            CredentialBackend::delete_group(&self.inner, name)
+        }
+
+        fn list_groups(&self) -> BoxFuture<'_, anyhow::Result<Vec<String>>> {
+            CredentialBackend::list_groups(&self.inner)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_user_timing() {
+        const SAMPLES: usize = 9;
+        const WRITE_DELAY: Duration = Duration::from_millis(200);
+        let handlers = Handlers::new(SlowStore {
+            inner: MemoryStore::new(),
+            write_delay: WRITE_DELAY,
+        })
+        // Cheap hashes keep the store write the biggest cost of a failed attempt, so any write on
+        // the response path would stand out
+        .with_argon2_config(Argon2Config {
+            memory_cost: 1024,
+            iterations: 1,
+            parallelism: 1,
+            ..Default::default()
+        })
+        .expect("Parameters should be valid")
+        // Lockouts would cut verification short for the known user part way through
+        .with_lockout_policy(LockoutPolicy {
+            max_failed_attempts: 0,
+            ..Default::default()
+        });
+        handlers
+            .add(add_request("foo", false))
+            .await
+            .expect("Should be able to add a user");
+
+        let time_verify = |username: &'static str| {
+            let handlers = handlers.clone();
+            async move {
+                let start = std::time::Instant::now();
+                let res = handlers.verify(username, "wrong".into()).await;
+                assert!(matches!(res, Err(HandleError::InvalidCredentials)));
+                start.elapsed()
+            }
+        };
+        // The first unknown user creates the dummy hash, so leave it out of the samples
+        time_verify("nobody").await;
+
+        // Interleave the samples so anything else slowing the machine down affects both equally
+        let mut known = Vec::with_capacity(SAMPLES);
+        let mut unknown = Vec::with_capacity(SAMPLES);
+        for _ in 0..SAMPLES {
+            known.push(time_verify("foo").await);
+            unknown.push(time_verify("nobody").await);
+        }
+        known.sort();
+        unknown.sort();
+        let known = known[SAMPLES / 2];
+        let unknown = unknown[SAMPLES / 2];
+        assert!(
+            known.abs_diff(unknown) < WRITE_DELAY / 2,
+            "Median verification time for unknown users ({unknown:?}) should be close to the time for known users ({known:?})"
+        );
+
+        // The failed attempts still get recorded once they are done in the background
+        handlers.wait_for_background_tasks().await;
+        assert_eq!(
+            handlers
+                .store
+                .get_user("foo")
+                .await
+                .unwrap()
+                .failed_attempts,
+            SAMPLES as u32
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hash_checked_before_rejecting() {
+        let handlers = Handlers::new(MemoryStore::new());
+        assert!(
+            matches!(
+                handlers.verify("nobody", "wrong".into()).await,
+                Err(HandleError::InvalidCredentials)
+            ),
+            "Should not verify an unknown user"
+        );
+        assert!(
+            handlers.dummy_hash.initialized(),
+            "Unknown users should be checked against the dummy hash"
+        );
+
+        // A hash that can't be read fails as soon as it is checked. Accounts that would otherwise be
+        // rejected because of their state failing with a system error instead shows that the (slow)
+        // hash check happens before the state is looked at
+        handlers
+            .add(add_request("foo", false))
+            .await
+            .expect("Should be able to add a user");
+        let user = handlers.store.get_user("foo").await.unwrap();
+        let unreadable = SecureString::from("not a hash");
+        for user in [
+            UserInfo {
+                hashed_password: unreadable.clone(),
+                locked_until: Some(get_expiry_duration(Duration::from_secs(60)).unwrap()),
+                ..user.clone()
+            },
+            UserInfo {
+                hashed_password: unreadable.clone(),
+                password_reset: Some(PasswordResetPhase::Locked),
+                ..user.clone()
+            },
+        ] {
+            handlers
+                .store
+                .put_user("foo".to_string(), user.clone())
+                .await
+                .unwrap();
+            assert!(
+                matches!(
+                    handlers.verify("foo", "supersecure".into()).await,
+                    Err(HandleError::SystemError(_))
+                ),
+                "The password should be checked before rejecting {user:?}"
+            );
+            assert!(
+                matches!(
+                    handlers
+                        .change_password("foo", "supersecure".into(), "newpassword".into())
+                        .await,
+                    Err(HandleError::SystemError(_))
+                ),
+                "The password should be checked before rejecting a password change for {user:?}"
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_failed_attempts() {
+        let handlers = Handlers::new(MemoryStore::new()).with_lockout_policy(LockoutPolicy {
+            max_failed_attempts: 5,
+            lockout_duration: Duration::from_secs(60),
+        });
+        handlers
+            .add(add_request("foo", false))
+            .await
+            .expect("Should be able to add a user");
+
+        // Every attempt should be counted even when they all happen at once
+        let attempts = (0..5).map(|_| {
+            let handlers = handlers.clone();
+            tokio::spawn(async move { handlers.verify("foo", "wrong".into()).await })
+        });
+        for res in futures::future::join_all(attempts).await {
+            assert!(matches!(res.unwrap(), Err(HandleError::InvalidCredentials)));
+        }
+        handlers.wait_for_background_tasks().await;
+        assert!(
+            handlers.get("foo").await.unwrap().locked_until.is_some(),
+            "User should be locked after concurrent failed attempts"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_policy() {
+        let handlers = Handlers::new(MemoryStore::new()).with_password_policy(PasswordPolicy {
+            min_length: 10,
+            ..Default::default()
+        });
+        let mut req = add_request("foo", false);
+        req.password = "short".into();
+        assert!(
+            matches!(
+                handlers.add(req).await,
+                Err(HandleError::PasswordPolicy(v)) if v == vec![PolicyViolation::TooShort { min_length: 10 }]
+            ),
+            "Should not be able to add a user with a password violating the policy"
+        );
+
+        handlers
+            .add(add_request("foo", false))
+            .await
+            .expect("Should be able to add a user");
+        assert!(
+            matches!(
+                handlers
+                    .change_password("foo", "supersecure".into(), "foo".into())
+                    .await,
+                Err(HandleError::PasswordPolicy(_))
+            ),
+            "Should not be able to change to a password violating the policy"
+        );
+        handlers
+            .verify("foo", "supersecure".into())
+            .await
+            .expect("Old password should still be valid");
+    }
+
+    #[tokio::test]
+    async fn test_posix_attributes() {
+        let handlers = Handlers::new(MemoryStore::new()).with_posix_config(PosixConfig {
+            uid_range: 5000..=5001,
+            ..Default::default()
+        });
+        handlers
+            .add(add_request("foo", false))
+            .await
+            .expect("Should be able to add a user");
+        let posix = handlers.get("foo").await.unwrap().posix.unwrap();
+        assert_eq!(
+            posix,
+            PosixAttributes {
+                uid: 5000,
+                gid: 5000,
+                home: "/home/foo".to_string(),
+                shell: "/bin/bash".to_string(),
+                gecos: String::new(),
+            }
+        );
+
+        let mut req = add_request("bar", false);
+        req.posix.uid = Some(5000);
+        assert!(
+            matches!(handlers.add(req).await, Err(HandleError::UidTaken(5000))),
+            "Should not be able to reuse a UID"
+        );
+        handlers
+            .add(add_request("bar", false))
+            .await
+            .expect("Should be able to add a user");
+        assert_eq!(handlers.get("bar").await.unwrap().posix.unwrap().uid, 5001);
+        assert!(
+            handlers.add(add_request("baz", false)).await.is_err(),
+            "Should fail when the UID range is exhausted"
+        );
+
+        // Deleting a user frees up their UID
+        handlers.delete("foo").await.unwrap();
+        handlers
+            .add(add_request("baz", false))
+            .await
+            .expect("Should be able to add a user");
+        assert_eq!(handlers.get("baz").await.unwrap().posix.unwrap().uid, 5000);
+
+        let posix = handlers
+            .set_posix_attributes(
+                "baz",
+                PosixAttributesRequest {
+                    uid: Some(6000),
+                    shell: Some("/bin/zsh".to_string()),
+                    gecos: Some("Baz Bazzington".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("Should be able to set attributes");
+        assert_eq!(posix.uid, 6000);
+        assert_eq!(posix.gid, 5000, "Unset attributes should be unchanged");
+        assert_eq!(posix.shell, "/bin/zsh");
+        assert_eq!(posix.gecos, "Baz Bazzington");
+        assert!(
+            matches!(
+                handlers
+                    .set_posix_attributes(
+                        "bar",
+                        PosixAttributesRequest {
+                            uid: Some(6000),
+                            ..Default::default()
+                        }
+                    )
+                    .await,
+                Err(HandleError::UidTaken(6000))
+            ),
+            "Should not be able to take another user's UID"
+        );
+
+        assert!(matches!(
+            handlers.add(add_request("_snas.uid.1", false)).await,
+            Err(HandleError::InvalidUsername(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_lookups() {
+        let handlers = Handlers::new(MemoryStore::new()).with_posix_config(PosixConfig {
+            uid_range: 5000..=5010,
+            ..Default::default()
+        });
+        handlers
+            .add(add_request("foo", false))
+            .await
+            .expect("Should be able to add a user");
+        let mut req = add_request("bar", false);
+        req.posix.gid = Some(100);
+        handlers
+            .add(req)
+            .await
+            .expect("Should be able to add a user");
+
+        let foo = handlers
+            .lookup_user(UserLookupRequest::Name("foo".to_string()))
+            .await
+            .expect("Should find user by name");
+        assert_eq!(foo.uid, 5000);
+        let bar = handlers
+            .lookup_user(UserLookupRequest::Uid(5001))
+            .await
+            .expect("Should find user by UID");
+        assert_eq!(bar.name, "bar");
+        assert!(matches!(
+            handlers.lookup_user(UserLookupRequest::Uid(1)).await,
+            Err(HandleError::UsernameDoesNotExist)
+        ));
+
+        let names: Vec<_> = handlers
+            .list_passwd()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, ["bar", "foo"]);
+
+        // Only foo has a private group as bar's primary group is a shared one
+        let group = handlers
+            .lookup_group(GroupLookupRequest::Gid(5000))
+            .await
+            .expect("Should find private group");
+        assert_eq!(group.name, "foo");
+        assert!(matches!(
+            handlers
+                .lookup_group(GroupLookupRequest::Name("bar".to_string()))
+                .await,
+            Err(HandleError::GroupDoesNotExist)
+        ));
+        assert_eq!(handlers.list_group_entries().await.unwrap().len(), 1);
+        assert!(handlers.user_group_ids("foo").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_groups() {
+        let handlers = Handlers::new(MemoryStore::new()).with_posix_config(PosixConfig {
+            uid_range: 5000..=5010,
+            ..Default::default()
+        });
+        handlers
+            .add(add_request("alice", false))
+            .await
+            .expect("Should be able to add a user");
+
+        let devs = handlers
+            .add_group(GroupAddRequest {
+                name: "devs".to_string(),
+                gid: None,
+                description: "Developers".to_string(),
+            })
+            .await
+            .expect("Should be able to add a group");
+        assert_eq!(devs.gid, 5001, "GIDs should not overlap with UIDs");
+        assert!(devs.members.is_empty());
+
+        let group_request = |name: &str, gid: Option<u32>| GroupAddRequest {
+            name: name.to_string(),
+            gid,
+            description: String::new(),
+        };
+        assert!(matches!(
+            handlers.add_group(group_request("devs", None)).await,
+            Err(HandleError::GroupTaken)
+        ));
+        assert!(matches!(
+            handlers.add_group(group_request("ops", Some(5000))).await,
+            Err(HandleError::GidTaken(5000))
+        ));
+        assert!(matches!(
+            handlers.add_group(group_request("alice", None)).await,
+            Err(HandleError::GroupTaken)
+        ));
+        assert!(matches!(
+            handlers.add_group(group_request("_snas.id.1", None)).await,
+            Err(HandleError::InvalidGroupName(_))
+        ));
+
+        assert!(matches!(
+            handlers.add(add_request("devs", false)).await,
+            Err(HandleError::InvalidUsername(_))
+        ));
+
+        handlers
+            .add_groups("alice", ["devs".to_string()].into())
+            .await
+            .expect("Should be able to add user to group");
+        let mut req = add_request("bob", false);
+        req.groups.insert("devs".to_string());
+        handlers
+            .add(req)
+            .await
+            .expect("Should be able to add a user");
+        let bob = handlers.get("bob").await.unwrap();
+        assert_eq!(
+            bob.posix.unwrap().uid,
+            5002,
+            "UIDs should not overlap with GIDs"
+        );
+
+        let devs = handlers
+            .get_group("devs")
+            .await
+            .expect("Should be able to get group");
+        assert_eq!(devs.description, "Developers");
+        assert_eq!(
+            devs.members,
+            ["alice".to_string(), "bob".to_string()].into()
+        );
+        assert_eq!(handlers.list_groups().await.unwrap(), ["devs"]);
+        assert_eq!(handlers.user_group_ids("alice").await.unwrap(), [5001]);
+        let entry = handlers
+            .lookup_group(GroupLookupRequest::Gid(5001))
+            .await
+            .expect("Should find created group");
+        assert_eq!(entry.name, "devs");
+        assert_eq!(entry.members, ["alice", "bob"]);
+        let entry = handlers
+            .lookup_group(GroupLookupRequest::Name("devs".to_string()))
+            .await
+            .expect("Should find created group by name");
+        assert_eq!(entry.gid, 5001);
+        assert_eq!(entry.members, ["alice", "bob"]);
+        let entry = handlers
+            .lookup_group(GroupLookupRequest::Name("alice".to_string()))
+            .await
+            .expect("Should find private group by name");
+        assert_eq!(entry.gid, 5000);
+        assert!(entry.members.is_empty());
+
+        handlers
+            .delete_group("devs")
+            .await
+            .expect("Should be able to delete group");
+        assert!(matches!(
+            handlers.get_group("devs").await,
+            Err(HandleError::GroupDoesNotExist)
+        ));
+        assert!(
+            !handlers.get("alice").await.unwrap().groups.contains("devs"),
+            "Group should be removed from its members"
+        );
+        assert!(handlers.user_group_ids("alice").await.unwrap().is_empty());
+        handlers
+            .add_group(group_request("ops", Some(5001)))
+            .await
+            .expect("GID should be released when the group is deleted");
+    }
 }
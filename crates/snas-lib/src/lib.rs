@@ -1,6 +1,8 @@
 pub mod clients;
 pub mod error;
 pub mod handlers;
+pub mod metrics;
+pub mod policy;
 pub mod servers;
 pub mod storage;
 pub mod types;
@@ -9,9 +11,12 @@ pub use types::*;
 
 pub(crate) const DEFAULT_ADMIN_NATS_SUBJECT_PREFIX: &str = "snas.admin";
 pub(crate) const DEFAULT_USER_NATS_SUBJECT_PREFIX: &str = "snas.user";
-pub(crate) const REQUEST_IDENTIFIER: &[u8] = "REQ\n".as_bytes();
-pub(crate) const RESPONSE_IDENTIFIER: &[u8] = "RES\n".as_bytes();
-pub(crate) const TERMINATOR: &[u8] = "\nEND\n".as_bytes();
+/// The prefix of every request sent over the user socket
+pub const REQUEST_IDENTIFIER: &[u8] = "REQ\n".as_bytes();
+/// The prefix of every response sent over the user socket
+pub const RESPONSE_IDENTIFIER: &[u8] = "RES\n".as_bytes();
+/// The terminator written after the body of every request and response over the user socket
+pub const TERMINATOR: &[u8] = "\nEND\n".as_bytes();
 #[cfg(unix)]
 pub const DEFAULT_SOCKET_PATH: &str = "/var/run/snas/user.sock";
 
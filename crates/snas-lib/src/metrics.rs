@@ -0,0 +1,246 @@
+//! Prometheus metrics for the server. Metrics are recorded into a process wide registry as requests
+//! are handled, and [`encode`] renders them in the Prometheus text format so they can be scraped.
+//! Recording is just a few atomic operations, so it happens whether or not anything ever scrapes
+//! the metrics
+
+use std::{
+    future::Future,
+    sync::{atomic::AtomicU64, LazyLock},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use prometheus_client::{
+    encoding::text,
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::{Registry, Unit},
+};
+
+use crate::error::{HandleError, Result};
+
+type Label = [(&'static str, &'static str); 1];
+type HistogramFamily = Family<Label, Histogram, fn() -> Histogram>;
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+struct Metrics {
+    registry: Registry,
+    verifications: Family<Label, Counter>,
+    password_changes: Family<Label, Counter>,
+    admin_operations: Family<[(&'static str, &'static str); 2], Counter>,
+    socket_connections: Counter,
+    password_hash_duration: HistogramFamily,
+    request_duration: HistogramFamily,
+    cache_entries: Family<Label, Gauge>,
+    watcher_lag: Gauge<f64, AtomicU64>,
+    watcher_pending_updates: Gauge,
+    cache_stale: Gauge,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let metrics = Metrics {
+            registry: Registry::with_prefix("snas"),
+            verifications: Family::default(),
+            password_changes: Family::default(),
+            admin_operations: Family::default(),
+            socket_connections: Counter::default(),
+            // Argon2 is deliberately slow, so these start at 5ms and go up to about 10s
+            password_hash_duration: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.005, 2.0, 12))
+            }),
+            // Requests that don't hash anything are answered from the cache, so these start at 1ms
+            request_duration: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.001, 2.0, 14))
+            }),
+            cache_entries: Family::default(),
+            watcher_lag: Gauge::default(),
+            watcher_pending_updates: Gauge::default(),
+            cache_stale: Gauge::default(),
+        };
+        let mut registry = metrics.registry;
+        registry.register(
+            "verifications",
+            "Credential verifications by outcome (valid, invalid, reset_expired, change_required or error)",
+            metrics.verifications.clone(),
+        );
+        registry.register(
+            "password_changes",
+            "Password changes by result (success or failure)",
+            metrics.password_changes.clone(),
+        );
+        registry.register(
+            "admin_operations",
+            "Admin API requests by method and result (success or failure)",
+            metrics.admin_operations.clone(),
+        );
+        registry.register(
+            "socket_connections",
+            "Connections accepted on the user socket",
+            metrics.socket_connections.clone(),
+        );
+        registry.register_with_unit(
+            "password_hash_duration",
+            "Time spent hashing or verifying passwords with Argon2, by operation",
+            Unit::Seconds,
+            metrics.password_hash_duration.clone(),
+        );
+        registry.register_with_unit(
+            "request_duration",
+            "Time taken to handle a request, by transport",
+            Unit::Seconds,
+            metrics.request_duration.clone(),
+        );
+        registry.register(
+            "cache_entries",
+            "Entries in the local cache of the credential store, by kind (users or groups)",
+            metrics.cache_entries.clone(),
+        );
+        registry.register_with_unit(
+            "cache_watcher_lag",
+            "Time between the last update applied to the cache being written to the store and it being received by the watcher",
+            Unit::Seconds,
+            metrics.watcher_lag.clone(),
+        );
+        registry.register(
+            "cache_watcher_pending_updates",
+            "Updates in the store the cache watcher has not received yet",
+            metrics.watcher_pending_updates.clone(),
+        );
+        registry.register(
+            "cache_stale",
+            "Whether the cache watcher has failed and the cache may be out of date (1) or not (0)",
+            metrics.cache_stale.clone(),
+        );
+        Metrics {
+            registry,
+            ..metrics
+        }
+    }
+}
+
+/// Renders all metrics in the Prometheus text format
+pub fn encode() -> anyhow::Result<String> {
+    let mut out = String::new();
+    text::encode(&mut out, &METRICS.registry)?;
+    Ok(out)
+}
+
+/// Records the outcome of a credential verification
+pub(crate) fn record_verification<T>(result: &Result<T>) {
+    let outcome = match result {
+        Ok(_) => "valid",
+        Err(HandleError::InvalidCredentials | HandleError::AccountLocked) => "invalid",
+        Err(HandleError::PasswordResetExpired) => "reset_expired",
+        Err(HandleError::PasswordChangeRequired) => "change_required",
+        Err(_) => "error",
+    };
+    METRICS
+        .verifications
+        .get_or_create(&[("outcome", outcome)])
+        .inc();
+}
+
+pub(crate) fn record_password_change<T>(result: &Result<T>) {
+    METRICS
+        .password_changes
+        .get_or_create(&[("result", result_label(result.is_ok()))])
+        .inc();
+}
+
+pub(crate) fn record_admin_operation(method: &'static str, success: bool) {
+    METRICS
+        .admin_operations
+        .get_or_create(&[("method", method), ("result", result_label(success))])
+        .inc();
+}
+
+pub(crate) fn record_socket_connection() {
+    METRICS.socket_connections.inc();
+}
+
+/// Runs the given password hashing operation (`hash` or `verify`), recording how long it took
+pub(crate) fn time_password_hash<T>(operation: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let res = f();
+    METRICS
+        .password_hash_duration
+        .get_or_create(&[("operation", operation)])
+        .observe(start.elapsed().as_secs_f64());
+    res
+}
+
+/// Handles a request that came in over the given transport, recording how long it took
+pub(crate) async fn time_request<F: Future>(transport: &'static str, fut: F) -> F::Output {
+    let start = Instant::now();
+    let res = fut.await;
+    METRICS
+        .request_duration
+        .get_or_create(&[("transport", transport)])
+        .observe(start.elapsed().as_secs_f64());
+    res
+}
+
+pub(crate) fn record_cache_size(users: usize, groups: usize) {
+    for (kind, size) in [("users", users), ("groups", groups)] {
+        METRICS
+            .cache_entries
+            .get_or_create(&[("kind", kind)])
+            .set(size as i64);
+    }
+}
+
+/// Records an update received by the cache watcher, given when the update was written to the store
+/// (in nanoseconds since the Unix epoch) and how many updates are still waiting behind it
+pub(crate) fn record_watcher_update(created_unix_nanos: i128, pending: u64) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_nanos() as i128)
+        .unwrap_or_default();
+    // Clocks on different machines can disagree a little, so never report a negative lag
+    let lag = (now - created_unix_nanos).max(0) as f64 / 1e9;
+    METRICS.watcher_lag.set(lag);
+    METRICS.watcher_pending_updates.set(pending as i64);
+}
+
+pub(crate) fn record_cache_stale(stale: bool) {
+    METRICS.cache_stale.set(stale as i64);
+}
+
+fn result_label(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        record_admin_operation("test_method", true);
+        time_password_hash("test_operation", || ());
+
+        let encoded = encode().expect("Should encode metrics");
+        assert!(
+            encoded.contains(
+                "snas_admin_operations_total{method=\"test_method\",result=\"success\"} 1"
+            ),
+            "Should include the admin operation counter:\n{encoded}"
+        );
+        assert!(
+            encoded.contains(
+                "snas_password_hash_duration_seconds_count{operation=\"test_operation\"} 1"
+            ),
+            "Should include the hash duration histogram:\n{encoded}"
+        );
+        assert!(encoded.ends_with("# EOF\n"));
+    }
+}
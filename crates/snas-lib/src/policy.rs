@@ -0,0 +1,176 @@
+use std::{collections::HashSet, path::Path};
+
+use anyhow::Context;
+
+use crate::{api::PolicyViolation, SecureString};
+
+/// Rules that passwords must satisfy before they are hashed and stored. The default policy only
+/// rejects empty and excessively long passwords so that it is safe to enable on existing
+/// deployments
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    /// The minimum number of characters a password must have
+    pub min_length: usize,
+    /// The maximum number of characters a password can have
+    pub max_length: usize,
+    /// Require at least one lowercase letter
+    pub require_lowercase: bool,
+    /// Require at least one uppercase letter
+    pub require_uppercase: bool,
+    /// Require at least one digit
+    pub require_digit: bool,
+    /// Require at least one character that is not a letter or digit
+    pub require_symbol: bool,
+    /// Reject passwords that are the same as the username (ignoring case)
+    pub differ_from_username: bool,
+    /// Passwords that are not allowed. These should be all lowercase as passwords are compared
+    /// case insensitively
+    pub banned_passwords: HashSet<String>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 1,
+            max_length: 1024,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            differ_from_username: true,
+            banned_passwords: HashSet::new(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Loads banned passwords from the given file and adds them to the policy. The file should
+    /// contain one password per line. Empty lines and lines starting with `#` are ignored
+    pub fn load_banned_passwords(mut self, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read banned password file {}", path.display()))?;
+        self.banned_passwords.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_lowercase),
+        );
+        Ok(self)
+    }
+
+    /// Checks the password for the given user against the policy, returning all of the rules that
+    /// were violated
+    pub fn check(
+        &self,
+        username: &str,
+        password: &SecureString,
+    ) -> std::result::Result<(), Vec<PolicyViolation>> {
+        let password: &str = password.as_ref();
+        let mut violations = Vec::new();
+
+        let length = password.chars().count();
+        if length < self.min_length {
+            violations.push(PolicyViolation::TooShort {
+                min_length: self.min_length,
+            });
+        }
+        if length > self.max_length {
+            violations.push(PolicyViolation::TooLong {
+                max_length: self.max_length,
+            });
+        }
+        if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+            violations.push(PolicyViolation::MissingLowercase);
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            violations.push(PolicyViolation::MissingUppercase);
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            violations.push(PolicyViolation::MissingDigit);
+        }
+        if self.require_symbol && password.chars().all(char::is_alphanumeric) {
+            violations.push(PolicyViolation::MissingSymbol);
+        }
+        if self.differ_from_username && password.to_lowercase() == username.to_lowercase() {
+            violations.push(PolicyViolation::MatchesUsername);
+        }
+        if !self.banned_passwords.is_empty()
+            && self.banned_passwords.contains(&password.to_lowercase())
+        {
+            violations.push(PolicyViolation::Banned);
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_policy_check() {
+        let policy = PasswordPolicy::default();
+        assert!(policy.check("foo", &"bar".into()).is_ok());
+        assert_eq!(
+            policy.check("foo", &"".into()).unwrap_err(),
+            vec![PolicyViolation::TooShort { min_length: 1 }]
+        );
+        assert_eq!(
+            policy.check("foo", &"FOO".into()).unwrap_err(),
+            vec![PolicyViolation::MatchesUsername]
+        );
+
+        let policy = PasswordPolicy {
+            min_length: 8,
+            max_length: 16,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: true,
+            ..Default::default()
+        };
+        assert!(policy.check("foo", &"Sup3r-secure".into()).is_ok());
+        assert_eq!(
+            policy.check("foo", &"abc".into()).unwrap_err(),
+            vec![
+                PolicyViolation::TooShort { min_length: 8 },
+                PolicyViolation::MissingUppercase,
+                PolicyViolation::MissingDigit,
+                PolicyViolation::MissingSymbol,
+            ]
+        );
+        assert_eq!(
+            policy
+                .check("foo", &"Sup3r-secure-but-long".into())
+                .unwrap_err(),
+            vec![PolicyViolation::TooLong { max_length: 16 }]
+        );
+    }
+
+    #[test]
+    fn test_banned_passwords() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("banned.txt");
+        std::fs::write(&path, "# common passwords\nPassword1\n\n  letmein  \n").unwrap();
+        let policy = PasswordPolicy::default()
+            .load_banned_passwords(&path)
+            .expect("Should be able to load banned passwords");
+        assert_eq!(policy.banned_passwords.len(), 2);
+        assert_eq!(
+            policy.check("foo", &"password1".into()).unwrap_err(),
+            vec![PolicyViolation::Banned]
+        );
+        assert_eq!(
+            policy.check("foo", &"LetMeIn".into()).unwrap_err(),
+            vec![PolicyViolation::Banned]
+        );
+        assert!(policy.check("foo", &"letmein2".into()).is_ok());
+    }
+}
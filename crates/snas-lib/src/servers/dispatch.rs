@@ -0,0 +1,573 @@
+//! Transport agnostic request handling. Every server parses a method name and a JSON body out of
+//! whatever framing it uses and hands them to a [`Dispatcher`], which calls the matching
+//! [`Handlers`] function and builds the [`GenericResponse`] to send back. This keeps the mapping
+//! of errors to responses in one place no matter which transport a request came in on.
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tracing::{instrument, trace};
+
+use crate::{
+    admin::{
+        GroupAddRequest, GroupDeleteRequest, GroupGetRequest, GroupMembersRequest,
+        GroupModifyRequest, ImportRequest, PasswordResetRequest, SetAttributesRequest,
+        SetPasswordRequest, UserAddRequest, UserDeleteRequest, UserGetRequest, UserUnlockRequest,
+    },
+    api::{
+        ErrorKind, GenericResponse, GroupLookupRequest, PasswordChangeRequest, UserGroupsRequest,
+        UserLookupRequest, VerificationRequest, VerificationResponse,
+    },
+    error::HandleError,
+    handlers::Handlers,
+    metrics,
+};
+
+/// The groups of methods a transport can expose
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Api {
+    /// Credential verification and password changes for end users
+    User,
+    /// Read only account lookups, such as the ones used by NSS
+    Lookup,
+    /// Account management
+    Admin,
+    /// Health checks for local monitoring
+    Health,
+}
+
+/// Every method that can be dispatched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Verify,
+    ChangePassword,
+    LookupUser,
+    LookupGroup,
+    EnumerateUsers,
+    EnumerateGroups,
+    UserGroupIds,
+    AddUser,
+    GetUser,
+    ListUsers,
+    RemoveUser,
+    ResetPassword,
+    SetPassword,
+    UnlockUser,
+    SetAttributes,
+    AddGroups,
+    RemoveGroups,
+    AddGroup,
+    GetGroup,
+    ListGroups,
+    RemoveGroup,
+    GroupMembers,
+    Export,
+    Import,
+    Health,
+}
+
+impl Method {
+    /// Every method that can be dispatched
+    pub const ALL: [Method; 25] = [
+        Method::Verify,
+        Method::ChangePassword,
+        Method::LookupUser,
+        Method::LookupGroup,
+        Method::EnumerateUsers,
+        Method::EnumerateGroups,
+        Method::UserGroupIds,
+        Method::AddUser,
+        Method::GetUser,
+        Method::ListUsers,
+        Method::RemoveUser,
+        Method::ResetPassword,
+        Method::SetPassword,
+        Method::UnlockUser,
+        Method::SetAttributes,
+        Method::AddGroups,
+        Method::RemoveGroups,
+        Method::AddGroup,
+        Method::GetGroup,
+        Method::ListGroups,
+        Method::RemoveGroup,
+        Method::GroupMembers,
+        Method::Export,
+        Method::Import,
+        Method::Health,
+    ];
+
+    /// Parses a method from the name used on the wire, returning `None` if it is unknown
+    pub fn from_name(name: &str) -> Option<Method> {
+        Method::ALL.into_iter().find(|method| method.name() == name)
+    }
+
+    /// The name of this method on the wire
+    pub fn name(&self) -> &'static str {
+        match self {
+            Method::Verify => "verify",
+            Method::ChangePassword => "change_password",
+            Method::LookupUser => "lookup_user",
+            Method::LookupGroup => "lookup_group",
+            Method::EnumerateUsers => "enumerate_users",
+            Method::EnumerateGroups => "enumerate_groups",
+            Method::UserGroupIds => "user_group_ids",
+            Method::AddUser => "add_user",
+            Method::GetUser => "get_user",
+            Method::ListUsers => "list_users",
+            Method::RemoveUser => "remove_user",
+            Method::ResetPassword => "reset_password",
+            Method::SetPassword => "set_password",
+            Method::UnlockUser => "unlock_user",
+            Method::SetAttributes => "set_attributes",
+            Method::AddGroups => "add_groups",
+            Method::RemoveGroups => "remove_groups",
+            Method::AddGroup => "add_group",
+            Method::GetGroup => "get_group",
+            Method::ListGroups => "list_groups",
+            Method::RemoveGroup => "remove_group",
+            Method::GroupMembers => "group_members",
+            Method::Export => "export",
+            Method::Import => "import",
+            Method::Health => "health",
+        }
+    }
+
+    /// The APIs this method is part of
+    pub fn apis(&self) -> &'static [Api] {
+        match self {
+            Method::Verify | Method::ChangePassword => &[Api::User],
+            Method::LookupUser
+            | Method::LookupGroup
+            | Method::EnumerateUsers
+            | Method::EnumerateGroups
+            | Method::UserGroupIds => &[Api::Lookup],
+            // Fetching a single user is read only, so it is also available to lookup clients
+            Method::GetUser => &[Api::Admin, Api::Lookup],
+            Method::AddUser
+            | Method::ListUsers
+            | Method::RemoveUser
+            | Method::ResetPassword
+            | Method::SetPassword
+            | Method::UnlockUser
+            | Method::SetAttributes
+            | Method::AddGroups
+            | Method::RemoveGroups
+            | Method::AddGroup
+            | Method::GetGroup
+            | Method::ListGroups
+            | Method::RemoveGroup
+            | Method::GroupMembers
+            | Method::Export
+            | Method::Import => &[Api::Admin],
+            Method::Health => &[Api::Health],
+        }
+    }
+}
+
+/// Dispatches requests to [`Handlers`] for the methods of the configured APIs
+#[derive(Clone)]
+pub struct Dispatcher {
+    handlers: Handlers,
+    transport: &'static str,
+    apis: Vec<Api>,
+}
+
+impl Dispatcher {
+    /// Creates a dispatcher that only serves methods belonging to one of the given APIs. The
+    /// transport name is used to label the request metrics
+    pub fn new(
+        handlers: Handlers,
+        transport: &'static str,
+        apis: impl IntoIterator<Item = Api>,
+    ) -> Dispatcher {
+        Dispatcher {
+            handlers,
+            transport,
+            apis: apis.into_iter().collect(),
+        }
+    }
+
+    /// Returns the method with the given name if it is served by this dispatcher
+    pub fn method(&self, name: &str) -> Option<Method> {
+        Method::from_name(name)
+            .filter(|method| method.apis().iter().any(|api| self.apis.contains(api)))
+    }
+
+    /// Handles a request for the given method with the given JSON body. Errors are always turned
+    /// into a failed response so there is always something to send back to the caller
+    #[instrument(level = "debug", skip(self, body))]
+    pub async fn dispatch(&self, method: &str, body: &[u8]) -> GenericResponse<Value> {
+        let Some(method) = self.method(method) else {
+            trace!(%method, "unknown method requested");
+            return failure_message(format!("invalid api method {method}"));
+        };
+        let resp = match metrics::time_request(self.transport, self.handle(method, body)).await {
+            Ok(resp) | Err(resp) => resp,
+        };
+        if method.apis().contains(&Api::Admin) {
+            metrics::record_admin_operation(method.name(), resp.success);
+        }
+        resp
+    }
+
+    /// Does the actual work of dispatching. Both variants are responses, the error variant just
+    /// lets us use `?` when parsing the body
+    async fn handle(
+        &self,
+        method: Method,
+        body: &[u8],
+    ) -> Result<GenericResponse<Value>, GenericResponse<Value>> {
+        let handlers = &self.handlers;
+        Ok(match method {
+            Method::Verify => {
+                let req: VerificationRequest = parse(body)?;
+                verification_response(handlers.verify(&req.username, req.password).await)
+            }
+            Method::ChangePassword => {
+                let req: PasswordChangeRequest = parse(body)?;
+                respond(
+                    handlers
+                        .change_password(&req.username, req.old_password, req.new_password)
+                        .await,
+                    "password changed",
+                    "password change failed",
+                )
+            }
+            Method::LookupUser => {
+                let req: UserLookupRequest = parse(body)?;
+                respond(handlers.lookup_user(req).await, "", "User not found")
+            }
+            Method::LookupGroup => {
+                let req: GroupLookupRequest = parse(body)?;
+                respond(handlers.lookup_group(req).await, "", "Group not found")
+            }
+            Method::EnumerateUsers => {
+                respond(handlers.list_passwd().await, "", "Unable to list users")
+            }
+            Method::EnumerateGroups => respond(
+                handlers.list_group_entries().await,
+                "",
+                "Unable to list groups",
+            ),
+            Method::UserGroupIds => {
+                let req: UserGroupsRequest = parse(body)?;
+                respond(
+                    handlers.user_group_ids(&req.username).await,
+                    "",
+                    "Unable to get user groups",
+                )
+            }
+            Method::AddUser => {
+                let req: UserAddRequest = parse(body)?;
+                let message = format!("User {} added", req.username);
+                respond(handlers.add(req).await, message, "Unable to add user")
+            }
+            Method::GetUser => {
+                let req: UserGetRequest = parse(body)?;
+                respond(handlers.get(&req.username).await, "", "Unable to get user")
+            }
+            Method::ListUsers => respond(handlers.list().await, "", "Unable to list users"),
+            Method::RemoveUser => {
+                let req: UserDeleteRequest = parse(body)?;
+                respond(
+                    handlers.delete(&req.username).await,
+                    format!("User {} deleted", req.username),
+                    "Unable to remove user",
+                )
+            }
+            Method::ResetPassword => {
+                let req: PasswordResetRequest = parse(body)?;
+                respond(
+                    handlers.reset_password(&req.username).await,
+                    format!("Password reset for user {}", req.username),
+                    "Unable to reset password for user",
+                )
+            }
+            Method::SetPassword => {
+                let req: SetPasswordRequest = parse(body)?;
+                respond(
+                    handlers
+                        .set_password(&req.username, req.password, req.force_password_change)
+                        .await,
+                    format!("Password set for user {}", req.username),
+                    "Unable to set password for user",
+                )
+            }
+            Method::UnlockUser => {
+                let req: UserUnlockRequest = parse(body)?;
+                respond(
+                    handlers.unlock(&req.username).await,
+                    format!("User {} unlocked", req.username),
+                    "Unable to unlock user",
+                )
+            }
+            Method::SetAttributes => {
+                let req: SetAttributesRequest = parse(body)?;
+                respond(
+                    handlers
+                        .set_posix_attributes(&req.username, req.attributes)
+                        .await,
+                    format!("Attributes set for user {}", req.username),
+                    "Unable to set attributes for user",
+                )
+            }
+            Method::AddGroups => {
+                let req: GroupModifyRequest = parse(body)?;
+                respond(
+                    handlers.add_groups(&req.username, req.groups).await,
+                    format!("Updated groups for user {}", req.username),
+                    "Unable to add groups for user",
+                )
+            }
+            Method::RemoveGroups => {
+                let req: GroupModifyRequest = parse(body)?;
+                respond(
+                    handlers.delete_groups(&req.username, req.groups).await,
+                    format!("Deleted groups from user {}", req.username),
+                    "Unable to delete groups for user",
+                )
+            }
+            Method::AddGroup => {
+                let req: GroupAddRequest = parse(body)?;
+                let message = format!("Group {} added", req.name);
+                respond(
+                    handlers.add_group(req).await,
+                    message,
+                    "Unable to add group",
+                )
+            }
+            Method::GetGroup => {
+                let req: GroupGetRequest = parse(body)?;
+                respond(
+                    handlers.get_group(&req.name).await,
+                    "",
+                    "Unable to get group",
+                )
+            }
+            Method::ListGroups => {
+                respond(handlers.list_groups().await, "", "Unable to list groups")
+            }
+            Method::RemoveGroup => {
+                let req: GroupDeleteRequest = parse(body)?;
+                respond(
+                    handlers.delete_group(&req.name).await,
+                    format!("Group {} deleted", req.name),
+                    "Unable to remove group",
+                )
+            }
+            Method::GroupMembers => {
+                let req: GroupMembersRequest = parse(body)?;
+                respond(
+                    handlers.group_members(&req.name).await,
+                    "",
+                    "Unable to get group members",
+                )
+            }
+            Method::Export => respond(
+                handlers.export_directory().await,
+                "",
+                "Unable to export directory",
+            ),
+            Method::Import => {
+                let req: ImportRequest = parse(body)?;
+                let message = if req.dry_run {
+                    "Import checked"
+                } else {
+                    "Import finished"
+                };
+                respond(
+                    handlers.import_directory(req).await,
+                    message,
+                    "Unable to import directory",
+                )
+            }
+            Method::Health => success("", handlers.health()),
+        })
+    }
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, GenericResponse<Value>> {
+    serde_json::from_slice(body).map_err(|err| {
+        failure_message(format!(
+            "invalid request, unable to deserialize body: {err}"
+        ))
+    })
+}
+
+/// Turns the result of a handler into a response. Any policy violations are returned as the
+/// response data of the failure so callers can show exactly which rules were broken, and failures
+/// clients may want to handle differently are tagged with their [`ErrorKind`]
+fn respond<T: Serialize>(
+    result: crate::error::Result<T>,
+    message: impl Into<String>,
+    error_context: &str,
+) -> GenericResponse<Value> {
+    match result {
+        Ok(response) => success(message, response),
+        Err(HandleError::PasswordPolicy(violations)) => GenericResponse {
+            success: false,
+            message: format!(
+                "{error_context}: {}",
+                HandleError::PasswordPolicy(violations.clone())
+            ),
+            response: serde_json::to_value(violations).ok(),
+            error: Some(ErrorKind::PasswordPolicy),
+        },
+        Err(err) => GenericResponse {
+            error: err.kind(),
+            ..failure_message(format!("{error_context}: {err}"))
+        },
+    }
+}
+
+/// Verification failures caused by the credentials themselves are still successful requests, so
+/// they are returned as an invalid [`VerificationResponse`] rather than as an error
+fn verification_response(
+    result: crate::error::Result<VerificationResponse>,
+) -> GenericResponse<Value> {
+    let failed = |err: HandleError| {
+        let needs_password_reset = matches!(err, HandleError::PasswordResetExpired);
+        success(
+            "Verification failed",
+            VerificationResponse {
+                valid: false,
+                message: err.to_string(),
+                needs_password_reset,
+                groups: Default::default(),
+                posix: None,
+            },
+        )
+    };
+    match result {
+        Ok(resp) => success("Verification succeeded", resp),
+        Err(
+            err @ (HandleError::InvalidCredentials
+            | HandleError::AccountLocked
+            | HandleError::PasswordResetExpired),
+        ) => failed(err),
+        Err(err) => failure_message(format!("verification failed: {err}")),
+    }
+}
+
+fn success<T: Serialize>(message: impl Into<String>, response: T) -> GenericResponse<Value> {
+    match serde_json::to_value(response) {
+        Ok(value) => GenericResponse {
+            success: true,
+            message: message.into(),
+            // Methods that don't return any data serialize to null, which we leave out entirely
+            response: (!value.is_null()).then_some(value),
+            error: None,
+        },
+        Err(err) => failure_message(format!("unable to serialize response: {err}")),
+    }
+}
+
+fn failure_message(message: String) -> GenericResponse<Value> {
+    GenericResponse {
+        success: false,
+        message,
+        response: None,
+        error: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Context;
+
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    #[test]
+    fn test_method_apis() {
+        let dispatcher = Dispatcher::new(Handlers::new(MemoryStore::new()), "test", [Api::User]);
+        assert_eq!(dispatcher.method("verify"), Some(Method::Verify));
+        assert_eq!(dispatcher.method("lookup_user"), None);
+        assert_eq!(dispatcher.method("add_user"), None);
+        assert_eq!(dispatcher.method("nope"), None);
+
+        let dispatcher = Dispatcher::new(
+            Handlers::new(MemoryStore::new()),
+            "test",
+            [Api::User, Api::Lookup],
+        );
+        assert_eq!(dispatcher.method("get_user"), Some(Method::GetUser));
+        assert_eq!(dispatcher.method("add_user"), None);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        let dispatcher = Dispatcher::new(
+            Handlers::new(MemoryStore::new()),
+            "test",
+            [Api::User, Api::Admin],
+        );
+
+        let resp = dispatcher
+            .dispatch(
+                "add_user",
+                br#"{"username":"foo","password":"bar","groups":[],"force_password_change":false}"#,
+            )
+            .await;
+        assert!(resp.success, "Should add user: {}", resp.message);
+        assert!(
+            resp.response.is_none(),
+            "Empty responses should be left out"
+        );
+
+        let resp = dispatcher
+            .dispatch("verify", br#"{"username":"foo","password":"wrong"}"#)
+            .await;
+        assert!(resp.success, "Invalid credentials are a successful request");
+        let verification: VerificationResponse =
+            serde_json::from_value(resp.response.unwrap()).unwrap();
+        assert!(!verification.valid);
+
+        let resp = dispatcher
+            .dispatch(
+                "change_password",
+                br#"{"username":"foo","old_password":"bar","new_password":"foo"}"#,
+            )
+            .await;
+        assert!(!resp.success, "Policy violations should fail");
+        assert_eq!(
+            resp.response,
+            Some(serde_json::json!([{ "rule": "matches_username" }]))
+        );
+        assert_eq!(resp.error, Some(ErrorKind::PasswordPolicy));
+
+        let resp = dispatcher
+            .dispatch(
+                "change_password",
+                br#"{"username":"foo","old_password":"wrong","new_password":"supersecure"}"#,
+            )
+            .await;
+        assert_eq!(resp.error, Some(ErrorKind::InvalidCredentials));
+        let err =
+            serde_json::from_value::<GenericResponse<()>>(serde_json::to_value(resp).unwrap())
+                .unwrap()
+                .into_result_empty()
+                .context("Should keep the kind through context")
+                .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<crate::api::ResponseError>()
+                .and_then(|e| e.kind),
+            Some(ErrorKind::InvalidCredentials)
+        );
+
+        let resp = dispatcher.dispatch("get_user", b"garbage").await;
+        assert!(!resp.success);
+        assert!(resp.message.starts_with("invalid request"));
+
+        let resp = dispatcher.dispatch("lookup_user", b"{}").await;
+        assert!(!resp.success);
+        assert_eq!(resp.message, "invalid api method lookup_user");
+
+        let dispatcher = Dispatcher::new(Handlers::new(MemoryStore::new()), "test", [Api::Health]);
+        let resp = dispatcher.dispatch("health", b"null").await;
+        assert!(resp.success, "Should check health: {}", resp.message);
+        assert_eq!(
+            resp.response,
+            Some(serde_json::json!({ "ready": true, "checks": [] }))
+        );
+    }
+}
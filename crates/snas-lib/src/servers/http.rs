@@ -0,0 +1,236 @@
+//! A minimal HTTP listener for operational endpoints. It serves:
+//!
+//! - `GET /metrics`: the [metrics](crate::metrics) in the Prometheus text format
+//! - `GET /healthz`: always `200 OK` while the server is running, for liveness checks
+//! - `GET /readyz`: the [health](Handlers::health) of the server as JSON. The status is `200 OK`
+//!   if it is ready to handle requests and `503 Service Unavailable` if it isn't
+//!
+//! Every response closes the connection, which is all a scraper or orchestrator needs.
+//!
+//! NOTE: Like the LDAP listener, this does not support TLS, so it should only be bound to localhost
+//! or a trusted network.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, trace};
+
+use crate::{handlers::Handlers, metrics};
+
+/// The most we'll read of a request before giving up on it. We only care about the request line,
+/// so this is plenty
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// How long a client has to send its request before we drop the connection
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const METRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+pub struct HttpServer {
+    handlers: Handlers,
+    listener: TcpListener,
+    shutdown: CancellationToken,
+}
+
+impl HttpServer {
+    /// Creates a new HTTP server listening on the given address. The handlers are used to check
+    /// readiness
+    pub async fn new(handlers: Handlers, addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        Ok(Self {
+            handlers,
+            listener: TcpListener::bind(addr).await?,
+            shutdown: CancellationToken::new(),
+        })
+    }
+
+    /// Sets the token that tells this server to shut down. Once it is cancelled, the server stops
+    /// accepting new connections
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Returns the address the server is listening on
+    pub fn local_addr(&self) -> anyhow::Result<std::net::SocketAddr> {
+        self.listener.local_addr().map_err(Into::into)
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        loop {
+            let (stream, peer) = tokio::select! {
+                _ = self.shutdown.cancelled() => {
+                    info!("Shutting down HTTP server");
+                    return Ok(());
+                }
+                res = self.listener.accept() => res?,
+            };
+            let handlers = self.handlers.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, &handlers).await {
+                    debug!(%peer, "Error handling HTTP connection: {}", e);
+                }
+            });
+        }
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: impl Into<String>) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+        .into_bytes()
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, handlers: &Handlers) -> anyhow::Result<()> {
+    let head = tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream))
+        .await
+        .context("Timed out reading request")??;
+    let response = route(handlers, &head);
+    stream.write_all(&response.encode()).await?;
+    stream.shutdown().await.map_err(Into::into)
+}
+
+/// Reads the request line and headers. Any body is ignored as none of the endpoints take one
+async fn read_request_head(stream: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let mut read_buf = [0u8; 1024];
+    while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_SIZE {
+            anyhow::bail!("Request is too large");
+        }
+        let read = stream.read(&mut read_buf).await?;
+        if read == 0 {
+            anyhow::bail!("Client disconnected before sending a full request");
+        }
+        buf.extend_from_slice(&read_buf[..read]);
+    }
+    Ok(buf)
+}
+
+fn route(handlers: &Handlers, head: &[u8]) -> Response {
+    let request_line = head
+        .split(|b| *b == b'\n')
+        .next()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Response::text("400 Bad Request", "Malformed request line\n");
+    };
+    // Scrapers sometimes add query parameters, which none of the endpoints use
+    let path = target.split('?').next().unwrap_or_default();
+    trace!(%method, %path, "Received HTTP request");
+    match (method, path) {
+        ("GET", "/metrics") => match metrics::encode() {
+            Ok(body) => Response {
+                status: "200 OK",
+                content_type: METRICS_CONTENT_TYPE,
+                body,
+            },
+            Err(e) => Response::text(
+                "500 Internal Server Error",
+                format!("Unable to encode metrics: {e}\n"),
+            ),
+        },
+        ("GET", "/healthz") => Response::text("200 OK", "ok\n"),
+        ("GET", "/readyz") => {
+            let health = handlers.health();
+            let status = if health.ready {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            match serde_json::to_string(&health) {
+                Ok(body) => Response {
+                    status,
+                    content_type: "application/json",
+                    body,
+                },
+                Err(e) => Response::text(
+                    "500 Internal Server Error",
+                    format!("Unable to encode health: {e}\n"),
+                ),
+            }
+        }
+        (_, "/metrics" | "/healthz" | "/readyz") => {
+            Response::text("405 Method Not Allowed", "Method not allowed\n")
+        }
+        _ => Response::text("404 Not Found", "Not found\n"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_endpoints() {
+        let shutdown = CancellationToken::new();
+        let server = HttpServer::new(Handlers::new(MemoryStore::new()), "127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_shutdown(shutdown.clone());
+        let addr = server.local_addr().unwrap();
+        let handle = tokio::spawn(server.run());
+
+        let response = get(addr, "/metrics?format=text").await;
+        assert!(
+            response.starts_with("HTTP/1.1 200 OK\r\n"),
+            "Should return metrics: {response}"
+        );
+        assert!(response.contains(METRICS_CONTENT_TYPE));
+        assert!(response.ends_with("# EOF\n"));
+
+        let response = get(addr, "/healthz").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let response = get(addr, "/readyz").await;
+        assert!(
+            response.starts_with("HTTP/1.1 200 OK\r\n"),
+            "A store without dependencies should always be ready: {response}"
+        );
+        assert!(response.ends_with(r#"{"ready":true,"checks":[]}"#));
+
+        let response = get(addr, "/nope").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        shutdown.cancel();
+        handle
+            .await
+            .unwrap()
+            .expect("Server should shut down cleanly");
+    }
+}
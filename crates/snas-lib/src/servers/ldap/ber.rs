@@ -0,0 +1,165 @@
+//! A minimal BER encoder and decoder covering the subset of ASN.1 used by LDAPv3. All LDAP tags fit
+//! in a single identifier octet, so we don't bother supporting the high tag number form.
+
+use anyhow::Context;
+
+pub(super) const BOOLEAN: u8 = 0x01;
+pub(super) const INTEGER: u8 = 0x02;
+pub(super) const OCTET_STRING: u8 = 0x04;
+pub(super) const ENUMERATED: u8 = 0x0a;
+pub(super) const SEQUENCE: u8 = 0x30;
+pub(super) const SET: u8 = 0x31;
+
+/// The largest element we are willing to read. LDAP requests from clients are tiny, so anything
+/// bigger than this is almost certainly garbage or a misbehaving client
+pub(super) const MAX_LENGTH: usize = 64 * 1024;
+
+/// A single BER encoded element (tag, length and value)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Tlv {
+    pub tag: u8,
+    pub value: Vec<u8>,
+}
+
+impl Tlv {
+    pub fn new(tag: u8, value: impl Into<Vec<u8>>) -> Self {
+        Self {
+            tag,
+            value: value.into(),
+        }
+    }
+
+    /// Creates an integer (or enumerated if given that tag) element
+    pub fn integer(tag: u8, value: i64) -> Self {
+        let bytes = value.to_be_bytes();
+        // Strip leading bytes that are only there for sign extension
+        let mut start = 0;
+        while start < bytes.len() - 1
+            && ((bytes[start] == 0x00 && bytes[start + 1] & 0x80 == 0)
+                || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0))
+        {
+            start += 1;
+        }
+        Self::new(tag, &bytes[start..])
+    }
+
+    #[cfg(test)]
+    pub fn boolean(value: bool) -> Self {
+        Self::new(BOOLEAN, [if value { 0xff } else { 0x00 }])
+    }
+
+    pub fn string(tag: u8, value: impl AsRef<[u8]>) -> Self {
+        Self::new(tag, value.as_ref())
+    }
+
+    /// Creates a constructed element containing all of the given elements
+    pub fn constructed(tag: u8, children: impl IntoIterator<Item = Tlv>) -> Self {
+        let mut value = Vec::new();
+        for child in children {
+            child.encode_into(&mut value);
+        }
+        Self::new(tag, value)
+    }
+
+    /// Parses a single element from the start of the buffer. Returns the element and the number of
+    /// bytes consumed, or `None` if the buffer doesn't contain a complete element yet
+    pub fn parse(buf: &[u8]) -> anyhow::Result<Option<(Tlv, usize)>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let tag = buf[0];
+        if tag & 0x1f == 0x1f {
+            anyhow::bail!("High tag numbers are not supported");
+        }
+        let (length, header_len) = match buf[1] {
+            len if len & 0x80 == 0 => (len as usize, 2),
+            0x80 => anyhow::bail!("Indefinite lengths are not supported"),
+            len => {
+                let num_bytes = (len & 0x7f) as usize;
+                if num_bytes > 4 {
+                    anyhow::bail!("Element length is too large");
+                }
+                if buf.len() < 2 + num_bytes {
+                    return Ok(None);
+                }
+                let length = buf[2..2 + num_bytes]
+                    .iter()
+                    .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+                (length, 2 + num_bytes)
+            }
+        };
+        if length > MAX_LENGTH {
+            anyhow::bail!("Element length of {length} exceeds maximum");
+        }
+        if buf.len() < header_len + length {
+            return Ok(None);
+        }
+        Ok(Some((
+            Tlv::new(tag, &buf[header_len..header_len + length]),
+            header_len + length,
+        )))
+    }
+
+    /// Parses all of the elements contained in this element
+    pub fn children(&self) -> anyhow::Result<Vec<Tlv>> {
+        let mut children = Vec::new();
+        let mut remaining = self.value.as_slice();
+        while !remaining.is_empty() {
+            let (child, consumed) =
+                Tlv::parse(remaining)?.context("Constructed element contains truncated data")?;
+            children.push(child);
+            remaining = &remaining[consumed..];
+        }
+        Ok(children)
+    }
+
+    pub fn as_integer(&self) -> anyhow::Result<i64> {
+        if self.value.is_empty() || self.value.len() > 8 {
+            anyhow::bail!("Invalid integer length {}", self.value.len());
+        }
+        let initial: i64 = if self.value[0] & 0x80 != 0 { -1 } else { 0 };
+        Ok(self
+            .value
+            .iter()
+            .fold(initial, |acc, b| (acc << 8) | *b as i64))
+    }
+
+    pub fn as_bool(&self) -> anyhow::Result<bool> {
+        match self.value.as_slice() {
+            [b] => Ok(*b != 0),
+            _ => anyhow::bail!("Invalid boolean"),
+        }
+    }
+
+    pub fn as_string(&self) -> anyhow::Result<String> {
+        String::from_utf8(self.value.clone()).context("Invalid UTF-8 string")
+    }
+
+    /// Returns an error if this element doesn't have the given tag
+    pub fn expect_tag(self, tag: u8) -> anyhow::Result<Self> {
+        if self.tag != tag {
+            anyhow::bail!("Expected tag {tag:#x}, got {:#x}", self.tag);
+        }
+        Ok(self)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.value.len() + 6);
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.push(self.tag);
+        let len = self.value.len();
+        if len < 0x80 {
+            buf.push(len as u8);
+        } else {
+            let bytes = (len as u32).to_be_bytes();
+            let skip = bytes.iter().take_while(|b| **b == 0).count();
+            buf.push(0x80 | (bytes.len() - skip) as u8);
+            buf.extend_from_slice(&bytes[skip..]);
+        }
+        buf.extend_from_slice(&self.value);
+    }
+}
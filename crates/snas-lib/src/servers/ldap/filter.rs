@@ -0,0 +1,194 @@
+use std::cmp::Ordering;
+
+use super::ber::{Tlv, OCTET_STRING, SEQUENCE};
+use super::Entry;
+
+const AND: u8 = 0xa0;
+const OR: u8 = 0xa1;
+const NOT: u8 = 0xa2;
+const EQUALITY_MATCH: u8 = 0xa3;
+const SUBSTRINGS: u8 = 0xa4;
+const GREATER_OR_EQUAL: u8 = 0xa5;
+const LESS_OR_EQUAL: u8 = 0xa6;
+const PRESENT: u8 = 0x87;
+const APPROX_MATCH: u8 = 0xa8;
+const EXTENSIBLE_MATCH: u8 = 0xa9;
+const SUBSTRING_INITIAL: u8 = 0x80;
+const SUBSTRING_ANY: u8 = 0x81;
+const SUBSTRING_FINAL: u8 = 0x82;
+
+/// How deeply and, or and not filters can be nested. Real clients only nest a few levels, and
+/// parsing (and matching) recurses once per level, so this keeps a client from overflowing the stack
+const MAX_DEPTH: usize = 32;
+
+/// A search filter. All value comparisons are case insensitive as every attribute we serve uses
+/// case insensitive matching rules (or is numeric)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Equality(String, String),
+    Substrings {
+        attribute: String,
+        initial: Option<String>,
+        any: Vec<String>,
+        last: Option<String>,
+    },
+    GreaterOrEqual(String, String),
+    LessOrEqual(String, String),
+    Present(String),
+    /// A filter we don't support (i.e. extensible matches), which never matches
+    Undefined,
+}
+
+impl Filter {
+    pub fn parse(tlv: Tlv) -> anyhow::Result<Self> {
+        Filter::parse_nested(tlv, 0)
+    }
+
+    fn parse_nested(tlv: Tlv, depth: usize) -> anyhow::Result<Self> {
+        if depth > MAX_DEPTH {
+            anyhow::bail!("Filter is nested more than {MAX_DEPTH} levels deep");
+        }
+        let parse_child = |child| Filter::parse_nested(child, depth + 1);
+        Ok(match tlv.tag {
+            AND => Filter::And(
+                tlv.children()?
+                    .into_iter()
+                    .map(parse_child)
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            OR => Filter::Or(
+                tlv.children()?
+                    .into_iter()
+                    .map(parse_child)
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            NOT => {
+                let inner = tlv
+                    .children()?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("Empty not filter"))?;
+                Filter::Not(Box::new(parse_child(inner)?))
+            }
+            // We treat approximate matches as equality matches
+            EQUALITY_MATCH | APPROX_MATCH => {
+                let (attr, value) = parse_assertion(tlv)?;
+                Filter::Equality(attr, value)
+            }
+            GREATER_OR_EQUAL => {
+                let (attr, value) = parse_assertion(tlv)?;
+                Filter::GreaterOrEqual(attr, value)
+            }
+            LESS_OR_EQUAL => {
+                let (attr, value) = parse_assertion(tlv)?;
+                Filter::LessOrEqual(attr, value)
+            }
+            PRESENT => Filter::Present(tlv.as_string()?),
+            SUBSTRINGS => {
+                let mut children = tlv.children()?.into_iter();
+                let attribute = children
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("Missing substring attribute"))?
+                    .expect_tag(OCTET_STRING)?
+                    .as_string()?;
+                let substrings = children
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("Missing substrings"))?
+                    .expect_tag(SEQUENCE)?;
+                let mut initial = None;
+                let mut any = Vec::new();
+                let mut last = None;
+                for part in substrings.children()? {
+                    let value = part.as_string()?.to_lowercase();
+                    match part.tag {
+                        SUBSTRING_INITIAL => initial = Some(value),
+                        SUBSTRING_ANY => any.push(value),
+                        SUBSTRING_FINAL => last = Some(value),
+                        tag => anyhow::bail!("Invalid substring tag {tag:#x}"),
+                    }
+                }
+                Filter::Substrings {
+                    attribute,
+                    initial,
+                    any,
+                    last,
+                }
+            }
+            EXTENSIBLE_MATCH => Filter::Undefined,
+            tag => anyhow::bail!("Invalid filter tag {tag:#x}"),
+        })
+    }
+
+    pub fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            Filter::And(filters) => filters.iter().all(|f| f.matches(entry)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(entry)),
+            Filter::Not(filter) => !filter.matches(entry),
+            Filter::Equality(attr, value) => {
+                values(entry, attr).any(|v| v.eq_ignore_ascii_case(value))
+            }
+            Filter::GreaterOrEqual(attr, value) => {
+                values(entry, attr).any(|v| compare(v, value) != Ordering::Less)
+            }
+            Filter::LessOrEqual(attr, value) => {
+                values(entry, attr).any(|v| compare(v, value) != Ordering::Greater)
+            }
+            Filter::Present(attr) => entry.get(attr).is_some_and(|v| !v.is_empty()),
+            Filter::Substrings {
+                attribute,
+                initial,
+                any,
+                last,
+            } => values(entry, attribute).any(|v| {
+                let v = v.to_lowercase();
+                let mut remaining = v.as_str();
+                if let Some(initial) = initial {
+                    match remaining.strip_prefix(initial.as_str()) {
+                        Some(rest) => remaining = rest,
+                        None => return false,
+                    }
+                }
+                for part in any {
+                    match remaining.find(part.as_str()) {
+                        Some(idx) => remaining = &remaining[idx + part.len()..],
+                        None => return false,
+                    }
+                }
+                last.as_ref()
+                    .map(|last| remaining.ends_with(last.as_str()))
+                    .unwrap_or(true)
+            }),
+            Filter::Undefined => false,
+        }
+    }
+}
+
+fn parse_assertion(tlv: Tlv) -> anyhow::Result<(String, String)> {
+    let mut children = tlv.children()?.into_iter();
+    let attr = children
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Missing attribute in assertion"))?
+        .expect_tag(OCTET_STRING)?
+        .as_string()?;
+    let value = children
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Missing value in assertion"))?
+        .expect_tag(OCTET_STRING)?
+        .as_string()?;
+    Ok((attr, value))
+}
+
+fn values<'a>(entry: &'a Entry, attr: &str) -> impl Iterator<Item = &'a String> {
+    entry.get(attr).unwrap_or_default().iter()
+}
+
+/// Compares values numerically if both are numbers, otherwise compares them case insensitively
+fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<i64>(), b.parse::<i64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.to_lowercase().cmp(&b.to_lowercase()),
+    }
+}
@@ -0,0 +1,1125 @@
+//! A read-only LDAPv3 front-end for the user directory. This is meant for applications that only
+//! know how to talk LDAP. It supports simple binds (backed by
+//! [`Handlers::verify_non_interactive`]) and searches over users and groups. Everything else is
+//! rejected.
+//!
+//! Entries are laid out under the configured base DN as follows:
+//!
+//! ```text
+//! dc=example,dc=com
+//! ├── ou=users
+//! │   └── uid=<username>
+//! └── ou=groups
+//!     └── cn=<group>
+//! ```
+//!
+//! NOTE: This listener does not support TLS (or StartTLS), so it should only be bound to localhost or
+//! a trusted network, or placed behind a TLS terminating proxy.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, instrument, trace, warn};
+
+use crate::{error::HandleError, handlers::Handlers, metrics, SecureString};
+
+mod ber;
+mod filter;
+
+use ber::{Tlv, ENUMERATED, INTEGER, OCTET_STRING, SEQUENCE, SET};
+use filter::Filter;
+
+// Protocol op tags
+const BIND_REQUEST: u8 = 0x60;
+const BIND_RESPONSE: u8 = 0x61;
+const UNBIND_REQUEST: u8 = 0x42;
+const SEARCH_REQUEST: u8 = 0x63;
+const SEARCH_RESULT_ENTRY: u8 = 0x64;
+const SEARCH_RESULT_DONE: u8 = 0x65;
+const MODIFY_REQUEST: u8 = 0x66;
+const ADD_REQUEST: u8 = 0x68;
+const DEL_REQUEST: u8 = 0x4a;
+const DEL_RESPONSE: u8 = 0x6b;
+const MODIFY_DN_REQUEST: u8 = 0x6c;
+const COMPARE_REQUEST: u8 = 0x6e;
+const ABANDON_REQUEST: u8 = 0x50;
+const EXTENDED_REQUEST: u8 = 0x77;
+const EXTENDED_RESPONSE: u8 = 0x78;
+// Context specific tags
+const SIMPLE_AUTH: u8 = 0x80;
+const EXTENDED_REQUEST_NAME: u8 = 0x80;
+const EXTENDED_RESPONSE_VALUE: u8 = 0x8b;
+
+const WHO_AM_I_OID: &str = "1.3.6.1.4.1.4203.1.11.3";
+
+/// The default maximum number of LDAP connections that are open at once
+pub const DEFAULT_MAX_CONNECTIONS: usize = 256;
+/// How long a connection can go without sending anything before we drop it. Clients that keep
+/// connections open reconnect on their own
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// LDAP result codes that we use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResultCode {
+    Success = 0,
+    OperationsError = 1,
+    ProtocolError = 2,
+    SizeLimitExceeded = 4,
+    AuthMethodNotSupported = 7,
+    NoSuchObject = 32,
+    InvalidCredentials = 49,
+    InsufficientAccessRights = 50,
+    UnwillingToPerform = 53,
+}
+
+/// The scope of a search request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Base,
+    OneLevel,
+    Subtree,
+}
+
+/// A single directory entry
+#[derive(Debug, Clone)]
+struct Entry {
+    dn: String,
+    attributes: Vec<(&'static str, Vec<String>)>,
+}
+
+impl Entry {
+    /// Returns the values of the given attribute. Attribute names are case insensitive
+    fn get(&self, name: &str) -> Option<&[String]> {
+        self.attributes
+            .iter()
+            .find(|(attr, _)| attr.eq_ignore_ascii_case(name))
+            .map(|(_, values)| values.as_slice())
+    }
+}
+
+pub struct LdapServer {
+    handlers: Handlers,
+    listener: TcpListener,
+    base_dn: String,
+    allow_anonymous: bool,
+    max_connections: usize,
+    shutdown: CancellationToken,
+}
+
+impl LdapServer {
+    /// Creates a new LDAP server listening on the given address. All entries will be served under
+    /// the given base DN (e.g. `dc=example,dc=com`). If `allow_anonymous` is false, clients must
+    /// bind as a valid user before they can search.
+    pub async fn new(
+        handlers: Handlers,
+        addr: impl ToSocketAddrs,
+        base_dn: String,
+        allow_anonymous: bool,
+    ) -> anyhow::Result<Self> {
+        let base_dn = base_dn.trim().to_string();
+        if base_dn.is_empty() {
+            anyhow::bail!("LDAP base DN must not be empty");
+        }
+        Ok(Self {
+            handlers,
+            listener: TcpListener::bind(addr).await?,
+            base_dn,
+            allow_anonymous,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            shutdown: CancellationToken::new(),
+        })
+    }
+
+    /// Sets the maximum number of connections this server will have open at once. Once it is
+    /// reached, new connections wait until an open one closes. Defaults to
+    /// [`DEFAULT_MAX_CONNECTIONS`]
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max.max(1);
+        self
+    }
+
+    /// Sets the token that tells this server to shut down. Once it is cancelled, the server stops
+    /// accepting new connections. LDAP clients reconnect on their own, so open connections are
+    /// dropped when the process exits rather than waited on
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Returns the address the server is listening on
+    pub fn local_addr(&self) -> anyhow::Result<std::net::SocketAddr> {
+        self.listener.local_addr().map_err(Into::into)
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        let connections = Arc::new(Semaphore::new(self.max_connections));
+        loop {
+            // Wait for a free slot before accepting, so extra connections queue up in the listen
+            // backlog instead of being accepted and left waiting
+            let (permit, (stream, peer)) = tokio::select! {
+                _ = self.shutdown.cancelled() => {
+                    info!("Shutting down LDAP server");
+                    return Ok(());
+                }
+                res = async {
+                    let permit = connections.clone().acquire_owned().await?;
+                    anyhow::Ok((permit, self.listener.accept().await?))
+                } => res?,
+            };
+            let conn = LdapConnection {
+                handlers: self.handlers.clone(),
+                base_dn: self.base_dn.clone(),
+                allow_anonymous: self.allow_anonymous,
+                bound_dn: None,
+            };
+            tokio::spawn(async move {
+                if let Err(e) = conn.handle(stream).await {
+                    error!(%peer, "Error handling LDAP connection: {}", e);
+                }
+                drop(permit);
+            });
+        }
+    }
+}
+
+struct LdapConnection {
+    handlers: Handlers,
+    base_dn: String,
+    allow_anonymous: bool,
+    /// The DN of the currently bound user, if any
+    bound_dn: Option<String>,
+}
+
+impl LdapConnection {
+    #[instrument(level = "trace", skip_all)]
+    async fn handle(mut self, mut stream: TcpStream) -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        let mut read_buf = [0u8; 4096];
+        loop {
+            // Handle all of the complete messages we have before reading more data
+            while let Some((message, consumed)) = Tlv::parse(&buf)? {
+                buf.drain(..consumed);
+                let (message_id, op) = match parse_message(message) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        // We can't reliably respond to a message we can't parse, so we just drop
+                        // the connection like most LDAP servers do
+                        warn!(err = %e, "Received malformed LDAP message, closing connection");
+                        return Ok(());
+                    }
+                };
+                trace!(message_id, tag = op.tag, "Received LDAP request");
+                if op.tag == UNBIND_REQUEST {
+                    trace!("Client unbound, closing connection");
+                    return Ok(());
+                }
+                for response in metrics::time_request("ldap", self.handle_op(op)).await {
+                    stream
+                        .write_all(&encode_message(message_id, response))
+                        .await?;
+                }
+            }
+            let read = match tokio::time::timeout(IDLE_TIMEOUT, stream.read(&mut read_buf)).await {
+                Ok(read) => read?,
+                Err(_) => {
+                    debug!("Closing idle LDAP connection");
+                    return Ok(());
+                }
+            };
+            if read == 0 {
+                trace!("Client disconnected");
+                return Ok(());
+            }
+            buf.extend_from_slice(&read_buf[..read]);
+        }
+    }
+
+    /// Handles the given protocol op and returns all of the responses that should be sent back
+    async fn handle_op(&mut self, op: Tlv) -> Vec<Tlv> {
+        match op.tag {
+            BIND_REQUEST => vec![self.handle_bind(op).await],
+            SEARCH_REQUEST => self.handle_search(op).await,
+            EXTENDED_REQUEST => vec![self.handle_extended(op)],
+            // Abandon requests don't get a response, and we handle everything sequentially anyway
+            ABANDON_REQUEST => Vec::new(),
+            DEL_REQUEST => vec![ldap_result(
+                DEL_RESPONSE,
+                ResultCode::UnwillingToPerform,
+                "This directory is read only",
+            )],
+            MODIFY_REQUEST | ADD_REQUEST | MODIFY_DN_REQUEST | COMPARE_REQUEST => {
+                // Each of these responses uses the tag directly after the request tag
+                vec![ldap_result(
+                    op.tag + 1,
+                    ResultCode::UnwillingToPerform,
+                    "This directory is read only",
+                )]
+            }
+            tag => {
+                debug!(tag, "Received unsupported LDAP operation");
+                vec![ldap_result(
+                    EXTENDED_RESPONSE,
+                    ResultCode::ProtocolError,
+                    "Unsupported operation",
+                )]
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn handle_bind(&mut self, op: Tlv) -> Tlv {
+        // Any bind attempt resets the current authentication state
+        self.bound_dn = None;
+        let (name, auth) = match op.children().and_then(|children| {
+            let mut children = children.into_iter();
+            let version = children
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Missing version"))?
+                .expect_tag(INTEGER)?
+                .as_integer()?;
+            if version != 3 {
+                anyhow::bail!("Unsupported LDAP version {version}");
+            }
+            let name = children
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Missing bind name"))?
+                .expect_tag(OCTET_STRING)?
+                .as_string()?;
+            let auth = children
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Missing authentication"))?;
+            Ok((name, auth))
+        }) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                return ldap_result(BIND_RESPONSE, ResultCode::ProtocolError, e.to_string());
+            }
+        };
+        if auth.tag != SIMPLE_AUTH {
+            return ldap_result(
+                BIND_RESPONSE,
+                ResultCode::AuthMethodNotSupported,
+                "Only simple authentication is supported",
+            );
+        }
+        let password = match auth.as_string() {
+            Ok(p) => SecureString::from(p),
+            Err(_) => {
+                return ldap_result(
+                    BIND_RESPONSE,
+                    ResultCode::InvalidCredentials,
+                    "Invalid credentials",
+                )
+            }
+        };
+        let password_str: &str = password.as_ref();
+        if name.is_empty() && password_str.is_empty() {
+            trace!("Anonymous bind");
+            return ldap_result(BIND_RESPONSE, ResultCode::Success, "");
+        }
+
+        let username = match self.username_from_dn(&name) {
+            Some(u) => u,
+            None => {
+                return ldap_result(
+                    BIND_RESPONSE,
+                    ResultCode::InvalidCredentials,
+                    "Invalid credentials",
+                )
+            }
+        };
+        // Much like NATS clients, LDAP clients have no way to change a password, so users that
+        // need to reset their password aren't allowed to bind (and a failed bind doesn't use up
+        // their reset)
+        match self
+            .handlers
+            .verify_non_interactive(&username, password)
+            .await
+        {
+            Ok(_) => {
+                self.bound_dn = Some(name);
+                ldap_result(BIND_RESPONSE, ResultCode::Success, "")
+            }
+            Err(HandleError::SystemError(err)) => {
+                error!(%err, "Error when verifying user");
+                ldap_result(
+                    BIND_RESPONSE,
+                    ResultCode::OperationsError,
+                    "Unable to verify user",
+                )
+            }
+            Err(HandleError::PasswordChangeRequired) => ldap_result(
+                BIND_RESPONSE,
+                ResultCode::InvalidCredentials,
+                "Password change required",
+            ),
+            Err(err) => {
+                debug!(%err, %username, "Bind failed");
+                ldap_result(
+                    BIND_RESPONSE,
+                    ResultCode::InvalidCredentials,
+                    "Invalid credentials",
+                )
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn handle_search(&self, op: Tlv) -> Vec<Tlv> {
+        if self.bound_dn.is_none() && !self.allow_anonymous {
+            return vec![ldap_result(
+                SEARCH_RESULT_DONE,
+                ResultCode::InsufficientAccessRights,
+                "Anonymous searches are not allowed",
+            )];
+        }
+        let request = match SearchRequest::parse(op) {
+            Ok(r) => r,
+            Err(e) => {
+                return vec![ldap_result(
+                    SEARCH_RESULT_DONE,
+                    ResultCode::ProtocolError,
+                    e.to_string(),
+                )]
+            }
+        };
+        trace!(?request, "Parsed search request");
+
+        // The root DSE is how clients discover what this server supports
+        if request.base.is_empty() && request.scope == Scope::Base {
+            let root_dse = Entry {
+                dn: String::new(),
+                attributes: vec![
+                    ("objectClass", vec!["top".to_string()]),
+                    ("namingContexts", vec![self.base_dn.clone()]),
+                    ("supportedLDAPVersion", vec!["3".to_string()]),
+                    ("supportedExtension", vec![WHO_AM_I_OID.to_string()]),
+                ],
+            };
+            let mut responses = Vec::new();
+            if request.filter.matches(&root_dse) {
+                responses.push(encode_entry(&root_dse, &request));
+            }
+            responses.push(ldap_result(SEARCH_RESULT_DONE, ResultCode::Success, ""));
+            return responses;
+        }
+
+        let entries = match self.entries().await {
+            Ok(entries) => entries,
+            Err(err) => {
+                error!(%err, "Unable to build directory entries");
+                return vec![ldap_result(
+                    SEARCH_RESULT_DONE,
+                    ResultCode::OperationsError,
+                    "Unable to fetch directory entries",
+                )];
+            }
+        };
+        let base = normalize_dn(&request.base);
+        if !entries.iter().any(|entry| normalize_dn(&entry.dn) == base) {
+            return vec![ldap_result(
+                SEARCH_RESULT_DONE,
+                ResultCode::NoSuchObject,
+                "",
+            )];
+        }
+
+        let mut responses = Vec::new();
+        for entry in entries
+            .iter()
+            .filter(|entry| in_scope(&normalize_dn(&entry.dn), &base, request.scope))
+            .filter(|entry| request.filter.matches(entry))
+        {
+            if request.size_limit > 0 && responses.len() >= request.size_limit {
+                responses.push(ldap_result(
+                    SEARCH_RESULT_DONE,
+                    ResultCode::SizeLimitExceeded,
+                    "",
+                ));
+                return responses;
+            }
+            responses.push(encode_entry(entry, &request));
+        }
+        responses.push(ldap_result(SEARCH_RESULT_DONE, ResultCode::Success, ""));
+        responses
+    }
+
+    fn handle_extended(&self, op: Tlv) -> Tlv {
+        let name = op
+            .children()
+            .ok()
+            .and_then(|children| children.into_iter().next())
+            .filter(|child| child.tag == EXTENDED_REQUEST_NAME)
+            .and_then(|child| child.as_string().ok());
+        match name.as_deref() {
+            Some(WHO_AM_I_OID) => {
+                let authz_id = self
+                    .bound_dn
+                    .as_ref()
+                    .map(|dn| format!("dn:{dn}"))
+                    .unwrap_or_default();
+                let mut resp = ldap_result(EXTENDED_RESPONSE, ResultCode::Success, "");
+                resp.value
+                    .extend(Tlv::string(EXTENDED_RESPONSE_VALUE, authz_id).encode());
+                resp
+            }
+            _ => ldap_result(
+                EXTENDED_RESPONSE,
+                ResultCode::ProtocolError,
+                "Unsupported extended operation",
+            ),
+        }
+    }
+
+    /// Extracts the username from a user DN under this server's base DN
+    fn username_from_dn(&self, dn: &str) -> Option<String> {
+        let (rdn, parent) = dn.split_once(',')?;
+        let (attr, value) = rdn.split_once('=')?;
+        if !attr.trim().eq_ignore_ascii_case("uid") && !attr.trim().eq_ignore_ascii_case("cn") {
+            return None;
+        }
+        if normalize_dn(parent) != normalize_dn(&self.users_dn()) {
+            return None;
+        }
+        Some(value.trim().to_string())
+    }
+
+    fn users_dn(&self) -> String {
+        format!("ou=users,{}", self.base_dn)
+    }
+
+    fn groups_dn(&self) -> String {
+        format!("ou=groups,{}", self.base_dn)
+    }
+
+    fn user_dn(&self, username: &str) -> String {
+        format!("uid={username},{}", self.users_dn())
+    }
+
+    fn group_dn(&self, group: &str) -> String {
+        format!("cn={group},{}", self.groups_dn())
+    }
+
+    /// Builds every entry in the directory from the current state of the store
+    async fn entries(&self) -> anyhow::Result<Vec<Entry>> {
+        let mut entries = vec![
+            self.base_entry(),
+            Entry {
+                dn: self.users_dn(),
+                attributes: vec![
+                    (
+                        "objectClass",
+                        vec!["top".to_string(), "organizationalUnit".to_string()],
+                    ),
+                    ("ou", vec!["users".to_string()]),
+                ],
+            },
+            Entry {
+                dn: self.groups_dn(),
+                attributes: vec![
+                    (
+                        "objectClass",
+                        vec!["top".to_string(), "organizationalUnit".to_string()],
+                    ),
+                    ("ou", vec!["groups".to_string()]),
+                ],
+            },
+        ];
+
+        // Created groups are listed even if they have no members yet
+        let group_info = self.handlers.group_info().await?;
+        let mut groups: BTreeMap<String, Vec<String>> = group_info
+            .keys()
+            .map(|name| (name.clone(), Vec::new()))
+            .collect();
+        let mut usernames = self.handlers.list().await?;
+        usernames.sort();
+        for username in usernames {
+            let user = match self.handlers.get(&username).await {
+                Ok(user) => user,
+                // The user could have been deleted since we listed them
+                Err(HandleError::UsernameDoesNotExist) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for group in user.groups.iter() {
+                groups
+                    .entry(group.clone())
+                    .or_default()
+                    .push(username.clone());
+            }
+            let mut object_classes = vec![
+                "top".to_string(),
+                "person".to_string(),
+                "organizationalPerson".to_string(),
+                "inetOrgPerson".to_string(),
+            ];
+            let mut attributes = vec![
+                ("uid", vec![username.clone()]),
+                ("cn", vec![username.clone()]),
+                ("sn", vec![username.clone()]),
+            ];
+            // posixAccount requires a UID, GID and home directory, so it is only added for users
+            // that actually have them
+            if let Some(posix) = user.posix {
+                object_classes.push("posixAccount".to_string());
+                attributes.extend([
+                    ("homeDirectory", vec![posix.home]),
+                    ("uidNumber", vec![posix.uid.to_string()]),
+                    ("gidNumber", vec![posix.gid.to_string()]),
+                    ("loginShell", vec![posix.shell]),
+                    (
+                        "gecos",
+                        Some(posix.gecos)
+                            .filter(|gecos| !gecos.is_empty())
+                            .into_iter()
+                            .collect(),
+                    ),
+                ]);
+            }
+            attributes.push((
+                "memberOf",
+                user.groups.iter().map(|g| self.group_dn(g)).collect(),
+            ));
+            attributes.insert(0, ("objectClass", object_classes));
+            entries.push(Entry {
+                dn: self.user_dn(&username),
+                attributes,
+            });
+        }
+
+        entries.extend(groups.into_iter().map(|(group, members)| {
+            let info = group_info.get(&group);
+            Entry {
+                dn: self.group_dn(&group),
+                attributes: vec![
+                    (
+                        "objectClass",
+                        // Groups that only exist through their members have no GID, which
+                        // posixGroup requires
+                        ["top", "groupOfNames"]
+                            .into_iter()
+                            .chain(info.map(|_| "posixGroup"))
+                            .map(String::from)
+                            .collect(),
+                    ),
+                    ("cn", vec![group.clone()]),
+                    (
+                        "gidNumber",
+                        info.iter().map(|i| i.gid.to_string()).collect(),
+                    ),
+                    (
+                        "description",
+                        info.iter()
+                            .filter(|i| !i.description.is_empty())
+                            .map(|i| i.description.clone())
+                            .collect(),
+                    ),
+                    ("member", members.iter().map(|m| self.user_dn(m)).collect()),
+                    ("memberUid", members),
+                ],
+            }
+        }));
+        Ok(entries)
+    }
+
+    fn base_entry(&self) -> Entry {
+        let mut attributes = vec![("objectClass", vec!["top".to_string(), "domain".to_string()])];
+        if let Some(dc) = self
+            .base_dn
+            .split(',')
+            .next()
+            .and_then(|rdn| rdn.split_once('='))
+            .filter(|(attr, _)| attr.trim().eq_ignore_ascii_case("dc"))
+            .map(|(_, value)| value.trim().to_string())
+        {
+            attributes.push(("dc", vec![dc]));
+        }
+        Entry {
+            dn: self.base_dn.clone(),
+            attributes,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SearchRequest {
+    base: String,
+    scope: Scope,
+    size_limit: usize,
+    types_only: bool,
+    filter: Filter,
+    attributes: Vec<String>,
+}
+
+impl SearchRequest {
+    fn parse(op: Tlv) -> anyhow::Result<Self> {
+        let mut children = op.children()?.into_iter();
+        let mut next = || {
+            children
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Truncated search request"))
+        };
+        let base = next()?.expect_tag(OCTET_STRING)?.as_string()?;
+        let scope = match next()?.expect_tag(ENUMERATED)?.as_integer()? {
+            0 => Scope::Base,
+            1 => Scope::OneLevel,
+            2 => Scope::Subtree,
+            other => anyhow::bail!("Invalid search scope {other}"),
+        };
+        // We don't have aliases, so we can ignore deref settings
+        let _deref = next()?.expect_tag(ENUMERATED)?;
+        let size_limit = next()?.expect_tag(INTEGER)?.as_integer()?.max(0) as usize;
+        // Searches are quick, so time limits are ignored
+        let _time_limit = next()?.expect_tag(INTEGER)?;
+        let types_only = next()?.expect_tag(ber::BOOLEAN)?.as_bool()?;
+        let filter = Filter::parse(next()?)?;
+        let attributes = next()?
+            .expect_tag(SEQUENCE)?
+            .children()?
+            .into_iter()
+            .map(|attr| attr.as_string())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            base,
+            scope,
+            size_limit,
+            types_only,
+            filter,
+            attributes,
+        })
+    }
+
+    /// Returns whether the given attribute should be included in results
+    fn wants_attribute(&self, name: &str) -> bool {
+        // No attributes or `*` means all user attributes. `1.1` means no attributes
+        self.attributes.is_empty()
+            || self
+                .attributes
+                .iter()
+                .any(|attr| attr == "*" || attr.eq_ignore_ascii_case(name))
+    }
+}
+
+fn parse_message(message: Tlv) -> anyhow::Result<(i64, Tlv)> {
+    let mut children = message.expect_tag(SEQUENCE)?.children()?.into_iter();
+    let message_id = children
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Missing message ID"))?
+        .expect_tag(INTEGER)?
+        .as_integer()?;
+    let op = children
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Missing protocol op"))?;
+    // Any controls are ignored as none of them are supported
+    Ok((message_id, op))
+}
+
+fn encode_message(message_id: i64, op: Tlv) -> Vec<u8> {
+    Tlv::constructed(SEQUENCE, [Tlv::integer(INTEGER, message_id), op]).encode()
+}
+
+/// Builds an LDAPResult with the given tag
+fn ldap_result(tag: u8, code: ResultCode, message: impl AsRef<[u8]>) -> Tlv {
+    Tlv::constructed(
+        tag,
+        [
+            Tlv::integer(ENUMERATED, code as i64),
+            Tlv::string(OCTET_STRING, ""),
+            Tlv::string(OCTET_STRING, message),
+        ],
+    )
+}
+
+fn encode_entry(entry: &Entry, request: &SearchRequest) -> Tlv {
+    let attributes = entry
+        .attributes
+        .iter()
+        .filter(|(name, values)| !values.is_empty() && request.wants_attribute(name))
+        .map(|(name, values)| {
+            let values = if request.types_only {
+                Vec::new()
+            } else {
+                values
+                    .iter()
+                    .map(|v| Tlv::string(OCTET_STRING, v))
+                    .collect()
+            };
+            Tlv::constructed(
+                SEQUENCE,
+                [
+                    Tlv::string(OCTET_STRING, name),
+                    Tlv::constructed(SET, values),
+                ],
+            )
+        });
+    Tlv::constructed(
+        SEARCH_RESULT_ENTRY,
+        [
+            Tlv::string(OCTET_STRING, &entry.dn),
+            Tlv::constructed(SEQUENCE, attributes),
+        ],
+    )
+}
+
+/// Normalizes a DN for comparison. Attribute names and values are case insensitive for everything
+/// we serve, and whitespace around separators is ignored.
+fn normalize_dn(dn: &str) -> String {
+    dn.split(',')
+        .map(|rdn| match rdn.split_once('=') {
+            Some((attr, value)) => format!("{}={}", attr.trim(), value.trim()),
+            None => rdn.trim().to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+        .to_lowercase()
+}
+
+/// Checks if the (normalized) DN is within the scope of the (normalized) search base
+fn in_scope(dn: &str, base: &str, scope: Scope) -> bool {
+    match scope {
+        Scope::Base => dn == base,
+        Scope::OneLevel => dn
+            .split_once(',')
+            .map(|(_, parent)| parent == base)
+            .unwrap_or(false),
+        Scope::Subtree => dn == base || dn.ends_with(&format!(",{base}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::admin::{GroupAddRequest, UserAddRequest};
+    use crate::storage::MemoryStore;
+
+    const BASE_DN: &str = "dc=example,dc=com";
+
+    async fn request(stream: &mut TcpStream, message_id: i64, op: Tlv) -> Vec<Tlv> {
+        stream
+            .write_all(&encode_message(message_id, op))
+            .await
+            .unwrap();
+        let mut responses = Vec::new();
+        let mut buf = Vec::new();
+        loop {
+            while let Some((message, consumed)) = Tlv::parse(&buf).unwrap() {
+                buf.drain(..consumed);
+                let (id, op) = parse_message(message).unwrap();
+                assert_eq!(id, message_id, "Response should have the same message ID");
+                let done = op.tag != SEARCH_RESULT_ENTRY;
+                responses.push(op);
+                if done {
+                    return responses;
+                }
+            }
+            let mut read_buf = [0u8; 4096];
+            let n = stream.read(&mut read_buf).await.unwrap();
+            assert!(n > 0, "Server should not close the connection");
+            buf.extend_from_slice(&read_buf[..n]);
+        }
+    }
+
+    fn bind_request(dn: &str, password: &str) -> Tlv {
+        Tlv::constructed(
+            BIND_REQUEST,
+            [
+                Tlv::integer(INTEGER, 3),
+                Tlv::string(OCTET_STRING, dn),
+                Tlv::string(SIMPLE_AUTH, password),
+            ],
+        )
+    }
+
+    fn search_request(base: &str, scope: i64, filter: Tlv) -> Tlv {
+        Tlv::constructed(
+            SEARCH_REQUEST,
+            [
+                Tlv::string(OCTET_STRING, base),
+                Tlv::integer(ENUMERATED, scope),
+                Tlv::integer(ENUMERATED, 0),
+                Tlv::integer(INTEGER, 0),
+                Tlv::integer(INTEGER, 0),
+                Tlv::boolean(false),
+                filter,
+                Tlv::constructed(SEQUENCE, [Tlv::string(OCTET_STRING, "uid")]),
+            ],
+        )
+    }
+
+    fn result_code(op: &Tlv) -> i64 {
+        op.children().unwrap()[0].as_integer().unwrap()
+    }
+
+    fn entry_dn(op: &Tlv) -> String {
+        op.children().unwrap()[0].as_string().unwrap()
+    }
+
+    #[test]
+    fn test_ber_roundtrip() {
+        for value in [0, 1, 127, 128, 255, 256, -1, -128, -129, i32::MAX as i64] {
+            let encoded = Tlv::integer(INTEGER, value).encode();
+            let (decoded, consumed) = Tlv::parse(&encoded).unwrap().unwrap();
+            assert_eq!(consumed, encoded.len());
+            assert_eq!(
+                decoded.as_integer().unwrap(),
+                value,
+                "Integer should roundtrip"
+            );
+        }
+
+        // Make sure long form lengths work
+        let long = Tlv::string(OCTET_STRING, "a".repeat(300));
+        let encoded = long.encode();
+        assert_eq!(&encoded[..4], &[OCTET_STRING, 0x82, 0x01, 0x2c]);
+        assert_eq!(Tlv::parse(&encoded).unwrap().unwrap().0, long);
+        assert!(
+            Tlv::parse(&encoded[..100]).unwrap().is_none(),
+            "Partial data should need more bytes"
+        );
+    }
+
+    #[test]
+    fn test_nested_filter_limit() {
+        let nested = |depth| {
+            (0..depth).fold(Tlv::string(0x87, "objectClass"), |inner, _| {
+                Tlv::constructed(0xa2, [inner])
+            })
+        };
+        Filter::parse(nested(8)).expect("Should parse a reasonably nested filter");
+        // Deep enough to overflow the stack if parsing wasn't limited
+        let request = search_request(BASE_DN, 2, nested(10_000));
+        assert!(
+            request.encode().len() < ber::MAX_LENGTH,
+            "The request should be small enough to be read"
+        );
+        SearchRequest::parse(request).expect_err("Should reject a deeply nested filter");
+    }
+
+    #[tokio::test]
+    async fn test_bind_and_search() {
+        // Users stored before POSIX attributes existed don't have any
+        let store = MemoryStore::new();
+        store
+            .put_user(
+                "legacy".to_string(),
+                crate::UserInfo {
+                    groups: ["admins".to_string()].into(),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let handlers = Handlers::new(store);
+        for (username, groups) in [("foo", vec!["admins", "users"]), ("bar", vec!["users"])] {
+            handlers
+                .add(UserAddRequest {
+                    username: username.to_string(),
+                    password: "supersecure".into(),
+                    groups: groups.into_iter().map(String::from).collect(),
+                    force_password_change: false,
+                    prehashed: false,
+                    posix: Default::default(),
+                })
+                .await
+                .unwrap();
+        }
+        handlers
+            .add_group(GroupAddRequest {
+                name: "empty".to_string(),
+                gid: None,
+                description: "A group with no members".to_string(),
+            })
+            .await
+            .unwrap();
+        let server = LdapServer::new(handlers, "127.0.0.1:0", BASE_DN.to_string(), false)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = tokio::spawn(server.run());
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let everything = Tlv::string(0x87, "objectClass");
+        let resp = request(
+            &mut stream,
+            1,
+            search_request(BASE_DN, 2, everything.clone()),
+        )
+        .await;
+        assert_eq!(
+            result_code(&resp[0]),
+            ResultCode::InsufficientAccessRights as i64,
+            "Should not be able to search without binding"
+        );
+
+        let resp = request(
+            &mut stream,
+            2,
+            bind_request("uid=foo,ou=users,dc=example,dc=com", "wrong"),
+        )
+        .await;
+        assert_eq!(result_code(&resp[0]), ResultCode::InvalidCredentials as i64);
+
+        let resp = request(
+            &mut stream,
+            3,
+            bind_request("uid=foo, ou=Users, dc=example, dc=com", "supersecure"),
+        )
+        .await;
+        assert_eq!(result_code(&resp[0]), ResultCode::Success as i64);
+
+        // Search for all members of the admins group
+        let filter = Tlv::constructed(
+            0xa0,
+            [
+                Tlv::constructed(
+                    0xa3,
+                    [
+                        Tlv::string(OCTET_STRING, "objectClass"),
+                        Tlv::string(OCTET_STRING, "posixAccount"),
+                    ],
+                ),
+                Tlv::constructed(
+                    0xa3,
+                    [
+                        Tlv::string(OCTET_STRING, "memberOf"),
+                        Tlv::string(OCTET_STRING, "cn=admins,ou=groups,dc=example,dc=com"),
+                    ],
+                ),
+            ],
+        );
+        let resp = request(&mut stream, 4, search_request(BASE_DN, 2, filter)).await;
+        assert_eq!(
+            resp.len(),
+            2,
+            "Should have one entry and a done message, as users without POSIX attributes aren't posixAccounts"
+        );
+        assert_eq!(entry_dn(&resp[0]), "uid=foo,ou=users,dc=example,dc=com");
+        assert_eq!(result_code(&resp[1]), ResultCode::Success as i64);
+
+        // A one level search of the groups OU should return all groups, including created groups
+        // without members
+        let resp = request(
+            &mut stream,
+            5,
+            search_request("ou=groups,dc=example,dc=com", 1, everything.clone()),
+        )
+        .await;
+        let dns: Vec<String> = resp[..resp.len() - 1].iter().map(entry_dn).collect();
+        assert_eq!(
+            dns,
+            [
+                "cn=admins,ou=groups,dc=example,dc=com",
+                "cn=empty,ou=groups,dc=example,dc=com",
+                "cn=users,ou=groups,dc=example,dc=com"
+            ]
+        );
+
+        // Searching outside of the base should not find anything
+        let resp = request(&mut stream, 6, search_request("dc=other", 2, everything)).await;
+        assert_eq!(result_code(&resp[0]), ResultCode::NoSuchObject as i64);
+
+        // Writes should be rejected
+        let resp = request(
+            &mut stream,
+            7,
+            Tlv::string(DEL_REQUEST, "uid=foo,ou=users,dc=example,dc=com"),
+        )
+        .await;
+        assert_eq!(result_code(&resp[0]), ResultCode::UnwillingToPerform as i64);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let server = LdapServer::new(
+            Handlers::new(MemoryStore::new()),
+            "127.0.0.1:0",
+            BASE_DN.to_string(),
+            true,
+        )
+        .await
+        .unwrap()
+        .with_max_connections(1);
+        let addr = server.local_addr().unwrap();
+        let handle = tokio::spawn(server.run());
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let resp = request(&mut first, 1, bind_request("", "")).await;
+        assert_eq!(result_code(&resp[0]), ResultCode::Success as i64);
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        second
+            .write_all(&encode_message(1, bind_request("", "")))
+            .await
+            .unwrap();
+        let mut read_buf = [0u8; 4096];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), second.read(&mut read_buf))
+                .await
+                .is_err(),
+            "Should not handle more connections than the limit"
+        );
+
+        drop(first);
+        let n = tokio::time::timeout(Duration::from_secs(5), second.read(&mut read_buf))
+            .await
+            .expect("Should handle the waiting connection once another one closes")
+            .unwrap();
+        let (message, _) = Tlv::parse(&read_buf[..n]).unwrap().unwrap();
+        let (_, op) = parse_message(message).unwrap();
+        assert_eq!(result_code(&op), ResultCode::Success as i64);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_bind_reset_user() {
+        let handlers = Handlers::new(MemoryStore::new());
+        handlers
+            .add(UserAddRequest {
+                username: "foo".to_string(),
+                password: "supersecure".into(),
+                groups: Default::default(),
+                force_password_change: true,
+                prehashed: false,
+                posix: Default::default(),
+            })
+            .await
+            .unwrap();
+        let server = LdapServer::new(handlers.clone(), "127.0.0.1:0", BASE_DN.to_string(), false)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = tokio::spawn(server.run());
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        for message_id in 1..=2 {
+            let resp = request(
+                &mut stream,
+                message_id,
+                bind_request("uid=foo,ou=users,dc=example,dc=com", "supersecure"),
+            )
+            .await;
+            assert_eq!(
+                result_code(&resp[0]),
+                ResultCode::InvalidCredentials as i64,
+                "Should not bind a user that needs to change their password"
+            );
+            assert!(
+                matches!(
+                    handlers.get("foo").await.unwrap().password_change_phase,
+                    Some(crate::PasswordResetPhase::Reset(_))
+                ),
+                "Denied binds should not move the reset to the next phase"
+            );
+        }
+
+        handle.abort();
+    }
+}
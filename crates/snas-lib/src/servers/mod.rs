@@ -1,3 +1,6 @@
+pub mod dispatch;
+pub mod http;
+pub mod ldap;
 pub mod nats;
 #[cfg(unix)]
 pub mod socket;
@@ -1,22 +1,20 @@
-use async_nats::{Client, Message, Subscriber};
-use futures::StreamExt;
-use tracing::{instrument, trace, warn};
+use async_nats::{Client, Subscriber};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
 
 use crate::{
-    admin::{
-        GroupModifyRequest, PasswordResetRequest, UserAddRequest, UserDeleteRequest, UserGetRequest,
-    },
     handlers::Handlers,
+    servers::dispatch::{Api, Dispatcher},
     DEFAULT_ADMIN_NATS_SUBJECT_PREFIX,
 };
 
 use super::*;
 
 pub struct NatsAdminServer {
-    handlers: Handlers,
-    client: Client,
+    api: DispatchApi,
     subscription: Subscriber,
-    prefix: String,
+    max_concurrent_requests: usize,
+    shutdown: CancellationToken,
 }
 
 impl NatsAdminServer {
@@ -36,279 +34,41 @@ impl NatsAdminServer {
             .queue_subscribe(format!("{subject_prefix}.*"), subject_prefix.clone())
             .await?;
         Ok(Self {
-            handlers,
+            api: DispatchApi {
+                dispatcher: Dispatcher::new(handlers, "nats_admin", [Api::Admin]),
+                client,
+                prefix: subject_prefix,
+            },
             subscription,
-            client,
-            prefix: subject_prefix,
+            max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+            shutdown: CancellationToken::new(),
         })
     }
 
-    #[instrument(level = "info", skip(self))]
-    pub async fn run(mut self) -> anyhow::Result<()> {
-        while let Some(msg) = self.subscription.next().await {
-            let action = match msg.subject.strip_prefix(&self.prefix) {
-                Some(a) => a.trim_start_matches('.'),
-                None => {
-                    warn!(subject = %msg.subject, "invalid subject received");
-                    send_error(
-                        &self.client,
-                        msg.reply,
-                        format!("invalid subject {}", msg.subject),
-                    )
-                    .await;
-                    continue;
-                }
-            };
-            match action {
-                "add_user" => {
-                    self.handle_add_user(msg).await;
-                }
-                "get_user" => {
-                    self.handle_get_user(msg).await;
-                }
-                "list_users" => {
-                    self.handle_list_users(msg).await;
-                }
-                "remove_user" => {
-                    self.handle_remove_user(msg).await;
-                }
-                "reset_password" => {
-                    self.handle_reset_password(msg).await;
-                }
-                "add_groups" => {
-                    self.handle_add_groups(msg).await;
-                }
-                "remove_groups" => {
-                    self.handle_delete_groups(msg).await;
-                }
-                _ => {
-                    trace!(subject = %msg.subject, "invalid subject received");
-                    send_error(
-                        &self.client,
-                        msg.reply,
-                        format!("invalid api method {}", action),
-                    )
-                    .await;
-                }
-            }
-        }
-        Err(anyhow::anyhow!("nats admin server exited"))
-    }
-
-    async fn handle_add_user(&self, msg: Message) {
-        let req =
-            deserialize_body::<UserAddRequest>(&self.client, &msg.payload, msg.reply.as_ref())
-                .await;
-        if req.is_err() {
-            // deserialize_body sends the error back for us so we can just return
-            return;
-        }
-        let req = req.unwrap();
-
-        let username = req.username.clone();
-        match self.handlers.add(req).await {
-            Ok(_) => {
-                send_response(
-                    &self.client,
-                    msg.reply,
-                    GenericResponse::new(true, format!("User {username} added")),
-                )
-                .await;
-            }
-            Err(e) => {
-                send_error(&self.client, msg.reply, format!("Unable to add user: {e}")).await;
-            }
-        }
-    }
-
-    async fn handle_get_user(&self, msg: Message) {
-        let req =
-            deserialize_body::<UserGetRequest>(&self.client, &msg.payload, msg.reply.as_ref())
-                .await;
-        if req.is_err() {
-            // deserialize_body sends the error back for us so we can just return
-            return;
-        }
-        let req = req.unwrap();
-
-        match self.handlers.get(&req.username).await {
-            Ok(user) => {
-                send_response(
-                    &self.client,
-                    msg.reply,
-                    GenericResponse {
-                        success: true,
-                        message: String::new(),
-                        response: Some(user),
-                    },
-                )
-                .await;
-            }
-            Err(e) => {
-                send_error(&self.client, msg.reply, format!("Unable to get user: {e}")).await;
-            }
-        }
+    /// Sets the maximum number of requests this server will handle at once. Defaults to
+    /// [`DEFAULT_MAX_CONCURRENT_REQUESTS`]
+    pub fn with_max_concurrent_requests(mut self, max: usize) -> Self {
+        self.max_concurrent_requests = max.max(1);
+        self
     }
 
-    async fn handle_list_users(&self, msg: Message) {
-        // We don't need to parse a body as we are listing all usernames
-
-        match self.handlers.list().await {
-            Ok(users) => {
-                send_response(
-                    &self.client,
-                    msg.reply,
-                    GenericResponse {
-                        success: true,
-                        message: String::new(),
-                        response: Some(users),
-                    },
-                )
-                .await;
-            }
-            Err(e) => {
-                send_error(
-                    &self.client,
-                    msg.reply,
-                    format!("Unable to list users: {e}"),
-                )
-                .await;
-            }
-        }
+    /// Sets the token that tells this server to shut down. Once it is cancelled, the server
+    /// unsubscribes and returns after finishing the requests it is already handling
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
     }
 
-    async fn handle_remove_user(&self, msg: Message) {
-        let req =
-            deserialize_body::<UserDeleteRequest>(&self.client, &msg.payload, msg.reply.as_ref())
-                .await;
-        if req.is_err() {
-            // deserialize_body sends the error back for us so we can just return
-            return;
-        }
-        let req = req.unwrap();
-
-        match self.handlers.delete(&req.username).await {
-            Ok(_) => {
-                send_response(
-                    &self.client,
-                    msg.reply,
-                    GenericResponse::new(true, format!("User {} deleted", req.username)),
-                )
-                .await;
-            }
-            Err(e) => {
-                send_error(
-                    &self.client,
-                    msg.reply,
-                    format!("Unable to remove user: {e}"),
-                )
-                .await;
-            }
-        }
-    }
-
-    async fn handle_reset_password(&self, msg: Message) {
-        let req = deserialize_body::<PasswordResetRequest>(
-            &self.client,
-            &msg.payload,
-            msg.reply.as_ref(),
+    #[instrument(level = "info", skip(self))]
+    pub async fn run(self) -> anyhow::Result<()> {
+        let api = &self.api;
+        serve(
+            "nats admin server",
+            self.subscription,
+            self.max_concurrent_requests,
+            self.shutdown,
+            |msg| api.handle_message(msg),
         )
-        .await;
-        if req.is_err() {
-            // deserialize_body sends the error back for us so we can just return
-            return;
-        }
-        let req = req.unwrap();
-
-        match self.handlers.reset_password(&req.username).await {
-            Ok(resp) => {
-                send_response(
-                    &self.client,
-                    msg.reply,
-                    GenericResponse {
-                        success: true,
-                        message: format!("Password reset for user {}", req.username),
-                        response: Some(resp),
-                    },
-                )
-                .await;
-            }
-            Err(e) => {
-                send_error(
-                    &self.client,
-                    msg.reply,
-                    format!("Unable to reset password for user: {e}"),
-                )
-                .await;
-            }
-        }
-    }
-
-    async fn handle_add_groups(&self, msg: Message) {
-        let req =
-            deserialize_body::<GroupModifyRequest>(&self.client, &msg.payload, msg.reply.as_ref())
-                .await;
-        if req.is_err() {
-            // deserialize_body sends the error back for us so we can just return
-            return;
-        }
-        let req = req.unwrap();
-
-        match self.handlers.add_groups(&req.username, req.groups).await {
-            Ok(resp) => {
-                send_response(
-                    &self.client,
-                    msg.reply,
-                    GenericResponse {
-                        success: true,
-                        message: format!("Updated groups for user {}", req.username),
-                        response: Some(resp),
-                    },
-                )
-                .await;
-            }
-            Err(e) => {
-                send_error(
-                    &self.client,
-                    msg.reply,
-                    format!("Unable to add groups for user: {e}"),
-                )
-                .await;
-            }
-        }
-    }
-
-    async fn handle_delete_groups(&self, msg: Message) {
-        let req =
-            deserialize_body::<GroupModifyRequest>(&self.client, &msg.payload, msg.reply.as_ref())
-                .await;
-        if req.is_err() {
-            // deserialize_body sends the error back for us so we can just return
-            return;
-        }
-        let req = req.unwrap();
-
-        match self.handlers.delete_groups(&req.username, req.groups).await {
-            Ok(resp) => {
-                send_response(
-                    &self.client,
-                    msg.reply,
-                    GenericResponse {
-                        success: true,
-                        message: format!("Deleted groups from user {}", req.username),
-                        response: Some(resp),
-                    },
-                )
-                .await;
-            }
-            Err(e) => {
-                send_error(
-                    &self.client,
-                    msg.reply,
-                    format!("Unable to delete groups for user: {e}"),
-                )
-                .await;
-            }
-        }
+        .await
     }
 }
@@ -0,0 +1,697 @@
+//! A NATS [auth callout](https://docs.nats.io/running-a-nats-service/configuration/securing_nats/auth_callout)
+//! service that allows NATS itself to authenticate users against SNAS.
+//!
+//! The NATS server sends an authorization request JWT for every connecting client. We verify the
+//! username and password from the connect options and respond with a signed user JWT whose
+//! permissions are derived from the user's groups.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use async_nats::{Client, Message, Subscriber};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use nkeys::{KeyPair, KeyPairType};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, instrument, warn};
+
+use crate::{error::HandleError, handlers::Handlers, metrics, SecureString};
+
+use super::{serve, DEFAULT_MAX_CONCURRENT_REQUESTS};
+
+/// The subject the NATS server sends authorization requests on
+pub const AUTH_CALLOUT_SUBJECT: &str = "$SYS.REQ.USER.AUTH";
+/// The queue group every SNAS instance subscribes to [`AUTH_CALLOUT_SUBJECT`] with, so each
+/// authorization request is only handled once
+pub const AUTH_CALLOUT_QUEUE_GROUP: &str = "snas.auth_callout";
+/// How long user JWTs issued by the auth callout are valid for by default. NATS disconnects clients
+/// when their JWT expires, so this bounds how long a removed or locked user stays connected
+pub const DEFAULT_USER_JWT_TTL: Duration = Duration::from_secs(5 * 60);
+/// The account users are placed in when not running NATS in operator mode
+pub const DEFAULT_AUTH_CALLOUT_ACCOUNT: &str = "$G";
+
+const JWT_ALGORITHM: &str = "ed25519-nkey";
+const JWT_VERSION: u8 = 2;
+const XKEY_HEADER: &str = "Nats-Server-Xkey";
+const AUTHORIZATION_REQUEST_AUDIENCE: &str = "nats-authorization-request";
+const AUTHORIZATION_REQUEST_TYPE: &str = "authorization_request";
+
+/// A set of allowed and denied subjects for either publishing or subscribing
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubjectPermission {
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub allow: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub deny: BTreeSet<String>,
+}
+
+impl SubjectPermission {
+    fn extend(&mut self, other: &SubjectPermission) {
+        self.allow.extend(other.allow.iter().cloned());
+        self.deny.extend(other.deny.iter().cloned());
+    }
+}
+
+/// The publish and subscribe permissions granted to a user
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Permissions {
+    #[serde(default, rename = "pub")]
+    pub publish: SubjectPermission,
+    #[serde(default, rename = "sub")]
+    pub subscribe: SubjectPermission,
+}
+
+impl Permissions {
+    fn extend(&mut self, other: &Permissions) {
+        self.publish.extend(&other.publish);
+        self.subscribe.extend(&other.subscribe);
+    }
+}
+
+/// A mapping of SNAS groups to the NATS permissions members of that group should receive. The
+/// permissions for a user are the union of the `default` permissions and the permissions of every
+/// group they are a member of. This is normally loaded from a JSON file like:
+///
+/// ```json
+/// {
+///   "default": { "sub": { "allow": ["_INBOX.>"] } },
+///   "groups": {
+///     "admins": { "pub": { "allow": [">"] }, "sub": { "allow": [">"] } }
+///   }
+/// }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GroupPermissionMap {
+    #[serde(default)]
+    pub default: Permissions,
+    #[serde(default)]
+    pub groups: BTreeMap<String, Permissions>,
+}
+
+impl GroupPermissionMap {
+    /// Returns the merged permissions for a member of the given groups
+    pub fn permissions_for<'a>(&self, groups: impl IntoIterator<Item = &'a String>) -> Permissions {
+        groups
+            .into_iter()
+            .filter_map(|group| self.groups.get(group))
+            .fold(self.default.clone(), |mut acc, perms| {
+                acc.extend(perms);
+                acc
+            })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct JwtHeader {
+    typ: String,
+    alg: String,
+}
+
+/// The claims of an authorization request sent by the NATS server. We only deserialize the fields
+/// we need
+#[derive(Deserialize, Debug)]
+struct AuthorizationRequestClaims {
+    #[serde(default)]
+    aud: String,
+    nats: AuthorizationRequest,
+}
+
+#[derive(Deserialize, Debug)]
+struct AuthorizationRequest {
+    #[serde(default, rename = "type")]
+    kind: String,
+    server_id: ServerId,
+    user_nkey: String,
+    connect_opts: ConnectOptions,
+}
+
+#[derive(Deserialize, Debug)]
+struct ServerId {
+    id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ConnectOptions {
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    pass: Option<SecureString>,
+}
+
+#[derive(Serialize, Debug)]
+struct AuthorizationResponseClaims {
+    jti: String,
+    iat: u64,
+    iss: String,
+    sub: String,
+    aud: String,
+    nats: AuthorizationResponse,
+}
+
+#[derive(Serialize, Debug)]
+struct AuthorizationResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jwt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    version: u8,
+}
+
+#[derive(Serialize, Debug)]
+struct UserClaims {
+    jti: String,
+    iat: u64,
+    exp: u64,
+    iss: String,
+    name: String,
+    sub: String,
+    aud: String,
+    nats: UserPermissionClaims,
+}
+
+#[derive(Serialize, Debug)]
+struct UserPermissionClaims {
+    #[serde(flatten)]
+    permissions: Permissions,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    version: u8,
+}
+
+pub struct NatsAuthCalloutServer {
+    api: CalloutApi,
+    client: Client,
+    subscription: Subscriber,
+    max_concurrent_requests: usize,
+    allow_any_server: bool,
+    shutdown: CancellationToken,
+}
+
+/// Everything needed to handle an authorization request. This is kept separate from the
+/// subscription so requests can be handled concurrently while we keep receiving messages
+struct CalloutApi {
+    handlers: Handlers,
+    issuer: KeyPair,
+    account: String,
+    permissions: GroupPermissionMap,
+    /// The public keys of the NATS servers allowed to send authorization requests. If empty, any
+    /// request correctly self-signed by a server key is accepted
+    trusted_servers: BTreeSet<String>,
+    /// How long issued user JWTs are valid for
+    user_jwt_ttl: Duration,
+}
+
+impl NatsAuthCalloutServer {
+    /// Creates a new auth callout server. The issuer seed is the account (or account signing key)
+    /// seed configured as the `issuer` of the auth callout in the NATS server config. Users will be
+    /// placed in the given account, or the global account (`$G`) if `None` is passed.
+    ///
+    /// The given client must be connected as one of the `auth_users` from the NATS server config.
+    pub async fn new(
+        handlers: Handlers,
+        client: Client,
+        issuer_seed: &str,
+        account: Option<String>,
+        permissions: GroupPermissionMap,
+    ) -> anyhow::Result<Self> {
+        let issuer = KeyPair::from_seed(issuer_seed.trim()).context("Invalid issuer seed")?;
+        let subscription = client
+            .queue_subscribe(AUTH_CALLOUT_SUBJECT, AUTH_CALLOUT_QUEUE_GROUP.to_string())
+            .await?;
+        Ok(Self {
+            api: CalloutApi {
+                handlers,
+                issuer,
+                account: account.unwrap_or_else(|| DEFAULT_AUTH_CALLOUT_ACCOUNT.to_string()),
+                permissions,
+                trusted_servers: BTreeSet::new(),
+                user_jwt_ttl: DEFAULT_USER_JWT_TTL,
+            },
+            client,
+            subscription,
+            max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+            allow_any_server: false,
+            shutdown: CancellationToken::new(),
+        })
+    }
+
+    /// Sets the maximum number of authorization requests this server will handle at once. Defaults
+    /// to [`DEFAULT_MAX_CONCURRENT_REQUESTS`]
+    pub fn with_max_concurrent_requests(mut self, max: usize) -> Self {
+        self.max_concurrent_requests = max.max(1);
+        self
+    }
+
+    /// Sets how long the user JWTs issued to authorized users are valid for. Defaults to
+    /// [`DEFAULT_USER_JWT_TTL`]
+    pub fn with_user_jwt_ttl(mut self, ttl: Duration) -> Self {
+        self.api.user_jwt_ttl = ttl;
+        self
+    }
+
+    /// Only accept authorization requests signed by one of the given NATS server public keys
+    /// (`N...`). At least one key must be given unless [`allow_any_server`](Self::allow_any_server)
+    /// is set
+    pub fn with_trusted_servers(
+        mut self,
+        keys: impl IntoIterator<Item = String>,
+    ) -> anyhow::Result<Self> {
+        self.api.trusted_servers = keys
+            .into_iter()
+            .map(|key| {
+                let key = key.trim().to_owned();
+                match KeyPair::from_public_key(&key).map(|kp| kp.key_pair_type()) {
+                    Ok(KeyPairType::Server) => Ok(key),
+                    _ => anyhow::bail!("{key} is not a valid NATS server public key"),
+                }
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(self)
+    }
+
+    /// Accept authorization requests signed by any NATS server key, so the server can run without
+    /// any [trusted servers](Self::with_trusted_servers). This is insecure, as anyone able to
+    /// publish on [`AUTH_CALLOUT_SUBJECT`] can sign their own requests and use them to guess
+    /// passwords, so publishing on that subject must be restricted to the NATS servers
+    pub fn allow_any_server(mut self, allow: bool) -> Self {
+        self.allow_any_server = allow;
+        self
+    }
+
+    /// Sets the token that tells this server to shut down. Once it is cancelled, the server
+    /// unsubscribes and returns after finishing the requests it is already handling
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    #[instrument(level = "info", skip(self))]
+    pub async fn run(self) -> anyhow::Result<()> {
+        if self.api.trusted_servers.is_empty() && !self.allow_any_server {
+            anyhow::bail!(
+                "The auth callout server needs at least one trusted NATS server key unless it is allowed to accept requests from any server"
+            );
+        }
+        let api = &self.api;
+        let client = &self.client;
+        serve(
+            "nats auth callout server",
+            self.subscription,
+            self.max_concurrent_requests,
+            self.shutdown,
+            |msg| metrics::time_request("nats_callout", api.handle_request(client, msg)),
+        )
+        .await
+    }
+}
+
+impl CalloutApi {
+    #[instrument(level = "debug", skip_all)]
+    async fn handle_request(&self, client: &Client, msg: Message) {
+        let reply = match msg.reply {
+            Some(reply) => reply,
+            None => {
+                warn!("received authorization request without a reply subject");
+                return;
+            }
+        };
+        if msg
+            .headers
+            .as_ref()
+            .and_then(|headers| headers.get(XKEY_HEADER))
+            .is_some()
+        {
+            // We can't respond here as the server expects an encrypted response
+            error!("received an encrypted authorization request. Encrypted auth callout (xkey) is not supported");
+            return;
+        }
+
+        let request = match std::str::from_utf8(&msg.payload)
+            .map_err(anyhow::Error::from)
+            .and_then(|token| decode_request(token, &self.trusted_servers))
+        {
+            Ok(req) => req,
+            Err(err) => {
+                // We don't have a server ID or user nkey to put in a response, so the request will
+                // just time out on the NATS server side
+                warn!(%err, "received invalid authorization request");
+                return;
+            }
+        };
+
+        let (jwt, error) = match self.authorize(&request).await {
+            Ok(jwt) => (Some(jwt), None),
+            Err(err) => (None, Some(err)),
+        };
+        let response = AuthorizationResponseClaims {
+            jti: generate_jti(),
+            iat: now(),
+            iss: self.issuer.public_key(),
+            sub: request.user_nkey,
+            aud: request.server_id.id,
+            nats: AuthorizationResponse {
+                jwt,
+                error,
+                kind: "authorization_response",
+                version: JWT_VERSION,
+            },
+        };
+        let encoded = match encode_jwt(&self.issuer, &response) {
+            Ok(encoded) => encoded,
+            Err(err) => {
+                error!(%err, "unable to encode authorization response");
+                return;
+            }
+        };
+        if let Err(err) = client.publish(reply, encoded.into()).await {
+            error!(%err, "unable to send authorization response");
+        }
+    }
+
+    /// Verifies the user from the request, returning an encoded user JWT if the user is valid or
+    /// the error to send back otherwise.
+    async fn authorize(&self, request: &AuthorizationRequest) -> Result<String, String> {
+        let (username, password) = match (
+            request.connect_opts.user.as_ref(),
+            request.connect_opts.pass.as_ref(),
+        ) {
+            (Some(user), Some(pass)) => (user, pass),
+            _ => return Err("username and password are required".to_string()),
+        };
+        // NATS clients have no way to go through the password change flow, so users with a pending
+        // reset are denied (without using up their reset) until they change their password
+        // elsewhere
+        let resp = match self
+            .handlers
+            .verify_non_interactive(username, password.clone())
+            .await
+        {
+            Ok(resp) => resp,
+            Err(HandleError::SystemError(err)) => {
+                error!(%err, "error when verifying user");
+                return Err("unable to verify user".to_string());
+            }
+            Err(err) => {
+                debug!(%err, %username, "denied authorization request");
+                return Err(err.to_string());
+            }
+        };
+        let iat = now();
+        let claims = UserClaims {
+            jti: generate_jti(),
+            iat,
+            exp: iat + self.user_jwt_ttl.as_secs(),
+            iss: self.issuer.public_key(),
+            name: username.to_owned(),
+            sub: request.user_nkey.clone(),
+            aud: self.account.clone(),
+            nats: UserPermissionClaims {
+                permissions: self.permissions.permissions_for(&resp.groups),
+                kind: "user",
+                version: JWT_VERSION,
+            },
+        };
+        encode_jwt(&self.issuer, &claims).map_err(|err| {
+            error!(%err, "unable to encode user JWT");
+            "unable to issue user JWT".to_string()
+        })
+    }
+}
+
+/// Decodes the authorization request JWT and verifies its signature against the `iss` key in the
+/// token, which must be a server key. On its own this only proves the token wasn't modified, not
+/// who sent it, so if any trusted servers are given the issuer must also be one of them. Tokens that aren't authorization
+/// requests (such as a user JWT signed by the same server) are rejected
+fn decode_request(
+    token: &str,
+    trusted_servers: &BTreeSet<String>,
+) -> anyhow::Result<AuthorizationRequest> {
+    let claims: AuthorizationRequestClaims = decode_jwt(token, |iss| {
+        if !trusted_servers.is_empty() && !trusted_servers.contains(iss) {
+            anyhow::bail!("Authorization request was issued by untrusted server {iss}");
+        }
+        let key =
+            KeyPair::from_public_key(iss).context("Invalid issuer in authorization request")?;
+        if key.key_pair_type() != KeyPairType::Server {
+            anyhow::bail!("Authorization request was not issued by a server key");
+        }
+        Ok(key)
+    })?;
+    if claims.aud != AUTHORIZATION_REQUEST_AUDIENCE {
+        anyhow::bail!(
+            "Authorization request has unexpected audience {:?}",
+            claims.aud
+        );
+    }
+    if claims.nats.kind != AUTHORIZATION_REQUEST_TYPE {
+        anyhow::bail!(
+            "Authorization request has unexpected type {:?}",
+            claims.nats.kind
+        );
+    }
+    Ok(claims.nats)
+}
+
+/// Decodes and verifies a JWT signed with an nkey. The given function is called with the `iss` of
+/// the claims and should return the key to verify the signature with.
+fn decode_jwt<T: DeserializeOwned>(
+    token: &str,
+    key_for_issuer: impl FnOnce(&str) -> anyhow::Result<KeyPair>,
+) -> anyhow::Result<T> {
+    #[derive(Deserialize)]
+    struct Issuer {
+        iss: String,
+    }
+
+    let mut parts = token.trim().split('.');
+    let (header, claims, signature) = match (parts.next(), parts.next(), parts.next(), parts.next())
+    {
+        (Some(header), Some(claims), Some(signature), None) => (header, claims, signature),
+        _ => anyhow::bail!("JWT must have three parts"),
+    };
+    let decoded_header: JwtHeader = serde_json::from_slice(
+        &URL_SAFE_NO_PAD
+            .decode(header)
+            .context("Invalid JWT header encoding")?,
+    )
+    .context("Invalid JWT header")?;
+    if decoded_header.alg != JWT_ALGORITHM {
+        anyhow::bail!("Unsupported JWT algorithm {}", decoded_header.alg);
+    }
+    let raw_claims = URL_SAFE_NO_PAD
+        .decode(claims)
+        .context("Invalid JWT claims encoding")?;
+    let Issuer { iss } = serde_json::from_slice(&raw_claims).context("Invalid JWT issuer")?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .context("Invalid JWT signature encoding")?;
+    key_for_issuer(&iss)?
+        .verify(format!("{header}.{claims}").as_bytes(), &signature)
+        .context("Invalid JWT signature")?;
+    serde_json::from_slice(&raw_claims).context("Invalid JWT claims")
+}
+
+fn encode_jwt<T: Serialize>(key: &KeyPair, claims: &T) -> anyhow::Result<String> {
+    let header = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&JwtHeader {
+        typ: "JWT".to_string(),
+        alg: JWT_ALGORITHM.to_string(),
+    })?);
+    let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims)?);
+    let signing_input = format!("{header}.{claims}");
+    let signature = key
+        .sign(signing_input.as_bytes())
+        .context("Unable to sign JWT")?;
+    Ok(format!(
+        "{signing_input}.{}",
+        URL_SAFE_NO_PAD.encode(signature)
+    ))
+}
+
+fn generate_jti() -> String {
+    rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .map(|c| char::from(c).to_ascii_uppercase())
+        .take(52)
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_jwt_roundtrip() {
+        let server = KeyPair::new_server();
+        let user = KeyPair::new_user();
+        let claims = serde_json::json!({
+            "jti": generate_jti(),
+            "iat": now(),
+            "iss": server.public_key(),
+            "sub": user.public_key(),
+            "aud": "nats-authorization-request",
+            "nats": {
+                "server_id": { "name": "test", "host": "0.0.0.0", "id": server.public_key() },
+                "user_nkey": user.public_key(),
+                "connect_opts": { "user": "foo", "pass": "bar", "protocol": 1 },
+                "type": "authorization_request",
+                "version": 2
+            }
+        });
+        let token = encode_jwt(&server, &claims).unwrap();
+        let request = decode_request(&token, &BTreeSet::new())
+            .expect("Should be able to decode a valid request");
+        assert_eq!(request.user_nkey, user.public_key());
+        assert_eq!(request.server_id.id, server.public_key());
+        assert_eq!(request.connect_opts.user.as_deref(), Some("foo"));
+        assert_eq!(
+            request.connect_opts.pass.as_ref().map(AsRef::<str>::as_ref),
+            Some("bar")
+        );
+
+        // Swap in the signature from a different key and make sure it fails
+        let other = encode_jwt(&KeyPair::new_server(), &claims).unwrap();
+        let (signing_input, _) = token.rsplit_once('.').unwrap();
+        let (_, bad_signature) = other.rsplit_once('.').unwrap();
+        decode_request(
+            &format!("{signing_input}.{bad_signature}"),
+            &BTreeSet::new(),
+        )
+        .expect_err("Should not accept a JWT with an invalid signature");
+
+        decode_request(&token, &[server.public_key()].into())
+            .expect("Should accept a request from a trusted server");
+        decode_request(&token, &[KeyPair::new_server().public_key()].into())
+            .expect_err("Should not accept a request from a server that isn't trusted");
+
+        let account = KeyPair::new_account();
+        let mut self_signed = claims.clone();
+        self_signed["iss"] = account.public_key().into();
+        decode_request(
+            &encode_jwt(&account, &self_signed).unwrap(),
+            &BTreeSet::new(),
+        )
+        .expect_err("Should not accept a request signed by a key that isn't a server key");
+
+        let mut wrong_audience = claims.clone();
+        wrong_audience["aud"] = "someone-else".into();
+        decode_request(
+            &encode_jwt(&server, &wrong_audience).unwrap(),
+            &BTreeSet::new(),
+        )
+        .expect_err("Should not accept a request for a different audience");
+        let mut wrong_type = claims.clone();
+        wrong_type["nats"]["type"] = "user".into();
+        decode_request(&encode_jwt(&server, &wrong_type).unwrap(), &BTreeSet::new())
+            .expect_err("Should not accept a JWT that isn't an authorization request");
+    }
+
+    #[tokio::test]
+    async fn test_authorize_reset_user() {
+        use crate::{admin::UserAddRequest, storage::MemoryStore, PasswordResetPhase};
+
+        let handlers = Handlers::new(MemoryStore::new());
+        handlers
+            .add(UserAddRequest {
+                username: "foo".to_string(),
+                password: "supersecure".into(),
+                groups: BTreeSet::new(),
+                force_password_change: false,
+                prehashed: false,
+                posix: Default::default(),
+            })
+            .await
+            .unwrap();
+        let api = CalloutApi {
+            handlers: handlers.clone(),
+            issuer: KeyPair::new_account(),
+            account: DEFAULT_AUTH_CALLOUT_ACCOUNT.to_string(),
+            permissions: GroupPermissionMap::default(),
+            trusted_servers: BTreeSet::new(),
+            user_jwt_ttl: DEFAULT_USER_JWT_TTL,
+        };
+        let request = |pass: &SecureString| AuthorizationRequest {
+            kind: AUTHORIZATION_REQUEST_TYPE.to_string(),
+            server_id: ServerId {
+                id: KeyPair::new_server().public_key(),
+            },
+            user_nkey: KeyPair::new_user().public_key(),
+            connect_opts: ConnectOptions {
+                user: Some("foo".to_string()),
+                pass: Some(pass.clone()),
+            },
+        };
+
+        let jwt = api
+            .authorize(&request(&"supersecure".into()))
+            .await
+            .expect("Should authorize a valid user");
+        let claims: serde_json::Value = decode_jwt(&jwt, |_| {
+            Ok(KeyPair::from_public_key(&api.issuer.public_key())?)
+        })
+        .expect("Should issue a valid user JWT");
+        assert_eq!(
+            claims["exp"].as_u64().unwrap(),
+            claims["iat"].as_u64().unwrap() + DEFAULT_USER_JWT_TTL.as_secs(),
+            "User JWTs should expire after the TTL"
+        );
+
+        let reset = handlers.reset_password("foo").await.unwrap();
+        for _ in 0..2 {
+            let err = api
+                .authorize(&request(&reset.temp_password))
+                .await
+                .expect_err("Should not authorize a user that needs to change their password");
+            assert_eq!(err, HandleError::PasswordChangeRequired.to_string());
+            assert!(
+                matches!(
+                    handlers.get("foo").await.unwrap().password_change_phase,
+                    Some(PasswordResetPhase::Reset(_))
+                ),
+                "Denied callouts should not move the reset to the next phase"
+            );
+        }
+
+        // The user can still log in somewhere that can change their password
+        let resp = handlers
+            .verify("foo", reset.temp_password)
+            .await
+            .expect("Should still be able to log in with the temporary password");
+        assert!(resp.needs_password_reset);
+    }
+
+    #[test]
+    fn test_permission_merge() {
+        let map: GroupPermissionMap = serde_json::from_str(
+            r#"{
+                "default": { "sub": { "allow": ["_INBOX.>"] } },
+                "groups": {
+                    "admins": { "pub": { "allow": [">"] }, "sub": { "allow": [">"] } },
+                    "readers": { "pub": { "deny": ["secret.>"] }, "sub": { "allow": ["data.>"] } }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let perms = map.permissions_for(&["readers".to_string(), "unknown".to_string()]);
+        assert_eq!(
+            perms.subscribe.allow,
+            ["_INBOX.>".to_string(), "data.>".to_string()].into()
+        );
+        assert_eq!(perms.publish.deny, ["secret.>".to_string()].into());
+        assert!(perms.publish.allow.is_empty());
+
+        let perms = map.permissions_for(&[]);
+        assert_eq!(perms, map.default, "No groups should get the defaults");
+    }
+}
@@ -1,69 +1,270 @@
-use async_nats::{Client, Subject};
-use serde::{de::DeserializeOwned, Serialize};
-use tracing::error;
+use std::future::Future;
 
-use crate::types::api::GenericResponse;
+use async_nats::{Client, Message, Subscriber};
+use futures::{stream::FuturesUnordered, Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, trace, warn};
+
+use crate::{servers::dispatch::Dispatcher, types::api::GenericResponse};
 
 pub mod admin;
+pub mod callout;
 pub mod user;
 
-async fn send_error(client: &Client, reply: Option<Subject>, message: String) {
-    if let Some(reply) = reply {
-        if let Err(err) = client
-            .publish(
-                reply,
-                serde_json::to_vec(&GenericResponse::new(false, message))
-                    .expect("Unable to serialize generic response, this is likely programmer error")
-                    .into(),
-            )
-            .await
-        {
-            error!(%err, "unable to send error response");
+/// The default maximum number of requests each NATS server handles at once
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 64;
+
+/// Where [`serve`] gets its messages from. This is only a trait so that `serve` can be tested
+/// without a NATS server
+trait Subscription: Stream + Unpin {
+    /// Stops receiving new messages
+    async fn unsubscribe(&mut self) -> anyhow::Result<()>;
+}
+
+impl Subscription for Subscriber {
+    async fn unsubscribe(&mut self) -> anyhow::Result<()> {
+        Subscriber::unsubscribe(self).await.map_err(Into::into)
+    }
+}
+
+/// Handles messages from the subscription with at most `max_concurrent` in flight until shutdown
+/// is requested. On shutdown we unsubscribe right away so new requests go to other members of the
+/// queue group, and then wait for the requests we already started to finish. Returns an error if
+/// the subscription ends on its own
+async fn serve<S, F, Fut>(
+    name: &str,
+    mut subscription: S,
+    max_concurrent: usize,
+    shutdown: CancellationToken,
+    handle: F,
+) -> anyhow::Result<()>
+where
+    S: Subscription,
+    F: Fn(S::Item) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut in_flight = FuturesUnordered::new();
+    let result = loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break Ok(()),
+            Some(_) = in_flight.next(), if !in_flight.is_empty() => {}
+            msg = subscription.next(), if in_flight.len() < max_concurrent => match msg {
+                Some(msg) => in_flight.push(handle(msg)),
+                None => break Err(anyhow::anyhow!("{name} exited")),
+            },
+        }
+    };
+    if result.is_ok() {
+        info!(in_flight = in_flight.len(), "Shutting down {name}");
+        if let Err(err) = subscription.unsubscribe().await {
+            warn!(%err, "Unable to unsubscribe {name}");
         }
     }
+    while in_flight.next().await.is_some() {}
+    result
+}
+
+/// Everything needed to handle a request. This is kept separate from the subscription so requests
+/// can be handled concurrently while we keep receiving messages
+struct DispatchApi {
+    dispatcher: Dispatcher,
+    client: Client,
+    prefix: String,
 }
 
-async fn send_response<T: Serialize>(
-    client: &Client,
-    reply: Option<Subject>,
-    response: GenericResponse<T>,
-) {
-    if let Some(reply) = reply {
-        let body = match serde_json::to_vec(&response) {
-            Ok(body) => body,
-            Err(err) => {
-                send_error(
-                    client,
-                    Some(reply),
-                    format!("unable to serialize response: {}", err),
-                )
-                .await;
-                return;
+impl DispatchApi {
+    /// Strips the subject prefix to get the method and sends back whatever the dispatcher returns
+    async fn handle_message(&self, msg: Message) {
+        let response = match msg.subject.strip_prefix(&self.prefix) {
+            Some(method) => {
+                self.dispatcher
+                    .dispatch(method.trim_start_matches('.'), &msg.payload)
+                    .await
+            }
+            None => {
+                warn!(subject = %msg.subject, "invalid subject received");
+                GenericResponse {
+                    success: false,
+                    message: format!("invalid subject {}", msg.subject),
+                    response: None,
+                    error: None,
+                }
             }
         };
-        if let Err(err) = client.publish(reply, body.into()).await {
+        let Some(reply) = msg.reply else {
+            trace!(subject = %msg.subject, "no reply subject, not sending response");
+            return;
+        };
+        let body = serde_json::to_vec(&response)
+            .expect("Unable to serialize generic response, this is likely programmer error");
+        if let Err(err) = self.client.publish(reply, body.into()).await {
             error!(%err, "unable to send response");
         }
     }
 }
 
-async fn deserialize_body<T: DeserializeOwned>(
-    client: &Client,
-    body: &[u8],
-    reply: Option<&Subject>,
-) -> anyhow::Result<T> {
-    match serde_json::from_slice(body) {
-        Ok(body) => Ok(body),
-        Err(err) => {
-            send_error(
-                client,
-                reply.cloned(),
-                format!("invalid request, unable to deserialize body: {}", err),
-            )
-            .await;
-            Err(anyhow::anyhow!(
-                "invalid request, unable to deserialize body"
-            ))
+#[cfg(test)]
+mod test {
+    use std::{
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use futures::channel::mpsc;
+    use tokio::sync::Semaphore;
+
+    use super::*;
+
+    struct FakeSubscription {
+        messages: mpsc::UnboundedReceiver<usize>,
+        unsubscribed: Arc<AtomicBool>,
+    }
+
+    impl Stream for FakeSubscription {
+        type Item = usize;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<usize>> {
+            self.messages.poll_next_unpin(cx)
         }
     }
+
+    impl Subscription for FakeSubscription {
+        async fn unsubscribe(&mut self) -> anyhow::Result<()> {
+            self.unsubscribed.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// Tracks the handlers that are running. Each one waits for a permit before finishing
+    struct BlockingHandler {
+        permits: Semaphore,
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+        finished: Mutex<Vec<usize>>,
+    }
+
+    impl BlockingHandler {
+        fn new() -> Self {
+            BlockingHandler {
+                permits: Semaphore::new(0),
+                running: AtomicUsize::new(0),
+                max_running: AtomicUsize::new(0),
+                finished: Mutex::new(Vec::new()),
+            }
+        }
+
+        async fn handle(&self, msg: usize) {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            self.permits.acquire().await.unwrap().forget();
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            self.finished.lock().unwrap().push(msg);
+        }
+
+        fn finished(&self) -> usize {
+            self.finished.lock().unwrap().len()
+        }
+    }
+
+    /// Lets the server run until the condition holds
+    async fn wait_for(condition: impl Fn() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !condition() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("Condition should be met");
+    }
+
+    #[tokio::test]
+    async fn test_serve_concurrency_and_shutdown() {
+        const MAX_CONCURRENT: usize = 3;
+
+        let (sender, messages) = mpsc::unbounded();
+        for msg in 0..10 {
+            sender.unbounded_send(msg).unwrap();
+        }
+        let unsubscribed = Arc::new(AtomicBool::new(false));
+        let subscription = FakeSubscription {
+            messages,
+            unsubscribed: unsubscribed.clone(),
+        };
+        let handler = Arc::new(BlockingHandler::new());
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn({
+            let handler = handler.clone();
+            let shutdown = shutdown.clone();
+            async move {
+                serve("test", subscription, MAX_CONCURRENT, shutdown, |msg| {
+                    let handler = handler.clone();
+                    async move { handler.handle(msg).await }
+                })
+                .await
+            }
+        });
+
+        // Only as many handler as allowed start, even though more messages are waiting
+        wait_for(|| handler.running.load(Ordering::SeqCst) == MAX_CONCURRENT).await;
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(handler.running.load(Ordering::SeqCst), MAX_CONCURRENT);
+
+        // Finishing some requests lets the next ones start, but never more than the limit
+        handler.permits.add_permits(2);
+        wait_for(|| handler.finished() == 2).await;
+        wait_for(|| handler.running.load(Ordering::SeqCst) == MAX_CONCURRENT).await;
+        assert_eq!(handler.max_running.load(Ordering::SeqCst), MAX_CONCURRENT);
+
+        // Shutting down stops taking new messages but waits for the ones in flight
+        shutdown.cancel();
+        wait_for(|| unsubscribed.load(Ordering::SeqCst)).await;
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(
+            !server.is_finished(),
+            "Server should wait for in flight requests before returning"
+        );
+
+        handler.permits.add_permits(10);
+        server
+            .await
+            .unwrap()
+            .expect("Server should shut down cleanly");
+        assert_eq!(
+            handler.finished(),
+            2 + MAX_CONCURRENT,
+            "Requests in flight should finish and no new ones should start"
+        );
+        assert_eq!(handler.running.load(Ordering::SeqCst), 0);
+        assert_eq!(handler.max_running.load(Ordering::SeqCst), MAX_CONCURRENT);
+    }
+
+    #[tokio::test]
+    async fn test_serve_subscription_ends() {
+        let (sender, messages) = mpsc::unbounded();
+        sender.unbounded_send(0).unwrap();
+        drop(sender);
+        let unsubscribed = Arc::new(AtomicBool::new(false));
+        let subscription = FakeSubscription {
+            messages,
+            unsubscribed: unsubscribed.clone(),
+        };
+        let handler = BlockingHandler::new();
+        handler.permits.add_permits(1);
+        serve("test", subscription, 1, CancellationToken::new(), |msg| {
+            handler.handle(msg)
+        })
+        .await
+        .expect_err("Should return an error when the subscription ends");
+        assert_eq!(handler.finished(), 1);
+        assert!(!unsubscribed.load(Ordering::SeqCst));
+    }
 }
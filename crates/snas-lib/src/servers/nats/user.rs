@@ -1,21 +1,20 @@
-use async_nats::{Client, Message, Subscriber};
-use futures::StreamExt;
-use tracing::{instrument, trace, warn};
+use async_nats::{Client, Subscriber};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
 
 use crate::{
-    api::{GenericResponse, PasswordChangeRequest, VerificationRequest, VerificationResponse},
-    error::HandleError,
     handlers::Handlers,
+    servers::dispatch::{Api, Dispatcher},
     DEFAULT_USER_NATS_SUBJECT_PREFIX,
 };
 
 use super::*;
 
 pub struct NatsUserServer {
-    handlers: Handlers,
-    client: Client,
+    api: DispatchApi,
     subscription: Subscriber,
-    prefix: String,
+    max_concurrent_requests: usize,
+    shutdown: CancellationToken,
 }
 
 impl NatsUserServer {
@@ -35,153 +34,41 @@ impl NatsUserServer {
             .queue_subscribe(format!("{subject_prefix}.*"), subject_prefix.clone())
             .await?;
         Ok(Self {
-            handlers,
+            api: DispatchApi {
+                dispatcher: Dispatcher::new(handlers, "nats_user", [Api::User]),
+                client,
+                prefix: subject_prefix,
+            },
             subscription,
-            client,
-            prefix: subject_prefix,
+            max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+            shutdown: CancellationToken::new(),
         })
     }
 
-    #[instrument(level = "info", skip(self))]
-    pub async fn run(mut self) -> anyhow::Result<()> {
-        while let Some(msg) = self.subscription.next().await {
-            let action = match msg.subject.strip_prefix(&self.prefix) {
-                Some(a) => a.trim_start_matches('.'),
-                None => {
-                    warn!(subject = %msg.subject, "invalid subject received");
-                    send_error(
-                        &self.client,
-                        msg.reply,
-                        format!("invalid subject {}", msg.subject),
-                    )
-                    .await;
-                    continue;
-                }
-            };
-            match action {
-                "change_password" => {
-                    self.handle_change_password(msg).await;
-                }
-                "verify" => {
-                    self.handle_verify(msg).await;
-                }
-                _ => {
-                    trace!(subject = %msg.subject, "invalid subject received");
-                    send_error(
-                        &self.client,
-                        msg.reply,
-                        format!("invalid api method {}", action),
-                    )
-                    .await;
-                }
-            }
-        }
-        Err(anyhow::anyhow!("nats user server exited"))
+    /// Sets the maximum number of requests this server will handle at once. Defaults to
+    /// [`DEFAULT_MAX_CONCURRENT_REQUESTS`]
+    pub fn with_max_concurrent_requests(mut self, max: usize) -> Self {
+        self.max_concurrent_requests = max.max(1);
+        self
     }
 
-    #[instrument(level = "debug", skip_all, fields(subject = %msg.subject))]
-    async fn handle_verify(&self, msg: Message) {
-        let req =
-            deserialize_body::<VerificationRequest>(&self.client, &msg.payload, msg.reply.as_ref())
-                .await;
-        if req.is_err() {
-            // deserialize_body sends the error back for us so we can just return
-            return;
-        }
-        let req = req.unwrap();
-        match self.handlers.verify(&req.username, req.password).await {
-            Ok(r) => {
-                send_response(
-                    &self.client,
-                    msg.reply,
-                    GenericResponse {
-                        success: true,
-                        message: "Verification succeeded".to_string(),
-                        response: Some(r),
-                    },
-                )
-                .await;
-            }
-            Err(HandleError::InvalidCredentials) => {
-                send_response(
-                    &self.client,
-                    msg.reply,
-                    GenericResponse {
-                        success: true,
-                        message: "Verification failed".to_string(),
-                        response: Some(VerificationResponse {
-                            valid: false,
-                            message: HandleError::InvalidCredentials.to_string(),
-                            needs_password_reset: false,
-                            groups: Default::default(),
-                        }),
-                    },
-                )
-                .await;
-            }
-            Err(HandleError::PasswordResetExpired) => {
-                send_response(
-                    &self.client,
-                    msg.reply,
-                    GenericResponse {
-                        success: true,
-                        message: "Verification failed".to_string(),
-                        response: Some(VerificationResponse {
-                            valid: false,
-                            message: HandleError::PasswordResetExpired.to_string(),
-                            needs_password_reset: true,
-                            groups: Default::default(),
-                        }),
-                    },
-                )
-                .await;
-            }
-            Err(err) => {
-                send_error(
-                    &self.client,
-                    msg.reply,
-                    format!("verification failed: {}", err),
-                )
-                .await;
-            }
-        }
+    /// Sets the token that tells this server to shut down. Once it is cancelled, the server
+    /// unsubscribes and returns after finishing the requests it is already handling
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
     }
 
-    #[instrument(level = "debug", skip_all, fields(subject = %msg.subject))]
-    async fn handle_change_password(&self, msg: Message) {
-        let req = deserialize_body::<PasswordChangeRequest>(
-            &self.client,
-            &msg.payload,
-            msg.reply.as_ref(),
+    #[instrument(level = "info", skip(self))]
+    pub async fn run(self) -> anyhow::Result<()> {
+        let api = &self.api;
+        serve(
+            "nats user server",
+            self.subscription,
+            self.max_concurrent_requests,
+            self.shutdown,
+            |msg| api.handle_message(msg),
         )
-        .await;
-        if req.is_err() {
-            // deserialize_body sends the error back for us so we can just return
-            return;
-        }
-        let req = req.unwrap();
-
-        match self
-            .handlers
-            .change_password(&req.username, req.old_password, req.new_password)
-            .await
-        {
-            Ok(_) => {
-                send_response(
-                    &self.client,
-                    msg.reply,
-                    GenericResponse::new(true, "password changed".to_string()),
-                )
-                .await;
-            }
-            Err(err) => {
-                send_error(
-                    &self.client,
-                    msg.reply,
-                    format!("password change failed: {}", err),
-                )
-                .await;
-            }
-        }
+        .await
     }
 }
@@ -0,0 +1,218 @@
+//! Access rules deciding which socket methods a connected process may call, based on the peer
+//! credentials the kernel reports for the connection
+
+use std::collections::BTreeSet;
+
+use serde::Deserialize;
+
+use crate::servers::dispatch::Method;
+
+/// The identity of the process on the other end of a socket connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    /// The process ID of the caller. Not every platform reports this
+    pub pid: Option<i32>,
+}
+
+/// How a caller is allowed to use a method
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// The method can be used for any user
+    Full,
+    /// The method can only be used with the caller's own username. The caller's account is found
+    /// by its UID, so callers whose UID doesn't belong to a SNAS user with POSIX attributes have no
+    /// account of their own and are always denied
+    SelfOnly,
+    /// The method can't be used at all
+    Denied,
+}
+
+/// A method name in an access rule, or `*` for every method
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum MethodPattern {
+    Any,
+    Method(Method),
+}
+
+impl TryFrom<String> for MethodPattern {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value == "*" {
+            return Ok(MethodPattern::Any);
+        }
+        Method::from_name(&value)
+            .map(MethodPattern::Method)
+            .ok_or_else(|| format!("unknown method {value}"))
+    }
+}
+
+impl MethodPattern {
+    fn matches(&self, method: Method) -> bool {
+        match self {
+            MethodPattern::Any => true,
+            MethodPattern::Method(m) => *m == method,
+        }
+    }
+}
+
+/// A single access rule. A rule applies to a caller if its UID is in `uids` or its GID is in
+/// `gids`. A rule with neither applies to every caller
+#[derive(Deserialize, Debug, Clone)]
+pub struct AccessRule {
+    #[serde(default)]
+    pub uids: BTreeSet<u32>,
+    #[serde(default)]
+    pub gids: BTreeSet<u32>,
+    /// The methods this rule allows
+    pub methods: Vec<MethodPattern>,
+    /// Only allow the methods for requests about the caller's own account. The caller's account is
+    /// the SNAS user whose UID matches the caller's UID. Users without POSIX attributes have no UID,
+    /// so they can't use methods that are only allowed this way
+    #[serde(default)]
+    pub self_only: bool,
+}
+
+impl AccessRule {
+    fn applies_to(&self, caller: &Caller) -> bool {
+        (self.uids.is_empty() && self.gids.is_empty())
+            || self.uids.contains(&caller.uid)
+            || self.gids.contains(&caller.gid)
+    }
+}
+
+/// The access rules for the user socket. A caller may use a method if any rule that applies to it
+/// allows the method. If no rule allows it, the request is denied. The rules are loaded from a JSON
+/// file that looks like this:
+///
+/// ```json
+/// {
+///   "rules": [
+///     { "uids": [0], "methods": ["*"] },
+///     { "gids": [900], "methods": ["lookup_user", "lookup_group"] },
+///     { "methods": ["verify", "change_password"], "self_only": true }
+///   ]
+/// }
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+pub struct SocketAccessRules {
+    pub rules: Vec<AccessRule>,
+}
+
+impl Default for SocketAccessRules {
+    /// Root can use every method. Everyone else can use the read only lookups that NSS needs and
+    /// the health check, and can verify, change the password of and fetch their own account
+    fn default() -> Self {
+        let methods = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| {
+                    MethodPattern::try_from(name.to_string()).expect("default methods are valid")
+                })
+                .collect()
+        };
+        SocketAccessRules {
+            rules: vec![
+                AccessRule {
+                    uids: BTreeSet::from([0]),
+                    gids: BTreeSet::new(),
+                    methods: vec![MethodPattern::Any],
+                    self_only: false,
+                },
+                AccessRule {
+                    uids: BTreeSet::new(),
+                    gids: BTreeSet::new(),
+                    methods: methods(&[
+                        "lookup_user",
+                        "lookup_group",
+                        "enumerate_users",
+                        "enumerate_groups",
+                        "user_group_ids",
+                        "health",
+                    ]),
+                    self_only: false,
+                },
+                AccessRule {
+                    uids: BTreeSet::new(),
+                    gids: BTreeSet::new(),
+                    methods: methods(&["verify", "change_password", "get_user"]),
+                    self_only: true,
+                },
+            ],
+        }
+    }
+}
+
+impl SocketAccessRules {
+    /// Returns how the caller may use the given method. Rules that allow the method for any user
+    /// take precedence over ones that only allow it for the caller's own account
+    pub fn access(&self, caller: &Caller, method: Method) -> Access {
+        self.rules
+            .iter()
+            .filter(|rule| {
+                rule.applies_to(caller) && rule.methods.iter().any(|m| m.matches(method))
+            })
+            .fold(Access::Denied, |access, rule| {
+                match (access, rule.self_only) {
+                    (Access::Full, _) | (_, false) => Access::Full,
+                    (_, true) => Access::SelfOnly,
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn caller(uid: u32, gid: u32) -> Caller {
+        Caller {
+            uid,
+            gid,
+            pid: None,
+        }
+    }
+
+    #[test]
+    fn test_default_rules() {
+        let rules = SocketAccessRules::default();
+        let root = caller(0, 0);
+        let user = caller(10000, 10000);
+
+        assert_eq!(rules.access(&root, Method::AddUser), Access::Full);
+        assert_eq!(rules.access(&root, Method::Verify), Access::Full);
+        assert_eq!(rules.access(&user, Method::LookupUser), Access::Full);
+        assert_eq!(rules.access(&user, Method::Health), Access::Full);
+        assert_eq!(rules.access(&user, Method::Verify), Access::SelfOnly);
+        assert_eq!(rules.access(&user, Method::GetUser), Access::SelfOnly);
+        assert_eq!(rules.access(&user, Method::AddUser), Access::Denied);
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules: SocketAccessRules = serde_json::from_str(
+            r#"{
+                "rules": [
+                    { "gids": [900], "methods": ["verify"] },
+                    { "methods": ["verify"], "self_only": true }
+                ]
+            }"#,
+        )
+        .expect("Should parse rules");
+        assert_eq!(rules.access(&caller(1, 900), Method::Verify), Access::Full);
+        assert_eq!(
+            rules.access(&caller(1, 1), Method::Verify),
+            Access::SelfOnly
+        );
+        assert_eq!(
+            rules.access(&caller(0, 0), Method::LookupUser),
+            Access::Denied
+        );
+
+        serde_json::from_str::<SocketAccessRules>(r#"{ "rules": [{ "methods": ["nope"] }] }"#)
+            .expect_err("Unknown methods should be rejected");
+    }
+}
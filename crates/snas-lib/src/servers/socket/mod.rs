@@ -0,0 +1,964 @@
+use std::{
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinSet;
+use tokio::time::error::Elapsed;
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, instrument, trace, warn, Instrument};
+
+use crate::api::{GenericResponse, UserLookupRequest};
+use crate::handlers::Handlers;
+use crate::metrics;
+use crate::servers::dispatch::{Api, Dispatcher};
+use crate::{REQUEST_IDENTIFIER, RESPONSE_IDENTIFIER, TERMINATOR};
+
+pub use access::{Access, AccessRule, Caller, MethodPattern, SocketAccessRules};
+
+mod access;
+
+const MISBEHAVING_LIMIT: usize = 2048;
+/// The APIs served over the socket. Which of their methods a caller can use is up to the access
+/// rules
+const SOCKET_APIS: [Api; 3] = [Api::User, Api::Lookup, Api::Health];
+
+pub struct SocketUserServer {
+    dispatcher: Dispatcher,
+    handlers: Handlers,
+    rules: Arc<SocketAccessRules>,
+    socket: UnixListener,
+    /// The path of the socket file if we created it, so it can be removed on shutdown
+    socket_path: Option<PathBuf>,
+    shutdown: CancellationToken,
+}
+
+/// Ownership and permissions to give the socket file
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    /// The UID to own the socket. Defaults to the user running the server
+    pub owner: Option<u32>,
+    /// The GID to own the socket. Defaults to the primary group of the user running the server
+    pub group: Option<u32>,
+    /// The permission bits of the socket. Connecting requires write permission
+    pub mode: u32,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            owner: None,
+            group: None,
+            mode: 0o700,
+        }
+    }
+}
+
+impl SocketUserServer {
+    /// Creates a new socket server using the default [`SocketOptions`], which only allow the user
+    /// running the server to connect
+    pub async fn new(handlers: Handlers, socket_path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::new_with_options(handlers, socket_path, SocketOptions::default()).await
+    }
+
+    /// Creates a new socket server with the given ownership and permissions for the socket file.
+    /// The parent directory is created if it doesn't exist and must not be world writable
+    pub async fn new_with_options(
+        handlers: Handlers,
+        socket_path: impl AsRef<Path>,
+        options: SocketOptions,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            dispatcher: Dispatcher::new(handlers.clone(), "socket", SOCKET_APIS),
+            handlers,
+            rules: Arc::new(SocketAccessRules::default()),
+            socket: get_socket(&socket_path, &options).await?,
+            socket_path: Some(socket_path.as_ref().to_owned()),
+            shutdown: CancellationToken::new(),
+        })
+    }
+
+    /// Creates a new socket server from a socket that is already listening, such as one passed in
+    /// by systemd socket activation. The ownership and permissions of the socket are left alone
+    pub fn from_listener(
+        handlers: Handlers,
+        listener: std::os::unix::net::UnixListener,
+    ) -> anyhow::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            dispatcher: Dispatcher::new(handlers.clone(), "socket", SOCKET_APIS),
+            handlers,
+            rules: Arc::new(SocketAccessRules::default()),
+            socket: UnixListener::from_std(listener)?,
+            // Whoever created the socket is responsible for cleaning it up
+            socket_path: None,
+            shutdown: CancellationToken::new(),
+        })
+    }
+
+    /// Sets the rules deciding which methods each caller may use. Defaults to
+    /// [`SocketAccessRules::default`]
+    pub fn with_access_rules(mut self, rules: SocketAccessRules) -> Self {
+        self.rules = Arc::new(rules);
+        self
+    }
+
+    /// Sets the token that tells this server to shut down. Once it is cancelled, the server stops
+    /// accepting connections, closes idle ones and returns once every in flight request is done
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        let mut connections = JoinSet::new();
+        let result = loop {
+            let stream = tokio::select! {
+                _ = self.shutdown.cancelled() => break Ok(()),
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                res = self.socket.accept() => match res {
+                    Ok((stream, _)) => stream,
+                    Err(e) => break Err(anyhow::Error::from(e)),
+                },
+            };
+            metrics::record_socket_connection();
+            let caller = match stream.peer_cred() {
+                Ok(cred) => Caller {
+                    uid: cred.uid(),
+                    gid: cred.gid(),
+                    pid: cred.pid(),
+                },
+                Err(e) => {
+                    // Without credentials we can't authorize anything, so just drop the connection
+                    error!(err = %e, "Unable to get peer credentials for socket connection");
+                    continue;
+                }
+            };
+            let span = info_span!(
+                "socket_connection",
+                uid = caller.uid,
+                gid = caller.gid,
+                pid = caller.pid
+            );
+            let handler = SocketHandler {
+                stream: BufReader::new(stream),
+                dispatcher: self.dispatcher.clone(),
+                handlers: self.handlers.clone(),
+                rules: self.rules.clone(),
+                caller,
+                shutdown: self.shutdown.clone(),
+            };
+            connections.spawn(
+                async move {
+                    if let Err(e) = handler.handle().await {
+                        error!("Error handling socket connection: {}", e);
+                    }
+                }
+                .instrument(span),
+            );
+        };
+
+        // Stop listening before cleaning up so nothing new can connect
+        drop(self.socket);
+        if let Some(path) = &self.socket_path {
+            match tokio::fs::remove_file(path).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => error!(err = %e, path = %path.display(), "Unable to remove socket file"),
+            }
+        }
+        if result.is_ok() {
+            info!(
+                connections = connections.len(),
+                "Shutting down socket server"
+            );
+            while connections.join_next().await.is_some() {}
+        } else {
+            // Idle connections only close on shutdown, so there is nothing to wait for here
+            connections.shutdown().await;
+        }
+        result
+    }
+}
+
+/// The part of a request we need to check whether a caller is asking about their own account
+#[derive(Deserialize)]
+struct TargetUser {
+    username: String,
+}
+
+async fn get_socket(
+    socket_path: impl AsRef<Path>,
+    options: &SocketOptions,
+) -> anyhow::Result<UnixListener> {
+    let socket_path = socket_path.as_ref();
+    if options.mode & !0o777 != 0 {
+        anyhow::bail!("Invalid socket mode {:o}", options.mode);
+    }
+    let file_name = socket_path
+        .file_name()
+        .context("Socket path must include a file name")?;
+    let parent = match socket_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(parent) => {
+            ensure_socket_dir(parent).await?;
+            parent
+        }
+        None => Path::new("."),
+    };
+
+    // The socket is created with whatever permissions the umask allows, so it is bound in a
+    // directory only we can get into and only moved into place once its ownership and permissions
+    // are set. That way nobody can connect while it still has looser permissions
+    let staging = parent.join(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    match tokio::fs::remove_dir_all(&staging).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    tokio::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .await
+        .with_context(|| format!("Unable to create directory {}", staging.display()))?;
+    let result = bind_staged(&staging.join(file_name), socket_path, options).await;
+    if let Err(e) = tokio::fs::remove_dir_all(&staging).await {
+        warn!(err = %e, path = %staging.display(), "Unable to remove temporary socket directory");
+    }
+    result
+}
+
+/// Binds the socket at the staging path, sets its ownership and permissions, then moves it to its
+/// final path (replacing any socket left over from a previous run)
+async fn bind_staged(
+    staging_path: &Path,
+    socket_path: &Path,
+    options: &SocketOptions,
+) -> anyhow::Result<UnixListener> {
+    let socket = UnixListener::bind(staging_path)?;
+    if options.owner.is_some() || options.group.is_some() {
+        std::os::unix::fs::chown(staging_path, options.owner, options.group)
+            .context("Unable to set socket ownership")?;
+    }
+    tokio::fs::set_permissions(staging_path, std::fs::Permissions::from_mode(options.mode)).await?;
+    tokio::fs::rename(staging_path, socket_path)
+        .await
+        .with_context(|| format!("Unable to move socket to {}", socket_path.display()))?;
+    Ok(socket)
+}
+
+/// Makes sure the directory the socket lives in exists and can't be tampered with. Anyone who can
+/// write to the directory could replace the socket with their own, so we refuse to use world
+/// writable directories or ones owned by anyone other than root or the user running the server
+async fn ensure_socket_dir(dir: &Path) -> anyhow::Result<()> {
+    let metadata = match tokio::fs::metadata(dir).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            tokio::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o755)
+                .create(dir)
+                .await
+                .with_context(|| format!("Unable to create socket directory {}", dir.display()))?;
+            // The umask may have stripped permissions, but clients need to be able to get into
+            // the directory to reach the socket
+            tokio::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o755)).await?;
+            tokio::fs::metadata(dir).await?
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.is_dir() {
+        anyhow::bail!("Socket parent {} is not a directory", dir.display());
+    }
+    if metadata.permissions().mode() & 0o002 != 0 {
+        anyhow::bail!(
+            "Refusing to create socket in world writable directory {}",
+            dir.display()
+        );
+    }
+    // SAFETY: geteuid has no preconditions and can't fail
+    let euid = unsafe { libc::geteuid() };
+    if metadata.uid() != 0 && metadata.uid() != euid {
+        anyhow::bail!(
+            "Refusing to create socket in directory {} owned by UID {}, which is neither root nor the user running the server",
+            dir.display(),
+            metadata.uid()
+        );
+    }
+    Ok(())
+}
+
+struct SocketHandler {
+    stream: BufReader<UnixStream>,
+    dispatcher: Dispatcher,
+    handlers: Handlers,
+    rules: Arc<SocketAccessRules>,
+    caller: Caller,
+    shutdown: CancellationToken,
+}
+
+impl SocketHandler {
+    #[instrument(level = "trace", skip(self))]
+    async fn handle(mut self) -> anyhow::Result<()> {
+        loop {
+            let parsed = tokio::select! {
+                // Closing the connection while waiting on a request drops any partially written
+                // request, but anything we already read gets a response first
+                _ = self.shutdown.cancelled() => Err(ParseError::ConnectionClosed),
+                res = parse_incoming(&mut self.stream) => res,
+            };
+            let (method, body) = match parsed {
+                Ok(v) => v,
+                Err(ParseError::ConnectionClosed) => {
+                    if let Err(e) = self.stream.shutdown().await {
+                        // This isn't fatal, but we should log
+                        error!(err= %e, "Error shutting down socket cleanly");
+                    }
+                    return Ok(());
+                }
+                Err(ParseError::BadRequest(e)) => {
+                    // We have to consume the leftover here if we have a malformed request, so we
+                    // have a nested block here
+                    match consume_leftover(&mut self.stream).await {
+                        // We don't return bad request from consume_leftover, so we can continue on
+                        Ok(_) | Err(ParseError::BadRequest(_)) => (),
+                        Err(ParseError::ConnectionClosed) => {
+                            if let Err(e) = self.stream.shutdown().await {
+                                // This isn't fatal, but we should log
+                                error!(err= %e, "Error shutting down socket cleanly");
+                            }
+                            return Ok(());
+                        }
+                        Err(ParseError::Error(e)) => {
+                            if let Err(e) = self.stream.shutdown().await {
+                                // This isn't fatal, but we should log
+                                error!(err= %e, "Error shutting down socket cleanly");
+                            }
+                            return Err(e);
+                        }
+                    }
+                    self.send_error(e).await;
+                    continue;
+                }
+                Err(ParseError::Error(e)) => {
+                    if let Err(e) = self.stream.shutdown().await {
+                        // This isn't fatal, but we should log
+                        error!(err= %e, "Error shutting down socket cleanly");
+                    }
+                    return Err(e);
+                }
+            };
+
+            trace!(%method, len=%body.len(), "Received request");
+            if let Err(message) = self.authorize(&method, &body).await {
+                warn!(%method, "Denied socket request");
+                self.send_error(message).await;
+                continue;
+            }
+            let response = self.dispatcher.dispatch(&method, &body).await;
+            self.send_response(response).await;
+        }
+    }
+
+    /// Checks the access rules for the method, returning the message to send back if the caller
+    /// isn't allowed to use it
+    async fn authorize(&self, method: &str, body: &[u8]) -> Result<(), String> {
+        // Unknown methods are left for the dispatcher to reject
+        let Some(parsed) = self.dispatcher.method(method) else {
+            return Ok(());
+        };
+        match self.rules.access(&self.caller, parsed) {
+            Access::Full => Ok(()),
+            Access::SelfOnly => {
+                let target = serde_json::from_slice::<TargetUser>(body).ok();
+                // This is an indexed lookup, so it is cheap enough to do for every request. Doing
+                // it each time means a change to the caller's UID is picked up right away.
+                let own = self
+                    .handlers
+                    .lookup_user(UserLookupRequest::Uid(self.caller.uid))
+                    .await
+                    .ok();
+                match (target, own) {
+                    (Some(target), Some(own)) if target.username == own.name => Ok(()),
+                    _ => Err(format!(
+                        "Not allowed to use {method} for any account other than your own"
+                    )),
+                }
+            }
+            Access::Denied => Err(format!("Not allowed to use {method}")),
+        }
+    }
+
+    async fn send_error(&mut self, message: impl ToString) {
+        // This is more allocations, but results in cleaner code. Highly doubt we need to optimize
+        // here
+        let mut data = RESPONSE_IDENTIFIER.to_vec();
+        if let Err(e) =
+            serde_json::to_writer(&mut data, &GenericResponse::new(false, message.to_string()))
+        {
+            error!(err = %e, "Error serializing error response");
+            return;
+        };
+        data.push(b'\r');
+        data.extend_from_slice(TERMINATOR);
+        if let Err(e) = self.stream.write_all(&data).await {
+            error!(err = %e, "Error sending error response");
+        }
+    }
+
+    async fn send_response<T: Serialize>(&mut self, response: T) {
+        // This is more allocations, but results in cleaner code. Highly doubt we need to optimize
+        // here
+        let mut data = RESPONSE_IDENTIFIER.to_vec();
+        if let Err(e) = serde_json::to_writer(&mut data, &response) {
+            error!(err = %e, "Error serializing response");
+            return;
+        };
+        data.push(b'\r');
+        data.extend_from_slice(TERMINATOR);
+        if let Err(e) = self.stream.write_all(&data).await {
+            error!(err = %e, "Error sending response");
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ParseError {
+    ConnectionClosed,
+    BadRequest(anyhow::Error),
+    Error(anyhow::Error),
+}
+/// Helper function that handles an io read future and handles errors and logging. Returns true if
+/// the handle function should exit or if an error occurred, the error can be returned via try
+async fn perform_read<F>(fut: F) -> Result<(), ParseError>
+where
+    F: std::future::Future<Output = std::io::Result<usize>>,
+{
+    match fut.await {
+        Ok(1..) => Ok(()),
+        Ok(0) => {
+            trace!("Client disconnected");
+            Err(ParseError::ConnectionClosed)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            warn!("Client disconnected due to unexpected EOF");
+            Err(ParseError::ConnectionClosed)
+        }
+        // Anything else probably means the socket is no longer valid so return the error
+        Err(e) => {
+            error!("Error reading from socket: {}", e);
+            Err(ParseError::Error(anyhow::anyhow!(
+                "Error reading from socket: {e}"
+            )))
+        }
+    }
+}
+
+async fn consume_leftover(stream: &mut BufReader<UnixStream>) -> Result<(), ParseError> {
+    // HACK ALERT: For some reason, when we are cleaning up data, particularly large amounts of
+    // garbage data, the buffer on the buf reader will not fill. Don't ask me why. So what we do
+    // instead is we manually consume all of the buffer, returning the amount of data we consumed.
+    // Then we read as much data as possible _directly_ from the socket (see a little further down).
+    // This should ensure that the buffer is empty, the socket is empty, and we can continue.
+    let total_read = if !stream.buffer().is_empty() {
+        let consumed = stream.buffer().len();
+        // Consume the entire buffer
+        stream.consume(consumed);
+        consumed
+    } else {
+        0
+    };
+    if total_read >= MISBEHAVING_LIMIT {
+        // We hit our limit so return without reading anything else
+        return Err(ParseError::Error(anyhow::anyhow!(
+            "Aborting connection due to too much garbage data"
+        )));
+    }
+    // Check how much data is left in the buffer. If it's greater than 2048 (2kb) including the
+    // amount we consumed from the buffer, something is probably wrong or the client is misbehaving
+    let remaining = MISBEHAVING_LIMIT - total_read;
+    let mut buf = vec![0u8; remaining];
+    match tokio::time::timeout(Duration::from_millis(300), stream.get_mut().read(&mut buf)).await {
+        Ok(Ok(0)) => {
+            trace!("Timed out waiting for leftover data from client");
+            Err(ParseError::ConnectionClosed)
+        }
+        Ok(Ok(n)) if n == remaining => {
+            // We filled the buffer so just return a shutdown
+            Err(ParseError::Error(anyhow::anyhow!(
+                "Aborting connection due to too much garbage data"
+            )))
+        }
+        Ok(Ok(n)) => {
+            // We cleared out the rest of the data, so we can return
+            trace!(len = n, "Read leftover data from client");
+            Ok(())
+        }
+        Ok(Err(e)) => Err(ParseError::Error(e.into())),
+        Err(_) => {
+            trace!("Timed out waiting for leftover data from client");
+            Ok(())
+        }
+    }
+}
+
+async fn parse_incoming(
+    stream: &mut BufReader<UnixStream>,
+) -> Result<(String, Vec<u8>), ParseError> {
+    let mut buf = [0u8; REQUEST_IDENTIFIER.len()];
+    // We don't timeout here because this is where we block waiting for a new request
+    perform_read(stream.read_exact(&mut buf)).await?;
+    if buf != REQUEST_IDENTIFIER {
+        return Err(ParseError::BadRequest(anyhow::anyhow!(
+            "Invalid request identifier: {}",
+            String::from_utf8_lossy(&buf)
+        )));
+    }
+    let mut method = Vec::new();
+    match tokio::time::timeout(
+        Duration::from_millis(500),
+        perform_read(stream.read_until(b'\n', &mut method)),
+    )
+    .await
+    {
+        Ok(Ok(_)) => (),
+        Ok(Err(e)) => {
+            return Err(e);
+        }
+        Err(Elapsed { .. }) => {
+            return Err(ParseError::BadRequest(anyhow::anyhow!(
+                "Timed out reading method"
+            )));
+        }
+    }
+    match method.pop() {
+        Some(b'\n') => (),
+        Some(_) => {
+            return Err(ParseError::BadRequest(anyhow::anyhow!(
+                "Method does not end in newline"
+            )));
+        }
+        None => {
+            return Err(ParseError::BadRequest(anyhow::anyhow!("Method was empty")));
+        }
+    }
+    let method = match String::from_utf8(method) {
+        Ok(m) => m,
+        Err(e) => {
+            return Err(ParseError::BadRequest(anyhow::anyhow!(
+                "Error parsing method as string: {e}"
+            )));
+        }
+    };
+
+    let mut body = Vec::new();
+    match tokio::time::timeout(
+        Duration::from_millis(500),
+        perform_read(stream.read_until(b'\r', &mut body)),
+    )
+    .await
+    {
+        Ok(Ok(_)) => (),
+        Ok(Err(e)) => {
+            return Err(e);
+        }
+        Err(Elapsed { .. }) => {
+            return Err(ParseError::BadRequest(anyhow::anyhow!(
+                "Timed out reading body"
+            )));
+        }
+    }
+    // Trim off the trailing \r that comes with the body
+    match body.pop() {
+        Some(b'\r') => (),
+        _ => {
+            return Err(ParseError::BadRequest(anyhow::anyhow!(
+                "Body does not end with carriage return"
+            )));
+        }
+    }
+    // Now make sure we've read to the end of the body
+    let mut buf = [0u8; TERMINATOR.len()];
+    match tokio::time::timeout(
+        Duration::from_millis(500),
+        perform_read(stream.read_exact(&mut buf)),
+    )
+    .await
+    {
+        Ok(Ok(_)) => (),
+        Ok(Err(e)) => {
+            return Err(e);
+        }
+        Err(Elapsed { .. }) => {
+            return Err(ParseError::BadRequest(anyhow::anyhow!(
+                "Timed out reading terminator"
+            )));
+        }
+    }
+    if buf != TERMINATOR {
+        return Err(ParseError::BadRequest(anyhow::anyhow!(
+            "Invalid terminator"
+        )));
+    }
+    Ok((method, body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Debug)]
+    struct TestBody {
+        foo: String,
+        bar: u32,
+    }
+
+    #[tokio::test]
+    async fn test_protocol() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut server = BufReader::new(server);
+
+        // Test a good request first
+        let mut req = REQUEST_IDENTIFIER.to_vec();
+
+        let test_body = TestBody {
+            foo: "hello".to_string(),
+            bar: 123,
+        };
+        req.extend_from_slice("coolmethod\n".as_bytes());
+        let body = serde_json::to_vec(&test_body).unwrap();
+        req.extend(body);
+        req.push(b'\r');
+        req.extend_from_slice(TERMINATOR);
+
+        client.write_all(&req).await.unwrap();
+        let (method, body) = parse_incoming(&mut server)
+            .await
+            .expect("Should be able to parse incoming body");
+        assert_eq!(method, "coolmethod", "Method should be correct");
+        let body: TestBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, test_body, "Body should be correct");
+
+        // Do the same request to make sure it works with a second request
+        client.write_all(&req).await.unwrap();
+        let (method, body) = parse_incoming(&mut server)
+            .await
+            .expect("Should be able to parse incoming body");
+        assert_eq!(method, "coolmethod", "Method should be correct");
+        let body: TestBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, test_body, "Body should be correct");
+
+        // Test closing the connection
+        client.shutdown().await.unwrap();
+        let err = parse_incoming(&mut server)
+            .await
+            .expect_err("Should error with EOF");
+        assert!(
+            matches!(err, ParseError::ConnectionClosed),
+            "Error should be connection closed, got {err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_protocol_bad_requests() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut server = BufReader::new(server);
+
+        // Write some garbage and make sure we get the right error
+        client.write_all(b"garbage").await.unwrap();
+        let err = parse_incoming(&mut server)
+            .await
+            .expect_err("Should error with garbage request");
+        assert!(
+            matches!(err, ParseError::BadRequest(_)),
+            "Should error with garbage request, got {err:?}"
+        );
+
+        consume_leftover(&mut server)
+            .await
+            .expect("Should be able to clean out extra data");
+
+        // Test an interrupted request
+        client.write_all(REQUEST_IDENTIFIER).await.unwrap();
+        let err = parse_incoming(&mut server)
+            .await
+            .expect_err("Should error with interrupted request");
+        assert!(
+            matches!(err, ParseError::BadRequest(_)),
+            "Should error with interrupted request, got {err:?}",
+        );
+
+        consume_leftover(&mut server)
+            .await
+            .expect("Should be able to clean out extra data");
+
+        // Test interrupted after method
+        client.write_all(REQUEST_IDENTIFIER).await.unwrap();
+        client.write_all(b"coolmethod\n").await.unwrap();
+        let err = parse_incoming(&mut server)
+            .await
+            .expect_err("Should error with interrupted request");
+        assert!(
+            matches!(err, ParseError::BadRequest(_)),
+            "Should error with interrupted request, got {err:?}"
+        );
+
+        consume_leftover(&mut server)
+            .await
+            .expect("Should be able to clean out extra data");
+
+        // Test interrupted after body
+        client.write_all(REQUEST_IDENTIFIER).await.unwrap();
+        client.write_all(b"coolmethod\n").await.unwrap();
+        client
+            .write_all(b"{\"foo\": \"hello\", \"bar\": 123}\r")
+            .await
+            .unwrap();
+        let err = parse_incoming(&mut server)
+            .await
+            .expect_err("Should error with interrupted request");
+        assert!(
+            matches!(err, ParseError::BadRequest(_)),
+            "Should error with interrupted request, got {err:?}"
+        );
+
+        consume_leftover(&mut server)
+            .await
+            .expect("Should be able to clean out extra data");
+
+        // Test garbage terminator
+        client.write_all(REQUEST_IDENTIFIER).await.unwrap();
+        client.write_all(b"coolmethod\n").await.unwrap();
+        client
+            .write_all(b"{\"foo\": \"hello\", \"bar\": 123}\r")
+            .await
+            .unwrap();
+        client.write_all(b"garbage").await.unwrap();
+        let err = parse_incoming(&mut server)
+            .await
+            .expect_err("Should error with garbage terminator");
+        assert!(
+            matches!(err, ParseError::BadRequest(_)),
+            "Should error with garbage terminator, got {err:?}"
+        );
+
+        consume_leftover(&mut server)
+            .await
+            .expect("Should be able to clean out extra data");
+
+        // Just for safety sake, let's do a valid request and make sure it can read it properly
+
+        client.write_all(REQUEST_IDENTIFIER).await.unwrap();
+        client.write_all(b"coolmethod\n").await.unwrap();
+        client
+            .write_all(b"{\"foo\": \"hello\", \"bar\": 123}\r")
+            .await
+            .unwrap();
+        client.write_all(TERMINATOR).await.unwrap();
+        let (method, _) = parse_incoming(&mut server)
+            .await
+            .expect("Should be able to handle subsequent valid request");
+        assert_eq!(method, "coolmethod", "Method should be correct");
+
+        // Test non-string method
+        client.write_all(REQUEST_IDENTIFIER).await.unwrap();
+        client.write_all(&[99, 111, 255]).await.unwrap();
+        client.write_u8(b'\n').await.unwrap();
+        client
+            .write_all(b"{\"foo\": \"hello\", \"bar\": 123}\r")
+            .await
+            .unwrap();
+        client.write_all(TERMINATOR).await.unwrap();
+        let err = parse_incoming(&mut server)
+            .await
+            .expect_err("Should error with non-string method");
+        assert!(
+            matches!(err, ParseError::BadRequest(_)),
+            "Should error with non-string method, got {err:?}"
+        );
+
+        // Create a vec of 3000 random bytes to make sure we error on too much data
+        let mut body = Vec::new();
+        body.resize(3000, 12u8);
+
+        client.write_all(&body).await.unwrap();
+
+        let err = consume_leftover(&mut server)
+            .await
+            .expect_err("Should error on consume leftover with too much data");
+        assert!(
+            matches!(err, ParseError::Error(_)),
+            "Should error with the correct type, got {err:?}"
+        )
+    }
+
+    #[tokio::test]
+    async fn test_self_only_access() {
+        use crate::{
+            admin::{PosixAttributesRequest, UserAddRequest},
+            storage::MemoryStore,
+            UserInfo,
+        };
+
+        let store = MemoryStore::new();
+        // Users created before SNAS managed POSIX attributes don't have a UID
+        store
+            .put_user("legacy".to_string(), UserInfo::default())
+            .await
+            .unwrap();
+        let handlers = Handlers::new(store);
+        handlers
+            .add(UserAddRequest {
+                username: "foo".to_string(),
+                password: "supersecure".into(),
+                groups: Default::default(),
+                force_password_change: false,
+                prehashed: false,
+                posix: PosixAttributesRequest {
+                    uid: Some(10000),
+                    ..Default::default()
+                },
+            })
+            .await
+            .unwrap();
+        let handler = |uid: u32| SocketHandler {
+            stream: BufReader::new(UnixStream::pair().unwrap().0),
+            dispatcher: Dispatcher::new(handlers.clone(), "socket", SOCKET_APIS),
+            handlers: handlers.clone(),
+            rules: Arc::new(SocketAccessRules::default()),
+            caller: Caller {
+                uid,
+                gid: uid,
+                pid: None,
+            },
+            shutdown: CancellationToken::new(),
+        };
+        let body = |username: &str| {
+            serde_json::to_vec(&serde_json::json!({ "username": username })).unwrap()
+        };
+
+        let foo = handler(10000);
+        foo.authorize("get_user", &body("foo"))
+            .await
+            .expect("Should be able to get your own account");
+        foo.authorize("get_user", &body("legacy"))
+            .await
+            .expect_err("Should not be able to get another account");
+        foo.authorize("get_user", b"not json")
+            .await
+            .expect_err("Requests without a username should be denied");
+
+        // A caller with a UID that isn't in SNAS has no account, which is also the case for the
+        // UID a user without POSIX attributes logs in with
+        let legacy = handler(20000);
+        legacy
+            .authorize("get_user", &body("legacy"))
+            .await
+            .expect_err("Users without POSIX attributes should never pass self only checks");
+
+        handlers
+            .set_posix_attributes(
+                "legacy",
+                PosixAttributesRequest {
+                    uid: Some(20000),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        legacy
+            .authorize("get_user", &body("legacy"))
+            .await
+            .expect("Should be able to get your own account once it has a UID");
+    }
+
+    #[tokio::test]
+    async fn test_socket_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("run").join("snas").join("user.sock");
+        let options = SocketOptions {
+            mode: 0o660,
+            ..Default::default()
+        };
+        let _socket = get_socket(&socket_path, &options)
+            .await
+            .expect("Should create socket and its parent directory");
+        let dir_mode = std::fs::metadata(socket_path.parent().unwrap())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(dir_mode & 0o777, 0o755);
+        let socket_mode = std::fs::metadata(&socket_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(socket_mode & 0o777, 0o660);
+
+        let open_dir = dir.path().join("open");
+        std::fs::create_dir(&open_dir).unwrap();
+        std::fs::set_permissions(&open_dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+        get_socket(open_dir.join("user.sock"), &SocketOptions::default())
+            .await
+            .expect_err("Should refuse to use a world writable directory");
+
+        // Only root can give a directory away, so this can only be checked when running as root
+        let other_dir = dir.path().join("other");
+        std::fs::create_dir(&other_dir).unwrap();
+        if std::os::unix::fs::chown(&other_dir, Some(12345), None).is_ok() {
+            get_socket(other_dir.join("user.sock"), &SocketOptions::default())
+                .await
+                .expect_err("Should refuse to use a directory owned by another user");
+        }
+
+        // Starting again replaces the old socket and doesn't leave anything else behind
+        let _socket = get_socket(&socket_path, &options)
+            .await
+            .expect("Should replace an existing socket");
+        let entries: Vec<_> = std::fs::read_dir(socket_path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["user.sock"]);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("user.sock");
+        let shutdown = CancellationToken::new();
+        let server = SocketUserServer::new(
+            Handlers::new(crate::storage::MemoryStore::new()),
+            &socket_path,
+        )
+        .await
+        .expect("Should create server")
+        .with_shutdown(shutdown.clone());
+        let handle = tokio::spawn(server.run());
+
+        // An idle connection shouldn't keep the server from shutting down
+        let mut client = UnixStream::connect(&socket_path).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("Server should shut down")
+            .unwrap()
+            .expect("Server should shut down cleanly");
+        assert!(!socket_path.exists(), "Socket file should be removed");
+        let mut buf = Vec::new();
+        assert_eq!(
+            client.read_to_end(&mut buf).await.unwrap(),
+            0,
+            "Idle connection should be closed"
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! An in memory copy of the users and groups in a store, along with indexes that let lookups by
+//! something other than name avoid scanning every user
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{
+    metrics,
+    types::{GroupInfo, UserInfo},
+};
+
+/// Users and groups keyed by name. All changes go through the methods here so the indexes stay in
+/// sync with the data
+#[derive(Default)]
+pub(super) struct Cache {
+    users: HashMap<String, UserInfo>,
+    groups: HashMap<String, GroupInfo>,
+    /// The username for each UID of users with POSIX attributes
+    uids: HashMap<u32, String>,
+    /// The users in each group, keyed by group name. Users can list groups that don't exist, so
+    /// this can have entries that aren't in `groups`
+    members: HashMap<String, BTreeSet<String>>,
+    /// The GID of each user's private group, for users whose GID matches their UID
+    private_groups: BTreeMap<String, u32>,
+    /// How many users (by UID) and groups (by GID) use each ID
+    ids: HashMap<u32, usize>,
+}
+
+impl Cache {
+    pub(super) fn user(&self, name: &str) -> Option<&UserInfo> {
+        self.users.get(name)
+    }
+
+    pub(super) fn user_names(&self) -> Vec<String> {
+        self.users.keys().cloned().collect()
+    }
+
+    /// Returns the name of the user with the given UID
+    pub(super) fn username_for_uid(&self, uid: u32) -> Option<&String> {
+        self.uids.get(&uid)
+    }
+
+    /// Returns the names of the users in the given group
+    pub(super) fn group_members(&self, group: &str) -> Option<&BTreeSet<String>> {
+        self.members.get(group)
+    }
+
+    /// Returns the GID of each user's private group, keyed by username
+    pub(super) fn private_groups(&self) -> &BTreeMap<String, u32> {
+        &self.private_groups
+    }
+
+    /// Returns true if any user has the ID as their UID or any group has it as its GID
+    pub(super) fn id_in_use(&self, id: u32) -> bool {
+        self.ids.contains_key(&id)
+    }
+
+    /// Adds or replaces the user, returning the previous version if there was one
+    pub(super) fn insert_user(&mut self, name: String, info: UserInfo) -> Option<UserInfo> {
+        let previous = self.remove_user(&name);
+        self.index_user(&name, &info);
+        self.users.insert(name, info);
+        previous
+    }
+
+    /// Removes the user, returning it if it existed
+    pub(super) fn remove_user(&mut self, name: &str) -> Option<UserInfo> {
+        let removed = self.users.remove(name);
+        if let Some(removed) = &removed {
+            self.unindex_user(name, removed);
+        }
+        removed
+    }
+
+    pub(super) fn group(&self, name: &str) -> Option<&GroupInfo> {
+        self.groups.get(name)
+    }
+
+    pub(super) fn group_names(&self) -> Vec<String> {
+        self.groups.keys().cloned().collect()
+    }
+
+    /// Adds or replaces the group, returning the previous version if there was one
+    pub(super) fn insert_group(&mut self, name: String, info: GroupInfo) -> Option<GroupInfo> {
+        self.add_id(info.gid);
+        let previous = self.groups.insert(name, info);
+        if let Some(previous) = &previous {
+            self.remove_id(previous.gid);
+        }
+        previous
+    }
+
+    /// Removes the group, returning it if it existed
+    pub(super) fn remove_group(&mut self, name: &str) -> Option<GroupInfo> {
+        let removed = self.groups.remove(name);
+        if let Some(removed) = &removed {
+            self.remove_id(removed.gid);
+        }
+        removed
+    }
+
+    /// Updates the cache size metrics. Called after every change to the cache
+    pub(super) fn record_size(&self) {
+        metrics::record_cache_size(self.users.len(), self.groups.len());
+    }
+
+    fn index_user(&mut self, name: &str, info: &UserInfo) {
+        for group in info.groups.iter() {
+            self.members
+                .entry(group.clone())
+                .or_default()
+                .insert(name.to_owned());
+        }
+        if let Some(posix) = &info.posix {
+            self.uids.insert(posix.uid, name.to_owned());
+            self.add_id(posix.uid);
+            if posix.gid == posix.uid {
+                self.private_groups.insert(name.to_owned(), posix.gid);
+            }
+        }
+    }
+
+    /// Removes the index entries for a user that is being replaced or removed. The UID entry is
+    /// left alone if it points at someone else, which happens when two users were given the same
+    /// UID
+    fn unindex_user(&mut self, name: &str, old: &UserInfo) {
+        for group in old.groups.iter() {
+            if let Some(members) = self.members.get_mut(group) {
+                members.remove(name);
+                if members.is_empty() {
+                    self.members.remove(group);
+                }
+            }
+        }
+        if let Some(posix) = &old.posix {
+            if self.uids.get(&posix.uid).is_some_and(|owner| owner == name) {
+                self.uids.remove(&posix.uid);
+            }
+            self.remove_id(posix.uid);
+            self.private_groups.remove(name);
+        }
+    }
+
+    fn add_id(&mut self, id: u32) {
+        *self.ids.entry(id).or_default() += 1;
+    }
+
+    fn remove_id(&mut self, id: u32) {
+        if let Some(count) = self.ids.get_mut(&id) {
+            *count -= 1;
+            if *count == 0 {
+                self.ids.remove(&id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::PosixAttributes;
+
+    fn user(uid: Option<u32>) -> UserInfo {
+        user_in(uid, &[])
+    }
+
+    fn user_in(uid: Option<u32>, groups: &[&str]) -> UserInfo {
+        UserInfo {
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            posix: uid.map(|uid| PosixAttributes {
+                uid,
+                gid: uid,
+                home: String::new(),
+                shell: String::new(),
+                gecos: String::new(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_uid_index() {
+        let mut cache = Cache::default();
+        cache.insert_user("foo".to_string(), user(Some(1000)));
+        cache.insert_user("bar".to_string(), user(None));
+        assert_eq!(cache.username_for_uid(1000).unwrap(), "foo");
+
+        // Changing the UID moves the index entry
+        cache.insert_user("foo".to_string(), user(Some(1001)));
+        assert!(cache.username_for_uid(1000).is_none());
+        assert_eq!(cache.username_for_uid(1001).unwrap(), "foo");
+
+        // Updating without changing the UID keeps it
+        cache.insert_user("foo".to_string(), user(Some(1001)));
+        assert_eq!(cache.username_for_uid(1001).unwrap(), "foo");
+
+        // A UID given to another user isn't removed when its old owner goes away
+        cache.insert_user("bar".to_string(), user(Some(1001)));
+        cache.remove_user("foo");
+        assert_eq!(cache.username_for_uid(1001).unwrap(), "bar");
+
+        cache.remove_user("bar");
+        assert!(cache.username_for_uid(1001).is_none());
+    }
+    #[test]
+    fn test_group_indexes() {
+        let mut cache = Cache::default();
+        cache.insert_user("foo".to_string(), user_in(Some(1000), &["admins", "users"]));
+        cache.insert_user("bar".to_string(), user_in(None, &["users"]));
+        cache.insert_group(
+            "users".to_string(),
+            GroupInfo {
+                gid: 2000,
+                description: String::new(),
+            },
+        );
+        assert_eq!(
+            cache.group_members("users").unwrap(),
+            &BTreeSet::from(["bar".to_string(), "foo".to_string()])
+        );
+        // Membership is tracked even if the group hasn't been created
+        assert_eq!(
+            cache.group_members("admins").unwrap(),
+            &BTreeSet::from(["foo".to_string()])
+        );
+        assert_eq!(
+            cache.private_groups(),
+            &BTreeMap::from([("foo".to_string(), 1000)])
+        );
+        assert!(cache.id_in_use(1000));
+        assert!(cache.id_in_use(2000));
+
+        // Updating a user moves their memberships
+        cache.insert_user("foo".to_string(), user_in(Some(1001), &["users"]));
+        assert!(cache.group_members("admins").is_none());
+        assert!(!cache.id_in_use(1000));
+        assert!(cache.id_in_use(1001));
+        assert_eq!(
+            cache.private_groups(),
+            &BTreeMap::from([("foo".to_string(), 1001)])
+        );
+
+        // An ID stays in use until nothing has it
+        cache.insert_user("bar".to_string(), user_in(Some(1001), &["users"]));
+        cache.remove_user("foo");
+        assert!(cache.id_in_use(1001));
+        assert_eq!(
+            cache.group_members("users").unwrap(),
+            &BTreeSet::from(["bar".to_string()])
+        );
+        cache.remove_user("bar");
+        assert!(!cache.id_in_use(1001));
+        assert!(cache.group_members("users").is_none());
+        assert!(cache.private_groups().is_empty());
+
+        cache.remove_group("users");
+        assert!(!cache.id_in_use(2000));
+    }
+}
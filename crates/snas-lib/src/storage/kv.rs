@@ -0,0 +1,950 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_nats::{
+    connection::State,
+    jetstream::kv::{CreateErrorKind, Entry, Operation, Store},
+    Client,
+};
+use futures::{future::BoxFuture, stream::BoxStream, StreamExt, TryStreamExt};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, instrument, trace, warn, Instrument};
+
+use crate::{
+    api::HealthCheck,
+    metrics,
+    types::{GroupInfo, UserInfo},
+};
+
+use super::{cache::Cache, CredentialBackend, GROUP_KEY_PREFIX, RESERVED_KEY_PREFIX};
+
+/// What a key in the bucket refers to
+enum KeyKind<'a> {
+    User(&'a str),
+    Group(&'a str),
+    /// Internal bookkeeping (such as ID reservations) that isn't cached
+    Internal,
+}
+
+impl<'a> KeyKind<'a> {
+    fn from_key(key: &'a str) -> Self {
+        if let Some(name) = key.strip_prefix(GROUP_KEY_PREFIX) {
+            KeyKind::Group(name)
+        } else if key.starts_with(RESERVED_KEY_PREFIX) {
+            KeyKind::Internal
+        } else {
+            KeyKind::User(key)
+        }
+    }
+}
+
+/// The longest we wait between attempts to recover the cache watcher
+const MAX_RECOVERY_BACKOFF: Duration = Duration::from_secs(30);
+const MIN_RECOVERY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// A [`CredentialBackend`] backed by a NATS KV bucket. All reads are served from a local cache that
+/// is kept up to date by watching the bucket. If the watcher fails, it is restarted from the last
+/// update it saw. While it is recovering the cache is [stale](CredStore::is_stale).
+pub struct CredStore {
+    store: Store,
+    cache: Arc<RwLock<Cache>>,
+    /// The client the store was created with, used to report the connection state in health checks
+    client: Option<Client>,
+    stale: Arc<AtomicBool>,
+    // REMINDER: If we need to implement clone, then this should be wrapped in a struct that
+    // implements drop rather than implementing drop on this struct.
+    stop_watcher: CancellationToken,
+}
+
+impl Drop for CredStore {
+    fn drop(&mut self) {
+        // Stop the watcher in between updates rather than aborting it part way through one
+        self.stop_watcher.cancel()
+    }
+}
+
+impl CredStore {
+    #[instrument(level = "info", skip_all)]
+    pub async fn new(store: Store) -> anyhow::Result<Self> {
+        // Start the watcher first so we can catch any updates that happen after we query all data
+        let watcher = UpdateSource::watch_all(&store).await?;
+        info!("Fetching initial data for local cache");
+        let (data, revision) = initial_data_fetch(&store).await?;
+        data.record_size();
+        let cache = Arc::new(RwLock::new(data));
+        debug!("Data initialization complete, starting watch");
+
+        let stale = Arc::new(AtomicBool::new(false));
+        metrics::record_cache_stale(false);
+        let stop_watcher = CancellationToken::new();
+        let updater = CacheUpdater {
+            source: Arc::new(store.clone()),
+            cache: cache.clone(),
+            stale: stale.clone(),
+            revision,
+            backoff: MIN_RECOVERY_BACKOFF,
+            catch_up_to: None,
+        };
+        tokio::spawn(
+            updater
+                .run(watcher, stop_watcher.clone())
+                .instrument(tracing::info_span!("cache_updater")),
+        );
+        info!("Cred store initialization complete");
+
+        Ok(Self {
+            store,
+            cache,
+            client: None,
+            stale,
+            stop_watcher,
+        })
+    }
+
+    /// Sets the NATS client the store was created with so its connection state is included in
+    /// [health checks](CredentialBackend::health_checks)
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Returns true if the cache watcher has failed and hasn't recovered yet. While this is true,
+    /// the cache may be missing changes made by other SNAS servers
+    pub fn is_stale(&self) -> bool {
+        self.stale.load(Ordering::Relaxed)
+    }
+
+    pub fn health_checks(&self) -> Vec<HealthCheck> {
+        let mut checks = Vec::with_capacity(2);
+        if let Some(client) = &self.client {
+            checks.push(HealthCheck::new(
+                "nats_connection",
+                matches!(client.connection_state(), State::Connected),
+                "Not connected to NATS",
+            ));
+        }
+        // The initial fetch has always completed by the time we have a store, so the cache is
+        // healthy as long as the watcher is keeping it up to date
+        checks.push(HealthCheck::new(
+            "cache_watcher",
+            !self.is_stale(),
+            "Cache watcher is recovering, the cache may be out of date",
+        ));
+        checks
+    }
+
+    /// Checks if the username exists. This will always check the cache first and then the store to
+    /// ensure that operations such as creating a new user are atomic.
+    #[instrument(level = "trace", skip(self))]
+    pub async fn exists(&self, username: &str) -> anyhow::Result<bool> {
+        if self.cache.read().await.user(username).is_some() {
+            return Ok(true);
+        }
+
+        self.store
+            .get(username)
+            .await
+            .map(|val| val.is_some())
+            .map_err(anyhow::Error::from)
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn get_user(&self, username: &str) -> Option<UserInfo> {
+        self.cache.read().await.user(username).cloned()
+    }
+
+    #[instrument(level = "trace", skip(self, info))]
+    pub async fn put_user(&self, username: String, info: UserInfo) -> anyhow::Result<()> {
+        // Serialize first so we can bail early if there is an error
+        let value = bincode::encode_to_vec(&info, bincode::config::standard())
+            .context("Unable to encode data")?;
+        // To be absolutely safe, fetch the current entry so we can grab its revision number so we
+        // don't collide on an update
+        trace!("Fetching current revision");
+        let revision = self
+            .store
+            .entry(&username)
+            .await
+            .context("Unable to fetch current revision")?
+            .map(|entry| entry.revision)
+            .unwrap_or(0);
+        trace!("Sending data to store");
+        self.store
+            .update(&username, value.into(), revision)
+            .await
+            .context("Unable to update store")?;
+
+        trace!("Updating data in cache after successful store operation");
+        {
+            let mut lock = self.cache.write().await;
+            if lock.insert_user(username, info).is_some() {
+                trace!("Entry was already in cache, updating");
+            } else {
+                trace!("Entry was not in cache, inserting");
+            }
+            lock.record_size();
+        }
+
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn delete_user(&self, username: &str) -> anyhow::Result<()> {
+        trace!("Purging user from store");
+        self.store
+            .purge(username)
+            .await
+            .context("Unable to delete user from store")?;
+        trace!("Purging user from cache after successful store operation");
+        let mut lock = self.cache.write().await;
+        if lock.remove_user(username).is_some() {
+            trace!("User was in cache, removing");
+        } else {
+            trace!("User was not in cache");
+        }
+        lock.record_size();
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn list_users(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.cache.read().await.user_names())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn username_for_uid(&self, uid: u32) -> Option<String> {
+        self.cache.read().await.username_for_uid(uid).cloned()
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn group_members(&self, group: &str) -> BTreeSet<String> {
+        self.cache
+            .read()
+            .await
+            .group_members(group)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn private_groups(&self) -> BTreeMap<String, u32> {
+        self.cache.read().await.private_groups().clone()
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn id_in_use(&self, id: u32) -> bool {
+        self.cache.read().await.id_in_use(id)
+    }
+
+    /// Reserves the ID by creating a key for it, which fails if something else already has it.
+    /// Because this goes straight to the store, it is safe across multiple SNAS servers
+    #[instrument(level = "trace", skip(self))]
+    pub async fn reserve_id(&self, id: u32, owner: &str) -> anyhow::Result<bool> {
+        match self
+            .store
+            .create(id_key(id), owner.to_owned().into_bytes().into())
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == CreateErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(anyhow::Error::from(e).context("Unable to reserve ID")),
+        }
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn release_id(&self, id: u32) -> anyhow::Result<()> {
+        self.store
+            .purge(id_key(id))
+            .await
+            .context("Unable to release ID")
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn get_group(&self, name: &str) -> Option<GroupInfo> {
+        self.cache.read().await.group(name).cloned()
+    }
+
+    /// Creates the group, failing if it already exists. Like [`reserve_id`](Self::reserve_id),
+    /// this goes straight to the store so it is safe across multiple SNAS servers
+    #[instrument(level = "trace", skip(self, info))]
+    pub async fn create_group(&self, name: String, info: GroupInfo) -> anyhow::Result<bool> {
+        let value = bincode::encode_to_vec(&info, bincode::config::standard())
+            .context("Unable to encode data")?;
+        match self.store.create(group_key(&name), value.into()).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == CreateErrorKind::AlreadyExists => return Ok(false),
+            Err(e) => return Err(anyhow::Error::from(e).context("Unable to create group")),
+        }
+        trace!("Adding group to cache after successful store operation");
+        let mut lock = self.cache.write().await;
+        lock.insert_group(name, info);
+        lock.record_size();
+        Ok(true)
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn delete_group(&self, name: &str) -> anyhow::Result<()> {
+        trace!("Purging group from store");
+        self.store
+            .purge(group_key(name))
+            .await
+            .context("Unable to delete group from store")?;
+        trace!("Purging group from cache after successful store operation");
+        let mut lock = self.cache.write().await;
+        lock.remove_group(name);
+        lock.record_size();
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn list_groups(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.cache.read().await.group_names())
+    }
+}
+
+impl CredentialBackend for CredStore {
+    fn exists<'a>(&'a self, username: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(CredStore::exists(self, username))
+    }
+
+    fn get_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Option<UserInfo>> {
+        Box::pin(CredStore::get_user(self, username))
+    }
+
+    fn put_user(&self, username: String, info: UserInfo) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(CredStore::put_user(self, username, info))
+    }
+
+    fn delete_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(CredStore::delete_user(self, username))
+    }
+
+    fn list_users(&self) -> BoxFuture<'_, anyhow::Result<Vec<String>>> {
+        Box::pin(CredStore::list_users(self))
+    }
+
+    fn username_for_uid(&self, uid: u32) -> BoxFuture<'_, Option<String>> {
+        Box::pin(CredStore::username_for_uid(self, uid))
+    }
+
+    fn group_members<'a>(&'a self, group: &'a str) -> BoxFuture<'a, BTreeSet<String>> {
+        Box::pin(CredStore::group_members(self, group))
+    }
+
+    fn private_groups(&self) -> BoxFuture<'_, BTreeMap<String, u32>> {
+        Box::pin(CredStore::private_groups(self))
+    }
+
+    fn id_in_use(&self, id: u32) -> BoxFuture<'_, bool> {
+        Box::pin(CredStore::id_in_use(self, id))
+    }
+
+    fn reserve_id<'a>(&'a self, id: u32, owner: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(CredStore::reserve_id(self, id, owner))
+    }
+
+    fn release_id(&self, id: u32) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(CredStore::release_id(self, id))
+    }
+
+    fn get_group<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Option<GroupInfo>> {
+        Box::pin(CredStore::get_group(self, name))
+    }
+
+    fn create_group(&self, name: String, info: GroupInfo) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(CredStore::create_group(self, name, info))
+    }
+
+    fn delete_group<'a>(&'a self, name: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(CredStore::delete_group(self, name))
+    }
+
+    fn list_groups(&self) -> BoxFuture<'_, anyhow::Result<Vec<String>>> {
+        Box::pin(CredStore::list_groups(self))
+    }
+
+    fn health_checks(&self) -> Vec<HealthCheck> {
+        CredStore::health_checks(self)
+    }
+
+    fn is_stale(&self) -> bool {
+        CredStore::is_stale(self)
+    }
+}
+
+/// A stream of updates from watching the bucket
+type Updates = BoxStream<'static, anyhow::Result<Entry>>;
+
+/// Where the [`CacheUpdater`] gets its data from. Outside of tests, this is always the bucket
+trait UpdateSource: Send + Sync + 'static {
+    /// Returns the first and last revisions currently in the bucket
+    fn revisions(&self) -> BoxFuture<'_, anyhow::Result<(u64, u64)>>;
+
+    /// Watches all keys, starting from the given revision
+    fn watch_from(&self, revision: u64) -> BoxFuture<'_, anyhow::Result<Updates>>;
+
+    /// Watches all keys, starting with their current values
+    fn watch_all(&self) -> BoxFuture<'_, anyhow::Result<Updates>>;
+
+    /// Fetches everything in the bucket. See [`initial_data_fetch`]
+    fn fetch_all(&self) -> BoxFuture<'_, anyhow::Result<(Cache, u64)>>;
+}
+
+impl UpdateSource for Store {
+    fn revisions(&self) -> BoxFuture<'_, anyhow::Result<(u64, u64)>> {
+        Box::pin(async move {
+            let info = self
+                .stream
+                .get_info()
+                .await
+                .context("Unable to get bucket info")?;
+            Ok((info.state.first_sequence, info.state.last_sequence))
+        })
+    }
+
+    fn watch_from(&self, revision: u64) -> BoxFuture<'_, anyhow::Result<Updates>> {
+        Box::pin(async move {
+            let watcher = self
+                .watch_all_from_revision(revision)
+                .await
+                .context("Unable to resume watching the store")?;
+            Ok(watcher.map_err(anyhow::Error::from).boxed())
+        })
+    }
+
+    fn watch_all(&self) -> BoxFuture<'_, anyhow::Result<Updates>> {
+        Box::pin(async move {
+            let watcher = Store::watch_all(self)
+                .await
+                .context("Unable to watch the store")?;
+            Ok(watcher.map_err(anyhow::Error::from).boxed())
+        })
+    }
+
+    fn fetch_all(&self) -> BoxFuture<'_, anyhow::Result<(Cache, u64)>> {
+        Box::pin(initial_data_fetch(self))
+    }
+}
+
+/// Keeps the cache up to date with the bucket. If the watcher fails, it resubscribes with backoff,
+/// resuming from the last revision it applied. If that isn't possible, it refetches everything.
+/// The cache stays stale until it has caught back up with the bucket
+struct CacheUpdater {
+    source: Arc<dyn UpdateSource>,
+    cache: Arc<RwLock<Cache>>,
+    stale: Arc<AtomicBool>,
+    /// The revision of the last update applied to the cache
+    revision: u64,
+    backoff: Duration,
+    /// After resuming the watcher, the last revision that was in the bucket at the time. The
+    /// cache is still missing updates until it has applied this one
+    catch_up_to: Option<u64>,
+}
+
+impl CacheUpdater {
+    async fn run(mut self, mut watcher: Updates, stop: CancellationToken) {
+        loop {
+            let err = tokio::select! {
+                _ = stop.cancelled() => break,
+                err = self.apply_updates(&mut watcher) => err,
+            };
+            error!(%err, "Cache watcher failed, the cache may be out of date until it recovers");
+            self.set_stale(true);
+            watcher = tokio::select! {
+                _ = stop.cancelled() => break,
+                watcher = self.recover() => watcher,
+            };
+            match self.catch_up_to {
+                Some(target) => info!(
+                    revision = self.revision,
+                    target, "Cache watcher resubscribed, catching up on missed updates"
+                ),
+                None => self.mark_recovered(),
+            }
+        }
+        info!("Stopping cache watcher");
+    }
+
+    /// Applies updates from the watcher to the cache until it fails, returning why it failed
+    async fn apply_updates(&mut self, watcher: &mut Updates) -> anyhow::Error {
+        loop {
+            let entry = match watcher.next().await {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => return err.context("Error when receiving next value"),
+                None => return anyhow::anyhow!("Watcher stopped receiving updates"),
+            };
+            metrics::record_watcher_update(entry.created.unix_timestamp_nanos(), entry.delta);
+            self.revision = self.revision.max(entry.revision);
+            // The watcher is working again, so the next failure starts backing off from scratch
+            self.backoff = MIN_RECOVERY_BACKOFF;
+            let seen_current = entry.seen_current;
+            handle_entry(entry, &self.cache).await;
+            if let Some(target) = self.catch_up_to {
+                if seen_current || self.revision >= target {
+                    self.catch_up_to = None;
+                    self.mark_recovered();
+                }
+            }
+        }
+    }
+
+    /// Resubscribes to the bucket, retrying with exponential backoff until it succeeds
+    async fn recover(&mut self) -> Updates {
+        loop {
+            tokio::time::sleep(self.backoff).await;
+            self.backoff = (self.backoff * 2).min(MAX_RECOVERY_BACKOFF);
+            match self.resubscribe().await {
+                Ok(watcher) => return watcher,
+                Err(err) => {
+                    warn!(%err, retry_in = ?self.backoff, "Unable to recover cache watcher");
+                }
+            }
+        }
+    }
+
+    /// Starts a new watcher. If it resumes from the last revision we saw, [`Self::catch_up_to`] is
+    /// set to the revision it has to reach before the cache is up to date again
+    async fn resubscribe(&mut self) -> anyhow::Result<Updates> {
+        self.catch_up_to = None;
+        let (first_revision, last_revision) = self.source.revisions().await?;
+        let next = self.revision + 1;
+        // If updates after the last one we saw have already been removed from the stream (such as
+        // by a max age), or the stream is behind us because the bucket was recreated, we can't be
+        // sure we haven't missed anything
+        if next >= first_revision && self.revision <= last_revision {
+            debug!(revision = next, "Resuming cache watcher");
+            let watcher = self.source.watch_from(next).await?;
+            if last_revision >= next {
+                self.catch_up_to = Some(last_revision);
+            }
+            return Ok(watcher);
+        }
+
+        warn!(
+            revision = self.revision,
+            first_revision,
+            last_revision,
+            "Unable to resume cache watcher from the last revision, refetching all data"
+        );
+        // As on startup, the watcher is started first so nothing that changes during the fetch is
+        // missed. Once the fetch is done the cache is up to date
+        let watcher = self.source.watch_all().await?;
+        let (data, revision) = self.source.fetch_all().await?;
+        let mut lock = self.cache.write().await;
+        *lock = data;
+        lock.record_size();
+        self.revision = revision;
+        Ok(watcher)
+    }
+
+    fn mark_recovered(&self) {
+        self.set_stale(false);
+        info!(revision = self.revision, "Cache watcher recovered");
+    }
+
+    fn set_stale(&self, stale: bool) {
+        self.stale.store(stale, Ordering::Relaxed);
+        metrics::record_cache_stale(stale);
+    }
+}
+
+fn id_key(id: u32) -> String {
+    format!("{RESERVED_KEY_PREFIX}id.{id}")
+}
+
+fn group_key(name: &str) -> String {
+    format!("{GROUP_KEY_PREFIX}{name}")
+}
+
+/// Fetches everything in the bucket. Returns the data along with the highest revision that was seen,
+/// which is where watching for updates can pick up from
+async fn initial_data_fetch(store: &Store) -> anyhow::Result<(Cache, u64)> {
+    let keys = store
+        .keys()
+        .await
+        .context("Unable to get keys from store")?;
+    let futs = keys
+        // Skip internal bookkeeping keys as they aren't cached
+        .try_filter(|k| futures::future::ready(!matches!(KeyKind::from_key(k), KeyKind::Internal)))
+        .map_ok(|k| store.entry(k))
+        .try_collect::<Vec<_>>()
+        .await
+        .context("Unable to get keys from store")?;
+    let entries = futures::future::join_all(futs)
+        .await
+        .into_iter()
+        .filter_map(|res| res.transpose())
+        // Keep any that are an error (so we can handle it) or that are puts. Any that are deletes or purges we don't care about
+        .filter(|res| {
+            res.as_ref()
+                .map(|entry| matches!(entry.operation, Operation::Put))
+                .unwrap_or(true)
+        });
+    let mut cache = Cache::default();
+    let mut revision = 0;
+    for res in entries {
+        let entry = res.context("Unable to get values from store")?;
+        revision = revision.max(entry.revision);
+        match KeyKind::from_key(&entry.key) {
+            KeyKind::User(name) => {
+                let data = decode(&entry.value).context("Unable to decode data from store")?;
+                cache.insert_user(name.to_owned(), data);
+            }
+            KeyKind::Group(name) => {
+                let data = decode(&entry.value).context("Unable to decode data from store")?;
+                cache.insert_group(name.to_owned(), data);
+            }
+            KeyKind::Internal => {}
+        }
+    }
+    Ok((cache, revision))
+}
+
+fn decode<T: bincode::Decode>(value: &[u8]) -> Result<T, bincode::error::DecodeError> {
+    bincode::decode_from_slice(value, bincode::config::standard()).map(|(data, _)| data)
+}
+
+#[instrument(level = "debug", skip_all, fields(key = %entry.key, operation = ?entry.operation))]
+async fn handle_entry(entry: Entry, cache: &Arc<RwLock<Cache>>) {
+    match KeyKind::from_key(&entry.key) {
+        KeyKind::User(name) => {
+            let mut lock = cache.write().await;
+            match decode_update(name, &entry) {
+                Update::Put(info) => {
+                    lock.insert_user(name.to_owned(), info);
+                }
+                Update::Remove => {
+                    lock.remove_user(name);
+                }
+                Update::Invalid => {}
+            }
+            lock.record_size();
+        }
+        KeyKind::Group(name) => {
+            let mut lock = cache.write().await;
+            match decode_update(name, &entry) {
+                Update::Put(info) => {
+                    lock.insert_group(name.to_owned(), info);
+                }
+                Update::Remove => {
+                    lock.remove_group(name);
+                }
+                Update::Invalid => {}
+            }
+            lock.record_size();
+        }
+        KeyKind::Internal => trace!("Skipping internal key"),
+    }
+}
+
+/// A change received from the watcher
+enum Update<T> {
+    Put(T),
+    Remove,
+    /// The entry couldn't be decoded, so it is skipped
+    Invalid,
+}
+
+fn decode_update<T: bincode::Decode>(name: &str, entry: &Entry) -> Update<T> {
+    match entry.operation {
+        Operation::Delete | Operation::Purge => {
+            trace!(%name, "Removing entry");
+            Update::Remove
+        }
+        Operation::Put => match decode(&entry.value) {
+            Ok(data) => {
+                trace!(%name, "Adding entry information");
+                Update::Put(data)
+            }
+            Err(err) => {
+                error!(%err, "Unable to decode entry received from store");
+                Update::Invalid
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::VecDeque, sync::Mutex};
+
+    use futures::channel::mpsc::{unbounded, UnboundedSender};
+
+    use super::*;
+
+    /// An [`UpdateSource`] that hands out the results it was given and records what was called
+    #[derive(Default)]
+    struct FakeSource {
+        revisions: Mutex<VecDeque<anyhow::Result<(u64, u64)>>>,
+        watchers: Mutex<VecDeque<Updates>>,
+        data: Mutex<Option<(Cache, u64)>>,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl FakeSource {
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn push_watcher(&self) -> UnboundedSender<anyhow::Result<Entry>> {
+            let (tx, rx) = unbounded();
+            self.watchers.lock().unwrap().push_back(rx.boxed());
+            tx
+        }
+
+        fn next_watcher(&self) -> anyhow::Result<Updates> {
+            self.watchers
+                .lock()
+                .unwrap()
+                .pop_front()
+                .context("No more watchers")
+        }
+    }
+
+    impl UpdateSource for FakeSource {
+        fn revisions(&self) -> BoxFuture<'_, anyhow::Result<(u64, u64)>> {
+            self.record("revisions".to_string());
+            let res = self
+                .revisions
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(|| Err(anyhow::anyhow!("No more revisions")));
+            Box::pin(futures::future::ready(res))
+        }
+
+        fn watch_from(&self, revision: u64) -> BoxFuture<'_, anyhow::Result<Updates>> {
+            self.record(format!("watch_from {revision}"));
+            Box::pin(futures::future::ready(self.next_watcher()))
+        }
+
+        fn watch_all(&self) -> BoxFuture<'_, anyhow::Result<Updates>> {
+            self.record("watch_all".to_string());
+            Box::pin(futures::future::ready(self.next_watcher()))
+        }
+
+        fn fetch_all(&self) -> BoxFuture<'_, anyhow::Result<(Cache, u64)>> {
+            self.record("fetch_all".to_string());
+            let res = self.data.lock().unwrap().take().context("No data");
+            Box::pin(futures::future::ready(res))
+        }
+    }
+
+    fn put(key: &str, revision: u64, seen_current: bool) -> Entry {
+        Entry {
+            bucket: "test".to_string(),
+            key: key.to_string(),
+            value: bincode::encode_to_vec(UserInfo::default(), bincode::config::standard())
+                .unwrap()
+                .into(),
+            revision,
+            delta: 0,
+            created: time::OffsetDateTime::now_utc(),
+            operation: Operation::Put,
+            seen_current,
+        }
+    }
+
+    struct Harness {
+        source: Arc<FakeSource>,
+        cache: Arc<RwLock<Cache>>,
+        stale: Arc<AtomicBool>,
+        stop: CancellationToken,
+    }
+
+    impl Harness {
+        /// Starts an updater that has applied everything up to the given revision
+        fn start(
+            source: FakeSource,
+            revision: u64,
+        ) -> (Harness, UnboundedSender<anyhow::Result<Entry>>) {
+            let source = Arc::new(source);
+            let (tx, rx) = unbounded();
+            let harness = Harness {
+                source: source.clone(),
+                cache: Arc::default(),
+                stale: Arc::default(),
+                stop: CancellationToken::new(),
+            };
+            let updater = CacheUpdater {
+                source,
+                cache: harness.cache.clone(),
+                stale: harness.stale.clone(),
+                revision,
+                backoff: MIN_RECOVERY_BACKOFF,
+                catch_up_to: None,
+            };
+            tokio::spawn(updater.run(rx.boxed(), harness.stop.clone()));
+            (harness, tx)
+        }
+
+        fn is_stale(&self) -> bool {
+            self.stale.load(Ordering::Relaxed)
+        }
+
+        async fn has_user(&self, name: &str) -> bool {
+            self.cache.read().await.user(name).is_some()
+        }
+
+        /// Waits until the condition is true, failing the test if it takes too long
+        async fn wait_for(&self, what: &str, condition: impl Fn(&Harness) -> bool) {
+            for _ in 0..1000 {
+                if condition(self) {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("Timed out waiting for {what}");
+        }
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            self.stop.cancel();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resume_after_stream_end() {
+        let source = FakeSource::default();
+        source.revisions.lock().unwrap().push_back(Ok((1, 4)));
+        let resumed = source.push_watcher();
+        let (harness, watcher) = Harness::start(source, 0);
+
+        watcher.unbounded_send(Ok(put("foo", 1, true))).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(harness.has_user("foo").await);
+        assert!(!harness.is_stale());
+        drop(watcher);
+        harness
+            .wait_for("resubscribe", |h| h.source.calls().len() == 2)
+            .await;
+        assert_eq!(harness.source.calls(), ["revisions", "watch_from 2"]);
+        assert!(
+            harness.is_stale(),
+            "Cache should be stale until it catches up"
+        );
+
+        // Updates that were missed while the watcher was down are replayed
+        resumed.unbounded_send(Ok(put("bar", 3, false))).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(harness.has_user("bar").await);
+        assert!(
+            harness.is_stale(),
+            "Cache should be stale until it reaches the last revision"
+        );
+        resumed.unbounded_send(Ok(put("baz", 4, true))).unwrap();
+        harness.wait_for("catch up", |h| !h.is_stale()).await;
+        assert!(harness.has_user("foo").await);
+        assert!(harness.has_user("baz").await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resume_with_nothing_missed() {
+        let source = FakeSource::default();
+        source.revisions.lock().unwrap().push_back(Ok((1, 1)));
+        let _resumed = source.push_watcher();
+        let (harness, watcher) = Harness::start(source, 1);
+
+        watcher
+            .unbounded_send(Err(anyhow::anyhow!("connection lost")))
+            .unwrap();
+        harness
+            .wait_for("recovery", |h| h.source.calls().len() == 2)
+            .await;
+        assert_eq!(harness.source.calls(), ["revisions", "watch_from 2"]);
+        harness
+            .wait_for("cache to be fresh", |h| !h.is_stale())
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refetch_after_error() {
+        let source = FakeSource::default();
+        // The updates after revision 2 were removed from the stream, so we can't resume
+        source.revisions.lock().unwrap().push_back(Ok((5, 10)));
+        let _resumed = source.push_watcher();
+        let mut data = Cache::default();
+        data.insert_user("bar".to_string(), UserInfo::default());
+        *source.data.lock().unwrap() = Some((data, 10));
+        let (harness, watcher) = Harness::start(source, 2);
+        harness
+            .cache
+            .write()
+            .await
+            .insert_user("foo".to_string(), UserInfo::default());
+
+        watcher
+            .unbounded_send(Err(anyhow::anyhow!("connection lost")))
+            .unwrap();
+        harness
+            .wait_for("refetch", |h| h.source.calls().len() == 3)
+            .await;
+        assert_eq!(
+            harness.source.calls(),
+            ["revisions", "watch_all", "fetch_all"],
+            "The watcher should be started before fetching data"
+        );
+        harness
+            .wait_for("cache to be fresh", |h| !h.is_stale())
+            .await;
+        assert!(harness.has_user("bar").await, "Cache should be replaced");
+        assert!(!harness.has_user("foo").await, "Cache should be replaced");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recovery_backoff() {
+        let source = FakeSource::default();
+        {
+            let mut revisions = source.revisions.lock().unwrap();
+            revisions.push_back(Err(anyhow::anyhow!("no responders")));
+            revisions.push_back(Err(anyhow::anyhow!("no responders")));
+            revisions.push_back(Ok((1, 1)));
+        }
+        let resumed = source.push_watcher();
+        let (harness, watcher) = Harness::start(source, 1);
+
+        let start = tokio::time::Instant::now();
+        drop(watcher);
+        harness.wait_for("stale", |h| h.is_stale()).await;
+        harness.wait_for("recovery", |h| !h.is_stale()).await;
+        let elapsed = start.elapsed();
+        assert_eq!(harness.source.calls().len(), 4, "Should have retried twice");
+        // 250ms, then 500ms, then 1s
+        assert!(
+            elapsed >= Duration::from_millis(1750) && elapsed < Duration::from_secs(2),
+            "Should back off exponentially between attempts, took {elapsed:?}"
+        );
+
+        // Once the watcher is working again, the backoff starts over
+        harness
+            .source
+            .revisions
+            .lock()
+            .unwrap()
+            .push_back(Ok((1, 2)));
+        let _resumed_again = harness.source.push_watcher();
+        resumed.unbounded_send(Ok(put("foo", 2, true))).unwrap();
+        drop(resumed);
+        let start = tokio::time::Instant::now();
+        harness.wait_for("stale", |h| h.is_stale()).await;
+        harness.wait_for("recovery", |h| !h.is_stale()).await;
+        assert!(
+            start.elapsed() < Duration::from_millis(500),
+            "Backoff should be reset after a successful update"
+        );
+        assert_eq!(harness.source.calls().last().unwrap(), "watch_from 3");
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use futures::future::BoxFuture;
+use tokio::sync::RwLock;
+use tracing::instrument;
+
+use crate::types::{GroupInfo, UserInfo};
+
+use super::{cache::Cache, CredentialBackend};
+
+/// A simple in-memory credential store. Data is not persisted or shared between instances, so this
+/// is only meant for testing or for embedding SNAS somewhere that doesn't need a NATS server.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: RwLock<Cache>,
+    ids: RwLock<HashMap<u32, String>>,
+}
+
+impl MemoryStore {
+    /// Creates a new, empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn exists(&self, username: &str) -> anyhow::Result<bool> {
+        Ok(self.data.read().await.user(username).is_some())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn get_user(&self, username: &str) -> Option<UserInfo> {
+        self.data.read().await.user(username).cloned()
+    }
+
+    #[instrument(level = "trace", skip(self, info))]
+    pub async fn put_user(&self, username: String, info: UserInfo) -> anyhow::Result<()> {
+        self.data.write().await.insert_user(username, info);
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn delete_user(&self, username: &str) -> anyhow::Result<()> {
+        self.data.write().await.remove_user(username);
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn list_users(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.data.read().await.user_names())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn username_for_uid(&self, uid: u32) -> Option<String> {
+        self.data.read().await.username_for_uid(uid).cloned()
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn group_members(&self, group: &str) -> BTreeSet<String> {
+        self.data
+            .read()
+            .await
+            .group_members(group)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn private_groups(&self) -> BTreeMap<String, u32> {
+        self.data.read().await.private_groups().clone()
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn id_in_use(&self, id: u32) -> bool {
+        self.data.read().await.id_in_use(id)
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn reserve_id(&self, id: u32, owner: &str) -> anyhow::Result<bool> {
+        let mut ids = self.ids.write().await;
+        if ids.contains_key(&id) {
+            return Ok(false);
+        }
+        ids.insert(id, owner.to_owned());
+        Ok(true)
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn release_id(&self, id: u32) -> anyhow::Result<()> {
+        self.ids.write().await.remove(&id);
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn get_group(&self, name: &str) -> Option<GroupInfo> {
+        self.data.read().await.group(name).cloned()
+    }
+
+    #[instrument(level = "trace", skip(self, info))]
+    pub async fn create_group(&self, name: String, info: GroupInfo) -> anyhow::Result<bool> {
+        let mut data = self.data.write().await;
+        if data.group(&name).is_some() {
+            return Ok(false);
+        }
+        data.insert_group(name, info);
+        Ok(true)
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn delete_group(&self, name: &str) -> anyhow::Result<()> {
+        self.data.write().await.remove_group(name);
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    pub async fn list_groups(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.data.read().await.group_names())
+    }
+}
+
+impl CredentialBackend for MemoryStore {
+    fn exists<'a>(&'a self, username: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(MemoryStore::exists(self, username))
+    }
+
+    fn get_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Option<UserInfo>> {
+        Box::pin(MemoryStore::get_user(self, username))
+    }
+
+    fn put_user(&self, username: String, info: UserInfo) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(MemoryStore::put_user(self, username, info))
+    }
+
+    fn delete_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(MemoryStore::delete_user(self, username))
+    }
+
+    fn list_users(&self) -> BoxFuture<'_, anyhow::Result<Vec<String>>> {
+        Box::pin(MemoryStore::list_users(self))
+    }
+
+    fn username_for_uid(&self, uid: u32) -> BoxFuture<'_, Option<String>> {
+        Box::pin(MemoryStore::username_for_uid(self, uid))
+    }
+
+    fn group_members<'a>(&'a self, group: &'a str) -> BoxFuture<'a, BTreeSet<String>> {
+        Box::pin(MemoryStore::group_members(self, group))
+    }
+
+    fn private_groups(&self) -> BoxFuture<'_, BTreeMap<String, u32>> {
+        Box::pin(MemoryStore::private_groups(self))
+    }
+
+    fn id_in_use(&self, id: u32) -> BoxFuture<'_, bool> {
+        Box::pin(MemoryStore::id_in_use(self, id))
+    }
+
+    fn reserve_id<'a>(&'a self, id: u32, owner: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(MemoryStore::reserve_id(self, id, owner))
+    }
+
+    fn release_id(&self, id: u32) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(MemoryStore::release_id(self, id))
+    }
+
+    fn get_group<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Option<GroupInfo>> {
+        Box::pin(MemoryStore::get_group(self, name))
+    }
+
+    fn create_group(&self, name: String, info: GroupInfo) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(MemoryStore::create_group(self, name, info))
+    }
+
+    fn delete_group<'a>(&'a self, name: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(MemoryStore::delete_group(self, name))
+    }
+
+    fn list_groups(&self) -> BoxFuture<'_, anyhow::Result<Vec<String>>> {
+        Box::pin(MemoryStore::list_groups(self))
+    }
+}
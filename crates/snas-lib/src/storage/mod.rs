@@ -0,0 +1,98 @@
+//! Storage for user and group data. [`Handlers`](crate::handlers::Handlers) work with any type that
+//! implements [`CredentialBackend`], which allows for swapping out the NATS KV backed
+//! [`CredStore`] for something like the [`MemoryStore`] when running tests or embedding SNAS.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use futures::future::BoxFuture;
+
+use crate::{
+    api::HealthCheck,
+    types::{GroupInfo, UserInfo},
+};
+
+mod cache;
+mod kv;
+mod memory;
+
+pub use kv::CredStore;
+pub use memory::MemoryStore;
+
+/// Keys starting with this prefix are used for internal bookkeeping (such as ID reservations) and
+/// are never valid usernames
+pub const RESERVED_KEY_PREFIX: &str = "_snas.";
+/// Groups are stored under this prefix (followed by the group name)
+pub const GROUP_KEY_PREFIX: &str = "_snas.group.";
+
+/// A backend for storing and fetching user credentials.
+///
+/// The methods on this trait return boxed futures so that the backend can be used as a trait
+/// object.
+pub trait CredentialBackend: Send + Sync + 'static {
+    /// Checks if the username exists. Implementations should make sure this is consistent with the
+    /// underlying store so that operations such as creating a new user are atomic.
+    fn exists<'a>(&'a self, username: &'a str) -> BoxFuture<'a, anyhow::Result<bool>>;
+
+    /// Gets the user with the given username. Returns `None` if the user does not exist.
+    fn get_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Option<UserInfo>>;
+
+    /// Creates or updates the user with the given username.
+    fn put_user(&self, username: String, info: UserInfo) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    /// Deletes the user with the given username.
+    fn delete_user<'a>(&'a self, username: &'a str) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Lists all usernames.
+    fn list_users(&self) -> BoxFuture<'_, anyhow::Result<Vec<String>>>;
+
+    /// Gets the name of the user with the given UID. Returns `None` if no user has POSIX attributes
+    /// with that UID. Implementations should index users by UID rather than scanning all of them,
+    /// as this is called to authorize socket requests
+    fn username_for_uid(&self, uid: u32) -> BoxFuture<'_, Option<String>>;
+
+    /// Gets the names of the users in the given group, whether or not the group exists.
+    /// Implementations should keep an index of group membership rather than scanning all users.
+    fn group_members<'a>(&'a self, group: &'a str) -> BoxFuture<'a, BTreeSet<String>>;
+
+    /// Gets the GID of each user's private group (for users whose GID matches their UID), keyed by
+    /// username.
+    fn private_groups(&self) -> BoxFuture<'_, BTreeMap<String, u32>>;
+
+    /// Checks whether any user has the given ID as their UID or any group has it as its GID. This
+    /// is only a hint for picking IDs to try, [`reserve_id`](Self::reserve_id) is what guarantees
+    /// an ID isn't handed out twice.
+    fn id_in_use(&self, id: u32) -> BoxFuture<'_, bool>;
+
+    /// Atomically reserves the given ID for the given owner (a username or group name). UIDs and
+    /// group GIDs share a single ID space because each user's private group uses their UID as its
+    /// GID. Returns `false` if the ID is already reserved.
+    fn reserve_id<'a>(&'a self, id: u32, owner: &'a str) -> BoxFuture<'a, anyhow::Result<bool>>;
+
+    /// Releases an ID reservation so that it can be allocated again.
+    fn release_id(&self, id: u32) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    /// Gets the group with the given name. Returns `None` if the group does not exist.
+    fn get_group<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Option<GroupInfo>>;
+
+    /// Atomically creates the group with the given name. Returns `false` if the group already
+    /// exists.
+    fn create_group(&self, name: String, info: GroupInfo) -> BoxFuture<'_, anyhow::Result<bool>>;
+
+    /// Deletes the group with the given name.
+    fn delete_group<'a>(&'a self, name: &'a str) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Lists all group names.
+    fn list_groups(&self) -> BoxFuture<'_, anyhow::Result<Vec<String>>>;
+
+    /// Checks the state of anything the backend depends on, such as its connection to a remote
+    /// store. Backends without any dependencies return no checks, which counts as healthy.
+    fn health_checks(&self) -> Vec<HealthCheck> {
+        Vec::new()
+    }
+
+    /// Returns true if the backend's data may be out of date, such as when a cache has lost track
+    /// of changes to the underlying store. Backends that are always up to date return false.
+    fn is_stale(&self) -> bool {
+        false
+    }
+}
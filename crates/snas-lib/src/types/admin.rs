@@ -2,7 +2,7 @@ use std::{collections::BTreeSet, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{types::SecureString, PasswordResetPhase};
+use crate::{types::SecureString, PasswordResetPhase, PosixAttributes};
 
 /// A request to create a new user with the given password and groups. This is for admin use only as
 /// users should not be able to create new groups
@@ -12,6 +12,15 @@ pub struct UserAddRequest {
     pub password: SecureString,
     pub groups: BTreeSet<String>,
     pub force_password_change: bool,
+    /// The password is an existing hash to import rather than a plain text password. Supported
+    /// formats are sha512-crypt (`$6$`), sha256-crypt (`$5$`), bcrypt (`$2b$`) and Argon2 PHC
+    /// strings. The password policy can't be checked for these, and they are converted to the
+    /// configured Argon2 format the next time the user logs in
+    #[serde(default)]
+    pub prehashed: bool,
+    /// POSIX attributes to give the user. Anything not set will use the server defaults
+    #[serde(default)]
+    pub posix: PosixAttributesRequest,
 }
 
 /// A request to get a specific user
@@ -32,6 +41,43 @@ pub struct UserResponse {
     pub username: String,
     pub groups: BTreeSet<String>,
     pub password_change_phase: Option<PasswordResetPhase>,
+    /// If set, the user is locked out until the given time (as measured in seconds since the unix
+    /// epoch)
+    #[serde(default)]
+    pub locked_until: Option<Duration>,
+    #[serde(default)]
+    pub posix: Option<PosixAttributes>,
+}
+
+/// POSIX attributes to set on a user. Any attribute that is not set is left unchanged (or uses the
+/// server default if the user doesn't have POSIX attributes yet). If no UID is given for a user
+/// without one, the server will allocate one
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PosixAttributesRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gecos: Option<String>,
+}
+
+/// A request to set the POSIX attributes of a user
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetAttributesRequest {
+    pub username: String,
+    #[serde(flatten)]
+    pub attributes: PosixAttributesRequest,
+}
+
+/// A request to unlock a user that was locked out due to failed login attempts
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserUnlockRequest {
+    pub username: String,
 }
 
 /// A request to add groups to a user
@@ -41,15 +87,148 @@ pub struct GroupModifyRequest {
     pub groups: BTreeSet<String>,
 }
 
+/// A request to create a new group
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupAddRequest {
+    pub name: String,
+    /// The GID for the group. If not set, the server will allocate one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// A request to get a specific group
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupGetRequest {
+    pub name: String,
+}
+
+/// A request to delete a group
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupDeleteRequest {
+    pub name: String,
+}
+
+/// A request to list the members of a group
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupMembersRequest {
+    pub name: String,
+}
+
+/// A group object returned in get requests
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupResponse {
+    pub name: String,
+    pub gid: u32,
+    pub description: String,
+    pub members: BTreeSet<String>,
+}
+
 /// A request to reset a user's password
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PasswordResetRequest {
     pub username: String,
 }
 
+/// A request to set a user's password to the given one without going through the reset flow
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetPasswordRequest {
+    pub username: String,
+    pub password: SecureString,
+    /// Make the user change the password the next time they log in. Otherwise any password reset
+    /// in progress is cleared
+    #[serde(default)]
+    pub force_password_change: bool,
+}
+
 /// Response for a password reset. Will contain a randomly generated token used for logging in
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PasswordResetResponse {
     pub temp_password: SecureString,
     pub expires_at: Duration,
 }
+
+/// A user with everything needed to recreate them on another server, including their password
+/// hash. Used when exporting and importing the directory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserRecord {
+    pub username: String,
+    /// The password hash. Any format that can be [imported](UserAddRequest::prehashed) is accepted.
+    /// A new user without one gets a random password and is locked until an admin resets it, while
+    /// an overwritten user keeps their current password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<SecureString>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_change_phase: Option<PasswordResetPhase>,
+    #[serde(default)]
+    pub groups: BTreeSet<String>,
+    /// POSIX attributes for the user. A new user without them gets the server defaults, while an
+    /// overwritten user keeps their current ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub posix: Option<PosixAttributes>,
+}
+
+/// A group with everything needed to recreate it on another server. Membership is part of each
+/// [`UserRecord`]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupRecord {
+    pub name: String,
+    pub gid: u32,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// Every user and group in the directory
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Directory {
+    #[serde(default)]
+    pub users: Vec<UserRecord>,
+    #[serde(default)]
+    pub groups: Vec<GroupRecord>,
+}
+
+/// What to do when an imported user or group already exists
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Leave the existing entry as it is
+    Skip,
+    /// Replace the existing entry with the imported one
+    Overwrite,
+    /// Don't import anything if any entry already exists
+    #[default]
+    Fail,
+}
+
+/// A request to import users and groups into the directory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportRequest {
+    #[serde(flatten)]
+    pub directory: Directory,
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+    /// Check the import and report what would happen without changing anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// The result of an import. For a dry run, this is what would have happened
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ImportSummary {
+    pub dry_run: bool,
+    pub users: ImportCounts,
+    pub groups: ImportCounts,
+    /// Why each failed entry couldn't be imported
+    #[serde(default)]
+    pub errors: Vec<String>,
+}
+
+/// The number of entries of one kind with each outcome of an import
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportCounts {
+    pub created: usize,
+    pub overwritten: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
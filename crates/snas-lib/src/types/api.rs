@@ -2,7 +2,7 @@ use std::collections::BTreeSet;
 
 use serde::{Deserialize, Serialize};
 
-use crate::types::SecureString;
+use crate::types::{PosixAttributes, SecureString};
 
 /// A generic response reused for many different requests
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -14,6 +14,38 @@ pub struct GenericResponse<T: 'static> {
     /// The response data, if any
     #[serde(skip_serializing_if = "Option::is_none")]
     pub response: Option<T>,
+    /// Why the request failed, for failures that clients may want to handle differently. This is
+    /// only set for failed responses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorKind>,
+}
+
+/// A machine readable reason for a failed request, so clients don't have to parse messages
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The username or password was wrong
+    InvalidCredentials,
+    /// The account is locked out after too many failed login attempts
+    AccountLocked,
+    /// The password was reset and the reset has expired
+    PasswordResetExpired,
+    /// The new password doesn't satisfy the password policy
+    PasswordPolicy,
+    /// The requested user or group doesn't exist
+    NotFound,
+    /// A kind of failure added in a newer version of the server
+    #[serde(other)]
+    Other,
+}
+
+/// The error returned for a failed [`GenericResponse`]. Clients return this wrapped in an
+/// [`anyhow::Error`], so use `downcast_ref` to check the kind of failure
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{message}")]
+pub struct ResponseError {
+    pub kind: Option<ErrorKind>,
+    pub message: String,
 }
 
 impl GenericResponse<()> {
@@ -23,34 +55,42 @@ impl GenericResponse<()> {
             success,
             message,
             response: None,
+            error: None,
         }
     }
+}
 
-    /// Helper function to convert the response into an `anyhow::Result`
+impl<T: 'static> GenericResponse<T> {
+    /// Helper function to convert the response into an `anyhow::Result`, ignoring any contained
+    /// response
     pub fn into_result_empty(self) -> anyhow::Result<()> {
         self.into_result().map(|_| ())
     }
-}
 
-impl<T: 'static> GenericResponse<T> {
     /// Helper function to convert the response into an `anyhow::Result` with the contained response
     pub fn into_result(self) -> anyhow::Result<Option<T>> {
         if self.success {
             Ok(self.response)
         } else {
-            Err(anyhow::anyhow!(self.message))
+            Err(self.into_error())
         }
     }
 
     /// Helper function similar to [`into_result`](Self::into_response) but returns an error if the inner response is None
     pub fn into_result_required(self) -> anyhow::Result<T> {
-        match (self.success, self.response) {
-            (true, Some(response)) => Ok(response),
-            (true, None) => Err(anyhow::anyhow!(
-                "Request was successful but contained no response"
-            )),
-            (false, None) | (false, Some(_)) => Err(anyhow::anyhow!(self.message)),
+        if !self.success {
+            return Err(self.into_error());
         }
+        self.response
+            .ok_or_else(|| anyhow::anyhow!("Request was successful but contained no response"))
+    }
+
+    fn into_error(self) -> anyhow::Error {
+        ResponseError {
+            kind: self.error,
+            message: self.message,
+        }
+        .into()
     }
 }
 
@@ -75,6 +115,9 @@ pub struct VerificationResponse {
     pub message: String,
     pub needs_password_reset: bool,
     pub groups: BTreeSet<String>,
+    /// The POSIX account information for the user, if the user has any
+    #[serde(default)]
+    pub posix: Option<PosixAttributes>,
 }
 
 /// A request to change a user's password
@@ -84,3 +127,98 @@ pub struct PasswordChangeRequest {
     pub old_password: SecureString,
     pub new_password: SecureString,
 }
+
+/// A single password policy rule that a password failed to satisfy. These are returned as the
+/// response body when a request is rejected due to the password policy
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[serde(rename_all = "snake_case", tag = "rule")]
+pub enum PolicyViolation {
+    #[error("must be at least {min_length} characters")]
+    TooShort { min_length: usize },
+    #[error("must be at most {max_length} characters")]
+    TooLong { max_length: usize },
+    #[error("must contain a lowercase letter")]
+    MissingLowercase,
+    #[error("must contain an uppercase letter")]
+    MissingUppercase,
+    #[error("must contain a digit")]
+    MissingDigit,
+    #[error("must contain a symbol")]
+    MissingSymbol,
+    #[error("must not match the username")]
+    MatchesUsername,
+    #[error("is a commonly used password")]
+    Banned,
+}
+
+/// A request to look up a user's account information by either name or UID
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum UserLookupRequest {
+    Name(String),
+    Uid(u32),
+}
+
+/// A request to look up a group by either name or GID
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupLookupRequest {
+    Name(String),
+    Gid(u32),
+}
+
+/// A request for the IDs of the supplementary groups a user is a member of
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserGroupsRequest {
+    pub username: String,
+}
+
+/// Account information for a user, matching the fields of a passwd entry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PasswdEntry {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub gecos: String,
+    pub home: String,
+    pub shell: String,
+}
+
+/// Information about a group, matching the fields of a group entry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GroupEntry {
+    pub name: String,
+    pub gid: u32,
+    pub members: Vec<String>,
+}
+
+/// The health of a server and the things it depends on
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HealthResponse {
+    /// Whether the server is ready to handle requests. This is only true if every check passed
+    pub ready: bool,
+    /// The individual checks that were run
+    pub checks: Vec<HealthCheck>,
+}
+
+/// The result of checking a single dependency of the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// The name of the check, such as `nats_connection`
+    pub name: String,
+    pub healthy: bool,
+    /// Why the check failed, if it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl HealthCheck {
+    /// Creates a check that fails with the given message if `healthy` is false
+    pub fn new(name: impl Into<String>, healthy: bool, failure_message: &str) -> HealthCheck {
+        HealthCheck {
+            name: name.into(),
+            healthy,
+            message: (!healthy).then(|| failure_message.to_owned()),
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use std::{collections::BTreeSet, fmt::Debug, time::Duration};
 
-use bincode::{Decode, Encode};
+use bincode::{de::Decoder, error::DecodeError, Decode, Encode};
 
 pub mod admin;
 pub mod api;
@@ -10,13 +10,69 @@ pub use secure::*;
 use serde::{Deserialize, Serialize};
 
 /// Information necessary to verify a user's credentials and identify their groups
-#[derive(Debug, Clone, Encode, Decode)]
+#[derive(Debug, Clone, Default, Encode)]
 pub struct UserInfo {
     // NOTE(thomastaylor312): Because we're using Argon2, the salt is included in the hashed
     // password
     pub hashed_password: SecureString,
     pub password_reset: Option<PasswordResetPhase>,
     pub groups: BTreeSet<String>,
+    // NOTE: Any fields added after this point must be decoded with `decode_or_default` so we can
+    // still read data written by older versions
+    /// The number of failed login attempts since the last successful login or lockout
+    pub failed_attempts: u32,
+    /// If set, the user is locked out until the given time (as measured in seconds since the unix
+    /// epoch)
+    pub locked_until: Option<Duration>,
+    /// The POSIX account information for the user. This is only `None` for users created before
+    /// SNAS managed POSIX attributes
+    pub posix: Option<PosixAttributes>,
+}
+
+impl Decode for UserInfo {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(Self {
+            hashed_password: Decode::decode(decoder)?,
+            password_reset: Decode::decode(decoder)?,
+            groups: Decode::decode(decoder)?,
+            failed_attempts: decode_or_default(decoder)?,
+            locked_until: decode_or_default(decoder)?,
+            posix: decode_or_default(decoder)?,
+        })
+    }
+}
+
+bincode::impl_borrow_decode!(UserInfo);
+
+/// POSIX account information for a user, allowing SNAS to be the source of truth for system
+/// accounts
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize, Deserialize)]
+pub struct PosixAttributes {
+    pub uid: u32,
+    /// The ID of the user's primary group
+    pub gid: u32,
+    pub home: String,
+    pub shell: String,
+    /// The full name of the user or other descriptive information
+    pub gecos: String,
+}
+
+/// A group that exists independently of its members. Membership is stored on each user (see
+/// [`UserInfo::groups`]) so that verifying a user only needs a single lookup
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct GroupInfo {
+    pub gid: u32,
+    /// A human readable description of the group
+    pub description: String,
+}
+
+/// Decodes a field that was added after a type was first stored. Data written before the field
+/// existed just ends early, so in that case we return the default value instead
+fn decode_or_default<T: Decode + Default, D: Decoder>(decoder: &mut D) -> Result<T, DecodeError> {
+    match T::decode(decoder) {
+        Err(DecodeError::UnexpectedEnd { .. }) => Ok(T::default()),
+        res => res,
+    }
 }
 
 /// The current state of a user's password reset process
@@ -32,3 +88,34 @@ pub enum PasswordResetPhase {
     /// their password and will need to be reset again
     Locked,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The user format before any trailing fields were added
+    #[derive(Encode)]
+    struct OriginalUserInfo {
+        hashed_password: SecureString,
+        password_reset: Option<PasswordResetPhase>,
+        groups: BTreeSet<String>,
+    }
+
+    #[test]
+    fn test_decode_original_user_info() {
+        let original = OriginalUserInfo {
+            hashed_password: "hashed".into(),
+            password_reset: Some(PasswordResetPhase::Locked),
+            groups: ["foo".to_string()].into(),
+        };
+        let encoded = bincode::encode_to_vec(original, bincode::config::standard()).unwrap();
+        let (decoded, _): (UserInfo, _) =
+            bincode::decode_from_slice(&encoded, bincode::config::standard())
+                .expect("Should be able to decode data from before new fields were added");
+        assert_eq!(decoded.hashed_password, SecureString::from("hashed"));
+        assert_eq!(decoded.groups, ["foo".to_string()].into());
+        assert_eq!(decoded.failed_attempts, 0);
+        assert!(decoded.locked_until.is_none());
+        assert!(decoded.posix.is_none());
+    }
+}
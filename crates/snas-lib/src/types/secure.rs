@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 
 /// A string wrapper type that will not leak credentials in logs or printing while still able to be
 /// used as a string. Will zero out the memory when dropped.
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, Default, PartialEq, Eq)]
 pub struct SecureString(String);
 
 impl Drop for SecureString {
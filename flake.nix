@@ -86,6 +86,8 @@
             fileset = lib.fileset.unions [
               ./Cargo.toml
               ./Cargo.lock
+              (craneLib.fileset.commonCargoSources ./crates/nss-socket)
+              (craneLib.fileset.commonCargoSources ./crates/pam-common)
               (craneLib.fileset.commonCargoSources ./crates/pam-nats)
               (craneLib.fileset.commonCargoSources ./crates/pam-socket)
               (craneLib.fileset.commonCargoSources ./crates/snas-lib)
@@ -110,6 +112,15 @@
           src = fileSetForCrate ./.;
           doInstallCargoArtifacts = true;
         });
+        # glibc loads NSS modules as libnss_<name>.so.2, so link the library under that name
+        nss-snas = craneLib.buildPackage (individualCrateArgs // {
+          pname = "nss-snas";
+          cargoExtraArgs = "-p nss-socket";
+          src = fileSetForCrate ./.;
+          postInstall = ''
+            ln -s libnss_snas.so $out/lib/libnss_snas.so.2
+          '';
+        });
       in {
         checks = {
           # Build the crates as part of `nix flake check` for convenience
@@ -151,6 +162,9 @@
               trap "kill $NATS_SERVER_PID" EXIT
             '';
           });
+        } // lib.optionalAttrs (!pkgs.stdenv.isDarwin) {
+          # NSS modules are only loaded by glibc
+          inherit nss-snas;
         };
 
         packages = {
@@ -159,6 +173,7 @@
             snas-lib;
           default = snas;
         } // lib.optionalAttrs (!pkgs.stdenv.isDarwin) {
+          inherit nss-snas;
           workspace-llvm-coverage = craneLibLLvmTools.cargoLlvmCov
             (commonArgs // { inherit cargoArtifacts; });
         };
@@ -136,6 +136,8 @@ pub async fn assert_user_server<T: snas_lib::clients::UserClient>(
         password: "supersecure".into(),
         groups: ["foo".into()].into(),
         force_password_change: false,
+        prehashed: false,
+        posix: Default::default(),
     };
     handlers
         .add(user_req.clone())
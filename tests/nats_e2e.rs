@@ -2,6 +2,7 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use futures::future::Either;
 use futures::FutureExt;
+use snas_lib::admin::{ConflictPolicy, PosixAttributesRequest};
 use snas_lib::clients::NatsClient;
 use snas_lib::{PasswordResetPhase, UserInfo};
 
@@ -63,7 +64,13 @@ async fn test_admin_api() {
     use snas_lib::clients::{AdminClient, GetUserClient};
 
     admin_client
-        .add_user("foo", "easy123".into(), ["foo".into()].into(), false)
+        .add_user(
+            "foo",
+            "easy123".into(),
+            ["foo".into()].into(),
+            false,
+            Default::default(),
+        )
         .await
         .expect("Should be able to add user");
 
@@ -84,6 +91,7 @@ async fn test_admin_api() {
         user.password_change_phase.is_none(),
         "User should not be locked"
     );
+    assert!(user.posix.is_some(), "User should have POSIX attributes");
 
     // Getting a non-existent user should error
     admin_client
@@ -93,7 +101,13 @@ async fn test_admin_api() {
 
     // Add one more user for testing
     admin_client
-        .add_user("bar", "easy123".into(), ["bar".into()].into(), false)
+        .add_user(
+            "bar",
+            "easy123".into(),
+            ["bar".into()].into(),
+            false,
+            Default::default(),
+        )
         .await
         .expect("Should be able to add user");
 
@@ -140,6 +154,47 @@ async fn test_admin_api() {
         "User should be in the reset phase",
     );
 
+    // Test setting a user's password directly, which clears the reset
+    admin_client
+        .set_password("bar", "servicepassword".into(), false)
+        .await
+        .expect("Should be able to set password");
+    let user = admin_client
+        .get_user("bar")
+        .await
+        .expect("Should be able to get user");
+    assert!(
+        user.password_change_phase.is_none(),
+        "Setting the password should clear the reset"
+    );
+    admin_client
+        .set_password("bar", "bar".into(), false)
+        .await
+        .expect_err("Should not be able to set a password that breaks the policy");
+
+    // Test setting POSIX attributes
+    let posix = admin_client
+        .set_attributes(
+            "bar",
+            PosixAttributesRequest {
+                shell: Some("/bin/zsh".into()),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("Should be able to set attributes");
+    assert_eq!(posix.shell, "/bin/zsh", "Should have the updated shell");
+
+    // Test unlocking a user
+    admin_client
+        .unlock_user("bar")
+        .await
+        .expect("Should be able to unlock user");
+    admin_client
+        .unlock_user("foo")
+        .await
+        .expect_err("Should not be able to unlock deleted user");
+
     // Test adding groups to a user
     let add_groups_result = admin_client
         .add_groups("bar", ["group1".to_string(), "group2".to_string()].into())
@@ -185,6 +240,78 @@ async fn test_admin_api() {
         ["bar".into(), "group2".into()].into(),
         "Should have the correct groups after delete"
     );
+
+    // Test group management
+    let group = admin_client
+        .add_group("group2", Some(20000), "The second group".to_string())
+        .await
+        .expect("Should be able to add a group");
+    assert_eq!(group.gid, 20000, "Should use the requested GID");
+    assert_eq!(
+        group.members,
+        ["bar".into()].into(),
+        "Existing members should be included in a new group"
+    );
+    admin_client
+        .add_group("group2", None, String::new())
+        .await
+        .expect_err("Should not be able to add a duplicate group");
+    assert_eq!(
+        admin_client
+            .list_groups()
+            .await
+            .expect("Should be able to list groups"),
+        ["group2"]
+    );
+    let group = admin_client
+        .get_group("group2")
+        .await
+        .expect("Should be able to get group");
+    assert_eq!(group.description, "The second group");
+    assert_eq!(
+        admin_client
+            .group_members("group2")
+            .await
+            .expect("Should be able to get group members"),
+        ["bar".into()].into()
+    );
+
+    admin_client
+        .remove_group("group2")
+        .await
+        .expect("Should be able to remove group");
+    admin_client
+        .get_group("group2")
+        .await
+        .expect_err("Should not be able to get a removed group");
+    let user = admin_client
+        .get_user("bar")
+        .await
+        .expect("Should be able to get user");
+    assert_eq!(
+        user.groups,
+        ["bar".into()].into(),
+        "Removed group should be removed from members"
+    );
+
+    let exported = admin_client
+        .export_directory()
+        .await
+        .expect("Should be able to export the directory");
+    assert!(exported
+        .users
+        .iter()
+        .all(|user| user.password_hash.is_some()));
+    admin_client
+        .import_directory(exported.clone(), ConflictPolicy::Fail, true)
+        .await
+        .expect_err("Should not be able to import existing users with the fail policy");
+    let summary = admin_client
+        .import_directory(exported.clone(), ConflictPolicy::Skip, false)
+        .await
+        .expect("Should be able to import the directory");
+    assert_eq!(summary.users.skipped, exported.users.len());
+    assert_eq!(summary.users.created, 0);
 }
 
 #[tokio::test(flavor = "multi_thread")]
@@ -228,7 +355,13 @@ async fn test_password_reset_flow() {
 
     // Add a user for the test
     client
-        .add_user("foo", "easy123".into(), ["foo".into()].into(), true)
+        .add_user(
+            "foo",
+            "easy123".into(),
+            ["foo".into()].into(),
+            true,
+            Default::default(),
+        )
         .await
         .expect("Should be able to add user");
 
@@ -45,10 +45,10 @@ ls -l "${SOCKET_PATH}" || (echo "Socket not created" && exit 1)
 
 echo "Seeding SNAS users via CLI..."
 SNAS_CLI=(snas --nats-server "${NATS_HOST}" --nats-port "${NATS_PORT}")
-"${SNAS_CLI[@]}" admin add-user \
-  --username foo --password supersecure --group testers
-"${SNAS_CLI[@]}" admin add-user \
-  --username bar --password temp123 --force-reset
+echo supersecure | "${SNAS_CLI[@]}" admin add-user \
+  --username foo --password-stdin --group testers
+echo temp123 | "${SNAS_CLI[@]}" admin add-user \
+  --username bar --password-stdin --force-reset
 
 echo "Test 1: Successful auth for foo"
 /usr/local/bin/pam_test snas foo supersecure
@@ -1,4 +1,6 @@
-use snas_lib::clients::SocketClient;
+use snas_lib::admin::UserAddRequest;
+use snas_lib::api::{GroupLookupRequest, UserLookupRequest};
+use snas_lib::clients::{LookupClient, SocketClient};
 
 pub mod helpers;
 
@@ -15,3 +17,69 @@ async fn test_user_api() {
 
     helpers::assert_user_server(user_client, &bundle.handlers).await;
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_lookup_api() {
+    let _ = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .try_init();
+    let bundle = helpers::TestSocketBundle::new("socket_lookup_api").await;
+
+    bundle
+        .handlers
+        .add(UserAddRequest {
+            username: "foo".into(),
+            password: "supersecure".into(),
+            groups: ["foo".into()].into(),
+            force_password_change: false,
+            prehashed: false,
+            posix: Default::default(),
+        })
+        .await
+        .expect("Should have been able to add a user");
+
+    let client = SocketClient::new(&bundle.socket_path)
+        .await
+        .expect("Should be able to create a client");
+
+    let user = client
+        .lookup_user(UserLookupRequest::Name("foo".into()))
+        .await
+        .expect("Should be able to look up user by name");
+    assert_eq!(user.home, "/home/foo", "Should have the default home");
+    let by_uid = client
+        .lookup_user(UserLookupRequest::Uid(user.uid))
+        .await
+        .expect("Should be able to look up user by UID");
+    assert_eq!(by_uid, user, "Lookups should return the same user");
+    client
+        .lookup_user(UserLookupRequest::Name("bar".into()))
+        .await
+        .expect_err("Should not find a non-existent user");
+
+    let group = client
+        .lookup_group(GroupLookupRequest::Gid(user.gid))
+        .await
+        .expect("Should be able to look up the user private group");
+    assert_eq!(group.name, "foo");
+
+    assert_eq!(
+        client
+            .enumerate_users()
+            .await
+            .expect("Should be able to enumerate users"),
+        vec![user]
+    );
+    assert_eq!(
+        client
+            .enumerate_groups()
+            .await
+            .expect("Should be able to enumerate groups"),
+        vec![group]
+    );
+    assert!(client
+        .user_group_ids("foo")
+        .await
+        .expect("Should be able to get user groups")
+        .is_empty());
+}
@@ -1,4 +1,4 @@
-use snas_lib::{storage::CredStore, UserInfo};
+use snas_lib::{storage::CredStore, GroupInfo, UserInfo};
 
 pub mod helpers;
 
@@ -17,6 +17,7 @@ async fn test_crud() {
         hashed_password: "bar".into(),
         password_reset: None,
         groups: ["foo".into()].into(),
+        ..Default::default()
     };
 
     store
@@ -63,6 +64,7 @@ async fn test_crud() {
         hashed_password: "baz".into(),
         password_reset: None,
         groups: ["foo".into()].into(),
+        ..Default::default()
     };
 
     store
@@ -107,11 +109,13 @@ async fn test_initialization() {
         hashed_password: "bar".into(),
         password_reset: None,
         groups: ["foo".into()].into(),
+        ..Default::default()
     };
     let bar_user = UserInfo {
         hashed_password: "baz".into(),
         password_reset: None,
         groups: ["foo".into()].into(),
+        ..Default::default()
     };
     // Insert some data
     store
@@ -167,6 +171,7 @@ async fn test_sync() {
         hashed_password: "bar".into(),
         password_reset: None,
         groups: ["foo".into()].into(),
+        ..Default::default()
     };
     main_store
         .put_user("foo".into(), foo_user.clone())
@@ -214,3 +219,64 @@ async fn test_sync() {
         "User should not exist in reflected store"
     );
 }
+
+#[tokio::test]
+async fn test_groups() {
+    let bucket = helpers::get_store("storage_groups").await;
+    let store = CredStore::new(bucket.clone())
+        .await
+        .expect("Should have been able to initialize a CredStore");
+    let reflected_store = CredStore::new(bucket.clone())
+        .await
+        .expect("Should have been able to initialize a CredStore");
+
+    let group = GroupInfo {
+        gid: 10000,
+        description: "Developers".to_string(),
+    };
+    assert!(
+        store
+            .create_group("devs".into(), group.clone())
+            .await
+            .expect("Should be able to create a group"),
+        "Group should be created"
+    );
+    assert!(
+        !store
+            .create_group("devs".into(), group.clone())
+            .await
+            .expect("Should be able to attempt to create a group"),
+        "Group should not be created twice"
+    );
+    assert_eq!(store.get_group("devs").await, Some(group.clone()));
+    assert_eq!(store.list_groups().await.unwrap(), ["devs"]);
+    assert!(
+        store.list_users().await.unwrap().is_empty(),
+        "Groups should not be listed as users"
+    );
+
+    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    assert_eq!(
+        reflected_store.get_group("devs").await,
+        Some(group.clone()),
+        "Group should exist in reflected store"
+    );
+
+    // Make sure groups are loaded on initialization
+    let initialized_store = CredStore::new(bucket)
+        .await
+        .expect("Should have been able to initialize a CredStore");
+    assert_eq!(initialized_store.get_group("devs").await, Some(group));
+
+    store
+        .delete_group("devs")
+        .await
+        .expect("Should be able to delete a group");
+    assert!(store.get_group("devs").await.is_none());
+
+    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    assert!(
+        reflected_store.get_group("devs").await.is_none(),
+        "Group should not exist in reflected store"
+    );
+}